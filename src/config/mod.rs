@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gilrs::Button as GilButton;
+use serde::{Deserialize, Serialize};
+
+use crate::control::{ControlConfig, ProfileRule};
+use crate::controller_abs::{GamepadAxis, GamepadButton, OutputMapping};
+use crate::controller_in::balance_board::{BalanceBoardConfig, BalanceBoardSource};
+use crate::controller_in::device_filter::DeviceFilter;
+use crate::controller_in::gestures::{Gesture, GestureConfig, GestureSource};
+use crate::controller_in::ir_pointer::IrPointerConfig;
+use crate::controller_in::layers::{Layer, LayerMode};
+use crate::controller_in::network::NetworkConfig;
+use crate::controller_in::WiiButton;
+use crate::controller_out::dsu::DsuConfig;
+use crate::controller_out::hid_gamepad::HidGamepadConfig;
+use crate::controller_out::keyboard_mouse::KeyboardMouseConfig;
+use crate::controller_out::usbip::UsbIpConfig;
+use crate::controller_out::xid::XidConfig;
+#[cfg(feature = "web")]
+use crate::web::WebConfig;
+
+// Top level mapping file, every section is optional.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub devices: DeviceFilter,
+    pub gilrs: GilRsConfig,
+    // Wii remotes and nunchuks for `--input wii`.
+    pub wii: WiiConfig,
+    // Wii balance boards for `--input balance_board`.
+    pub balance_board: BalanceBoardSection,
+    // Report layout for `--output hid`.
+    pub hid_gamepad: HidGamepadConfig,
+    // Keys, mouse buttons and mouse movement for `--output keyboard`.
+    pub keyboard_mouse: KeyboardMouseConfig,
+    // Analog face button sources for `--output xid`.
+    pub xid: XidConfig,
+    // Where `--input network` listens.
+    pub network: NetworkConfig,
+    // Where `--dsu` serves motion to emulators.
+    pub dsu: DsuConfig,
+    // Where `--output usbip` accepts hosts.
+    pub usbip: UsbIpConfig,
+    // The JSON-RPC socket, see `control`.
+    pub control: ControlConfig,
+    // Numbered profiles to switch between with a chord or by the connected devices.
+    pub profiles: Vec<ProfileRule>,
+    // Where `--web` serves the browser UI.
+    #[cfg(feature = "web")]
+    pub web: WebConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct GilRsConfig {
+    // Overrides for the default button layout, `null` leaves a button unmapped.
+    pub buttons: HashMap<GilButton, Option<OutputMapping>>,
+    // Buttons gilrs doesn't know about, keyed by their code as printed in the event log, e.g. "KEY(704)".
+    pub extra_buttons: HashMap<String, OutputMapping>,
+    // SDL2 gamecontrollerdb.txt lines, for pads gilrs doesn't map properly on its own.
+    pub sdl_mappings: Vec<String>,
+    // Files in gamecontrollerdb.txt format, e.g. a checkout of SDL_GameControllerDB.
+    pub sdl_mapping_files: Vec<PathBuf>,
+    // Fraction of the sticks' travel around the center that reads as centered.
+    pub stick_deadzone: f64,
+    // 1 is linear, higher values give finer control near the center.
+    pub stick_curve: f64,
+    // Shift layers, later ones win where active layers map the same button.
+    pub layers: Vec<GilRsLayer>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GilRsLayer {
+    // Only switches the layer, it isn't mapped to anything itself.
+    pub modifier: GilButton,
+    #[serde(default)]
+    pub mode: LayerMode,
+    // Same as `GilRsConfig::buttons`, on top of them while the layer is active.
+    #[serde(default)]
+    pub buttons: HashMap<GilButton, Option<OutputMapping>>,
+    #[serde(default)]
+    pub extra_buttons: HashMap<String, Option<OutputMapping>>,
+}
+
+impl Default for GilRsConfig {
+    fn default() -> Self {
+        GilRsConfig {
+            buttons: HashMap::new(),
+            extra_buttons: HashMap::new(),
+            sdl_mappings: vec![],
+            sdl_mapping_files: vec![],
+            stick_deadzone: 0.0,
+            stick_curve: 1.0,
+            layers: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WiiConfig {
+    // Overrides for the default button layout, `null` leaves a button unmapped.
+    pub buttons: HashMap<WiiButton, Option<OutputMapping>>,
+    // Axes the nunchuk stick drives, `null` leaves it unmapped.
+    pub nunchuk_stick: Option<WiiStick>,
+    // Fraction of the nunchuk stick's travel around the center that reads as centered.
+    pub stick_deadzone: f64,
+    // Tracks the sensor bar through the IR camera onto a pair of axes.
+    pub ir_pointer: Option<WiiIrPointer>,
+    // Motions that briefly press an output, e.g. shaking the remote.
+    pub gestures: Vec<WiiGesture>,
+    // Shift layers, later ones win where active layers map the same button.
+    pub layers: Vec<WiiLayer>,
+    // Forward the remote's accelerometer as motion, e.g. for `--dsu`.
+    pub motion: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WiiLayer {
+    // Only switches the layer, it isn't mapped to anything itself.
+    pub modifier: WiiButton,
+    #[serde(default)]
+    pub mode: LayerMode,
+    // Same as `WiiConfig::buttons`, on top of them while the layer is active.
+    #[serde(default)]
+    pub buttons: HashMap<WiiButton, Option<OutputMapping>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WiiStick {
+    pub x: GamepadAxis,
+    pub y: GamepadAxis,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct WiiIrPointer {
+    pub x_axis: GamepadAxis,
+    pub y_axis: GamepadAxis,
+    #[serde(flatten)]
+    pub pointer: IrPointerConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WiiGesture {
+    #[serde(default)]
+    pub source: GestureSource,
+    pub gesture: Gesture,
+    pub output: OutputMapping,
+    #[serde(flatten)]
+    pub config: GestureConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct BalanceBoardSection {
+    #[serde(flatten)]
+    pub board: BalanceBoardConfig,
+    // Replaces the default of leaning onto the left stick.
+    pub mappings: Vec<BalanceBoardMapping>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceBoardMapping {
+    pub source: BalanceBoardSource,
+    pub output: OutputMapping,
+}
+
+impl Default for BalanceBoardSection {
+    fn default() -> Self {
+        BalanceBoardSection {
+            board: BalanceBoardConfig::default(),
+            mappings: vec![
+                BalanceBoardMapping {
+                    source: BalanceBoardSource::LeanX,
+                    output: OutputMapping::Axis(GamepadAxis::LeftJoystickX),
+                },
+                BalanceBoardMapping {
+                    source: BalanceBoardSource::LeanY,
+                    output: OutputMapping::Axis(GamepadAxis::LeftJoystickY),
+                },
+            ],
+        }
+    }
+}
+
+impl Default for WiiConfig {
+    fn default() -> Self {
+        WiiConfig {
+            buttons: HashMap::new(),
+            nunchuk_stick: Some(WiiStick {
+                x: GamepadAxis::LeftJoystickX,
+                y: GamepadAxis::LeftJoystickY,
+            }),
+            stick_deadzone: 0.05,
+            ir_pointer: None,
+            gestures: vec![],
+            layers: vec![],
+            motion: false,
+        }
+    }
+}
+
+impl Default for WiiIrPointer {
+    fn default() -> Self {
+        WiiIrPointer {
+            x_axis: GamepadAxis::RightJoystickX,
+            y_axis: GamepadAxis::RightJoystickY,
+            pointer: IrPointerConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        Config::from_json(&contents)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Config, String> {
+        let config: Config =
+            serde_json::from_str(contents).map_err(|err| format!("Invalid config: {}", err))?;
+        config.hid_gamepad.validate()?;
+        for line in config.gilrs.sdl_mappings.iter() {
+            validate_sdl_mapping(line)?;
+        }
+        config.wii.validate()?;
+        for profile in config.profiles.iter() {
+            profile.validate()?;
+        }
+        Ok(config)
+    }
+}
+
+// Checks the shape of a mapping line, "<guid>,<name>,<key>:<value>,...".
+pub fn validate_sdl_mapping(line: &str) -> Result<(), String> {
+    let mut fields = line.trim().split(',');
+    let guid = fields.next().unwrap_or_default();
+    if guid.len() != 32 || !guid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid SDL mapping GUID: {}", guid));
+    }
+    if fields.next().unwrap_or_default().is_empty() {
+        return Err(format!("SDL mapping without a name: {}", line));
+    }
+    let mut mapped = fields.filter(|field| !field.is_empty()).peekable();
+    if mapped.peek().is_none() || !mapped.all(|field| field.contains(':')) {
+        return Err(format!("Invalid SDL mapping fields: {}", line));
+    }
+    Ok(())
+}
+
+impl GilRsConfig {
+    // All SDL mappings from the config and the mapping files, one per line. Unreadable files
+    // are skipped so the rest still apply.
+    pub fn sdl_mappings(&self) -> String {
+        let mut lines = self.sdl_mappings.clone();
+        for path in self.sdl_mapping_files.iter() {
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    log_error!("Skipping SDL mappings in {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            for line in contents.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match validate_sdl_mapping(line) {
+                    Ok(()) => lines.push(line.to_string()),
+                    Err(err) => log_error!("Skipping line in {}: {}", path.display(), err),
+                }
+            }
+        }
+        lines.join("\n")
+    }
+
+    // Default layout with the overrides from the config applied.
+    pub fn button_mappings(&self) -> HashMap<GilButton, OutputMapping> {
+        let mut mappings = default_gilrs_buttons();
+        for (button, mapping) in self.buttons.iter() {
+            match mapping {
+                Some(mapping) => mappings.insert(*button, mapping.clone()),
+                None => mappings.remove(button),
+            };
+        }
+        mappings
+    }
+}
+
+impl WiiConfig {
+    // Default layout with the overrides from the config applied.
+    pub fn button_mappings(&self) -> HashMap<WiiButton, OutputMapping> {
+        let mut mappings = default_wii_buttons();
+        for (button, mapping) in self.buttons.iter() {
+            match mapping {
+                Some(mapping) => mappings.insert(*button, mapping.clone()),
+                None => mappings.remove(button),
+            };
+        }
+        mappings
+    }
+
+    pub fn button_layers(&self) -> Vec<Layer<WiiButton>> {
+        self.layers
+            .iter()
+            .map(|layer| Layer {
+                modifier: layer.modifier,
+                mode: layer.mode,
+                buttons: layer.buttons.clone(),
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for gesture in self.gestures.iter() {
+            if !gesture.source.supports(gesture.gesture) {
+                return Err(format!(
+                    "{:?} can't be detected on the {:?}, it has no z axis",
+                    gesture.gesture, gesture.source
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn default_gilrs_buttons() -> HashMap<GilButton, OutputMapping> {
+    let button = OutputMapping::Button;
+    HashMap::from([
+        (GilButton::South, button(GamepadButton::South)),
+        (GilButton::East, button(GamepadButton::East)),
+        (GilButton::North, button(GamepadButton::North)),
+        (GilButton::West, button(GamepadButton::West)),
+        (
+            GilButton::LeftTrigger,
+            button(GamepadButton::LeftShoulderButton),
+        ),
+        (
+            GilButton::RightTrigger,
+            button(GamepadButton::RightShoulderButton),
+        ),
+        // Analog on most pads, digital ones just report full scale.
+        (
+            GilButton::LeftTrigger2,
+            OutputMapping::Axis(GamepadAxis::LeftTrigger),
+        ),
+        (
+            GilButton::RightTrigger2,
+            OutputMapping::Axis(GamepadAxis::RightTrigger),
+        ),
+        (GilButton::Select, button(GamepadButton::Select)),
+        (GilButton::Start, button(GamepadButton::Start)),
+        (GilButton::Mode, button(GamepadButton::Mode)),
+        (GilButton::LeftThumb, button(GamepadButton::LeftThumb)),
+        (GilButton::RightThumb, button(GamepadButton::RightThumb)),
+        (GilButton::DPadUp, button(GamepadButton::DPadUp)),
+        (GilButton::DPadDown, button(GamepadButton::DPadDown)),
+        (GilButton::DPadLeft, button(GamepadButton::DPadLeft)),
+        (GilButton::DPadRight, button(GamepadButton::DPadRight)),
+        // C and Z have no 360 counterpart, map them in the config if needed.
+    ])
+}
+
+// Laid out for Rocket League, remote pointing at the screen with the nunchuk in the other hand.
+pub fn default_wii_buttons() -> HashMap<WiiButton, OutputMapping> {
+    let button = OutputMapping::Button;
+    HashMap::from([
+        // Jump, throttle, ball cam
+        (WiiButton::A, button(GamepadButton::South)),
+        (WiiButton::B, OutputMapping::Axis(GamepadAxis::RightTrigger)),
+        (WiiButton::One, button(GamepadButton::North)),
+        (WiiButton::Two, button(GamepadButton::DPadDown)),
+        (WiiButton::Plus, button(GamepadButton::Start)),
+        (WiiButton::Minus, button(GamepadButton::Select)),
+        (WiiButton::Home, button(GamepadButton::Mode)),
+        (WiiButton::Up, button(GamepadButton::DPadUp)),
+        // Boost
+        (WiiButton::Down, button(GamepadButton::East)),
+        (WiiButton::Left, button(GamepadButton::DPadLeft)),
+        (WiiButton::Right, button(GamepadButton::DPadRight)),
+        // Handbrake and brake
+        (WiiButton::C, button(GamepadButton::West)),
+        (WiiButton::Z, OutputMapping::Axis(GamepadAxis::LeftTrigger)),
+    ])
+}
+
+#[test]
+fn test_gilrs_config_overrides() {
+    let config = Config::from_json(
+        r#"{
+            "gilrs": {
+                "buttons": {
+                    "C": {"Button": "LeftThumb"},
+                    "LeftTrigger2": {"Button": "LeftShoulderButton"},
+                    "Mode": null
+                },
+                "extra_buttons": {"KEY(704)": {"Axis": "RightTrigger"}}
+            }
+        }"#,
+    )
+    .unwrap();
+    let mappings = config.gilrs.button_mappings();
+    assert_eq!(
+        mappings.get(&GilButton::C),
+        Some(&OutputMapping::Button(GamepadButton::LeftThumb))
+    );
+    assert_eq!(
+        mappings.get(&GilButton::LeftTrigger2),
+        Some(&OutputMapping::Button(GamepadButton::LeftShoulderButton))
+    );
+    assert_eq!(mappings.get(&GilButton::Mode), None);
+    assert_eq!(
+        mappings.get(&GilButton::RightTrigger2),
+        Some(&OutputMapping::Axis(GamepadAxis::RightTrigger))
+    );
+    assert_eq!(
+        config.gilrs.extra_buttons.get("KEY(704)"),
+        Some(&OutputMapping::Axis(GamepadAxis::RightTrigger))
+    );
+
+    // Empty config keeps the defaults
+    let empty = Config::from_json("{}").unwrap();
+    assert_eq!(empty.gilrs.button_mappings(), default_gilrs_buttons());
+    assert!(Config::from_json(r#"{"gilrs": {"buttons": {"Nope": null}}}"#).is_err());
+
+    let layer = r#"{"modifier": "C", "buttons": {"DPadUp": {"Button": "LeftThumb"}}}"#;
+    let layered = Config::from_json(&format!(r#"{{"gilrs": {{"layers": [{}]}}}}"#, layer)).unwrap();
+    assert_eq!(layered.gilrs.layers[0].mode, LayerMode::Hold);
+    // A layer needs something to switch it.
+    assert!(Config::from_json(r#"{"gilrs": {"layers": [{"mode": "Toggle"}]}}"#).is_err());
+}
+
+#[test]
+fn test_sdl_mappings() {
+    let line = "030000005e0400008e02000010010000,Odd Pad,a:b0,b:b1,lefttrigger:a5,righty:-a4,platform:Linux,";
+    let config =
+        Config::from_json(&format!(r#"{{"gilrs": {{"sdl_mappings": ["{}"]}}}}"#, line)).unwrap();
+    assert_eq!(config.gilrs.sdl_mappings(), line);
+
+    assert!(validate_sdl_mapping("xbox,Pad,a:b0").is_err());
+    assert!(validate_sdl_mapping("030000005e0400008e02000010010000,,a:b0").is_err());
+    assert!(validate_sdl_mapping("030000005e0400008e02000010010000,Pad,b0").is_err());
+    assert!(Config::from_json(r#"{"gilrs": {"sdl_mappings": ["nope"]}}"#).is_err());
+
+    let missing = GilRsConfig {
+        sdl_mappings: vec![line.to_string()],
+        sdl_mapping_files: vec![PathBuf::from("/nonexistent/gamecontrollerdb.txt")],
+        ..Default::default()
+    };
+    assert_eq!(missing.sdl_mappings(), line);
+}
+
+#[test]
+fn test_wii_config() {
+    use crate::controller_in::ir_pointer::IrLostBehaviour;
+
+    let config = Config::from_json(
+        r#"{
+            "wii": {
+                "buttons": {"B": {"Button": "RightShoulderButton"}, "Home": null},
+                "nunchuk_stick": null,
+                "ir_pointer": {"mode": "Relative", "on_lost": "Tilt"},
+                "motion": true
+            }
+        }"#,
+    )
+    .unwrap();
+    let mappings = config.wii.button_mappings();
+    assert_eq!(
+        mappings.get(&WiiButton::B),
+        Some(&OutputMapping::Button(GamepadButton::RightShoulderButton))
+    );
+    assert_eq!(mappings.get(&WiiButton::Home), None);
+    assert_eq!(config.wii.nunchuk_stick, None);
+    assert!(config.wii.motion);
+    let ir_pointer = config.wii.ir_pointer.unwrap();
+    assert_eq!(ir_pointer.x_axis, GamepadAxis::RightJoystickX);
+    assert_eq!(ir_pointer.pointer.on_lost, IrLostBehaviour::Tilt);
+    assert_eq!(ir_pointer.pointer.range, IrPointerConfig::default().range);
+
+    let gestures = Config::from_json(
+        r#"{
+            "wii": {
+                "gestures": [
+                    {"gesture": "Shake", "output": {"Button": "North"}, "cooldown_ms": 500},
+                    {"source": "Nunchuk", "gesture": "Thrust", "output": {"Button": "West"}}
+                ]
+            }
+        }"#,
+    )
+    .unwrap();
+    let shake = &gestures.wii.gestures[0];
+    assert_eq!(shake.source, GestureSource::Remote);
+    assert_eq!(shake.config.cooldown_ms, 500);
+    assert_eq!(shake.config.pulse_ms, GestureConfig::default().pulse_ms);
+    assert!(Config::from_json(
+        r#"{"wii": {"gestures": [{"source": "Nunchuk", "gesture": "Twist", "output": {"Button": "West"}}]}}"#
+    )
+    .is_err());
+
+    let layers = Config::from_json(
+        r#"{
+            "wii": {
+                "layers": [
+                    {"modifier": "C", "buttons": {"Up": {"Button": "LeftShoulderButton"}, "Down": null}}
+                ]
+            }
+        }"#,
+    )
+    .unwrap();
+    let layer = &layers.wii.button_layers()[0];
+    assert_eq!(layer.modifier, WiiButton::C);
+    assert_eq!(layer.mode, LayerMode::Hold);
+    assert_eq!(
+        layer.buttons.get(&WiiButton::Up),
+        Some(&Some(OutputMapping::Button(
+            GamepadButton::LeftShoulderButton
+        )))
+    );
+    assert_eq!(layer.buttons.get(&WiiButton::Down), Some(&None));
+
+    let empty = Config::from_json("{}").unwrap();
+    assert_eq!(empty.wii.button_mappings(), default_wii_buttons());
+    assert!(empty.wii.nunchuk_stick.is_some());
+    assert!(!empty.wii.motion);
+    assert!(Config::from_json(r#"{"wii": {"buttons": {"Nope": null}}}"#).is_err());
+}
+
+#[test]
+fn test_balance_board_config() {
+    let config = Config::from_json(
+        r#"{
+            "balance_board": {
+                "lean_range": 0.4,
+                "mappings": [
+                    {"source": "LeanX", "output": {"Axis": "LeftJoystickX"}},
+                    {"source": {"WeightBelow": 10.0}, "output": {"Button": "South"}}
+                ]
+            }
+        }"#,
+    )
+    .unwrap();
+    let board = config.balance_board;
+    assert_eq!(board.board.lean_range, 0.4);
+    assert_eq!(
+        board.board.tare_samples,
+        BalanceBoardConfig::default().tare_samples
+    );
+    assert_eq!(board.mappings.len(), 2);
+    assert_eq!(
+        board.mappings[1].source,
+        BalanceBoardSource::WeightBelow(10.0)
+    );
+
+    let empty = Config::from_json("{}").unwrap();
+    assert_eq!(empty.balance_board, BalanceBoardSection::default());
+}
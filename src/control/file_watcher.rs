@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use futures_util::StreamExt;
+use inotify::{Inotify, WatchDescriptor, WatchMask, Watches};
+use tokio::sync::mpsc;
+
+// Tells which files changed on disk, for picking up mapping edits without a restart.
+// Watches directories rather than the files themselves, editors often save by replacing them.
+pub struct FileWatcher {
+    watches: Watches,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    changes: mpsc::UnboundedReceiver<(WatchDescriptor, OsString)>,
+}
+
+impl FileWatcher {
+    // Needs to be called from within the tokio runtime.
+    pub fn new() -> Result<FileWatcher, String> {
+        let mut events = Inotify::init()
+            .and_then(|inotify| inotify.into_event_stream([0u8; 4096]))
+            .map_err(|err| format!("Could not watch files: {}", err))?;
+        let watches = events.watches();
+        let (sender, changes) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    // The watcher is gone.
+                    _ = sender.closed() => return,
+                };
+                match event {
+                    Some(Ok(event)) => {
+                        if let Some(name) = event.name {
+                            let _ = sender.send((event.wd, name));
+                        }
+                    }
+                    Some(Err(err)) => {
+                        log_error!("Stopped watching files: {}", err);
+                        return;
+                    }
+                    None => return,
+                }
+            }
+        });
+        Ok(FileWatcher {
+            watches,
+            dirs: HashMap::new(),
+            changes,
+        })
+    }
+
+    pub fn watch(&mut self, path: &Path) -> Result<(), String> {
+        // Kept as given, so changed paths compare equal to the watched one.
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let watched = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let wd = self
+            .watches
+            .add(watched, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+            .map_err(|err| format!("Could not watch {}: {}", watched.display(), err))?;
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    // Files written or moved into a watched directory since the last call, once each.
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        while let Ok((wd, name)) = self.changes.try_recv() {
+            if let Some(dir) = self.dirs.get(&wd) {
+                let path = dir.join(name);
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[tokio::test]
+async fn test_file_watcher() {
+    use std::fs;
+
+    let dir = std::env::temp_dir().join(format!("cursed_controls_watch_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mapping = dir.join("mapping.json");
+    fs::write(&mapping, "{}").unwrap();
+
+    let mut watcher = FileWatcher::new().unwrap();
+    watcher.watch(&mapping).unwrap();
+    // Saved the way most editors do it, to a temporary file renamed over the original.
+    fs::write(dir.join("mapping.json.tmp"), "{ }").unwrap();
+    fs::rename(dir.join("mapping.json.tmp"), &mapping).unwrap();
+    let mut changed = vec![];
+    for _ in 0..100 {
+        changed.extend(watcher.changed());
+        if changed.contains(&mapping) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(changed.contains(&mapping));
+    let _ = fs::remove_dir_all(&dir);
+}
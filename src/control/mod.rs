@@ -0,0 +1,977 @@
+pub mod file_watcher;
+
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use strum::IntoEnumIterator;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::config::Config;
+use crate::controller_abs::{ControllerInput, Feedback, Gamepad, GamepadAxis, GamepadButton};
+use crate::controller_in::device_filter::{DeviceInfo, DeviceRule};
+use crate::controller_in::ConfigurableInput;
+use file_watcher::FileWatcher;
+
+// JSON-RPC 2.0 over a Unix socket, one message per line, for scripts and UIs to look at and
+// change a running instance. Methods:
+//
+//   list_devices               the input and outputs in use
+//   get_state                  the current `Gamepad`, see `gamepad_json`
+//   get_mapping                the active profile and mapping file contents
+//   list_profiles              mapping files `switch_profile` can load
+//   switch_profile {"name"}    loads `<name>.json` from the profile directory
+//   save_profile {"name", "config"}
+//                              writes a profile, applying it if it's the active one
+//   reload_config              reads the active mapping file again
+//   calibrate                  re-centers sticks, tares balance boards
+//   subscribe / unsubscribe    "state" notifications whenever the `Gamepad` changes
+//
+// Try it with `socat - UNIX-CONNECT:/run/cursed_controls.sock`.
+//
+// The `profiles` section of the mapping file also switches profiles without the socket: by a
+// chord held on the pad, or when the connected devices match a profile's `devices` rules.
+// With `control.watch` the active mapping file is reloaded whenever it's saved.
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Implementation defined range, for mapping files that don't load and the like.
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ControlConfig {
+    // Off unless set here or with `--control <path>`.
+    pub socket: Option<PathBuf>,
+    // Where profiles are looked up, the directory of `--config` by default.
+    pub profile_dir: Option<PathBuf>,
+    // Reload the mapping file when it changes on disk.
+    pub watch: bool,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            socket: None,
+            profile_dir: None,
+            watch: true,
+        }
+    }
+}
+
+// A numbered profile, counting from 1 in the order they're listed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ProfileRule {
+    // `<name>.json` in the profile directory.
+    pub name: String,
+    // Output buttons held together to switch to it, e.g. ["Mode", "South"].
+    pub chord: Vec<GamepadButton>,
+    // Picked when every rule matches a connected device.
+    pub devices: Vec<DeviceRule>,
+}
+
+impl ProfileRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if !valid_profile_name(&self.name) {
+            return Err(format!("Invalid profile name: {:?}", self.name));
+        }
+        Ok(())
+    }
+
+    fn chord_held(&self, gamepad: &Gamepad) -> bool {
+        !self.chord.is_empty()
+            && self
+                .chord
+                .iter()
+                .all(|button| gamepad.buttons.get(button) == Some(&true))
+    }
+
+    fn devices_present(&self, devices: &[DeviceInfo]) -> bool {
+        !self.devices.is_empty()
+            && self
+                .devices
+                .iter()
+                .all(|rule| devices.iter().any(|device| rule.matches(device)))
+    }
+}
+
+// Names that stay inside the profile directory.
+fn valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\', '.'])
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Triggers go from 0 to 1, sticks from -1 to 1 with up and right positive.
+pub fn gamepad_json(gamepad: &Gamepad) -> Value {
+    let buttons: serde_json::Map<String, Value> = GamepadButton::iter()
+        .map(|button| {
+            let pressed = gamepad.buttons.get(&button).copied().unwrap_or(false);
+            (format!("{:?}", button), Value::Bool(pressed))
+        })
+        .collect();
+    let axes: serde_json::Map<String, Value> = GamepadAxis::iter()
+        .map(|axis| {
+            let value = gamepad.axes.get(&axis).map_or(0, |axis| axis.value) as f64;
+            let value = value / u64::MAX as f64;
+            let value = match axis {
+                GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => value,
+                _ => value * 2.0 - 1.0,
+            };
+            (format!("{:?}", axis), json!(value))
+        })
+        .collect();
+    json!({
+        "buttons": buttons,
+        "axes": axes,
+        "power": gamepad.power.map(|power| power.to_string()),
+        "motion": gamepad.motion.map(|motion| json!({
+            "accel": [motion.accel.0, motion.accel.1, motion.accel.2],
+            "gyro": [motion.gyro.0, motion.gyro.1, motion.gyro.2],
+        })),
+        "touches": gamepad.touches.iter()
+            .map(|touch| json!({ "id": touch.id, "x": touch.x, "y": touch.y }))
+            .collect::<Vec<_>>(),
+        "keys": gamepad.keys.iter().collect::<Vec<_>>(),
+        "mouse_buttons": gamepad.mouse_buttons.iter().collect::<Vec<_>>(),
+    })
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": err.code, "message": err.message },
+        }),
+    }
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+// What the socket can see and change besides the input itself.
+pub struct ControlSession {
+    pub config: Config,
+    pub config_path: Option<PathBuf>,
+    pub profile_dir: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub outputs: Vec<String>,
+    // The mapping file we started with, it alone decides `profile_rules`.
+    pub base_path: Option<PathBuf>,
+    pub profile_rules: Vec<ProfileRule>,
+}
+
+impl ControlSession {
+    pub fn new(config: Config, config_path: Option<PathBuf>, outputs: Vec<String>) -> Self {
+        let profile_dir = config.control.profile_dir.clone().or_else(|| {
+            config_path
+                .as_ref()
+                .and_then(|path| path.parent())
+                .map(|dir| dir.to_path_buf())
+        });
+        // The mapping file counts as a profile when it sits with the others.
+        let profile = config_path
+            .as_ref()
+            .filter(|path| path.parent() == profile_dir.as_deref())
+            .and_then(|path| path.file_stem()?.to_str().map(str::to_string));
+        ControlSession {
+            base_path: config_path.clone(),
+            profile_rules: config.profiles.clone(),
+            config,
+            config_path,
+            profile_dir,
+            profile,
+            outputs,
+        }
+    }
+
+    // Position in `profiles` counting from 1, 0 when the active profile isn't listed.
+    pub fn profile_number(&self) -> usize {
+        self.profile_rules
+            .iter()
+            .position(|rule| Some(&rule.name) == self.profile.as_ref())
+            .map_or(0, |index| index + 1)
+    }
+
+    pub fn profiles(&self) -> Vec<String> {
+        let entries = match self.profile_dir.as_ref().map(fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            _ => return vec![],
+        };
+        let mut profiles: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.path().file_name()?.to_str().map(str::to_string))
+            .filter_map(|name| name.strip_suffix(".json").map(str::to_string))
+            .collect();
+        profiles.sort();
+        profiles
+    }
+
+    // `<name>.json` in the profile directory, for names that stay inside it.
+    fn profile_path(&self, params: &Value) -> Result<(String, PathBuf), RpcError> {
+        let name = params
+            .get("name")
+            .and_then(Value::as_str)
+            .filter(|name| valid_profile_name(name))
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Expected a profile name"))?;
+        let dir = self.profile_dir.as_ref().ok_or_else(|| {
+            RpcError::new(
+                SERVER_ERROR,
+                "No profile directory, set control.profile_dir",
+            )
+        })?;
+        Ok((name.to_string(), dir.join(format!("{}.json", name))))
+    }
+
+    fn mapping(&self) -> Value {
+        json!({
+            "profile": self.profile,
+            "number": self.profile_number(),
+            "path": self.config_path,
+            "config": self.config,
+        })
+    }
+}
+
+struct Call {
+    method: String,
+    params: Value,
+    reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+// Reaches the input from other tasks, the socket and web UI each hold one.
+#[derive(Clone)]
+pub struct ControlHandle {
+    calls: mpsc::UnboundedSender<Call>,
+    state: watch::Receiver<Value>,
+}
+
+impl ControlHandle {
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let (reply, result) = oneshot::channel();
+        let call = Call {
+            method: method.to_string(),
+            params,
+            reply,
+        };
+        if self.calls.send(call).is_err() {
+            return Err(RpcError::new(SERVER_ERROR, "Input stopped"));
+        }
+        result
+            .await
+            .unwrap_or_else(|_| Err(RpcError::new(SERVER_ERROR, "Input stopped")))
+    }
+
+    pub fn state(&self) -> watch::Receiver<Value> {
+        self.state.clone()
+    }
+}
+
+// One JSON-RPC peer, whatever carries its messages.
+pub struct RpcClient {
+    handle: ControlHandle,
+    state: watch::Receiver<Value>,
+    subscribed: bool,
+}
+
+impl RpcClient {
+    pub fn new(handle: ControlHandle) -> RpcClient {
+        RpcClient {
+            state: handle.state(),
+            handle,
+            subscribed: false,
+        }
+    }
+
+    // The response, unless the message was a notification.
+    pub async fn handle_message(&mut self, message: &str) -> Option<String> {
+        let request: Value = match serde_json::from_str(message) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = RpcError::new(PARSE_ERROR, err.to_string());
+                return Some(response(Value::Null, Err(error)).to_string());
+            }
+        };
+        // Notifications (no id) get no response.
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let result = match method {
+            _ if request.get("jsonrpc") != Some(&json!("2.0")) => Err(RpcError::new(
+                INVALID_REQUEST,
+                "Expected a JSON-RPC 2.0 request",
+            )),
+            None => Err(RpcError::new(INVALID_REQUEST, "Missing method")),
+            Some("subscribe") => {
+                self.subscribed = true;
+                Ok(self.state.borrow_and_update().clone())
+            }
+            Some("unsubscribe") => {
+                self.subscribed = false;
+                Ok(Value::Null)
+            }
+            Some(method) => {
+                let params = request.get("params").cloned().unwrap_or(Value::Null);
+                self.handle.call(method, params).await
+            }
+        };
+        id.map(|id| response(id, result).to_string())
+    }
+
+    // Waits for the next "state" notification, forever when not subscribed. None once the
+    // input is gone.
+    pub async fn next_notification(&mut self) -> Option<String> {
+        if !self.subscribed {
+            return std::future::pending().await;
+        }
+        self.state.changed().await.ok()?;
+        let params = self.state.borrow_and_update().clone();
+        Some(notification("state", params).to_string())
+    }
+}
+
+pub struct ControlServer {
+    socket: Option<PathBuf>,
+    calls_sender: mpsc::UnboundedSender<Call>,
+    calls: mpsc::UnboundedReceiver<Call>,
+    state: watch::Sender<Value>,
+}
+
+impl ControlServer {
+    pub fn new() -> ControlServer {
+        let (calls_sender, calls) = mpsc::unbounded_channel();
+        ControlServer {
+            socket: None,
+            calls_sender,
+            calls,
+            state: watch::Sender::new(Value::Null),
+        }
+    }
+
+    pub fn handle(&self) -> ControlHandle {
+        ControlHandle {
+            calls: self.calls_sender.clone(),
+            state: self.state.subscribe(),
+        }
+    }
+
+    // Needs to be called from within the tokio runtime.
+    pub fn listen(&mut self, path: &Path) -> Result<(), String> {
+        // Left behind by a previous run that didn't shut down cleanly.
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            let _ = fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|err| format!("Could not listen on {}: {}", path.display(), err))?;
+        let handle = self.handle();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let client = RpcClient::new(handle.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = serve_client(stream, client).await {
+                        log_error!("Control socket: {}", err);
+                    }
+                });
+            }
+        });
+        log!("Control socket listening on {}", path.display());
+        self.socket = Some(path.to_path_buf());
+        Ok(())
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            let _ = fs::remove_file(socket);
+        }
+    }
+}
+
+async fn serve_client(stream: UnixStream, mut client: RpcClient) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let reply = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => client.handle_message(&line).await,
+                None => return Ok(()),
+            },
+            notification = client.next_notification() => match notification {
+                Some(notification) => Some(notification),
+                None => return Ok(()),
+            },
+        };
+        if let Some(mut reply) = reply {
+            reply.push('\n');
+            writer.write_all(reply.as_bytes()).await?;
+        }
+    }
+}
+
+// Answers control socket calls between reads of the wrapped input.
+pub struct ControlledInput<'a, I: ConfigurableInput> {
+    input: &'a mut I,
+    server: ControlServer,
+    session: ControlSession,
+    // The profile whose chord is down, so holding it switches once.
+    chord_held: Option<usize>,
+    // Chord buttons stay released on the output until they're let go, so switching doesn't also
+    // press them. `gamepad` is the input's with those released.
+    suppressed: Vec<GamepadButton>,
+    gamepad: Gamepad,
+    // Names of the devices last seen, profiles are only picked by devices when they change.
+    device_names: Vec<String>,
+    watcher: Option<FileWatcher>,
+}
+
+impl<'a, I: ConfigurableInput> ControlledInput<'a, I> {
+    pub fn new(
+        input: &'a mut I,
+        server: ControlServer,
+        session: ControlSession,
+    ) -> ControlledInput<'a, I> {
+        if !session.profile_rules.is_empty() {
+            input.show_profile(session.profile_number());
+        }
+        ControlledInput {
+            input,
+            server,
+            session,
+            chord_held: None,
+            suppressed: vec![],
+            gamepad: Gamepad::new(),
+            device_names: vec![],
+            watcher: None,
+        }
+    }
+
+    // Reloads the mapping file, and the one we started with, whenever they're saved.
+    // Needs to be called from within the tokio runtime.
+    pub fn watch_mapping(&mut self) -> Result<(), String> {
+        let mut watcher = FileWatcher::new()?;
+        for path in [&self.session.base_path, &self.session.config_path]
+            .into_iter()
+            .flatten()
+        {
+            watcher.watch(path)?;
+        }
+        self.watcher = Some(watcher);
+        Ok(())
+    }
+
+    fn set_profile_rules(&mut self, config: &Config) {
+        if self.session.profile_rules != config.profiles {
+            self.session.profile_rules = config.profiles.clone();
+            self.chord_held = None;
+            self.device_names = vec![];
+        }
+    }
+
+    fn load(&mut self, path: PathBuf, profile: Option<String>) -> Result<Value, RpcError> {
+        let config = Config::load(&path).map_err(|err| RpcError::new(SERVER_ERROR, err))?;
+        self.input.reconfigure(&config);
+        log!("Loaded mapping {}", path.display());
+        if self.session.base_path.as_ref() == Some(&path) {
+            self.set_profile_rules(&config);
+        }
+        if let Some(watcher) = self.watcher.as_mut() {
+            if let Err(err) = watcher.watch(&path) {
+                log_error!("{}", err);
+            }
+        }
+        self.session.config = config;
+        self.session.config_path = Some(path);
+        self.session.profile = profile;
+        if !self.session.profile_rules.is_empty() {
+            self.input.show_profile(self.session.profile_number());
+        }
+        Ok(self.session.mapping())
+    }
+
+    fn switch_to(&mut self, index: usize) {
+        let name = self.session.profile_rules[index].name.clone();
+        if self.session.profile.as_ref() == Some(&name) {
+            return;
+        }
+        let result = self
+            .session
+            .profile_path(&json!({ "name": name }))
+            .and_then(|(name, path)| self.load(path, Some(name)));
+        if let Err(err) = result {
+            log_error!("Could not switch to profile {}: {}", name, err.message);
+        }
+    }
+
+    // A file that doesn't load leaves everything as it was.
+    fn reload_changed_files(&mut self) {
+        let changed = match self.watcher.as_mut() {
+            Some(watcher) => watcher.changed(),
+            None => return,
+        };
+        for path in changed {
+            if self.session.config_path.as_ref() == Some(&path) {
+                let profile = self.session.profile.clone();
+                if let Err(err) = self.load(path, profile) {
+                    log_error!("Keeping the old mapping: {}", err.message);
+                }
+            } else if self.session.base_path.as_ref() == Some(&path) {
+                // Only its profile list matters while another profile is active.
+                match Config::load(&path) {
+                    Ok(config) => self.set_profile_rules(&config),
+                    Err(err) => log_error!("Keeping the old profile list: {}", err),
+                }
+            }
+        }
+    }
+
+    // Chords and device changes from the `profiles` section.
+    fn switch_profiles(&mut self) {
+        if self.session.profile_rules.is_empty() {
+            return;
+        }
+        let gamepad = self.input.to_gamepad();
+        // The longest chord wins, so Mode+South can coexist with Mode.
+        let held = self
+            .session
+            .profile_rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.chord_held(gamepad))
+            .max_by_key(|(_, rule)| rule.chord.len())
+            .map(|(index, _)| index);
+        // Letting go of part of a chord doesn't count as pressing a shorter one.
+        let rules = &self.session.profile_rules;
+        let previous = self.chord_held.map_or(0, |index| rules[index].chord.len());
+        let pressed = held.filter(|index| rules[*index].chord.len() > previous);
+        self.chord_held = held;
+        if let Some(index) = held {
+            for button in rules[index].chord.iter() {
+                if !self.suppressed.contains(button) {
+                    self.suppressed.push(button.clone());
+                }
+            }
+        }
+        if let Some(index) = pressed {
+            self.switch_to(index);
+        }
+
+        let devices = self.input.devices();
+        let names: Vec<String> = devices.iter().map(|device| device.name.clone()).collect();
+        if names == self.device_names {
+            return;
+        }
+        self.device_names = names;
+        let matching = self
+            .session
+            .profile_rules
+            .iter()
+            .position(|rule| rule.devices_present(&devices));
+        if let Some(index) = matching {
+            self.switch_to(index);
+        }
+    }
+
+    fn suppress_chords(&mut self) {
+        let gamepad = self.input.to_gamepad();
+        self.suppressed
+            .retain(|button| gamepad.buttons.get(button).copied().unwrap_or(false));
+        if self.suppressed.is_empty() {
+            return;
+        }
+        self.gamepad = gamepad.clone();
+        for button in self.suppressed.iter() {
+            self.gamepad.set_button(button.clone(), false);
+        }
+    }
+
+    fn handle(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "list_devices" => Ok(json!({
+                "inputs": [self.input.name()],
+                "outputs": self.session.outputs,
+            })),
+            "get_state" => Ok(gamepad_json(self.to_gamepad())),
+            "get_mapping" => Ok(self.session.mapping()),
+            "list_profiles" => Ok(json!({
+                "active": self.session.profile,
+                "profiles": self.session.profiles(),
+            })),
+            "switch_profile" => {
+                let (name, path) = self.session.profile_path(params)?;
+                self.load(path, Some(name))
+            }
+            "save_profile" => {
+                let (name, path) = self.session.profile_path(params)?;
+                let config = params.get("config").cloned().unwrap_or(Value::Null);
+                // Same checks as loading, so a bad edit never reaches the disk.
+                Config::from_json(&config.to_string())
+                    .map_err(|err| RpcError::new(INVALID_PARAMS, err))?;
+                let contents = serde_json::to_string_pretty(&config).unwrap();
+                fs::write(&path, contents + "\n").map_err(|err| {
+                    RpcError::new(
+                        SERVER_ERROR,
+                        format!("Could not write {}: {}", path.display(), err),
+                    )
+                })?;
+                log!("Saved profile {}", name);
+                let active = self.session.profile.as_deref() == Some(name.as_str());
+                if active {
+                    self.load(path.clone(), Some(name))?;
+                }
+                Ok(json!({ "path": path, "active": active }))
+            }
+            "reload_config" => {
+                let path = self.session.config_path.clone().ok_or_else(|| {
+                    RpcError::new(
+                        SERVER_ERROR,
+                        "No mapping file to reload, start with --config",
+                    )
+                })?;
+                let profile = self.session.profile.clone();
+                self.load(path, profile)
+            }
+            "calibrate" => {
+                self.input.calibrate();
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {}", method),
+            )),
+        }
+    }
+}
+
+impl<I: ConfigurableInput> ControllerInput for ControlledInput<'_, I> {
+    type ControllerType = I::ControllerType;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        if self.suppressed.is_empty() {
+            self.input.to_gamepad()
+        } else {
+            &self.gamepad
+        }
+    }
+
+    fn prep_for_input_events(&mut self) {
+        self.input.prep_for_input_events();
+    }
+
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        let res = self.input.get_next_inputs().await;
+        self.reload_changed_files();
+        self.switch_profiles();
+        self.suppress_chords();
+        while let Ok(call) = self.server.calls.try_recv() {
+            let result = self.handle(&call.method, &call.params);
+            let _ = call.reply.send(result);
+        }
+        let state = gamepad_json(self.to_gamepad());
+        self.server.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+        res
+    }
+
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        self.input.apply_feedback(feedback);
+    }
+
+    fn name(&self) -> String {
+        self.input.name()
+    }
+}
+
+#[test]
+fn test_gamepad_json() {
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value = u64::MAX;
+    let state = gamepad_json(&gamepad);
+    assert_eq!(state["buttons"]["South"], json!(true));
+    assert_eq!(state["buttons"]["North"], json!(false));
+    assert_eq!(state["axes"]["LeftTrigger"], json!(0.0));
+    assert_eq!(state["axes"]["LeftJoystickX"], json!(1.0));
+    assert_eq!(state["power"], Value::Null);
+}
+
+#[tokio::test]
+async fn test_control_socket() {
+    use crate::controller_in::network::{NetworkConfig, NetworkInput, NetworkPacket};
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+
+    type Client = (
+        Lines<BufReader<ReadHalf<UnixStream>>>,
+        WriteHalf<UnixStream>,
+    );
+    async fn rpc(client: &mut Client, request: Value) -> Value {
+        let line = format!("{}\n", request);
+        client.1.write_all(line.as_bytes()).await.unwrap();
+        let reply = client.0.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&reply).unwrap()
+    }
+    fn request(id: u32, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    let dir = std::env::temp_dir().join(format!("cursed_controls_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let racing = r#"{ "gilrs": { "buttons": { "South": null } } }"#;
+    fs::write(dir.join("racing.json"), racing).unwrap();
+    fs::write(dir.join("broken.json"), "{").unwrap();
+    let socket = dir.join("control.sock");
+
+    let mut input = NetworkInput::bind(&NetworkConfig {
+        listen: "127.0.0.1:0".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    let input_addr = input.local_addr().unwrap();
+    let mut server = ControlServer::new();
+    server.listen(&socket).unwrap();
+    let outputs = vec!["ds4".to_string()];
+    let session = ControlSession::new(Config::default(), None, outputs);
+    let mut controlled = ControlledInput::new(&mut input, server, session);
+    controlled.session.profile_dir = Some(dir.clone());
+
+    let client = async {
+        let (reader, writer) = tokio::io::split(UnixStream::connect(&socket).await.unwrap());
+        let mut client = (BufReader::new(reader).lines(), writer);
+
+        let devices = rpc(&mut client, request(1, "list_devices", Value::Null)).await;
+        assert_eq!(devices["id"], json!(1));
+        assert_eq!(devices["result"]["outputs"], json!(["ds4"]));
+        let input_name = devices["result"]["inputs"][0].as_str().unwrap();
+        assert!(input_name.starts_with("network"));
+
+        let profiles = rpc(&mut client, request(2, "list_profiles", Value::Null)).await;
+        assert_eq!(profiles["result"]["profiles"], json!(["broken", "racing"]));
+        let switched = rpc(
+            &mut client,
+            request(3, "switch_profile", json!({"name": "racing"})),
+        );
+        let switched = switched.await;
+        assert_eq!(switched["result"]["profile"], json!("racing"));
+        assert_eq!(
+            switched["result"]["config"]["gilrs"]["buttons"]["South"],
+            Value::Null
+        );
+        // A broken file leaves the active profile alone.
+        let broken = rpc(
+            &mut client,
+            request(4, "switch_profile", json!({"name": "broken"})),
+        );
+        assert_eq!(broken.await["error"]["code"], json!(SERVER_ERROR));
+        let escape = rpc(
+            &mut client,
+            request(5, "switch_profile", json!({"name": "../x"})),
+        );
+        assert_eq!(escape.await["error"]["code"], json!(INVALID_PARAMS));
+        let mapping = rpc(&mut client, request(6, "get_mapping", Value::Null)).await;
+        assert_eq!(mapping["result"]["profile"], json!("racing"));
+        let unknown = rpc(&mut client, request(7, "launch", Value::Null)).await;
+        assert_eq!(unknown["error"]["code"], json!(METHOD_NOT_FOUND));
+
+        let kart = json!({ "gilrs": { "buttons": { "East": { "Button": "South" } } } });
+        let params = json!({ "name": "kart", "config": kart });
+        let saved = rpc(&mut client, request(8, "save_profile", params)).await;
+        assert_eq!(saved["result"]["active"], json!(false));
+        let written = fs::read_to_string(dir.join("kart.json")).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&written).unwrap(), kart);
+        // Invalid mappings aren't written.
+        let params = json!({ "name": "kart", "config": { "devices": 3 } });
+        let invalid = rpc(&mut client, request(9, "save_profile", params)).await;
+        assert_eq!(invalid["error"]["code"], json!(INVALID_PARAMS));
+
+        // Pressing a button on the input shows up as a notification.
+        let subscribed = rpc(&mut client, request(10, "subscribe", Value::Null)).await;
+        assert_eq!(subscribed["result"]["buttons"]["North"], json!(false));
+        let mut gamepad = Gamepad::new();
+        gamepad.set_button(GamepadButton::North, true);
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let packet = NetworkPacket::from_gamepad(&gamepad, 1).encode();
+        sender.send_to(&packet, input_addr).unwrap();
+        loop {
+            let line = client.0.next_line().await.unwrap().unwrap();
+            let message: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(message["method"], json!("state"));
+            if message["params"]["buttons"]["North"] == json!(true) {
+                break;
+            }
+        }
+    };
+    tokio::select! {
+        _ = client => {}
+        _ = async {
+            loop {
+                let _ = controlled.get_next_inputs().await;
+            }
+        } => {}
+    }
+    drop(controlled);
+    assert!(!socket.exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_profile_switching() {
+    use crate::controller_in::network::{NetworkConfig, NetworkInput, NetworkPacket};
+    use crate::controller_in::profile_leds;
+
+    let dir = std::env::temp_dir().join(format!("cursed_controls_profiles_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("kart.json"), "{}").unwrap();
+    fs::write(dir.join("racing.json"), "{}").unwrap();
+    let config = Config::from_json(
+        r#"{
+            "profiles": [
+                { "name": "kart", "chord": ["Mode"] },
+                { "name": "racing", "chord": ["Mode", "South"], "devices": [{ "name": "Wheel" }] }
+            ]
+        }"#,
+    )
+    .unwrap();
+    assert!(Config::from_json(r#"{"profiles": [{"name": "../kart"}]}"#).is_err());
+
+    let mut input = NetworkInput::bind(&NetworkConfig {
+        listen: "127.0.0.1:0".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    let mut session = ControlSession::new(config, None, vec![]);
+    session.profile_dir = Some(dir.clone());
+    let mut controlled = ControlledInput::new(&mut input, ControlServer::new(), session);
+
+    struct Sender {
+        socket: std::net::UdpSocket,
+        sequence: u32,
+    }
+    impl Sender {
+        // Sends a pad with only `buttons` pressed, then the profile once the input has read it.
+        async fn press(
+            &mut self,
+            controlled: &mut ControlledInput<'_, NetworkInput>,
+            buttons: &[GamepadButton],
+        ) -> Option<String> {
+            let mut gamepad = Gamepad::new();
+            for button in buttons {
+                gamepad.set_button(button.clone(), true);
+            }
+            self.sequence += 1;
+            let packet = NetworkPacket::from_gamepad(&gamepad, self.sequence).encode();
+            let input_addr = controlled.input.local_addr().unwrap();
+            self.socket.send_to(&packet, input_addr).unwrap();
+            while controlled.input.to_gamepad().buttons != gamepad.buttons {
+                let _ = controlled.get_next_inputs().await;
+            }
+            controlled.session.profile.clone()
+        }
+    }
+    let mut sender = Sender {
+        socket: std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+        sequence: 0,
+    };
+    let pressed = |controlled: &mut ControlledInput<NetworkInput>, button: GamepadButton| {
+        controlled.to_gamepad().buttons[&button]
+    };
+
+    // The longest chord held wins, letting go of South doesn't fall back to Mode's profile.
+    // Chord buttons don't reach the output until they're let go.
+    let (kart, racing) = (Some("kart".to_string()), Some("racing".to_string()));
+    let mode_south = [GamepadButton::Mode, GamepadButton::South];
+    let mode = [GamepadButton::Mode];
+    assert_eq!(sender.press(&mut controlled, &mode_south).await, racing);
+    assert!(!pressed(&mut controlled, GamepadButton::Mode));
+    assert!(!pressed(&mut controlled, GamepadButton::South));
+    assert_eq!(sender.press(&mut controlled, &mode).await, racing);
+    assert!(!pressed(&mut controlled, GamepadButton::Mode));
+    assert_eq!(sender.press(&mut controlled, &[]).await, racing);
+    let south = [GamepadButton::South];
+    assert_eq!(sender.press(&mut controlled, &south).await, racing);
+    assert!(pressed(&mut controlled, GamepadButton::South));
+    assert_eq!(sender.press(&mut controlled, &mode).await, kart);
+    assert_eq!(controlled.session.profile_number(), 1);
+
+    let rules = &controlled.session.profile_rules;
+    let wheel = DeviceInfo {
+        name: "Fanatec Wheel".to_string(),
+        ..Default::default()
+    };
+    assert!(rules[1].devices_present(&[DeviceInfo::default(), wheel]));
+    assert!(!rules[1].devices_present(&[]));
+    // No device rules, never picked by devices.
+    assert!(!rules[0].devices_present(&[DeviceInfo::default()]));
+
+    assert_eq!(profile_leds(2), [false, true, false, false]);
+    assert_eq!(profile_leds(5), [true, false, true, false]);
+    assert_eq!(profile_leds(0), [false; 4]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_mapping_hot_reload() {
+    use crate::controller_in::network::{NetworkConfig, NetworkInput};
+    use gilrs::Button as GilButton;
+
+    let dir = std::env::temp_dir().join(format!("cursed_controls_reload_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mapping = dir.join("mapping.json");
+    fs::write(&mapping, "{}").unwrap();
+
+    let mut input = NetworkInput::bind(&NetworkConfig {
+        listen: "127.0.0.1:0".to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    let session = ControlSession::new(Config::default(), Some(mapping.clone()), vec![]);
+    let mut controlled = ControlledInput::new(&mut input, ControlServer::new(), session);
+    controlled.watch_mapping().unwrap();
+    let south = |controlled: &ControlledInput<NetworkInput>| {
+        let buttons = &controlled.session.config.gilrs.buttons;
+        buttons.get(&GilButton::South).cloned()
+    };
+
+    fs::write(&mapping, r#"{ "gilrs": { "buttons": { "South": null } } }"#).unwrap();
+    for _ in 0..200 {
+        let _ = controlled.get_next_inputs().await;
+        if south(&controlled).is_some() {
+            break;
+        }
+    }
+    assert_eq!(south(&controlled), Some(None));
+
+    // A broken save keeps the mapping that worked.
+    fs::write(&mapping, "{ \"gilrs\": ").unwrap();
+    for _ in 0..40 {
+        let _ = controlled.get_next_inputs().await;
+    }
+    assert_eq!(south(&controlled), Some(None));
+    assert_eq!(controlled.session.config_path, Some(mapping));
+    let _ = fs::remove_dir_all(&dir);
+}
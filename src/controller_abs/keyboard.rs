@@ -0,0 +1,161 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// A key as its HID keyboard usage id, named like "A", "Space" or "LeftShift" in configs.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct KeyCode(pub u8);
+
+const NAMED_KEYS: &[(&str, u8)] = &[
+    ("Enter", 0x28),
+    ("Escape", 0x29),
+    ("Backspace", 0x2a),
+    ("Tab", 0x2b),
+    ("Space", 0x2c),
+    ("Minus", 0x2d),
+    ("Equal", 0x2e),
+    ("LeftBracket", 0x2f),
+    ("RightBracket", 0x30),
+    ("Backslash", 0x31),
+    ("Semicolon", 0x33),
+    ("Apostrophe", 0x34),
+    ("Grave", 0x35),
+    ("Comma", 0x36),
+    ("Dot", 0x37),
+    ("Slash", 0x38),
+    ("CapsLock", 0x39),
+    ("PrintScreen", 0x46),
+    ("ScrollLock", 0x47),
+    ("Pause", 0x48),
+    ("Insert", 0x49),
+    ("Home", 0x4a),
+    ("PageUp", 0x4b),
+    ("Delete", 0x4c),
+    ("End", 0x4d),
+    ("PageDown", 0x4e),
+    ("Right", 0x4f),
+    ("Left", 0x50),
+    ("Down", 0x51),
+    ("Up", 0x52),
+    ("LeftCtrl", 0xe0),
+    ("LeftShift", 0xe1),
+    ("LeftAlt", 0xe2),
+    ("LeftMeta", 0xe3),
+    ("RightCtrl", 0xe4),
+    ("RightShift", 0xe5),
+    ("RightAlt", 0xe6),
+    ("RightMeta", 0xe7),
+];
+
+impl KeyCode {
+    pub fn from_name(name: &str) -> Option<KeyCode> {
+        let bytes = name.as_bytes();
+        match bytes {
+            [letter @ b'A'..=b'Z'] => return Some(KeyCode(letter - b'A' + 0x04)),
+            [b'0'] => return Some(KeyCode(0x27)),
+            [digit @ b'1'..=b'9'] => return Some(KeyCode(digit - b'1' + 0x1e)),
+            _ => {}
+        }
+        if let Some(number) = name.strip_prefix('F').and_then(|n| n.parse::<u8>().ok()) {
+            if (1..=12).contains(&number) {
+                return Some(KeyCode(0x3a + number - 1));
+            }
+        }
+        NAMED_KEYS
+            .iter()
+            .find(|(key_name, _)| *key_name == name)
+            .map(|(_, usage)| KeyCode(*usage))
+    }
+
+    pub fn name(&self) -> Option<String> {
+        match self.0 {
+            0x04..=0x1d => Some(((self.0 - 0x04 + b'A') as char).to_string()),
+            0x1e..=0x26 => Some(((self.0 - 0x1e + b'1') as char).to_string()),
+            0x27 => Some("0".to_string()),
+            0x3a..=0x45 => Some(format!("F{}", self.0 - 0x3a + 1)),
+            usage => NAMED_KEYS
+                .iter()
+                .find(|(_, key_usage)| *key_usage == usage)
+                .map(|(name, _)| name.to_string()),
+        }
+    }
+
+    // Ctrl, shift, alt and meta go into the modifier byte instead of the key array.
+    pub fn modifier_bit(&self) -> Option<u8> {
+        match self.0 {
+            0xe0..=0xe7 => Some(1 << (self.0 - 0xe0)),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for KeyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.name() {
+            Some(name) => serializer.serialize_str(&name),
+            None => serializer.serialize_u8(self.0),
+        }
+    }
+}
+
+// Accepts key names or raw usage ids.
+impl<'de> Deserialize<'de> for KeyCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<KeyCode, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Key {
+            Usage(u8),
+            Name(String),
+        }
+        match Key::deserialize(deserializer)? {
+            Key::Usage(usage) => Ok(KeyCode(usage)),
+            Key::Name(name) => KeyCode::from_name(&name)
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown key: {}", name))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+impl MouseButton {
+    // Bit in the boot protocol mouse report.
+    pub fn bit(&self) -> u8 {
+        match self {
+            MouseButton::Left => 0x01,
+            MouseButton::Right => 0x02,
+            MouseButton::Middle => 0x04,
+            MouseButton::Back => 0x08,
+            MouseButton::Forward => 0x10,
+        }
+    }
+}
+
+#[test]
+fn test_key_names() {
+    assert_eq!(KeyCode::from_name("A"), Some(KeyCode(0x04)));
+    assert_eq!(KeyCode::from_name("0"), Some(KeyCode(0x27)));
+    assert_eq!(KeyCode::from_name("F12"), Some(KeyCode(0x45)));
+    assert_eq!(KeyCode::from_name("F13"), None);
+    assert_eq!(KeyCode::from_name("LeftShift"), Some(KeyCode(0xe1)));
+    assert_eq!(KeyCode::from_name("Nope"), None);
+    for usage in 0x04..=0xe7 {
+        if let Some(name) = KeyCode(usage).name() {
+            assert_eq!(KeyCode::from_name(&name), Some(KeyCode(usage)));
+        }
+    }
+    assert_eq!(KeyCode(0xe1).modifier_bit(), Some(0x02));
+    assert_eq!(KeyCode(0x2c).modifier_bit(), None);
+
+    let keys: Vec<KeyCode> = serde_json::from_str(r#"["W", "Space", 100]"#).unwrap();
+    assert_eq!(keys, vec![KeyCode(0x1a), KeyCode(0x2c), KeyCode(100)]);
+    assert_eq!(
+        serde_json::to_string(&keys).unwrap(),
+        r#"["W","Space",100]"#
+    );
+    assert!(serde_json::from_str::<KeyCode>(r#""Hyper""#).is_err());
+}
@@ -1,13 +1,17 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::{Div, Sub},
-    u64,
 };
 
 use num_traits::{Bounded, FromPrimitive, NumCast, ToPrimitive};
+use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+pub mod keyboard;
+
+use keyboard::{KeyCode, MouseButton};
+
 pub trait NormalizableNumber:
     Bounded + ToPrimitive + FromPrimitive + NumCast + Sub<Output = Self> + Div<Output = Self> + Copy
 {
@@ -110,7 +114,18 @@ fn test_normalization() {
     assert_eq!(normalize::<f64, u8, _, _>(0.9, -1.0, 1.0, None, None), 242);
 }
 
+// Signed speed factor in [-1, 1] after the deadzone and curve.
+pub fn speed_curve(position: f64, deadzone: f64, exponent: f64) -> f64 {
+    let magnitude = position.abs().min(1.0);
+    if magnitude <= deadzone {
+        return 0.0;
+    }
+    let scaled = (magnitude - deadzone) / (1.0 - deadzone);
+    scaled.powf(exponent).copysign(position)
+}
+
 // Values in axis are all u64, most likely controllers will have smaller sizes, so more easily convertible.
+#[derive(Clone)]
 pub struct Axis {
     pub value: u64,
     min: u64,
@@ -155,39 +170,6 @@ impl Axis {
         }
     }
 
-    pub fn set_values<T>(&mut self, from_value: T, min: Option<T>, max: Option<T>)
-    where
-        T: NormalizableNumber,
-    {
-        let min_val = normalize!(
-            min.unwrap_or_else(|| T::min_value()),
-            T::min_value(),
-            T::max_value()
-        );
-        let max_val = normalize!(
-            max.unwrap_or_else(|| T::max_value()),
-            T::min_value(),
-            T::max_value()
-        );
-        self.value = normalize(
-            from_value,
-            min,
-            max,
-            min.unwrap().to_u64(),
-            max.unwrap().to_u64(),
-        );
-        self.min = min_val;
-        self.max = max_val;
-    }
-
-    pub fn get_value(&mut self) -> &u64 {
-        &self.value
-    }
-
-    pub fn get_normalized_value(&mut self) -> f64 {
-        return normalize(self.value, self.min, self.max, 0.0, 1.0);
-    }
-
     pub fn get_min(&mut self) -> &u64 {
         &self.min
     }
@@ -200,10 +182,6 @@ impl Axis {
         self.deadzones = Some(deadzones);
     }
 
-    pub fn get_deadzones(&mut self) -> &Option<Vec<std::ops::Range<u64>>> {
-        return &self.deadzones;
-    }
-
     pub fn make_deadzone<T>(
         &self,
         input: Vec<std::ops::Range<T>>,
@@ -266,7 +244,7 @@ impl Axis {
             }
         }
 
-        return normalize!(self.value, self.min, self.max);
+        normalize!(self.value, self.min, self.max)
     }
 
     pub fn invert(&self) -> Self {
@@ -301,18 +279,13 @@ fn test_axis() {
 #[derive(Clone)]
 pub struct BitPackedButton {
     // Button with it's corresponding address
-    name: Option<String>,
     pub value: bool,
     addr: u8,
 }
 
 impl BitPackedButton {
-    pub fn new<N: Into<Option<String>>>(name: N, addr: u8) -> BitPackedButton {
-        BitPackedButton {
-            name: name.into(),
-            value: false,
-            addr,
-        }
+    pub fn new(addr: u8) -> BitPackedButton {
+        BitPackedButton { value: false, addr }
     }
 }
 
@@ -321,19 +294,24 @@ pub struct BitPackedButtons {
 }
 
 impl BitPackedButtons {
-    pub fn get_by_name(self: &Self, name: &String) -> Option<&BitPackedButton> {
-        self.buttons
-            .iter()
-            .find(|button| button.name.as_ref() == Some(name))
-    }
-
-    pub fn to_bytes_repr(self: &Self) -> u8 {
+    pub fn to_bytes_repr(&self) -> u8 {
         let mut buttons_sorted = self.buttons.to_vec();
         buttons_sorted.sort_by_key(|button| button.addr);
-        return buttons_sorted
+        buttons_sorted
             .iter()
             .map(|button| (button.value as u8) << button.addr)
-            .fold(0, |acc, bit| acc | bit);
+            .fold(0, |acc, bit| acc | bit)
+    }
+
+    // Same packing over `len` bytes, address 8 is the lowest bit of the second byte.
+    pub fn to_multi_byte_repr(&self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        for button in self.buttons.iter().filter(|button| button.value) {
+            if let Some(byte) = bytes.get_mut(button.addr as usize / 8) {
+                *byte |= 1 << (button.addr % 8);
+            }
+        }
+        bytes
     }
 }
 
@@ -357,7 +335,7 @@ impl JoystickState {
 }
 
 // Generic gamepad
-#[derive(EnumIter, Hash, Eq, PartialEq, Clone)]
+#[derive(EnumIter, Hash, Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub enum GamepadButton {
     North,
     East,
@@ -374,9 +352,11 @@ pub enum GamepadButton {
     DPadDown,
     DPadLeft,
     DPadRight,
+    // Share/capture button on newer pads
+    Share,
 }
 
-#[derive(EnumIter, PartialEq, Eq, Hash, Clone)]
+#[derive(EnumIter, PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub enum GamepadAxis {
     LeftTrigger,
     RightTrigger,
@@ -386,9 +366,73 @@ pub enum GamepadAxis {
     RightJoystickY,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PowerState {
+    Wired,
+    Discharging(u8),
+    Charging(u8),
+    Charged,
+}
+
+impl PowerState {
+    // Battery percentage, if there is a battery at all.
+    pub fn level(&self) -> Option<u8> {
+        match self {
+            PowerState::Wired => None,
+            PowerState::Discharging(level) | PowerState::Charging(level) => Some(*level),
+            PowerState::Charged => Some(100),
+        }
+    }
+}
+
+impl std::fmt::Display for PowerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowerState::Wired => write!(f, "wired"),
+            PowerState::Discharging(level) => write!(f, "{}%", level),
+            PowerState::Charging(level) => write!(f, "{}% (charging)", level),
+            PowerState::Charged => write!(f, "charged"),
+        }
+    }
+}
+
+// Axes as a DualShock 4 reports them: x right, y up out of the face buttons, z towards the player.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct MotionState {
+    // In g
+    pub accel: (f64, f64, f64),
+    // In degrees per second
+    pub gyro: (f64, f64, f64),
+}
+
+// A finger on a touch surface, x/y in [0, 1] from the top left.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TouchPoint {
+    pub id: u8,
+    pub x: f64,
+    pub y: f64,
+}
+
+// Sent back by an output's host, for the inputs to play.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Feedback {
+    // Motor strengths in [0, 1]
+    Rumble { strong: f64, weak: f64 },
+    LightBar { red: u8, green: u8, blue: u8 },
+}
+
+#[derive(Clone)]
 pub struct Gamepad {
     pub buttons: HashMap<GamepadButton, bool>,
     pub axes: HashMap<GamepadAxis, Axis>,
+    // None until the input has been able to read it.
+    pub power: Option<PowerState>,
+    // Only for inputs with motion sensors.
+    pub motion: Option<MotionState>,
+    pub touches: Vec<TouchPoint>,
+    // Keys and mouse buttons inputs press directly, for the keyboard/mouse output.
+    pub keys: HashSet<KeyCode>,
+    pub mouse_buttons: HashSet<MouseButton>,
 }
 
 impl Gamepad {
@@ -405,28 +449,63 @@ impl Gamepad {
             axes.insert(axis_type, AxisNew!(0));
         }
 
-        Gamepad { buttons, axes }
+        Gamepad {
+            buttons,
+            axes,
+            power: None,
+            motion: None,
+            touches: vec![],
+            keys: HashSet::new(),
+            mouse_buttons: HashSet::new(),
+        }
     }
 
-    pub fn set_button(self: &mut Self, button: GamepadButton, value: bool) {
+    pub fn set_button(&mut self, button: GamepadButton, value: bool) {
         *self.buttons.get_mut(&button).unwrap() = value;
     }
 
-    pub fn get_axis_ref(self: &mut Self, axis: GamepadAxis) -> &mut Axis {
-        return self.axes.get_mut(&axis).unwrap();
+    pub fn get_axis_ref(&mut self, axis: GamepadAxis) -> &mut Axis {
+        self.axes.get_mut(&axis).unwrap()
     }
-}
 
-pub enum InputType {
-    Button,
-    Axis,
+    // Digital sources (keys, gestures, thresholds) drive axes full scale.
+    pub fn set_digital_output(&mut self, output: &OutputMapping, pressed: bool) {
+        match output {
+            OutputMapping::Button(button) => self.set_button(button.clone(), pressed),
+            OutputMapping::Axis(axis) => {
+                let output_axis = self.get_axis_ref(axis.clone());
+                output_axis.value = if pressed {
+                    *output_axis.get_max()
+                } else {
+                    *output_axis.get_min()
+                };
+            }
+            OutputMapping::Key(key) => {
+                if pressed {
+                    self.keys.insert(*key);
+                } else {
+                    self.keys.remove(key);
+                }
+            }
+            OutputMapping::MouseButton(button) => {
+                if pressed {
+                    self.mouse_buttons.insert(*button);
+                } else {
+                    self.mouse_buttons.remove(button);
+                }
+            }
+        }
+    }
 }
 
 // Mappings
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum OutputMapping {
     Button(GamepadButton),
     Axis(GamepadAxis),
+    // Only the keyboard/mouse output uses these, analog sources press them past half way.
+    Key(KeyCode),
+    MouseButton(MouseButton),
 }
 pub struct ControllerMapping<T>
 where
@@ -438,8 +517,11 @@ where
 
 pub trait ControllerInput {
     type ControllerType;
-    fn to_gamepad<'a>(&'a mut self) -> &'a Gamepad;
-    fn discover_all() -> Vec<Self::ControllerType>;
+    fn to_gamepad(&mut self) -> &Gamepad;
     fn prep_for_input_events(&mut self);
     async fn get_next_inputs(&mut self) -> Result<bool, &'static str>;
+    // Inputs without rumble/lights just ignore it.
+    fn apply_feedback(&mut self, _feedback: &Feedback) {}
+    // Readable name for logs and the control socket.
+    fn name(&self) -> String;
 }
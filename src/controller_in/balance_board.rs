@@ -0,0 +1,471 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use xwiimote::{events::Event, Channels, Device};
+
+use crate::config::{BalanceBoardSection, Config};
+use crate::controller_abs::{
+    Axis, ControllerInput, ControllerMapping, Gamepad, OutputMapping, PowerState,
+};
+use crate::controller_in::battery::{poll_wii_battery, BatteryMonitor, WiiBatteryAlert};
+use crate::controller_in::device_filter::{DeviceFilter, DeviceInfo};
+use crate::controller_in::{
+    connect_wii_device, wii_device_name, wii_device_paths, ConfigurableInput,
+};
+use crate::dashboard;
+
+// xwiimote-rs maps `Channels::BALANCE_BOARD` onto the pro controller interface, so use the raw bit.
+const BALANCE_BOARD_CHANNEL: u32 = 0x000800;
+pub const BALANCE_BOARD_KIND: &str = "balanceboard";
+
+// Sensor order as reported by the kernel
+const TOP_RIGHT: usize = 0;
+const BOTTOM_RIGHT: usize = 1;
+const TOP_LEFT: usize = 2;
+const BOTTOM_LEFT: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum BalanceBoardSource {
+    // Center of pressure, -1 (left/back) to 1 (right/forward)
+    LeanX,
+    LeanY,
+    // Total weight, 0 to `max_weight` kg
+    Weight,
+    // Thresholds in kg, e.g. stepping on/off or jumping
+    WeightAbove(f64),
+    WeightBelow(f64),
+    // Thresholds on the center of pressure, 0 to 1
+    LeanLeft(f64),
+    LeanRight(f64),
+    LeanForward(f64),
+    LeanBack(f64),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BalanceBoardConfig {
+    // Below this weight (kg) nobody is standing on the board, lean reads as centered.
+    pub min_weight: f64,
+    pub max_weight: f64,
+    // Center of pressure offset that maps to full stick deflection.
+    pub lean_range: f64,
+    // How far a value has to fall back past its threshold before the button releases.
+    pub hysteresis: f64,
+    // Exponential smoothing factor, 0 is off.
+    pub smoothing: f64,
+    pub tare_samples: usize,
+}
+
+impl Default for BalanceBoardConfig {
+    fn default() -> Self {
+        BalanceBoardConfig {
+            min_weight: 5.0,
+            max_weight: 150.0,
+            lean_range: 0.6,
+            hysteresis: 0.1,
+            smoothing: 0.3,
+            tare_samples: 50,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct BalanceBoardReading {
+    // Per sensor load in kg, see the sensor order constants.
+    pub sensors: [f64; 4],
+}
+
+impl BalanceBoardReading {
+    // The kernel reports each sensor in units of 10 grams.
+    pub fn from_raw(raw: [i32; 4]) -> BalanceBoardReading {
+        BalanceBoardReading {
+            sensors: raw.map(|value| value as f64 / 100.0),
+        }
+    }
+
+    pub fn total_weight(&self) -> f64 {
+        self.sensors.iter().sum()
+    }
+
+    pub fn center_of_pressure(&self) -> (f64, f64) {
+        let total = self.total_weight();
+        if total <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let s = &self.sensors;
+        let x = (s[TOP_RIGHT] + s[BOTTOM_RIGHT] - s[TOP_LEFT] - s[BOTTOM_LEFT]) / total;
+        let y = (s[TOP_RIGHT] + s[TOP_LEFT] - s[BOTTOM_RIGHT] - s[BOTTOM_LEFT]) / total;
+        (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0))
+    }
+}
+
+struct TareProgress {
+    sums: [f64; 4],
+    samples: usize,
+}
+
+// Everything but the device handle, so mapping can be driven without a board attached.
+pub struct BalanceBoardTracker {
+    config: BalanceBoardConfig,
+    gamepad: Gamepad,
+    mappings: Vec<ControllerMapping<BalanceBoardSource>>,
+    pressed: Vec<bool>,
+    offsets: [f64; 4],
+    tare: Option<TareProgress>,
+    smoothed: Option<BalanceBoardReading>,
+}
+
+impl BalanceBoardTracker {
+    pub fn new(config: BalanceBoardConfig) -> BalanceBoardTracker {
+        BalanceBoardTracker {
+            config,
+            gamepad: Gamepad::new(),
+            mappings: vec![],
+            pressed: vec![],
+            offsets: [0.0; 4],
+            tare: None,
+            smoothed: None,
+        }
+    }
+
+    pub fn map_source(&mut self, source: BalanceBoardSource, to_mapping: OutputMapping) {
+        self.mappings.push(ControllerMapping {
+            input: source,
+            output: to_mapping,
+        });
+        self.pressed.push(false);
+    }
+
+    // New config and mappings, e.g. from a reloaded profile. Everything the old mappings drove
+    // is released, the next reading applies the new ones.
+    pub fn reconfigure(&mut self, config: BalanceBoardConfig) {
+        self.config = config;
+        self.mappings.clear();
+        self.pressed.clear();
+        self.gamepad = Gamepad::new();
+    }
+
+    // Zero the sensors over the next `tare_samples` readings, the board should be empty.
+    pub fn start_tare(&mut self) {
+        self.tare = Some(TareProgress {
+            sums: [0.0; 4],
+            samples: 0,
+        });
+    }
+
+    #[cfg(test)]
+    pub fn is_taring(&self) -> bool {
+        self.tare.is_some()
+    }
+
+    #[cfg(test)]
+    pub fn get_offsets(&self) -> &[f64; 4] {
+        &self.offsets
+    }
+
+    pub fn to_gamepad(&self) -> &Gamepad {
+        &self.gamepad
+    }
+
+    pub fn set_power(&mut self, power: Option<PowerState>) {
+        self.gamepad.power = power;
+    }
+
+    pub fn update(&mut self, raw: [i32; 4]) {
+        let reading = BalanceBoardReading::from_raw(raw);
+
+        if let Some(tare) = &mut self.tare {
+            for (sum, value) in tare.sums.iter_mut().zip(reading.sensors) {
+                *sum += value;
+            }
+            tare.samples += 1;
+            if tare.samples >= self.config.tare_samples.max(1) {
+                let samples = tare.samples as f64;
+                self.offsets = tare.sums.map(|sum| sum / samples);
+                self.tare = None;
+                log!("Balance board tared: {:?}", self.offsets);
+            }
+            return;
+        }
+
+        let mut tared = reading;
+        for (value, offset) in tared.sensors.iter_mut().zip(self.offsets) {
+            *value = (*value - offset).max(0.0);
+        }
+        let smoothed = match self.smoothed {
+            Some(previous) => {
+                let alpha = self.config.smoothing.clamp(0.0, 0.99);
+                let mut smoothed = tared;
+                for (value, previous) in smoothed.sensors.iter_mut().zip(previous.sensors) {
+                    *value = previous * alpha + *value * (1.0 - alpha);
+                }
+                smoothed
+            }
+            None => tared,
+        };
+        self.smoothed = Some(smoothed);
+        self.apply_mappings(&smoothed);
+    }
+
+    fn apply_mappings(&mut self, reading: &BalanceBoardReading) {
+        let weight = reading.total_weight();
+        let (lean_x, lean_y) = if weight < self.config.min_weight {
+            (0.0, 0.0)
+        } else {
+            reading.center_of_pressure()
+        };
+        let lean_range = self.config.lean_range;
+        let max_weight = self.config.max_weight;
+        let stick = |lean: f64| (lean / lean_range).clamp(-1.0, 1.0);
+        let hysteresis = self.config.hysteresis;
+        // Weight thresholds are in kg, scale the hysteresis along with them.
+        let weight_hysteresis = hysteresis * max_weight * 0.1;
+
+        for index in 0..self.mappings.len() {
+            let input = self.mappings[index].input;
+            let output = self.mappings[index].output.clone();
+            let was_pressed = self.pressed[index];
+            let threshold = |value: f64, threshold: f64, margin: f64| {
+                if was_pressed {
+                    value > threshold - margin
+                } else {
+                    value > threshold
+                }
+            };
+            let pressed = match input {
+                BalanceBoardSource::LeanX => {
+                    let value = stick(lean_x);
+                    self.set_axis_or_button(&output, value, -1.0, 1.0);
+                    continue;
+                }
+                BalanceBoardSource::LeanY => {
+                    let value = stick(lean_y);
+                    self.set_axis_or_button(&output, value, -1.0, 1.0);
+                    continue;
+                }
+                BalanceBoardSource::Weight => {
+                    let value = weight.clamp(0.0, max_weight);
+                    self.set_axis_or_button(&output, value, 0.0, max_weight);
+                    continue;
+                }
+                BalanceBoardSource::WeightAbove(kg) => threshold(weight, kg, weight_hysteresis),
+                BalanceBoardSource::WeightBelow(kg) => threshold(-weight, -kg, weight_hysteresis),
+                BalanceBoardSource::LeanLeft(t) => threshold(-lean_x, t, hysteresis),
+                BalanceBoardSource::LeanRight(t) => threshold(lean_x, t, hysteresis),
+                BalanceBoardSource::LeanForward(t) => threshold(lean_y, t, hysteresis),
+                BalanceBoardSource::LeanBack(t) => threshold(-lean_y, t, hysteresis),
+            };
+            self.pressed[index] = pressed;
+            self.gamepad.set_digital_output(&output, pressed);
+        }
+    }
+
+    fn set_axis_or_button(&mut self, output: &OutputMapping, value: f64, min: f64, max: f64) {
+        match output {
+            OutputMapping::Axis(axis) => {
+                self.gamepad.get_axis_ref(axis.clone()).value =
+                    Axis::new(value, min, max).convert_into(false);
+            }
+            // An analog source on a button presses once it's three quarters of the way up.
+            output => {
+                let press_point = min + (max - min) * 0.75;
+                self.gamepad.set_digital_output(output, value > press_point);
+            }
+        }
+    }
+}
+
+pub struct BalanceBoardInput {
+    device: Device,
+    tracker: BalanceBoardTracker,
+    battery: BatteryMonitor,
+    battery_alert: Option<WiiBatteryAlert>,
+}
+
+impl BalanceBoardInput {
+    pub fn new(device: Device, name: String, config: BalanceBoardConfig) -> BalanceBoardInput {
+        BalanceBoardInput {
+            device,
+            tracker: BalanceBoardTracker::new(config),
+            battery: BatteryMonitor::new(name),
+            battery_alert: None,
+        }
+    }
+
+    pub fn discover_filtered(filter: &DeviceFilter) -> Vec<BalanceBoardInput> {
+        let paths = match wii_device_paths() {
+            Ok(paths) => paths,
+            Err(err) => {
+                log_error!("{}", err);
+                return vec![];
+            }
+        };
+
+        let mut inps: Vec<BalanceBoardInput> = vec![];
+        for path in paths {
+            let name = wii_device_name(&path);
+            if !filter.allows(&DeviceInfo::from_wii_path(&path, name.clone())) {
+                continue;
+            }
+            let device = match connect_wii_device(&path) {
+                Ok(device) => device,
+                Err(err) => {
+                    log_error!("{}", err);
+                    continue;
+                }
+            };
+            if device.kind().unwrap_or_default() != BALANCE_BOARD_KIND {
+                continue;
+            }
+            inps.push(BalanceBoardInput::new(
+                device,
+                name,
+                BalanceBoardConfig::default(),
+            ));
+        }
+
+        inps
+    }
+
+    pub fn discover_configured(config: &Config) -> Vec<BalanceBoardInput> {
+        let mut inps = Self::discover_filtered(&config.devices);
+        for inp in inps.iter_mut() {
+            inp.set_config(&config.balance_board);
+        }
+        inps
+    }
+
+    pub fn set_config(&mut self, config: &BalanceBoardSection) {
+        self.tracker.reconfigure(config.board);
+        for mapping in config.mappings.iter() {
+            self.tracker
+                .map_source(mapping.source, mapping.output.clone());
+        }
+    }
+
+    pub fn tare(&mut self) {
+        log!("Taring balance board, keep it empty...");
+        self.tracker.start_tare();
+    }
+}
+
+impl ControllerInput for BalanceBoardInput {
+    type ControllerType = BalanceBoardInput;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        self.tracker.to_gamepad()
+    }
+
+    fn prep_for_input_events(&mut self) {
+        self.device
+            .open(Channels::from_bits_retain(BALANCE_BOARD_CHANNEL), false)
+            .unwrap();
+        log!(
+            "BalanceBoardInput connected: {}",
+            self.device.kind().unwrap()
+        );
+        self.tare();
+    }
+
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        let power = poll_wii_battery(&mut self.device, &mut self.battery, &mut self.battery_alert);
+        self.tracker.set_power(power);
+
+        let maybe_event = {
+            let event_stream = &mut self.device.events().unwrap();
+            tokio::select! {
+                res = event_stream.try_next() => match res {
+                    Ok(event) => event,
+                    Err(_) => return Err("Error reading events.")
+                },
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    return Ok(false);
+                },
+            }
+        };
+
+        match maybe_event {
+            Some((Event::BalanceBoard(raw), _time)) => {
+                dashboard::raw_event(self.battery.name(), || format!("{:?}", raw));
+                self.tracker.update(raw);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.battery.name().to_string()
+    }
+}
+
+impl ConfigurableInput for BalanceBoardInput {
+    fn calibrate(&mut self) {
+        self.tare();
+    }
+}
+
+#[test]
+fn test_balance_board_center_of_pressure() {
+    // 20kg on each sensor, centered
+    let centered = BalanceBoardReading::from_raw([2000; 4]);
+    assert_eq!(centered.total_weight(), 80.0);
+    assert_eq!(centered.center_of_pressure(), (0.0, 0.0));
+
+    // All weight on the right side
+    let right = BalanceBoardReading::from_raw([4000, 4000, 0, 0]);
+    assert_eq!(right.center_of_pressure(), (1.0, 0.0));
+
+    // All weight on the front
+    let forward = BalanceBoardReading::from_raw([4000, 0, 4000, 0]);
+    assert_eq!(forward.center_of_pressure(), (0.0, 1.0));
+}
+
+#[test]
+fn test_balance_board_tare_and_thresholds() {
+    use crate::controller_abs::{GamepadAxis, GamepadButton};
+
+    let mut tracker = BalanceBoardTracker::new(BalanceBoardConfig {
+        smoothing: 0.0,
+        tare_samples: 2,
+        ..Default::default()
+    });
+    tracker.map_source(
+        BalanceBoardSource::LeanRight(0.3),
+        OutputMapping::Button(GamepadButton::DPadRight),
+    );
+    tracker.map_source(
+        BalanceBoardSource::WeightAbove(40.0),
+        OutputMapping::Button(GamepadButton::South),
+    );
+    tracker.map_source(
+        BalanceBoardSource::LeanX,
+        OutputMapping::Axis(GamepadAxis::LeftJoystickX),
+    );
+
+    // Empty board reads a small offset
+    tracker.start_tare();
+    tracker.update([100; 4]);
+    tracker.update([100; 4]);
+    assert!(!tracker.is_taring());
+    assert_eq!(tracker.get_offsets(), &[1.0; 4]);
+
+    // 60kg leaning right
+    tracker.update([2100, 2100, 1100, 1100]);
+    assert!(tracker.to_gamepad().buttons[&GamepadButton::DPadRight]);
+    assert!(tracker.to_gamepad().buttons[&GamepadButton::South]);
+    assert!(tracker.to_gamepad().axes[&GamepadAxis::LeftJoystickX].value > u64::MAX / 2);
+
+    // Stepping off releases everything
+    tracker.update([100; 4]);
+    assert!(!tracker.to_gamepad().buttons[&GamepadButton::DPadRight]);
+    assert!(!tracker.to_gamepad().buttons[&GamepadButton::South]);
+
+    // A new config drops the old mappings and what they pressed
+    tracker.update([2100, 2100, 1100, 1100]);
+    tracker.reconfigure(BalanceBoardConfig::default());
+    tracker.update([2100, 2100, 1100, 1100]);
+    assert!(!tracker.to_gamepad().buttons[&GamepadButton::South]);
+    assert_eq!(tracker.get_offsets(), &[1.0; 4]);
+}
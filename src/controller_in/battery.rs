@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use xwiimote::{Device, Led};
+
+use crate::controller_abs::PowerState;
+
+pub const LEDS: [Led; 4] = [Led::One, Led::Two, Led::Three, Led::Four];
+
+// Polls a device's power state every so often and decides when to warn about it.
+pub struct BatteryMonitor {
+    name: String,
+    interval: Duration,
+    low_threshold: u8,
+    last_poll: Option<Instant>,
+    state: Option<PowerState>,
+    warned: bool,
+}
+
+impl BatteryMonitor {
+    pub fn new(name: String) -> BatteryMonitor {
+        BatteryMonitor {
+            name,
+            interval: Duration::from_secs(60),
+            low_threshold: 15,
+            last_poll: None,
+            state: None,
+            warned: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_state(&self) -> Option<PowerState> {
+        self.state
+    }
+
+    pub fn poll_due(&self, now: Instant) -> bool {
+        match self.last_poll {
+            Some(last_poll) => now.duration_since(last_poll) >= self.interval,
+            None => true,
+        }
+    }
+
+    // Returns true once when the battery drops below the low threshold.
+    pub fn update(&mut self, state: Option<PowerState>, now: Instant) -> bool {
+        self.last_poll = Some(now);
+        if state != self.state {
+            if let Some(state) = state {
+                log!("Battery {}: {}", self.name, state);
+            }
+            self.state = state;
+        }
+
+        let level = match state {
+            Some(PowerState::Discharging(level)) => level,
+            // Charging or wired, nothing to warn about.
+            _ => {
+                self.warned = false;
+                return false;
+            }
+        };
+        if level >= self.low_threshold.saturating_add(5) {
+            self.warned = false;
+        }
+        if level >= self.low_threshold || self.warned {
+            return false;
+        }
+        self.warned = true;
+        log_error!("Battery low on {}: {}%, replace soon!", self.name, level);
+        true
+    }
+}
+
+// Blinks the LEDs and buzzes a Wii remote so it's obvious which one is running low.
+pub struct WiiBatteryAlert {
+    start: Instant,
+    saved_leds: [bool; 4],
+    phase: Option<usize>,
+    rumbling: bool,
+}
+
+const ALERT_RUMBLE: Duration = Duration::from_millis(300);
+const ALERT_BLINK: Duration = Duration::from_millis(250);
+const ALERT_BLINKS: u32 = 8;
+
+impl WiiBatteryAlert {
+    pub fn start(device: &mut Device, now: Instant) -> WiiBatteryAlert {
+        let mut saved_leds = [false; 4];
+        for (saved, led) in saved_leds.iter_mut().zip(LEDS) {
+            *saved = device.led(led).unwrap_or(false);
+        }
+        let rumbling = device.set_rumble(true).is_ok();
+        WiiBatteryAlert {
+            start: now,
+            saved_leds,
+            phase: None,
+            rumbling,
+        }
+    }
+
+    // Returns false once the alert is over and the LEDs are restored.
+    pub fn update(&mut self, device: &mut Device, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.start);
+        if self.rumbling && elapsed >= ALERT_RUMBLE {
+            self.rumbling = device.set_rumble(false).is_err();
+        }
+        if elapsed >= ALERT_BLINK * ALERT_BLINKS {
+            for (saved, led) in self.saved_leds.iter().zip(LEDS) {
+                let _ = device.set_led(led, *saved);
+            }
+            return false;
+        }
+        let phase = (elapsed.as_millis() / ALERT_BLINK.as_millis()) as usize;
+        if self.phase != Some(phase) {
+            self.phase = Some(phase);
+            for led in LEDS {
+                let _ = device.set_led(led, phase & 1 == 0);
+            }
+        }
+        true
+    }
+}
+
+// Shared by the xwiimote based inputs, call it every tick.
+pub fn poll_wii_battery(
+    device: &mut Device,
+    monitor: &mut BatteryMonitor,
+    alert: &mut Option<WiiBatteryAlert>,
+) -> Option<PowerState> {
+    let now = Instant::now();
+    if let Some(active) = alert {
+        if !active.update(device, now) {
+            *alert = None;
+        }
+    }
+    if monitor.poll_due(now) {
+        // Wii remotes and balance boards run on disposable batteries, so they're always discharging.
+        let state = device.battery().ok().map(PowerState::Discharging);
+        if monitor.update(state, now) && alert.is_none() {
+            *alert = Some(WiiBatteryAlert::start(device, now));
+        }
+    }
+    monitor.get_state()
+}
+
+#[test]
+fn test_battery_monitor_warns_once() {
+    let now = Instant::now();
+    let mut monitor = BatteryMonitor::new("test".to_string());
+    assert!(monitor.poll_due(now));
+
+    assert!(!monitor.update(Some(PowerState::Discharging(50)), now));
+    assert!(!monitor.poll_due(now + Duration::from_secs(1)));
+    assert!(monitor.poll_due(now + Duration::from_secs(60)));
+
+    assert!(monitor.update(Some(PowerState::Discharging(10)), now));
+    assert!(!monitor.update(Some(PowerState::Discharging(9)), now));
+    // Hovering around the threshold doesn't re-arm
+    assert!(!monitor.update(Some(PowerState::Discharging(16)), now));
+    assert!(!monitor.update(Some(PowerState::Discharging(14)), now));
+
+    // Fresh batteries, then draining again
+    assert!(!monitor.update(Some(PowerState::Discharging(100)), now));
+    assert!(monitor.update(Some(PowerState::Discharging(5)), now));
+
+    assert!(!monitor.update(Some(PowerState::Charging(5)), now));
+    assert_eq!(monitor.get_state(), Some(PowerState::Charging(5)));
+}
@@ -0,0 +1,317 @@
+use std::fs;
+use std::path::Path;
+
+use gilrs::Gamepad as GilGamepad;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceBackend {
+    GilRs,
+    XWiimote,
+}
+
+// What we know about a device before opening it as an input.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub backend: Option<DeviceBackend>,
+    pub name: String,
+    pub os_name: Option<String>,
+    // SDL style GUID, lowercase hex.
+    pub guid: Option<String>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub path: Option<String>,
+    pub mac: Option<String>,
+}
+
+impl DeviceInfo {
+    pub fn from_gilrs(gamepad: &GilGamepad) -> DeviceInfo {
+        DeviceInfo {
+            backend: Some(DeviceBackend::GilRs),
+            name: gamepad.name().to_string(),
+            os_name: Some(gamepad.os_name().to_string()),
+            guid: Some(
+                gamepad
+                    .uuid()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect(),
+            ),
+            vendor_id: gamepad.vendor_id(),
+            product_id: gamepad.product_id(),
+            // gilrs doesn't expose the evdev node.
+            path: None,
+            mac: None,
+        }
+    }
+
+    pub fn from_wii_path(path: &Path, name: String) -> DeviceInfo {
+        // HID devices are named <bus>:<vendor>:<product>.<instance>
+        let hid_id = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut ids = hid_id.split(['.', ':']).skip(1);
+        let vendor_id = ids.next().and_then(|id| u16::from_str_radix(id, 16).ok());
+        let product_id = ids.next().and_then(|id| u16::from_str_radix(id, 16).ok());
+
+        let mut os_name = None;
+        let mut mac = None;
+        for line in fs::read_to_string(path.join("uevent"))
+            .unwrap_or_default()
+            .lines()
+        {
+            if let Some(value) = line.strip_prefix("HID_NAME=") {
+                os_name = Some(value.to_string());
+            } else if let Some(value) = line.strip_prefix("HID_UNIQ=") {
+                mac = Some(value.to_lowercase());
+            }
+        }
+
+        DeviceInfo {
+            backend: Some(DeviceBackend::XWiimote),
+            name,
+            os_name,
+            guid: None,
+            vendor_id,
+            product_id,
+            path: Some(path.to_string_lossy().into_owned()),
+            mac,
+        }
+    }
+}
+
+// Accepts ids as plain numbers or as hex strings like "057e"/"0x057e".
+fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(u16),
+        Hex(String),
+    }
+    match Option::<Id>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Id::Number(id)) => Ok(Some(id)),
+        Some(Id::Hex(hex)) => u16::from_str_radix(hex.trim_start_matches("0x"), 16)
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid device id: {}", hex))),
+    }
+}
+
+// Compiled once when the config is loaded, an invalid pattern fails the load.
+#[derive(Clone, Debug)]
+pub struct NameRegex(Regex);
+
+impl NameRegex {
+    pub fn new(pattern: &str) -> Result<NameRegex, String> {
+        Regex::new(pattern)
+            .map(NameRegex)
+            .map_err(|err| format!("Invalid name_regex: {}", err))
+    }
+}
+
+impl PartialEq for NameRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for NameRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for NameRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        NameRegex::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+// Matches when every field that is set matches, an empty rule matches everything.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct DeviceRule {
+    pub backend: Option<DeviceBackend>,
+    // Substring of the name or the OS name.
+    pub name: Option<String>,
+    pub name_regex: Option<NameRegex>,
+    pub guid: Option<String>,
+    #[serde(deserialize_with = "deserialize_id")]
+    pub vendor_id: Option<u16>,
+    #[serde(deserialize_with = "deserialize_id")]
+    pub product_id: Option<u16>,
+    // Substring of the device path. Only Wii devices have one, gilrs doesn't expose the evdev
+    // node, so a rule with a path never matches a gilrs device.
+    pub path: Option<String>,
+    pub mac: Option<String>,
+}
+
+impl DeviceRule {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        let names = || std::iter::once(device.name.as_str()).chain(device.os_name.as_deref());
+        let same = |want: &Option<String>, have: &Option<String>| match (want, have) {
+            (None, _) => true,
+            (Some(want), Some(have)) => want.eq_ignore_ascii_case(have),
+            (Some(_), None) => false,
+        };
+
+        if self.backend.is_some() && self.backend != device.backend {
+            return false;
+        }
+        if let Some(name) = &self.name {
+            if !names().any(|n| n.contains(name.as_str())) {
+                return false;
+            }
+        }
+        if let Some(NameRegex(regex)) = &self.name_regex {
+            if !names().any(|n| regex.is_match(n)) {
+                return false;
+            }
+        }
+        if self.vendor_id.is_some() && self.vendor_id != device.vendor_id {
+            return false;
+        }
+        if self.product_id.is_some() && self.product_id != device.product_id {
+            return false;
+        }
+        if let Some(path) = &self.path {
+            if !device
+                .path
+                .as_ref()
+                .is_some_and(|p| p.contains(path.as_str()))
+            {
+                return false;
+            }
+        }
+        same(&self.guid, &device.guid) && same(&self.mac, &device.mac)
+    }
+}
+
+// Decides which devices the input backends pick up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DeviceFilter {
+    // When not empty, only devices matching one of these are used.
+    pub include: Vec<DeviceRule>,
+    pub exclude: Vec<DeviceRule>,
+    // Adds `default_exclude_rules` to `exclude`.
+    pub default_rules: bool,
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        DeviceFilter {
+            include: vec![],
+            exclude: vec![],
+            default_rules: true,
+        }
+    }
+}
+
+// Wii remotes also show up in gilrs, they're read through xwiimote instead.
+pub fn default_exclude_rules() -> Vec<DeviceRule> {
+    ["Wii", "Nunchuk"]
+        .iter()
+        .map(|name| DeviceRule {
+            backend: Some(DeviceBackend::GilRs),
+            name: Some(name.to_string()),
+            ..Default::default()
+        })
+        .collect()
+}
+
+impl DeviceFilter {
+    pub fn allows(&self, device: &DeviceInfo) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|rule| rule.matches(device)) {
+            return false;
+        }
+        let defaults = if self.default_rules {
+            default_exclude_rules()
+        } else {
+            vec![]
+        };
+        !self
+            .exclude
+            .iter()
+            .chain(defaults.iter())
+            .any(|rule| rule.matches(device))
+    }
+}
+
+#[test]
+fn test_device_filter_rules() {
+    let wii_in_gilrs = DeviceInfo {
+        backend: Some(DeviceBackend::GilRs),
+        name: "Nintendo Wii Remote Nunchuk".to_string(),
+        ..Default::default()
+    };
+    let wii_in_xwiimote = DeviceInfo {
+        backend: Some(DeviceBackend::XWiimote),
+        name: "Wii 0005:057E:0306.0001".to_string(),
+        vendor_id: Some(0x057e),
+        product_id: Some(0x0306),
+        mac: Some("00:1f:32:aa:bb:cc".to_string()),
+        ..Default::default()
+    };
+    let keyboard = DeviceInfo {
+        backend: Some(DeviceBackend::GilRs),
+        name: "Logitech K400".to_string(),
+        os_name: Some("Logitech USB Receiver Consumer Control".to_string()),
+        guid: Some("030000006d0400004dc4000011010000".to_string()),
+        ..Default::default()
+    };
+
+    let filter = DeviceFilter::default();
+    assert!(!filter.allows(&wii_in_gilrs));
+    assert!(filter.allows(&wii_in_xwiimote));
+    assert!(filter.allows(&keyboard));
+
+    let filter: DeviceFilter = serde_json::from_str(
+        r#"{
+            "exclude": [
+                {"name_regex": "Consumer Control$"},
+                {"vendor_id": "0x057e", "mac": "00:1F:32:AA:BB:CC"}
+            ],
+            "default_rules": false
+        }"#,
+    )
+    .unwrap();
+    assert!(filter.allows(&wii_in_gilrs));
+    assert!(!filter.allows(&wii_in_xwiimote));
+    assert!(!filter.allows(&keyboard));
+
+    let only_keyboard = DeviceFilter {
+        include: vec![DeviceRule {
+            guid: Some("030000006D0400004DC4000011010000".to_string()),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(only_keyboard.allows(&keyboard));
+    assert!(!only_keyboard.allows(&wii_in_xwiimote));
+
+    let bad_regex = serde_json::from_str::<DeviceFilter>(r#"{"include": [{"name_regex": "("}]}"#);
+    assert!(bad_regex.is_err());
+}
+
+#[test]
+fn test_wii_device_info() {
+    let dir = std::env::temp_dir().join(format!("cursed_controls_wii_{}", std::process::id()));
+    let path = dir.join("0005:057E:0306.0001");
+    fs::create_dir_all(&path).unwrap();
+    fs::write(
+        path.join("uevent"),
+        "DRIVER=wiimote\nHID_NAME=Nintendo RVL-CNT-01\nHID_UNIQ=00:1F:32:AA:BB:CC\n",
+    )
+    .unwrap();
+
+    let name = super::wii_device_name(&path);
+    assert_eq!(name, "Wii 0005:057E:0306.0001");
+    let info = DeviceInfo::from_wii_path(&path, name);
+    assert_eq!(info.vendor_id, Some(0x057e));
+    assert_eq!(info.product_id, Some(0x0306));
+    assert_eq!(info.os_name.as_deref(), Some("Nintendo RVL-CNT-01"));
+    assert_eq!(info.mac.as_deref(), Some("00:1f:32:aa:bb:cc"));
+    assert_eq!(info.path, Some(path.to_string_lossy().into_owned()));
+    fs::remove_dir_all(&dir).unwrap();
+}
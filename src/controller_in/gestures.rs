@@ -0,0 +1,263 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+// Roughly what the Wii remote/nunchuk accelerometers report for 1g.
+pub const ONE_G: f64 = 100.0;
+// How quickly the gravity estimate follows the raw readings.
+const GRAVITY_FILTER: f64 = 0.1;
+const HISTORY_WINDOW: Duration = Duration::from_millis(500);
+
+const FLICK_THRESHOLD: f64 = 1.5 * ONE_G;
+const SHAKE_THRESHOLD: f64 = 1.0 * ONE_G;
+const SHAKE_REVERSALS: usize = 3;
+const TWIST_ANGLE: f64 = PI / 3.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Gesture {
+    Shake,
+    FlickUp,
+    FlickDown,
+    FlickLeft,
+    FlickRight,
+    // Jab forward along the pointing direction
+    Thrust,
+    // Quick roll around the pointing direction
+    Twist,
+}
+
+// The nunchuk only reports x/y acceleration, so up/down flicks and twists need the remote.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum GestureSource {
+    #[default]
+    Remote,
+    Nunchuk,
+}
+
+impl GestureSource {
+    pub fn supports(&self, gesture: Gesture) -> bool {
+        match self {
+            GestureSource::Remote => true,
+            GestureSource::Nunchuk => !matches!(
+                gesture,
+                Gesture::FlickUp | Gesture::FlickDown | Gesture::Twist
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GestureConfig {
+    // Higher is more sensitive, scales the acceleration thresholds down.
+    pub sensitivity: f64,
+    // Minimum time between two detections.
+    pub cooldown_ms: u64,
+    // How long the mapped output is held down per detection.
+    pub pulse_ms: u64,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        GestureConfig {
+            sensitivity: 1.0,
+            cooldown_ms: 300,
+            pulse_ms: 80,
+        }
+    }
+}
+
+struct GestureSample {
+    time: Instant,
+    raw: (f64, f64, f64),
+    linear: (f64, f64, f64),
+}
+
+pub struct GestureDetector {
+    gesture: Gesture,
+    config: GestureConfig,
+    gravity: Option<(f64, f64, f64)>,
+    history: VecDeque<GestureSample>,
+    last_fired: Option<Instant>,
+    pressed_until: Option<Instant>,
+}
+
+fn component(vector: (f64, f64, f64), axis: usize) -> f64 {
+    match axis {
+        0 => vector.0,
+        1 => vector.1,
+        _ => vector.2,
+    }
+}
+
+fn roll(raw: (f64, f64, f64)) -> f64 {
+    raw.0.atan2(raw.2)
+}
+
+fn angle_between(a: f64, b: f64) -> f64 {
+    let diff = (a - b).rem_euclid(2.0 * PI);
+    if diff > PI {
+        2.0 * PI - diff
+    } else {
+        diff
+    }
+}
+
+impl GestureDetector {
+    pub fn new(gesture: Gesture, config: GestureConfig) -> GestureDetector {
+        GestureDetector {
+            gesture,
+            config,
+            gravity: None,
+            history: VecDeque::new(),
+            last_fired: None,
+            pressed_until: None,
+        }
+    }
+
+    // Feed an accelerometer reading, returns whether the gesture fired on it.
+    pub fn update(&mut self, raw: (f64, f64, f64), now: Instant) -> bool {
+        let gravity = match self.gravity {
+            Some(g) => (
+                g.0 + (raw.0 - g.0) * GRAVITY_FILTER,
+                g.1 + (raw.1 - g.1) * GRAVITY_FILTER,
+                g.2 + (raw.2 - g.2) * GRAVITY_FILTER,
+            ),
+            None => raw,
+        };
+        self.gravity = Some(gravity);
+        self.history.push_back(GestureSample {
+            time: now,
+            raw,
+            linear: (raw.0 - gravity.0, raw.1 - gravity.1, raw.2 - gravity.2),
+        });
+        while let Some(oldest) = self.history.front() {
+            if now.duration_since(oldest.time) <= HISTORY_WINDOW {
+                break;
+            }
+            self.history.pop_front();
+        }
+
+        if let Some(fired) = self.last_fired {
+            if now.duration_since(fired) < Duration::from_millis(self.config.cooldown_ms) {
+                return false;
+            }
+        }
+        if !self.detect() {
+            return false;
+        }
+        self.last_fired = Some(now);
+        self.pressed_until = Some(now + Duration::from_millis(self.config.pulse_ms));
+        // Don't let the tail of this gesture count towards the next one.
+        self.history.clear();
+        true
+    }
+
+    pub fn is_pressed(&self, now: Instant) -> bool {
+        self.pressed_until.is_some_and(|until| now < until)
+    }
+
+    fn detect(&self) -> bool {
+        let latest = match self.history.back() {
+            Some(latest) => latest,
+            None => return false,
+        };
+        let sensitivity = self.config.sensitivity.max(0.01);
+        match self.gesture {
+            Gesture::FlickRight => self.is_flick(latest.linear, 0, FLICK_THRESHOLD / sensitivity),
+            Gesture::FlickLeft => self.is_flick(latest.linear, 0, -FLICK_THRESHOLD / sensitivity),
+            Gesture::Thrust => self.is_flick(latest.linear, 1, FLICK_THRESHOLD / sensitivity),
+            Gesture::FlickUp => self.is_flick(latest.linear, 2, FLICK_THRESHOLD / sensitivity),
+            Gesture::FlickDown => self.is_flick(latest.linear, 2, -FLICK_THRESHOLD / sensitivity),
+            Gesture::Shake => (0..3).any(|axis| {
+                self.count_reversals(axis, SHAKE_THRESHOLD / sensitivity) >= SHAKE_REVERSALS
+            }),
+            Gesture::Twist => {
+                // Only trust the roll angle while gravity dominates the reading.
+                let current = roll(latest.raw);
+                self.history.iter().any(|sample| {
+                    (sample.raw.0.abs() + sample.raw.2.abs()) > ONE_G * 0.5
+                        && angle_between(roll(sample.raw), current) > TWIST_ANGLE / sensitivity
+                })
+            }
+        }
+    }
+
+    // A flick is a spike on one axis that clearly dominates the other two.
+    fn is_flick(&self, linear: (f64, f64, f64), axis: usize, threshold: f64) -> bool {
+        let value = component(linear, axis);
+        if value.abs() < threshold.abs() || value.signum() != threshold.signum() {
+            return false;
+        }
+        (0..3)
+            .filter(|other| *other != axis)
+            .all(|other| component(linear, other).abs() * 1.5 < value.abs())
+    }
+
+    fn count_reversals(&self, axis: usize, threshold: f64) -> usize {
+        let mut reversals = 0;
+        let mut last_sign = 0.0;
+        for sample in &self.history {
+            let value = component(sample.linear, axis);
+            if value.abs() < threshold {
+                continue;
+            }
+            if last_sign != 0.0 && value.signum() != last_sign {
+                reversals += 1;
+            }
+            last_sign = value.signum();
+        }
+        reversals
+    }
+}
+
+#[test]
+fn test_gesture_flick_and_cooldown() {
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    let rest = (0.0, 0.0, ONE_G);
+
+    let mut flick = GestureDetector::new(Gesture::FlickRight, GestureConfig::default());
+    assert!(!flick.update(rest, at(0)));
+    assert!(!flick.update(rest, at(10)));
+    // Sharp jerk to the right
+    assert!(flick.update((250.0, 0.0, ONE_G), at(20)));
+    assert!(flick.is_pressed(at(50)));
+    // Pulse released, still cooling down
+    assert!(!flick.is_pressed(at(120)));
+    assert!(!flick.update((250.0, 0.0, ONE_G), at(130)));
+    assert!(flick.update((400.0, 0.0, ONE_G), at(400)));
+
+    // Wrong direction doesn't count
+    let mut left = GestureDetector::new(Gesture::FlickLeft, GestureConfig::default());
+    left.update(rest, at(0));
+    assert!(!left.update((250.0, 0.0, ONE_G), at(10)));
+}
+
+#[test]
+fn test_gesture_shake_and_twist() {
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+
+    let mut shake = GestureDetector::new(Gesture::Shake, GestureConfig::default());
+    shake.update((0.0, 0.0, ONE_G), at(0));
+    let mut fired = false;
+    for i in 1..10 {
+        let x = if i % 2 == 0 { 200.0 } else { -200.0 };
+        fired |= shake.update((x, 0.0, ONE_G), at(i * 40));
+    }
+    assert!(fired);
+
+    // A single flick isn't a shake
+    let mut not_shake = GestureDetector::new(Gesture::Shake, GestureConfig::default());
+    not_shake.update((0.0, 0.0, ONE_G), at(0));
+    assert!(!not_shake.update((250.0, 0.0, ONE_G), at(10)));
+
+    // Rolling the remote onto its side
+    let mut twist = GestureDetector::new(Gesture::Twist, GestureConfig::default());
+    twist.update((0.0, 0.0, ONE_G), at(0));
+    twist.update((50.0, 0.0, 87.0), at(50));
+    assert!(twist.update((ONE_G, 0.0, 0.0), at(100)));
+}
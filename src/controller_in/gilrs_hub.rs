@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self as std_mpsc, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use gilrs::ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder, Replay, Ticks};
+use gilrs::{
+    Event as GilEvent, EventType as GilEventType, GamepadId as GilGamepadId, Gilrs, GilrsBuilder,
+    MappingSource, PowerInfo as GilPowerInfo,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use super::device_filter::DeviceInfo;
+
+// Events queued per pad before new ones get dropped, in case an input stops reading.
+const MAX_QUEUED_EVENTS: usize = 256;
+// How long the reader waits for gilrs events before looking at commands again.
+const COMMAND_POLL: Duration = Duration::from_millis(5);
+
+// A pad gilrs saw when the hub started.
+pub struct GilRsPad {
+    pub id: GilGamepadId,
+    pub info: DeviceInfo,
+    pub mapping_source: MappingSource,
+}
+
+enum Command {
+    Register(GilGamepadId, mpsc::Sender<GilEvent>),
+    PowerInfo(GilGamepadId, oneshot::Sender<GilPowerInfo>),
+    Rumble(GilGamepadId, f64, f64),
+    BatteryWarning(GilGamepadId),
+}
+
+// Owns the one Gilrs context on a reader thread, which hands each pad's events to the input
+// reading it. Cheap to clone, the thread stops once every clone is gone.
+#[derive(Clone)]
+pub struct GilRsHub {
+    commands: std_mpsc::Sender<Command>,
+    connected: Arc<Mutex<Vec<(GilGamepadId, DeviceInfo)>>>,
+}
+
+impl GilRsHub {
+    // `sdl_mappings` are gamecontrollerdb.txt lines, on top of the ones bundled with gilrs.
+    pub fn start(sdl_mappings: &str) -> Result<(GilRsHub, Vec<GilRsPad>), String> {
+        let (commands, command_rx) = std_mpsc::channel();
+        let (ready, ready_rx) = std_mpsc::channel();
+        let connected = Arc::new(Mutex::new(vec![]));
+        let reader_connected = connected.clone();
+        let sdl_mappings = sdl_mappings.to_string();
+        thread::Builder::new()
+            .name("gilrs".to_string())
+            .spawn(move || {
+                let gil_rs = match GilrsBuilder::new().add_mappings(&sdl_mappings).build() {
+                    Ok(gil_rs) => gil_rs,
+                    Err(err) => {
+                        let _ = ready.send(Err(format!("Could not start gilrs: {}", err)));
+                        return;
+                    }
+                };
+                let pads = gil_rs
+                    .gamepads()
+                    .map(|(id, gamepad)| GilRsPad {
+                        id,
+                        info: DeviceInfo::from_gilrs(&gamepad),
+                        mapping_source: gamepad.mapping_source(),
+                    })
+                    .collect::<Vec<_>>();
+                *reader_connected.lock().unwrap() =
+                    pads.iter().map(|pad| (pad.id, pad.info.clone())).collect();
+                let _ = ready.send(Ok(pads));
+                Reader {
+                    gil_rs,
+                    commands: command_rx,
+                    connected: reader_connected,
+                    pads: HashMap::new(),
+                }
+                .run();
+            })
+            .map_err(|err| format!("Could not start the gilrs thread: {}", err))?;
+        let pads = ready_rx
+            .recv()
+            .map_err(|_| "The gilrs thread stopped".to_string())??;
+        Ok((
+            GilRsHub {
+                commands,
+                connected,
+            },
+            pads,
+        ))
+    }
+
+    // Events for `id` from now on, only registered pads get their events queued.
+    pub fn register(&self, id: GilGamepadId) -> mpsc::Receiver<GilEvent> {
+        let (events, receiver) = mpsc::channel(MAX_QUEUED_EVENTS);
+        let _ = self.commands.send(Command::Register(id, events));
+        receiver
+    }
+
+    // Answered by the reader thread, poll the receiver rather than waiting on it.
+    pub fn power_info(&self, id: GilGamepadId) -> oneshot::Receiver<GilPowerInfo> {
+        let (reply, receiver) = oneshot::channel();
+        let _ = self.commands.send(Command::PowerInfo(id, reply));
+        receiver
+    }
+
+    // Strengths from 0 to 1, zero for both stops it.
+    pub fn rumble(&self, id: GilGamepadId, strong: f64, weak: f64) {
+        let _ = self.commands.send(Command::Rumble(id, strong, weak));
+    }
+
+    pub fn battery_warning(&self, id: GilGamepadId) {
+        let _ = self.commands.send(Command::BatteryWarning(id));
+    }
+
+    // Every connected pad, not only the registered ones.
+    pub fn connected_devices(&self) -> Vec<DeviceInfo> {
+        let connected = self.connected.lock().unwrap();
+        connected.iter().map(|(_, info)| info.clone()).collect()
+    }
+}
+
+struct Pad {
+    events: mpsc::Sender<GilEvent>,
+    // Dropping an effect stops it, so they're kept until replaced.
+    rumble: Option<Effect>,
+    battery_rumble: Option<Effect>,
+}
+
+struct Reader {
+    gil_rs: Gilrs,
+    commands: std_mpsc::Receiver<Command>,
+    connected: Arc<Mutex<Vec<(GilGamepadId, DeviceInfo)>>>,
+    pads: HashMap<GilGamepadId, Pad>,
+}
+
+impl Reader {
+    fn run(mut self) {
+        loop {
+            loop {
+                match self.commands.try_recv() {
+                    Ok(command) => self.handle(command),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            if let Some(event) = self.gil_rs.next_event_blocking(Some(COMMAND_POLL)) {
+                self.dispatch(event);
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Register(id, events) => {
+                self.pads.insert(
+                    id,
+                    Pad {
+                        events,
+                        rumble: None,
+                        battery_rumble: None,
+                    },
+                );
+            }
+            Command::PowerInfo(id, reply) => {
+                let _ = reply.send(self.gil_rs.gamepad(id).power_info());
+            }
+            Command::Rumble(id, strong, weak) => {
+                let magnitude = |strength: f64| (strength.clamp(0.0, 1.0) * u16::MAX as f64) as u16;
+                let scheduling = Replay {
+                    play_for: Ticks::from_ms(50),
+                    ..Default::default()
+                };
+                let effects = [
+                    BaseEffect {
+                        kind: BaseEffectType::Strong {
+                            magnitude: magnitude(strong),
+                        },
+                        scheduling,
+                        ..Default::default()
+                    },
+                    BaseEffect {
+                        kind: BaseEffectType::Weak {
+                            magnitude: magnitude(weak),
+                        },
+                        scheduling,
+                        ..Default::default()
+                    },
+                ];
+                let stop = strong <= 0.0 && weak <= 0.0;
+                let effect = self.play(id, &effects, stop);
+                if let Some(pad) = self.pads.get_mut(&id) {
+                    pad.rumble = effect;
+                }
+            }
+            Command::BatteryWarning(id) => {
+                let effect = BaseEffect {
+                    kind: BaseEffectType::Strong { magnitude: 40_000 },
+                    scheduling: Replay {
+                        play_for: Ticks::from_ms(300),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let effect = self.play(id, &[effect], false);
+                if let Some(pad) = self.pads.get_mut(&id) {
+                    pad.battery_rumble = effect;
+                }
+            }
+        }
+    }
+
+    fn play(&mut self, id: GilGamepadId, effects: &[BaseEffect], stop: bool) -> Option<Effect> {
+        if stop || !self.gil_rs.gamepad(id).is_ff_supported() {
+            return None;
+        }
+        let mut builder = EffectBuilder::new();
+        for effect in effects {
+            builder.add_effect(*effect);
+        }
+        match builder.gamepads(&[id]).finish(&mut self.gil_rs) {
+            Ok(effect) => {
+                let _ = effect.play();
+                Some(effect)
+            }
+            Err(err) => {
+                log_error!(
+                    "Could not rumble {}: {}",
+                    self.gil_rs.gamepad(id).name(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    fn dispatch(&mut self, event: GilEvent) {
+        match event.event {
+            GilEventType::Connected => {
+                let info = DeviceInfo::from_gilrs(&self.gil_rs.gamepad(event.id));
+                let mut connected = self.connected.lock().unwrap();
+                connected.retain(|(id, _)| *id != event.id);
+                connected.push((event.id, info));
+                connected.sort_by_key(|(id, _)| usize::from(*id));
+            }
+            GilEventType::Disconnected => {
+                let mut connected = self.connected.lock().unwrap();
+                connected.retain(|(id, _)| *id != event.id);
+            }
+            _ => {}
+        }
+        if let Some(pad) = self.pads.get(&event.id) {
+            // Full when the input stopped reading, it catches up from later events.
+            let _ = pad.events.try_send(event);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use xwiimote::events::IrSource;
+
+// Wii remote IR camera resolution
+const IR_MAX_X: f64 = 1023.0;
+const IR_MAX_Y: f64 = 767.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum IrPointerMode {
+    // Pointer position maps directly onto the stick, light-gun style.
+    Absolute,
+    // Pointer movement maps onto stick deflection, like a camera/mouse look.
+    Relative,
+}
+
+// What to do once the sensor bar is out of view for longer than `lost_grace_ms`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum IrLostBehaviour {
+    HoldLast,
+    Recenter,
+    Tilt,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default)]
+pub struct IrPointerConfig {
+    pub mode: IrPointerMode,
+    pub on_lost: IrLostBehaviour,
+    // Fraction of the camera's view that maps to full deflection (absolute mode).
+    pub range: f64,
+    // Deflection per unit of normalized pointer movement (relative mode).
+    pub sensitivity: f64,
+    pub lost_grace_ms: u64,
+    // Accelerometer reading that maps to full deflection when falling back to tilt.
+    pub tilt_range: f64,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Default for IrPointerConfig {
+    fn default() -> Self {
+        IrPointerConfig {
+            mode: IrPointerMode::Absolute,
+            on_lost: IrLostBehaviour::HoldLast,
+            range: 0.8,
+            sensitivity: 8.0,
+            lost_grace_ms: 100,
+            tilt_range: 100.0,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+// Tracks the sensor bar through the IR camera and turns it into a stick position in [-1, 1].
+pub struct IrPointer {
+    config: IrPointerConfig,
+    // Sensor bar midpoint and half its span, in camera coordinates.
+    last_midpoint: Option<(f64, f64)>,
+    last_half_span: Option<(f64, f64)>,
+    last_position: Option<(f64, f64)>,
+    last_seen: Option<Instant>,
+    output: (f64, f64),
+}
+
+fn clamp_unit(value: f64) -> f64 {
+    value.clamp(-1.0, 1.0)
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+impl IrPointer {
+    pub fn new(config: IrPointerConfig) -> IrPointer {
+        IrPointer {
+            config,
+            last_midpoint: None,
+            last_half_span: None,
+            last_position: None,
+            last_seen: None,
+            output: (0.0, 0.0),
+        }
+    }
+
+    pub fn update_ir(&mut self, sources: &[Option<IrSource>], now: Instant) -> (f64, f64) {
+        let midpoint = match self.locate_sensor_bar(sources) {
+            Some(midpoint) => midpoint,
+            None => {
+                self.output = self.lost_output(now);
+                return self.output;
+            }
+        };
+
+        let position = self.to_pointer_position(midpoint);
+        self.output = match self.config.mode {
+            IrPointerMode::Absolute => (
+                clamp_unit(position.0 / self.config.range),
+                clamp_unit(position.1 / self.config.range),
+            ),
+            IrPointerMode::Relative => match self.last_position {
+                Some(last) => (
+                    clamp_unit((position.0 - last.0) * self.config.sensitivity),
+                    clamp_unit((position.1 - last.1) * self.config.sensitivity),
+                ),
+                None => (0.0, 0.0),
+            },
+        };
+        self.last_midpoint = Some(midpoint);
+        self.last_position = Some(position);
+        self.last_seen = Some(now);
+        self.output
+    }
+
+    // Only produces output while the IR lock is lost and tilt is the fallback.
+    pub fn update_tilt(&mut self, x: i32, y: i32, now: Instant) -> Option<(f64, f64)> {
+        if self.config.on_lost != IrLostBehaviour::Tilt || !self.is_lost(now) {
+            return None;
+        }
+        self.output = self.apply_inversion((
+            clamp_unit(x as f64 / self.config.tilt_range),
+            clamp_unit(y as f64 / self.config.tilt_range),
+        ));
+        Some(self.output)
+    }
+
+    pub fn is_lost(&self, now: Instant) -> bool {
+        match self.last_seen {
+            Some(seen) => {
+                now.duration_since(seen) > Duration::from_millis(self.config.lost_grace_ms)
+            }
+            None => true,
+        }
+    }
+
+    fn lost_output(&mut self, now: Instant) -> (f64, f64) {
+        // Relative output is a velocity, so without a pointer there's no motion to report.
+        let hold = match self.config.mode {
+            IrPointerMode::Absolute => self.output,
+            IrPointerMode::Relative => (0.0, 0.0),
+        };
+        if !self.is_lost(now) {
+            return hold;
+        }
+        // Don't turn the reacquired position into a jump.
+        self.last_position = None;
+        match self.config.on_lost {
+            IrLostBehaviour::HoldLast => hold,
+            IrLostBehaviour::Recenter => (0.0, 0.0),
+            // Tilt output is driven by accelerometer events, keep what we have until then.
+            IrLostBehaviour::Tilt => self.output,
+        }
+    }
+
+    fn locate_sensor_bar(&mut self, sources: &[Option<IrSource>]) -> Option<(f64, f64)> {
+        let visible: Vec<(f64, f64)> = sources
+            .iter()
+            .flatten()
+            .map(|source| (source.x as f64, source.y as f64))
+            .collect();
+
+        match visible.len() {
+            0 => None,
+            1 => {
+                // Guess where the missing dot is from the last known span.
+                let half_span = self.last_half_span?;
+                let dot = visible[0];
+                let candidate_a = (dot.0 + half_span.0, dot.1 + half_span.1);
+                let candidate_b = (dot.0 - half_span.0, dot.1 - half_span.1);
+                let last = self.last_midpoint.unwrap_or(candidate_a);
+                if distance(candidate_a, last) <= distance(candidate_b, last) {
+                    Some(candidate_a)
+                } else {
+                    Some(candidate_b)
+                }
+            }
+            _ => {
+                // More than two dots are usually reflections, the sensor bar is the widest pair.
+                let mut pair = (visible[0], visible[1]);
+                for (i, a) in visible.iter().enumerate() {
+                    for b in visible.iter().skip(i + 1) {
+                        if (a.0 - b.0).abs() > (pair.0 .0 - pair.1 .0).abs() {
+                            pair = (*a, *b);
+                        }
+                    }
+                }
+                let (left, right) = if pair.0 .0 <= pair.1 .0 {
+                    pair
+                } else {
+                    (pair.1, pair.0)
+                };
+                self.last_half_span = Some(((right.0 - left.0) / 2.0, (right.1 - left.1) / 2.0));
+                Some(((left.0 + right.0) / 2.0, (left.1 + right.1) / 2.0))
+            }
+        }
+    }
+
+    fn to_pointer_position(&self, midpoint: (f64, f64)) -> (f64, f64) {
+        let center = (IR_MAX_X / 2.0, IR_MAX_Y / 2.0);
+        let mut offset = (midpoint.0 - center.0, midpoint.1 - center.1);
+
+        // Undo the remote's roll so the pointer keeps moving along the screen axes.
+        if let Some(half_span) = self.last_half_span {
+            let roll = half_span.1.atan2(half_span.0);
+            let (sin, cos) = (-roll).sin_cos();
+            offset = (
+                offset.0 * cos - offset.1 * sin,
+                offset.0 * sin + offset.1 * cos,
+            );
+        }
+
+        // The dots move opposite to where the remote points.
+        self.apply_inversion((
+            clamp_unit(-offset.0 / center.0),
+            clamp_unit(offset.1 / center.1),
+        ))
+    }
+
+    fn apply_inversion(&self, position: (f64, f64)) -> (f64, f64) {
+        let sign = |invert: bool| if invert { -1.0 } else { 1.0 };
+        (
+            position.0 * sign(self.config.invert_x),
+            position.1 * sign(self.config.invert_y),
+        )
+    }
+}
+
+#[test]
+fn test_ir_pointer_absolute() {
+    let mut pointer = IrPointer::new(IrPointerConfig {
+        range: 1.0,
+        ..Default::default()
+    });
+    let now = Instant::now();
+
+    // Sensor bar centered in view
+    let centered = [
+        Some(IrSource { x: 411, y: 383 }),
+        Some(IrSource { x: 611, y: 383 }),
+        None,
+        None,
+    ];
+    let (x, y) = pointer.update_ir(&centered, now);
+    assert!(x.abs() < 0.01 && y.abs() < 0.01);
+
+    // Dots on the left of the camera means the remote points right
+    let left = [
+        Some(IrSource { x: 0, y: 383 }),
+        Some(IrSource { x: 200, y: 383 }),
+        None,
+        None,
+    ];
+    let (x, _) = pointer.update_ir(&left, now);
+    assert!(x > 0.7);
+
+    // Losing one dot keeps tracking with the last known span
+    let one_dot = [None, Some(IrSource { x: 200, y: 383 }), None, None];
+    let (x_single, _) = pointer.update_ir(&one_dot, now);
+    assert!((x_single - x).abs() < 0.01);
+}
+
+#[test]
+fn test_ir_pointer_lost() {
+    let now = Instant::now();
+    let later = now + Duration::from_secs(1);
+    let centered_right = [
+        Some(IrSource { x: 100, y: 383 }),
+        Some(IrSource { x: 300, y: 383 }),
+        None,
+        None,
+    ];
+
+    let mut hold = IrPointer::new(IrPointerConfig::default());
+    let held = hold.update_ir(&centered_right, now);
+    assert_eq!(hold.update_ir(&[None; 4], later), held);
+
+    let mut recenter = IrPointer::new(IrPointerConfig {
+        on_lost: IrLostBehaviour::Recenter,
+        ..Default::default()
+    });
+    recenter.update_ir(&centered_right, now);
+    // Still within the grace period
+    assert_ne!(recenter.update_ir(&[None; 4], now), (0.0, 0.0));
+    assert_eq!(recenter.update_ir(&[None; 4], later), (0.0, 0.0));
+
+    let mut tilt = IrPointer::new(IrPointerConfig {
+        on_lost: IrLostBehaviour::Tilt,
+        ..Default::default()
+    });
+    tilt.update_ir(&centered_right, now);
+    assert_eq!(tilt.update_tilt(50, -200, now), None);
+    assert_eq!(tilt.update_tilt(50, -200, later), Some((0.5, -1.0)));
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use crate::controller_abs::OutputMapping;
+
+// Shift layers: modifier buttons that swap in alternate mappings, for inputs with few buttons.
+// Several layers can be active at once, later ones in the list win where they overlap. Presses
+// are remembered, so a layer change mid-press releases what the press went to, and a press that
+// went nowhere picks up its mapping once a layer stops hiding it.
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LayerMode {
+    // Active while the modifier is held.
+    #[default]
+    Hold,
+    // Each press of the modifier turns it on or off.
+    Toggle,
+}
+
+pub struct Layer<K> {
+    pub modifier: K,
+    pub mode: LayerMode,
+    // `None` leaves a button unmapped while the layer is active.
+    pub buttons: HashMap<K, Option<OutputMapping>>,
+}
+
+pub struct LayeredButtons<K> {
+    base: HashMap<K, OutputMapping>,
+    layers: Vec<Layer<K>>,
+    active: Vec<bool>,
+    // What each held input drives right now, `None` for presses that go nowhere.
+    held: HashMap<K, Option<OutputMapping>>,
+}
+
+impl<K: Hash + Eq + Clone> LayeredButtons<K> {
+    pub fn new(base: HashMap<K, OutputMapping>, layers: Vec<Layer<K>>) -> LayeredButtons<K> {
+        LayeredButtons {
+            active: vec![false; layers.len()],
+            base,
+            layers,
+            held: HashMap::new(),
+        }
+    }
+
+    // New mappings, e.g. from a reloaded profile. Returns the output changes to apply, held
+    // inputs move to wherever they map now. Layers keep their state when their modifier stays.
+    pub fn set_mappings(
+        &mut self,
+        base: HashMap<K, OutputMapping>,
+        layers: Vec<Layer<K>>,
+    ) -> Vec<(OutputMapping, bool)> {
+        self.active = layers
+            .iter()
+            .map(|layer| {
+                self.layers
+                    .iter()
+                    .zip(self.active.iter())
+                    .any(|(old, active)| *active && old.modifier == layer.modifier)
+            })
+            .collect();
+        self.base = base;
+        self.layers = layers;
+        self.remap_held()
+    }
+
+    // Where an input goes with the active layers, modifiers don't go anywhere.
+    pub fn output(&self, input: &K) -> Option<OutputMapping> {
+        if self.is_modifier(input) {
+            return None;
+        }
+        let layered = self
+            .layers
+            .iter()
+            .zip(self.active.iter())
+            .rev()
+            .filter(|(_, active)| **active)
+            .find_map(|(layer, _)| layer.buttons.get(input));
+        match layered {
+            Some(output) => output.clone(),
+            None => self.base.get(input).cloned(),
+        }
+    }
+
+    // Returns the output changes to apply, as (output, pressed).
+    pub fn press(&mut self, input: K, pressed: bool) -> Vec<(OutputMapping, bool)> {
+        if self.is_modifier(&input) {
+            for (layer, active) in self.layers.iter().zip(self.active.iter_mut()) {
+                if layer.modifier == input {
+                    *active = match layer.mode {
+                        LayerMode::Hold => pressed,
+                        LayerMode::Toggle if pressed => !*active,
+                        LayerMode::Toggle => *active,
+                    };
+                }
+            }
+            // A press from before the input became a modifier.
+            if !pressed {
+                self.held.remove(&input);
+            }
+            return self.remap_held();
+        }
+
+        let before = self.driven();
+        if pressed {
+            let output = self.output(&input);
+            self.held.insert(input, output);
+        } else {
+            self.held.remove(&input);
+        }
+        self.changes(before)
+    }
+
+    // Forgets presses and layers, for when the input went away and its outputs were reset.
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.active.iter_mut().for_each(|active| *active = false);
+    }
+
+    fn is_modifier(&self, input: &K) -> bool {
+        self.layers.iter().any(|layer| layer.modifier == *input)
+    }
+
+    fn remap_held(&mut self) -> Vec<(OutputMapping, bool)> {
+        let before = self.driven();
+        let inputs: Vec<K> = self.held.keys().cloned().collect();
+        for input in inputs {
+            let output = self.output(&input);
+            self.held.insert(input, output);
+        }
+        self.changes(before)
+    }
+
+    // Outputs some held input drives. Counting them this way keeps an output down until the
+    // last input driving it lets go.
+    fn driven(&self) -> Vec<OutputMapping> {
+        let mut outputs = vec![];
+        for output in self.held.values().flatten() {
+            if !outputs.contains(output) {
+                outputs.push(output.clone());
+            }
+        }
+        outputs
+    }
+
+    fn changes(&self, before: Vec<OutputMapping>) -> Vec<(OutputMapping, bool)> {
+        let after = self.driven();
+        // All releases first, so an output that moved between two inputs ends up pressed.
+        let releases = before
+            .iter()
+            .filter(|output| !after.contains(output))
+            .map(|output| (output.clone(), false));
+        let presses = after
+            .iter()
+            .filter(|output| !before.contains(output))
+            .map(|output| (output.clone(), true));
+        releases.chain(presses).collect()
+    }
+}
+
+#[test]
+fn test_layers() {
+    use crate::controller_abs::GamepadButton;
+
+    let button = |button| Some(OutputMapping::Button(button));
+    let base = HashMap::from([
+        ("up", OutputMapping::Button(GamepadButton::DPadUp)),
+        ("a", OutputMapping::Button(GamepadButton::South)),
+    ]);
+    let layers = vec![
+        Layer {
+            modifier: "c",
+            mode: LayerMode::Hold,
+            buttons: HashMap::from([("up", button(GamepadButton::LeftShoulderButton))]),
+        },
+        Layer {
+            modifier: "z",
+            mode: LayerMode::Toggle,
+            buttons: HashMap::from([("up", button(GamepadButton::LeftThumb)), ("a", None)]),
+        },
+    ];
+    let mut buttons = LayeredButtons::new(base, layers);
+    let pressed = |mapping: Option<OutputMapping>, pressed| vec![(mapping.unwrap(), pressed)];
+
+    // Holding C while up is down moves the press to LB, and back when C is let go.
+    assert_eq!(
+        buttons.press("up", true),
+        pressed(button(GamepadButton::DPadUp), true)
+    );
+    assert_eq!(
+        buttons.press("c", true),
+        vec![
+            (OutputMapping::Button(GamepadButton::DPadUp), false),
+            (
+                OutputMapping::Button(GamepadButton::LeftShoulderButton),
+                true
+            ),
+        ]
+    );
+    assert_eq!(buttons.output(&"c"), None);
+    assert_eq!(buttons.press("c", false).len(), 2);
+    assert_eq!(
+        buttons.press("up", false),
+        pressed(button(GamepadButton::DPadUp), false)
+    );
+
+    // Z toggles, and wins over C since it comes later.
+    assert!(buttons.press("z", true).is_empty());
+    assert!(buttons.press("z", false).is_empty());
+    assert_eq!(buttons.output(&"up"), button(GamepadButton::LeftThumb));
+    buttons.press("c", true);
+    assert_eq!(buttons.output(&"up"), button(GamepadButton::LeftThumb));
+    assert_eq!(buttons.output(&"a"), None);
+    assert!(buttons.press("a", true).is_empty());
+    assert!(buttons.press("a", false).is_empty());
+
+    // Toggling Z off mid-press moves the press to C's layer, and it's released from there.
+    assert_eq!(
+        buttons.press("up", true),
+        pressed(button(GamepadButton::LeftThumb), true)
+    );
+    assert_eq!(
+        buttons.press("z", true),
+        vec![
+            (OutputMapping::Button(GamepadButton::LeftThumb), false),
+            (
+                OutputMapping::Button(GamepadButton::LeftShoulderButton),
+                true
+            ),
+        ]
+    );
+    assert_eq!(
+        buttons.press("up", false),
+        pressed(button(GamepadButton::LeftShoulderButton), false)
+    );
+
+    // A press hidden by a layer picks up its base mapping when the layer goes off.
+    assert!(buttons.press("z", true).is_empty());
+    assert!(buttons.press("a", true).is_empty());
+    assert_eq!(
+        buttons.press("z", true),
+        pressed(button(GamepadButton::South), true)
+    );
+
+    // Two inputs on one output keep it down until both let go.
+    let mut shared = LayeredButtons::new(
+        HashMap::from([
+            ("a", OutputMapping::Button(GamepadButton::South)),
+            ("b", OutputMapping::Button(GamepadButton::South)),
+        ]),
+        vec![],
+    );
+    assert_eq!(shared.press("a", true).len(), 1);
+    assert!(shared.press("b", true).is_empty());
+    assert!(shared.press("a", false).is_empty());
+    assert_eq!(
+        shared.press("b", false),
+        pressed(button(GamepadButton::South), false)
+    );
+
+    // Reloaded mappings move held presses too, and keep layers whose modifier stayed.
+    buttons.press("c", true);
+    let same_layer = vec![Layer {
+        modifier: "c",
+        mode: LayerMode::Hold,
+        buttons: HashMap::from([("a", button(GamepadButton::West))]),
+    }];
+    assert_eq!(
+        buttons.set_mappings(HashMap::new(), same_layer),
+        vec![
+            (OutputMapping::Button(GamepadButton::South), false),
+            (OutputMapping::Button(GamepadButton::West), true),
+        ]
+    );
+    let changes = buttons.set_mappings(HashMap::new(), vec![]);
+    assert_eq!(changes, pressed(button(GamepadButton::West), false));
+    assert!(buttons.press("a", false).is_empty());
+}
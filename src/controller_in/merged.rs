@@ -0,0 +1,151 @@
+use futures::future::join_all;
+
+use crate::config::Config;
+use crate::controller_abs::{ControllerInput, Feedback, Gamepad, GamepadAxis};
+use crate::controller_in::device_filter::DeviceInfo;
+use crate::controller_in::ConfigurableInput;
+
+// Several inputs driving one virtual pad, e.g. every pad gilrs found. Buttons are down while any
+// input presses them, each axis follows whichever input moves it the furthest.
+pub struct MergedInput<I> {
+    inputs: Vec<I>,
+    gamepad: Gamepad,
+}
+
+impl<I: ControllerInput> MergedInput<I> {
+    pub fn new(inputs: Vec<I>) -> Result<MergedInput<I>, String> {
+        if inputs.is_empty() {
+            return Err("No input devices found".to_string());
+        }
+        Ok(MergedInput {
+            inputs,
+            gamepad: Gamepad::new(),
+        })
+    }
+}
+
+pub fn merge_gamepads<'a>(gamepads: impl IntoIterator<Item = &'a Gamepad>) -> Gamepad {
+    // Axes nothing has touched yet keep their initial value, they don't count as moved.
+    let untouched = Gamepad::new();
+    let mut merged = Gamepad::new();
+    for gamepad in gamepads {
+        for (button, pressed) in gamepad.buttons.iter() {
+            if *pressed {
+                merged.set_button(button.clone(), true);
+            }
+        }
+        for (axis, value) in gamepad.axes.iter() {
+            let rest = match axis {
+                GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => u64::MIN,
+                _ => u64::MAX / 2,
+            };
+            let initial = untouched.axes[axis].value;
+            let current = merged.get_axis_ref(axis.clone());
+            if value.value != initial
+                && (current.value == initial
+                    || value.value.abs_diff(rest) > current.value.abs_diff(rest))
+            {
+                current.value = value.value;
+            }
+        }
+        merged.power = merged.power.or(gamepad.power);
+        merged.motion = merged.motion.or(gamepad.motion);
+        merged.touches.extend(gamepad.touches.iter().copied());
+        merged.keys.extend(gamepad.keys.iter().copied());
+        merged
+            .mouse_buttons
+            .extend(gamepad.mouse_buttons.iter().copied());
+    }
+    merged
+}
+
+impl<I: ControllerInput<ControllerType = I>> ControllerInput for MergedInput<I> {
+    type ControllerType = MergedInput<I>;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        &self.gamepad
+    }
+
+    fn prep_for_input_events(&mut self) {
+        for input in self.inputs.iter_mut() {
+            input.prep_for_input_events();
+        }
+    }
+
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        let results = join_all(self.inputs.iter_mut().map(|input| input.get_next_inputs())).await;
+        // Battery levels and the like change without an event, so this is rebuilt every time.
+        self.gamepad = merge_gamepads(self.inputs.iter_mut().map(|input| input.to_gamepad()));
+        let mut changed = false;
+        for result in results {
+            changed |= result?;
+        }
+        Ok(changed)
+    }
+
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        for input in self.inputs.iter_mut() {
+            input.apply_feedback(feedback);
+        }
+    }
+
+    fn name(&self) -> String {
+        let names: Vec<String> = self.inputs.iter().map(|input| input.name()).collect();
+        names.join(" + ")
+    }
+}
+
+impl<I: ConfigurableInput<ControllerType = I>> ConfigurableInput for MergedInput<I> {
+    fn reconfigure(&mut self, config: &Config) {
+        for input in self.inputs.iter_mut() {
+            input.reconfigure(config);
+        }
+    }
+
+    fn calibrate(&mut self) {
+        for input in self.inputs.iter_mut() {
+            input.calibrate();
+        }
+    }
+
+    // Inputs sharing a backend all see the same devices.
+    fn devices(&self) -> Vec<DeviceInfo> {
+        let mut devices: Vec<DeviceInfo> = vec![];
+        for device in self.inputs.iter().flat_map(|input| input.devices()) {
+            if !devices.contains(&device) {
+                devices.push(device);
+            }
+        }
+        devices
+    }
+
+    fn show_profile(&mut self, number: usize) {
+        for input in self.inputs.iter_mut() {
+            input.show_profile(number);
+        }
+    }
+}
+
+#[test]
+fn test_merge_gamepads() {
+    use crate::controller_abs::{Axis, GamepadButton};
+
+    let mut first = Gamepad::new();
+    let mut second = Gamepad::new();
+    first.set_button(GamepadButton::South, true);
+    *first.get_axis_ref(GamepadAxis::LeftJoystickX) = Axis::new(0.2, -1.0, 1.0);
+    *second.get_axis_ref(GamepadAxis::LeftJoystickX) = Axis::new(-0.9, -1.0, 1.0);
+    // A released trigger counts as moved, one nothing touched yet doesn't.
+    *second.get_axis_ref(GamepadAxis::LeftTrigger) = Axis::new(0.0, 0.0, 1.0);
+
+    let mut merged = merge_gamepads([&first, &second]);
+    assert!(merged.buttons[&GamepadButton::South]);
+    assert!(!merged.buttons[&GamepadButton::East]);
+    assert_eq!(
+        merged.get_axis_ref(GamepadAxis::LeftJoystickX).value,
+        second.get_axis_ref(GamepadAxis::LeftJoystickX).value
+    );
+    assert_eq!(merged.get_axis_ref(GamepadAxis::LeftTrigger).value, 0);
+
+    assert!(MergedInput::<crate::controller_in::network::NetworkInput>::new(vec![]).is_err());
+}
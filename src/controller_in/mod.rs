@@ -1,222 +1,485 @@
-use std::time::Duration;
+pub mod balance_board;
+pub mod battery;
+pub mod device_filter;
+pub mod gestures;
+pub mod gilrs_hub;
+pub mod ir_pointer;
+pub mod layers;
+pub mod merged;
+pub mod network;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use futures::TryStreamExt;
-use futures_util::StreamExt;
 use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
 use xwiimote::{
-    events::{Event, KeyState},
-    Address, Channels, Device, Monitor,
+    events::{Event, Key, KeyState, NunchukKey},
+    Address, Channels, Device,
 };
 
 use crate::controller_abs::{
-    Axis, ControllerInput, ControllerMapping, Gamepad, GamepadAxis, GamepadButton, OutputMapping,
+    speed_curve, Axis, ControllerInput, Feedback, Gamepad, GamepadAxis, OutputMapping, PowerState,
 };
-use futures::executor::block_on;
 use gilrs::{
-    Axis as GilAxis, Button as GilButton, Event as GilEvent, Gamepad as GilGamepad,
-    GamepadId as GilGamepadId, Gilrs,
+    Axis as GilAxis, Button as GilButton, Event as GilEvent, EventType as GilEventType,
+    GamepadId as GilGamepadId, PowerInfo as GilPowerInfo,
 };
 
-use gilrs::ev::state::AxisData as GilAxisData;
+use crate::config::{Config, GilRsConfig, WiiConfig, WiiStick};
+use crate::dashboard;
+use battery::{poll_wii_battery, BatteryMonitor, WiiBatteryAlert};
+use device_filter::{DeviceFilter, DeviceInfo};
+use gestures::{Gesture, GestureConfig, GestureDetector, GestureSource};
 use gilrs::ev::Code as GilCode;
+use gilrs_hub::GilRsHub;
+use ir_pointer::{IrLostBehaviour, IrPointer, IrPointerConfig};
+use layers::{Layer, LayeredButtons};
+
+// Runtime management on top of reading an input, for the control socket, profiles and
+// mapping reloads.
+pub trait ConfigurableInput: ControllerInput {
+    // Picks up changed mappings at runtime, anything needing a reconnect is left alone.
+    fn reconfigure(&mut self, _config: &Config) {}
+    // Re-centers sticks, tares scales etc., for inputs that have something to calibrate.
+    fn calibrate(&mut self) {}
+    // Connected devices, for picking a profile by the device set.
+    fn devices(&self) -> Vec<DeviceInfo> {
+        vec![]
+    }
+    // Shows the active profile's number (1 onwards, 0 for none), e.g. on a Wii remote's LEDs.
+    fn show_profile(&mut self, _number: usize) {}
+}
 
 // TODO: use actix?
 
-struct XWiiEvent(Event);
+// Wii remote and nunchuk buttons, as named in the mapping file.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum WiiButton {
+    A,
+    B,
+    One,
+    Two,
+    Plus,
+    Minus,
+    Home,
+    Up,
+    Down,
+    Left,
+    Right,
+    // Nunchuk
+    C,
+    Z,
+}
 
-impl XWiiEvent {
-    // Constructor to wrap an Event into MyEvent
-    fn new(event: xwiimote::events::Event) -> Self {
-        XWiiEvent(event)
+impl WiiButton {
+    // The button and whether it's down, auto repeats count as held.
+    fn from_event(event: &Event) -> Option<(WiiButton, bool)> {
+        let (button, state) = match *event {
+            Event::Key(key, state) => (
+                match key {
+                    Key::A => WiiButton::A,
+                    Key::B => WiiButton::B,
+                    Key::One => WiiButton::One,
+                    Key::Two => WiiButton::Two,
+                    Key::Plus => WiiButton::Plus,
+                    Key::Minus => WiiButton::Minus,
+                    Key::Home => WiiButton::Home,
+                    Key::Up => WiiButton::Up,
+                    Key::Down => WiiButton::Down,
+                    Key::Left => WiiButton::Left,
+                    Key::Right => WiiButton::Right,
+                },
+                state,
+            ),
+            Event::NunchukKey(key, state) => (
+                match key {
+                    NunchukKey::C => WiiButton::C,
+                    NunchukKey::Z => WiiButton::Z,
+                },
+                state,
+            ),
+            _ => return None,
+        };
+        Some((button, !matches!(state, KeyState::Up)))
     }
 }
 
-impl PartialEq for XWiiEvent {
-    fn eq(&self, other: &Self) -> bool {
-        match (&self.0, &other.0) {
-            (Event::Key(key1, _), Event::Key(key2, _)) => {
-                std::mem::discriminant(key1) == std::mem::discriminant(key2)
-            }
-            (Event::NunchukKey(key1, _), Event::NunchukKey(key2, _)) => {
-                std::mem::discriminant(key1) == std::mem::discriminant(key2)
-            }
-            (Event::NunchukMove { .. }, Event::NunchukMove { .. }) => true,
-            // FIXME: Add others...
-            _ => false,
+struct IrPointerMapping {
+    pointer: IrPointer,
+    x_axis: GamepadAxis,
+    y_axis: GamepadAxis,
+}
+
+struct GestureMapping {
+    source: GestureSource,
+    detector: GestureDetector,
+    output: OutputMapping,
+    pressed: bool,
+}
+
+// Where the kernel's hid-wiimote driver links the devices it's bound to, the same ones xwiimote's
+// monitor enumerates.
+const WIIMOTE_DRIVER_PATH: &str = "/sys/bus/hid/drivers/wiimote";
+
+// Sysfs paths of the connected Wii devices. Listed here rather than through xwiimote's `Monitor`,
+// its `Address` keeps the path private.
+pub fn wii_device_paths() -> Result<Vec<PathBuf>, String> {
+    let entries = match fs::read_dir(WIIMOTE_DRIVER_PATH) {
+        Ok(entries) => entries,
+        // The driver isn't loaded until a Wii device connects.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(format!("Could not look for Wii devices: {}", err)),
+    };
+    let mut paths = vec![];
+    for entry in entries.flatten() {
+        // HID devices are named <bus>:<vendor>:<product>.<instance>, the rest are driver files.
+        if !entry.file_name().to_string_lossy().contains(':') {
+            continue;
+        }
+        match fs::canonicalize(entry.path()) {
+            Ok(path) => paths.push(path),
+            Err(err) => log_error!("Could not resolve {}: {}", entry.path().display(), err),
         }
     }
+    paths.sort();
+    Ok(paths)
+}
+
+// Readable name for logs, the last part of the device's sysfs path.
+pub fn wii_device_name(path: &Path) -> String {
+    let hid_id = path.file_name().unwrap_or_default().to_string_lossy();
+    format!("Wii {}", hid_id)
+}
+
+pub fn connect_wii_device(path: &Path) -> Result<Device, String> {
+    Device::connect(&Address::from(path.to_path_buf()))
+        .map_err(|err| format!("Could not connect to {}: {}", wii_device_name(path), err))
+}
+
+// For device filters changed at runtime, the device stays open either way.
+fn log_filter_change(name: &str, excluded: bool) {
+    if excluded {
+        log!("Ignoring {}: excluded by device filter", name);
+    } else {
+        log!("Using {} again: allowed by device filter", name);
+    }
+}
+
+// Profiles 1 to 4 light up their LED like player numbers, higher ones count in binary.
+pub fn profile_leds(number: usize) -> [bool; 4] {
+    match number {
+        1..=4 => std::array::from_fn(|led| led + 1 == number),
+        _ => std::array::from_fn(|led| number & (1 << led) != 0),
+    }
 }
 
 pub struct XWiiInput {
     device: Device,
     gamepad: Gamepad,
     channels: Channels,
-    mappings: Vec<ControllerMapping<Event>>,
+    motion: bool,
+    buttons: LayeredButtons<WiiButton>,
+    nunchuk_stick: Option<WiiStick>,
+    ir_pointers: Vec<IrPointerMapping>,
+    gestures: Vec<GestureMapping>,
+    battery: BatteryMonitor,
+    battery_alert: Option<WiiBatteryAlert>,
+    info: DeviceInfo,
+    // See `GilRsInput::excluded`.
+    excluded: bool,
     nunchuck_x_min: i32,
     nunchuck_x_max: i32,
     nunchuck_y_min: i32,
     nunchuck_y_max: i32,
-    deadzone_percentage: f64,
+    stick_deadzone: f64,
 }
 
 impl XWiiInput {
-    pub fn new(address: &Address) -> XWiiInput {
-        XWiiInput {
-            device: Device::connect(address).unwrap(),
+    pub fn new(path: &Path) -> Result<XWiiInput, String> {
+        let config = WiiConfig::default();
+        let name = wii_device_name(path);
+        Ok(XWiiInput {
+            device: connect_wii_device(path)?,
             gamepad: Gamepad::new(),
-            // TODO: Make this into a ::new arg.
             channels: Channels::CORE | Channels::NUNCHUK,
-            mappings: vec![],
+            motion: false,
+            buttons: LayeredButtons::new(config.button_mappings(), config.button_layers()),
+            nunchuk_stick: config.nunchuk_stick,
+            ir_pointers: vec![],
+            gestures: vec![],
+            battery: BatteryMonitor::new(name.clone()),
+            battery_alert: None,
+            info: DeviceInfo::from_wii_path(path, name),
+            excluded: false,
             nunchuck_x_min: 0,
             nunchuck_x_max: 0,
             nunchuck_y_min: 0,
             nunchuck_y_max: 0,
-            deadzone_percentage: 0.05, // 5%
-        }
+            stick_deadzone: config.stick_deadzone,
+        })
     }
 
-    pub fn map_event(&mut self, event: Event, to_mapping: OutputMapping) {
-        self.mappings.push(ControllerMapping {
-            input: event,
-            output: to_mapping.clone(),
-        });
-    }
+    pub fn discover_filtered(filter: &DeviceFilter) -> Vec<XWiiInput> {
+        let paths = match wii_device_paths() {
+            Ok(paths) => paths,
+            Err(err) => {
+                log_error!("{}", err);
+                return vec![];
+            }
+        };
 
-    fn map_event_to_gamepad(&mut self, event: Event) {
-        macro_rules! button_to_gamepad {
-            ($self:expr, $controller_mapping_output:expr, $key_state:expr) => {
-                let button_down = !matches!($key_state, KeyState::Up);
-                match ($controller_mapping_output) {
-                    OutputMapping::Axis(gamepad_axis) => {
-                        let output_axis = $self.gamepad.get_axis_ref(gamepad_axis.to_owned());
-                        let output_value;
-                        if button_down {
-                            output_value = output_axis.get_max().clone();
-                        } else {
-                            output_value = output_axis.get_min().clone();
-                        };
-                        output_axis.value = output_value;
-                    }
-                    OutputMapping::Button(gamepad_button) => {
-                        self.gamepad
-                            .set_button(gamepad_button.to_owned(), button_down);
-                    }
+        let mut inps: Vec<XWiiInput> = vec![];
+        for path in paths {
+            let name = wii_device_name(&path);
+            if !filter.allows(&DeviceInfo::from_wii_path(&path, name.clone())) {
+                log!("Ignoring {}: excluded by device filter", name);
+                continue;
+            }
+            let inp = match XWiiInput::new(&path) {
+                Ok(inp) => inp,
+                Err(err) => {
+                    log_error!("{}", err);
+                    continue;
                 }
             };
+            // Balance boards are handled by `BalanceBoardInput`.
+            if inp.device.kind().unwrap_or_default() == balance_board::BALANCE_BOARD_KIND {
+                continue;
+            }
+            inps.push(inp);
         }
 
-        for controller_mapping in &self.mappings {
-            if XWiiEvent::new(controller_mapping.input) != XWiiEvent::new(event) {
-                continue;
+        inps
+    }
+
+    pub fn discover_configured(config: &Config) -> Vec<XWiiInput> {
+        let mut inps = Self::discover_filtered(&config.devices);
+        for inp in inps.iter_mut() {
+            inp.set_config(&config.wii);
+        }
+        inps
+    }
+
+    // Mappings from the config, before the device is opened.
+    pub fn set_config(&mut self, config: &WiiConfig) {
+        let changes = self
+            .buttons
+            .set_mappings(config.button_mappings(), config.button_layers());
+        for (output, pressed) in changes {
+            self.gamepad.set_digital_output(&output, pressed);
+        }
+        self.nunchuk_stick = config.nunchuk_stick.clone();
+        self.stick_deadzone = config.stick_deadzone;
+        if config.motion {
+            self.report_motion();
+        } else {
+            self.motion = false;
+            self.gamepad.motion = None;
+        }
+        self.ir_pointers.clear();
+        if let Some(ir_pointer) = &config.ir_pointer {
+            self.map_ir_pointer(
+                ir_pointer.pointer,
+                ir_pointer.x_axis.clone(),
+                ir_pointer.y_axis.clone(),
+            );
+        }
+        self.gestures.clear();
+        for gesture in config.gestures.iter() {
+            self.map_gesture(
+                gesture.source,
+                gesture.gesture,
+                gesture.config,
+                gesture.output.clone(),
+            );
+        }
+    }
+
+    // Track the sensor bar through the IR camera and map it onto a pair of axes, e.g. the right stick.
+    pub fn map_ir_pointer(
+        &mut self,
+        config: IrPointerConfig,
+        x_axis: GamepadAxis,
+        y_axis: GamepadAxis,
+    ) {
+        self.channels |= Channels::IR;
+        if config.on_lost == IrLostBehaviour::Tilt {
+            self.channels |= Channels::ACCELEROMETER;
+        }
+        self.ir_pointers.push(IrPointerMapping {
+            pointer: IrPointer::new(config),
+            x_axis,
+            y_axis,
+        });
+    }
+
+    fn map_ir_to_gamepad(&mut self, event: &Event) {
+        let now = Instant::now();
+        for ir_mapping in self.ir_pointers.iter_mut() {
+            let position = match *event {
+                Event::Ir(sources) => Some(ir_mapping.pointer.update_ir(&sources, now)),
+                Event::Accelerometer { x, y, z: _ } => ir_mapping.pointer.update_tilt(x, y, now),
+                _ => None,
+            };
+            if let Some((x, y)) = position {
+                self.gamepad.get_axis_ref(ir_mapping.x_axis.clone()).value =
+                    Axis::new(x, -1.0, 1.0).convert_into(false);
+                self.gamepad.get_axis_ref(ir_mapping.y_axis.clone()).value =
+                    Axis::new(y, -1.0, 1.0).convert_into(false);
             }
-            // If we have found our input key, we still need to do some basic matching to ensure correct mapping.
-            // E.g. button -> Axis is a little weird.
-            match event {
-                Event::Key(_key, key_state) => {
-                    button_to_gamepad!(self, &controller_mapping.output, key_state);
-                }
-                Event::NunchukKey(_key, key_state) => {
-                    button_to_gamepad!(self, &controller_mapping.output, key_state);
-                }
-                Event::NunchukMove {
-                    x,
-                    y,
-                    x_acceleration: _,
-                    y_acceleration: _,
-                } => {
-                    // println!("nunchuck X {}", x);
-                    // println!("nunchuck Y {}", y);
-
-                    if x < self.nunchuck_x_min {
-                        self.nunchuck_x_min = x;
-                    }
-                    if x > self.nunchuck_x_max {
-                        self.nunchuck_x_max = x;
-                    }
+        }
+    }
 
-                    if y < self.nunchuck_y_min {
-                        self.nunchuck_y_min = y;
-                    }
-                    if y > self.nunchuck_y_max {
-                        self.nunchuck_y_max = y;
-                    }
+    // Gestures show up as a short press on the mapped output.
+    pub fn map_gesture(
+        &mut self,
+        source: GestureSource,
+        gesture: Gesture,
+        config: GestureConfig,
+        to_mapping: OutputMapping,
+    ) {
+        self.channels |= match source {
+            GestureSource::Remote => Channels::ACCELEROMETER,
+            GestureSource::Nunchuk => Channels::NUNCHUK,
+        };
+        self.gestures.push(GestureMapping {
+            source,
+            detector: GestureDetector::new(gesture, config),
+            output: to_mapping,
+            pressed: false,
+        });
+    }
 
-                    let mut nunchuck_x = Axis::new(x, self.nunchuck_x_min, self.nunchuck_x_max);
-                    // println!("nunchuck X {}", nunchuck_x.get_normalized_value());
-                    let mut nunchuck_y = Axis::new(y, self.nunchuck_y_min, self.nunchuck_y_max);
-                    // println!("nunchuck Y {}", nunchuck_y.get_normalized_value());
-
-                    let deadzone_range_x = (self.deadzone_percentage
-                        * (self.nunchuck_x_min - self.nunchuck_x_max)
-                            .abs()
-                            .to_f64()
-                            .unwrap())
-                    .to_i32()
-                    .unwrap();
-                    let deadzone_range_y = (self.deadzone_percentage
-                        * (self.nunchuck_y_min - self.nunchuck_y_max)
-                            .abs()
-                            .to_f64()
-                            .unwrap())
-                    .to_i32()
-                    .unwrap();
-
-                    nunchuck_x.set_deadzones(nunchuck_x.make_deadzone(
-                        vec![-deadzone_range_x..deadzone_range_x].to_owned(),
-                        self.nunchuck_x_min,
-                        self.nunchuck_x_max,
-                    ));
-                    nunchuck_y.set_deadzones(nunchuck_y.make_deadzone(
-                        vec![-deadzone_range_y..deadzone_range_y].to_owned(),
-                        self.nunchuck_y_min,
-                        self.nunchuck_y_max,
-                    ));
-
-                    match &controller_mapping.output {
-                        OutputMapping::Axis(gamepad_axis) => {
-                            let output_axis = self.gamepad.get_axis_ref(gamepad_axis.to_owned());
-                            match gamepad_axis {
-                                GamepadAxis::LeftJoystickX | GamepadAxis::RightJoystickX => {
-                                    output_axis.value = nunchuck_x.convert_into(true)
-                                }
-                                GamepadAxis::LeftJoystickY | GamepadAxis::RightJoystickY => {
-                                    output_axis.value = nunchuck_y.convert_into(true)
-                                }
-                                _ => {
-                                    // Triggers?... could maybe?
-                                }
-                            }
-                        }
-                        OutputMapping::Button(_gamepad_button) => {
-                            // not sure yet...
-                        }
-                    }
+    // Also called without an event, so pulses get released on time.
+    fn map_gestures_to_gamepad(&mut self, event: Option<&Event>) {
+        let now = Instant::now();
+        for gesture_mapping in self.gestures.iter_mut() {
+            let acceleration = match (event, gesture_mapping.source) {
+                (Some(Event::Accelerometer { x, y, z }), GestureSource::Remote) => {
+                    Some((*x as f64, *y as f64, *z as f64))
                 }
-
-                _ => {}
+                (
+                    Some(Event::NunchukMove {
+                        x_acceleration,
+                        y_acceleration,
+                        ..
+                    }),
+                    GestureSource::Nunchuk,
+                ) => Some((*x_acceleration as f64, *y_acceleration as f64, 0.0)),
+                _ => None,
+            };
+            if let Some(acceleration) = acceleration {
+                gesture_mapping.detector.update(acceleration, now);
+            }
+            // Only write on changes, so other mappings onto the same output still work.
+            let pressed = gesture_mapping.detector.is_pressed(now);
+            if pressed != gesture_mapping.pressed {
+                gesture_mapping.pressed = pressed;
+                self.gamepad.set_digital_output(&gesture_mapping.output, pressed);
             }
         }
     }
-}
 
-impl ControllerInput for XWiiInput {
-    type ControllerType = XWiiInput;
+    // Forward the remote's accelerometer for outputs with motion controls.
+    pub fn report_motion(&mut self) {
+        self.motion = true;
+        self.channels |= Channels::ACCELEROMETER;
+    }
+
+    fn map_event_to_gamepad(&mut self, event: Event) {
+        dashboard::raw_event(self.battery.name(), || format!("{:?}", event));
+        self.map_ir_to_gamepad(&event);
+        self.map_gestures_to_gamepad(Some(&event));
+        match event {
+            Event::Accelerometer { x, y, z } if self.motion => {
+                // The remote has z up and y pointing away from the player.
+                let motion = self.gamepad.motion.get_or_insert_with(Default::default);
+                motion.accel = (
+                    x as f64 / gestures::ONE_G,
+                    z as f64 / gestures::ONE_G,
+                    -y as f64 / gestures::ONE_G,
+                );
+            }
+            _ => {}
+        }
 
-    fn to_gamepad<'a>(&'a mut self) -> &'a Gamepad {
-        return &self.gamepad;
+        if let Some((button, pressed)) = WiiButton::from_event(&event) {
+            for (output, pressed) in self.buttons.press(button, pressed) {
+                self.gamepad.set_digital_output(&output, pressed);
+            }
+        }
+        if let Event::NunchukMove { x, y, .. } = event {
+            self.map_nunchuk_stick(x, y);
+        }
     }
 
-    fn discover_all() -> Vec<Self::ControllerType> {
-        let monitor = Monitor::enumerate().unwrap();
+    fn map_nunchuk_stick(&mut self, x: i32, y: i32) {
+        let stick = match &self.nunchuk_stick {
+            Some(stick) => stick.clone(),
+            None => return,
+        };
 
-        let addresses: Vec<_> = block_on(async { monitor.collect().await });
+        if x < self.nunchuck_x_min {
+            self.nunchuck_x_min = x;
+        }
+        if x > self.nunchuck_x_max {
+            self.nunchuck_x_max = x;
+        }
 
-        let mut inps: Vec<Self::ControllerType> = vec![];
-        for address in addresses {
-            inps.push(Self::ControllerType::new(&address.unwrap()));
+        if y < self.nunchuck_y_min {
+            self.nunchuck_y_min = y;
+        }
+        if y > self.nunchuck_y_max {
+            self.nunchuck_y_max = y;
         }
 
-        return inps;
+        let mut nunchuck_x = Axis::new(x, self.nunchuck_x_min, self.nunchuck_x_max);
+        let mut nunchuck_y = Axis::new(y, self.nunchuck_y_min, self.nunchuck_y_max);
+
+        let deadzone_range_x = (self.stick_deadzone
+            * (self.nunchuck_x_min - self.nunchuck_x_max)
+                .abs()
+                .to_f64()
+                .unwrap())
+        .to_i32()
+        .unwrap();
+        let deadzone_range_y = (self.stick_deadzone
+            * (self.nunchuck_y_min - self.nunchuck_y_max)
+                .abs()
+                .to_f64()
+                .unwrap())
+        .to_i32()
+        .unwrap();
+        let deadzone_x = -deadzone_range_x..deadzone_range_x;
+        let deadzone_y = -deadzone_range_y..deadzone_range_y;
+
+        nunchuck_x.set_deadzones(nunchuck_x.make_deadzone(
+            vec![deadzone_x],
+            self.nunchuck_x_min,
+            self.nunchuck_x_max,
+        ));
+        nunchuck_y.set_deadzones(nunchuck_y.make_deadzone(
+            vec![deadzone_y],
+            self.nunchuck_y_min,
+            self.nunchuck_y_max,
+        ));
+
+        self.gamepad.get_axis_ref(stick.x).value = nunchuck_x.convert_into(true);
+        self.gamepad.get_axis_ref(stick.y).value = nunchuck_y.convert_into(true);
+    }
+}
+
+impl ControllerInput for XWiiInput {
+    type ControllerType = XWiiInput;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        &self.gamepad
     }
 
     fn prep_for_input_events(&mut self) {
@@ -224,10 +487,13 @@ impl ControllerInput for XWiiInput {
         self.device
             .open(Channels::from_bits(self.channels.bits()).unwrap(), true)
             .unwrap();
-        println!("XWiiInput connected: {}", self.device.kind().unwrap());
+        log!("XWiiInput connected: {}", self.device.kind().unwrap());
     }
 
     async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        self.gamepad.power =
+            poll_wii_battery(&mut self.device, &mut self.battery, &mut self.battery_alert);
+
         let maybe_event = {
             let event_stream = &mut self.device.events().unwrap();
             tokio::select! {
@@ -236,202 +502,329 @@ impl ControllerInput for XWiiInput {
                     Err(_) => return Err("Error reading events.")
                 },
                 // TODO: Make this a setting somehow?
-                _ = tokio::time::sleep(Duration::from_millis(5)) => {
-                    return Ok(false);
-                },
+                _ = tokio::time::sleep(Duration::from_millis(5)) => None,
             }
         };
 
         let (event, _time) = match maybe_event {
             Some(event) => event,
             None => {
+                self.map_gestures_to_gamepad(None);
                 return Ok(false);
             }
         };
 
+        if self.excluded {
+            return Ok(false);
+        }
         self.map_event_to_gamepad(event);
-        return Ok(true);
+        Ok(true)
     }
+
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        // Just the one motor, and the LEDs aren't colored.
+        if let Feedback::Rumble { strong, weak } = *feedback {
+            let _ = self.device.set_rumble(strong.max(weak) > 0.25);
+        }
+    }
+
+    fn name(&self) -> String {
+        self.battery.name().to_string()
+    }
+}
+
+impl ConfigurableInput for XWiiInput {
+    fn reconfigure(&mut self, config: &Config) {
+        let excluded = !config.devices.allows(&self.info);
+        if excluded != self.excluded {
+            self.excluded = excluded;
+            log_filter_change(self.battery.name(), excluded);
+            let power = self.gamepad.power;
+            self.gamepad = Gamepad::new();
+            self.gamepad.power = power;
+            self.buttons.clear();
+        }
+    }
+
+    fn devices(&self) -> Vec<DeviceInfo> {
+        vec![self.info.clone()]
+    }
+
+    fn show_profile(&mut self, number: usize) {
+        for (on, led) in profile_leds(number).into_iter().zip(battery::LEDS) {
+            let _ = self.device.set_led(led, on);
+        }
+    }
+
+    // The nunchuck range is learned as the stick moves, start over.
+    fn calibrate(&mut self) {
+        self.nunchuck_x_min = 0;
+        self.nunchuck_x_max = 0;
+        self.nunchuck_y_min = 0;
+        self.nunchuck_y_max = 0;
+    }
+}
+
+// Named gilrs buttons, or the code of ones gilrs has no name for.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum GilInput {
+    Button(GilButton),
+    Code(String),
+}
+
+impl GilInput {
+    fn new(button: GilButton, code: GilCode) -> GilInput {
+        match button {
+            GilButton::Unknown => GilInput::Code(code.to_string()),
+            _ => GilInput::Button(button),
+        }
+    }
+}
+
+fn gilrs_button_layers(
+    config: &GilRsConfig,
+) -> (HashMap<GilInput, OutputMapping>, Vec<Layer<GilInput>>) {
+    let codes = |code: &String| GilInput::Code(code.clone());
+    let mut base: HashMap<GilInput, OutputMapping> = config
+        .button_mappings()
+        .into_iter()
+        .map(|(button, output)| (GilInput::Button(button), output))
+        .collect();
+    for (code, output) in config.extra_buttons.iter() {
+        base.insert(codes(code), output.clone());
+    }
+    let layers = config
+        .layers
+        .iter()
+        .map(|layer| {
+            let mut buttons: HashMap<GilInput, Option<OutputMapping>> = layer
+                .buttons
+                .iter()
+                .map(|(button, output)| (GilInput::Button(*button), output.clone()))
+                .collect();
+            for (code, output) in layer.extra_buttons.iter() {
+                buttons.insert(codes(code), output.clone());
+            }
+            Layer {
+                modifier: GilInput::Button(layer.modifier),
+                mode: layer.mode,
+                buttons,
+            }
+        })
+        .collect();
+    (base, layers)
 }
 
 pub struct GilRsInput {
     gamepad: Gamepad,
-    gil_rs: Gilrs,
+    hub: GilRsHub,
+    events: mpsc::Receiver<GilEvent>,
     gil_rs_device_id: GilGamepadId,
-    deadzone_percentage: f64,
+    name: String,
+    battery: BatteryMonitor,
+    // Asked for when a battery poll is due, picked up once the hub answers.
+    power_reply: Option<oneshot::Receiver<GilPowerInfo>>,
+    buttons: LayeredButtons<GilInput>,
+    // Last stick positions as gilrs reports them, and where `calibrate` found them resting.
+    raw_sticks: HashMap<GamepadAxis, (f32, bool)>,
+    stick_centers: HashMap<GamepadAxis, f32>,
+    stick_deadzone: f64,
+    stick_curve: f64,
+    info: DeviceInfo,
+    // Left out by a reloaded device filter, events are dropped until it's let back in.
+    excluded: bool,
 }
 
 impl GilRsInput {
-    pub fn new(gil_rs: Gilrs, gil_rs_device_id: GilGamepadId) -> GilRsInput {
+    pub fn new(hub: GilRsHub, gil_rs_device_id: GilGamepadId, info: DeviceInfo) -> GilRsInput {
+        let name = info.name.clone();
+        let (base, layers) = gilrs_button_layers(&GilRsConfig::default());
         GilRsInput {
             gamepad: Gamepad::new(),
-            gil_rs,
+            events: hub.register(gil_rs_device_id),
+            hub,
             gil_rs_device_id,
-            deadzone_percentage: 0.05, // 5%
-        }
-    }
-
-    fn get_gilrs_gamepad(&self) -> GilGamepad {
-        self.gil_rs.gamepad(self.gil_rs_device_id)
-    }
-
-    fn map_gilrs_to_gamepad(&mut self) {
-        let buttons = [
-            GilButton::South,
-            GilButton::East,
-            GilButton::North,
-            GilButton::West,
-            // GilButton::C,
-            // GilButton::Z,
-            GilButton::LeftTrigger,
-            // GilButton::LeftTrigger2,
-            GilButton::RightTrigger,
-            // GilButton::RightTrigger2,
-            GilButton::Select,
-            GilButton::Start,
-            GilButton::Mode,
-            GilButton::LeftThumb,
-            GilButton::RightThumb,
-            GilButton::DPadUp,
-            GilButton::DPadDown,
-            GilButton::DPadLeft,
-            GilButton::DPadRight,
-        ];
-        // We also NEED to consume events here, otherwise data is not filled properly on the gamepad
-        while let Some(GilEvent { id, event, time }) = self.gil_rs.next_event() {
-            // FIXME: gamepad state seems inconsistent?/mappings might be weird...
-            println!("{:?} New event from {}: {:?}", time, id, event);
-        }
-
-        for button in buttons.iter() {
-            let gilrs_gamepad = self.get_gilrs_gamepad();
-
-            let is_pressed = gilrs_gamepad.is_pressed(button.clone());
-
-            // Button to button
-            match button {
-                GilButton::South => self.gamepad.set_button(GamepadButton::South, is_pressed),
-                GilButton::East => self.gamepad.set_button(GamepadButton::East, is_pressed),
-                GilButton::North => self.gamepad.set_button(GamepadButton::North, is_pressed),
-                GilButton::West => self.gamepad.set_button(GamepadButton::West, is_pressed),
-                GilButton::LeftTrigger => self
-                    .gamepad
-                    .set_button(GamepadButton::LeftShoulderButton, is_pressed),
-                GilButton::RightTrigger => self
-                    .gamepad
-                    .set_button(GamepadButton::RightShoulderButton, is_pressed),
-                GilButton::Select => self.gamepad.set_button(GamepadButton::Select, is_pressed),
-                GilButton::Start => self.gamepad.set_button(GamepadButton::Start, is_pressed),
-                GilButton::Mode => self.gamepad.set_button(GamepadButton::Mode, is_pressed),
-                GilButton::LeftThumb => self
-                    .gamepad
-                    .set_button(GamepadButton::LeftThumb, is_pressed),
-                GilButton::RightThumb => self
-                    .gamepad
-                    .set_button(GamepadButton::RightThumb, is_pressed),
-                GilButton::DPadUp => self.gamepad.set_button(GamepadButton::DPadUp, is_pressed),
-                GilButton::DPadDown => self.gamepad.set_button(GamepadButton::DPadDown, is_pressed),
-                GilButton::DPadLeft => self.gamepad.set_button(GamepadButton::DPadLeft, is_pressed),
-                GilButton::DPadRight => self
-                    .gamepad
-                    .set_button(GamepadButton::DPadRight, is_pressed),
-                _ => (),
-            };
+            name: name.clone(),
+            battery: BatteryMonitor::new(name),
+            power_reply: None,
+            buttons: LayeredButtons::new(base, layers),
+            raw_sticks: HashMap::new(),
+            stick_centers: HashMap::new(),
+            stick_deadzone: 0.0,
+            stick_curve: 1.0,
+            info,
+            excluded: false,
         }
-        // Axis to axis
-        let axes = [
-            GilAxis::LeftStickX,
-            GilAxis::LeftStickY,
-            GilAxis::RightStickX,
-            GilAxis::RightStickY,
-            GilAxis::RightZ,
-            GilAxis::LeftZ,
-        ];
-
-        let mut current_state: Vec<(GilCode, GilAxisData)> = vec![];
-        {
-            for (code, axis) in self.get_gilrs_gamepad().state().axes() {
-                current_state.push((code.clone(), axis.clone()));
+    }
+
+    pub fn discover_configured(config: &Config) -> Result<Vec<GilRsInput>, String> {
+        // All pads share one context, so each event is read once.
+        let (hub, pads) = GilRsHub::start(&config.gilrs.sdl_mappings())?;
+
+        let mut found = vec![];
+        for pad in pads {
+            if !config.devices.allows(&pad.info) {
+                log!("Ignoring {}: excluded by device filter", pad.info.name);
+                continue;
             }
+            log!(
+                "Detected!: {}/{}/{} ({:?} mapping)",
+                pad.id,
+                pad.info.name,
+                pad.info.os_name.as_deref().unwrap_or_default(),
+                pad.mapping_source
+            );
+            let mut inp = GilRsInput::new(hub.clone(), pad.id, pad.info);
+            inp.set_config(&config.gilrs);
+            found.push(inp);
         }
+        Ok(found)
+    }
 
-        // Axis mapping is pretty weird...
-        for (code, axis) in current_state {
-            let in_axis = Axis::new(axis.value(), -1.0, 1.0);
-            let mut known_axis = false;
-            for ax in axes {
-                if (self.get_gilrs_gamepad().axis_code(ax)) != Some(code) {
-                    break;
-                }
+    fn poll_battery(&mut self) {
+        let now = Instant::now();
+        if self.power_reply.is_none() && self.battery.poll_due(now) {
+            self.power_reply = Some(self.hub.power_info(self.gil_rs_device_id));
+        }
+        let info = match self.power_reply.as_mut().map(|reply| reply.try_recv()) {
+            Some(Ok(info)) => info,
+            Some(Err(oneshot::error::TryRecvError::Empty)) | None => return,
+            Some(Err(oneshot::error::TryRecvError::Closed)) => {
+                self.power_reply = None;
+                return;
+            }
+        };
+        self.power_reply = None;
+        let state = match info {
+            GilPowerInfo::Unknown => None,
+            GilPowerInfo::Wired => Some(PowerState::Wired),
+            GilPowerInfo::Discharging(level) => Some(PowerState::Discharging(level)),
+            GilPowerInfo::Charging(level) => Some(PowerState::Charging(level)),
+            GilPowerInfo::Charged => Some(PowerState::Charged),
+        };
+        if self.battery.update(state, now) {
+            self.hub.battery_warning(self.gil_rs_device_id);
+        }
+        self.gamepad.power = state;
+    }
 
-                match ax {
-                    GilAxis::LeftStickX => {
-                        known_axis = true;
-                        self.gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value =
-                            in_axis.convert_into(false);
-                    }
-                    GilAxis::LeftStickY => {
-                        known_axis = true;
-                        self.gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value =
-                            in_axis.convert_into(false);
-                    }
-                    GilAxis::RightStickX => {
-                        self.gamepad.get_axis_ref(GamepadAxis::RightJoystickX).value =
-                            in_axis.convert_into(false);
-                    }
-                    GilAxis::RightStickY => {
-                        self.gamepad.get_axis_ref(GamepadAxis::RightJoystickY).value =
-                            in_axis.convert_into(false);
-                    }
-                    GilAxis::LeftZ => {
-                        self.gamepad.get_axis_ref(GamepadAxis::RightJoystickX).value =
-                            in_axis.convert_into(false);
-                    }
-                    GilAxis::RightZ => {
-                        self.gamepad.get_axis_ref(GamepadAxis::RightJoystickY).value =
-                            in_axis.invert().convert_into(false);
-                    }
+    pub fn set_config(&mut self, config: &GilRsConfig) {
+        let (base, layers) = gilrs_button_layers(config);
+        let changes = self.buttons.set_mappings(base, layers);
+        self.apply_button_changes(changes);
+        self.stick_deadzone = config.stick_deadzone;
+        self.stick_curve = config.stick_curve;
+    }
 
-                    _ => {}
-                }
+    fn set_stick_output(&mut self, axis: GamepadAxis, value: f32, invert: bool) {
+        self.raw_sticks.insert(axis.clone(), (value, invert));
+        let center = self.stick_centers.get(&axis).copied().unwrap_or(0.0);
+        let position = speed_curve(
+            (value - center) as f64,
+            self.stick_deadzone,
+            self.stick_curve,
+        );
+        let in_axis = Axis::new(position, -1.0, 1.0);
+        let in_axis = if invert { in_axis.invert() } else { in_axis };
+        self.gamepad.get_axis_ref(axis).value = in_axis.convert_into(false);
+    }
+
+    fn set_axis_output(&mut self, axis: GamepadAxis, value: f32) {
+        // Analog triggers report their travel, digital ones jump straight to 1.0.
+        self.gamepad.get_axis_ref(axis).value =
+            Axis::new(value as f64, 0.0, 1.0).convert_into(false);
+    }
+
+    // Axis outputs follow ButtonChanged while pressed, only their releases are applied here.
+    fn apply_button_changes(&mut self, changes: Vec<(OutputMapping, bool)>) -> bool {
+        let changed = !changes.is_empty();
+        for (output, pressed) in changes {
+            if pressed && matches!(output, OutputMapping::Axis(_)) {
+                continue;
             }
-            if !known_axis {
-                match format!("{}", code).as_str() {
-                    // Right trigger
-                    "ABS(9)" => {
-                        self.gamepad.get_axis_ref(GamepadAxis::RightTrigger).value =
-                            in_axis.convert_into(false);
-                    }
-                    // Left trigger
-                    "ABS(10)" => {
-                        self.gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value =
-                            in_axis.convert_into(false);
-                    }
-                    // Left stick
-                    "ABS(0)" => {
-                        self.gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value =
-                            in_axis.convert_into(false);
-                    }
-                    "ABS(1)" => {
-                        self.gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value =
-                            in_axis.convert_into(false);
-                    }
-                    // Right stick
-                    "ABS(2)" => {
-                        self.gamepad.get_axis_ref(GamepadAxis::RightJoystickX).value =
-                            in_axis.convert_into(false);
-                    }
-                    "ABS(5)" => {
-                        // For some reason this is inverted..
-                        self.gamepad.get_axis_ref(GamepadAxis::RightJoystickY).value =
-                            in_axis.invert().convert_into(false);
-                    }
-                    _ => {
-                        // FIXME: turned off for spam, some axes seem duplicated?...
-                        println!("Unknown axis!: {}/{}", code, axis.value());
+            self.gamepad.set_digital_output(&output, pressed);
+        }
+        changed
+    }
+
+    // Don't leave buttons stuck down.
+    fn reset_gamepad(&mut self) {
+        let power = self.gamepad.power;
+        self.gamepad = Gamepad::new();
+        self.gamepad.power = power;
+        self.buttons.clear();
+    }
+
+    // Returns the output axis and whether it needs inverting.
+    fn axis_output(axis: GilAxis, code: GilCode) -> Option<(GamepadAxis, bool)> {
+        match axis {
+            GilAxis::LeftStickX => Some((GamepadAxis::LeftJoystickX, false)),
+            GilAxis::LeftStickY => Some((GamepadAxis::LeftJoystickY, false)),
+            GilAxis::RightStickX => Some((GamepadAxis::RightJoystickX, false)),
+            GilAxis::RightStickY => Some((GamepadAxis::RightJoystickY, false)),
+            GilAxis::LeftZ => Some((GamepadAxis::RightJoystickX, false)),
+            GilAxis::RightZ => Some((GamepadAxis::RightJoystickY, true)),
+            // Axis mapping is pretty weird, pads without an SDL mapping only give us codes.
+            // Prefer adding one through `sdl_mappings` in the config over more cases here.
+            _ => match format!("{}", code).as_str() {
+                // Right trigger
+                "ABS(9)" => Some((GamepadAxis::RightTrigger, false)),
+                // Left trigger
+                "ABS(10)" => Some((GamepadAxis::LeftTrigger, false)),
+                // Left stick
+                "ABS(0)" => Some((GamepadAxis::LeftJoystickX, false)),
+                "ABS(1)" => Some((GamepadAxis::LeftJoystickY, false)),
+                // Right stick
+                "ABS(2)" => Some((GamepadAxis::RightJoystickX, false)),
+                // For some reason this is inverted..
+                "ABS(5)" => Some((GamepadAxis::RightJoystickY, true)),
+                _ => None,
+            },
+        }
+    }
+
+    // Applies a single gilrs event, returns whether the gamepad changed.
+    fn map_gilrs_event_to_gamepad(&mut self, event: GilEvent) -> bool {
+        dashboard::raw_event(&self.name, || format!("{:?}", event.event));
+        if self.excluded {
+            return false;
+        }
+        match event.event {
+            GilEventType::ButtonPressed(button, code)
+            | GilEventType::ButtonReleased(button, code) => {
+                let pressed = matches!(event.event, GilEventType::ButtonPressed(..));
+                let changes = self.buttons.press(GilInput::new(button, code), pressed);
+                self.apply_button_changes(changes)
+            }
+            GilEventType::ButtonChanged(button, value, code) => {
+                match self.buttons.output(&GilInput::new(button, code)) {
+                    Some(OutputMapping::Axis(output)) => {
+                        self.set_axis_output(output, value);
+                        true
                     }
+                    _ => false,
                 }
             }
+            GilEventType::AxisChanged(axis, value, code) => match Self::axis_output(axis, code) {
+                Some((output, invert)) => {
+                    self.set_stick_output(output, value, invert);
+                    true
+                }
+                None => {
+                    log_error!("Unknown axis!: {}/{}", code, value);
+                    false
+                }
+            },
+            GilEventType::Disconnected => {
+                log!("GilRsInput disconnected: {}", self.name);
+                self.reset_gamepad();
+                true
+            }
+            GilEventType::Connected => {
+                log!("GilRsInput connected: {}", self.name);
+                false
+            }
+            _ => false,
         }
     }
 }
@@ -439,52 +832,70 @@ impl GilRsInput {
 impl ControllerInput for GilRsInput {
     type ControllerType = GilRsInput;
 
-    fn to_gamepad<'b>(&'b mut self) -> &'b Gamepad {
-        return &self.gamepad;
+    fn to_gamepad(&mut self) -> &Gamepad {
+        &self.gamepad
     }
 
-    fn discover_all() -> Vec<Self::ControllerType> {
-        let gilrs = Gilrs::new().unwrap();
-
-        let mut inps: Vec<Self::ControllerType> = vec![];
+    fn prep_for_input_events(&mut self) {
+        log!("GilRsInput connected: {}", self.name);
+    }
 
-        macro_rules! ignore_controller {
-            ($gamepad:expr, $s:expr) => {
-                if $gamepad.name().contains($s) | $gamepad.os_name().contains($s) {
-                    continue;
-                }
-            }
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        self.poll_battery();
+
+        let event = tokio::select! {
+            event = self.events.recv() => match event {
+                Some(event) => event,
+                None => return Err("The gilrs thread stopped."),
+            },
+            // TODO: Make this a setting somehow?
+            _ = tokio::time::sleep(Duration::from_millis(5)) => return Ok(false),
+        };
+        let mut changed = self.map_gilrs_event_to_gamepad(event);
+        while let Ok(event) = self.events.try_recv() {
+            changed |= self.map_gilrs_event_to_gamepad(event);
         }
+        Ok(changed)
+    }
 
-        for (_id, gamepad) in gilrs.gamepads() {
-            let gilrs_current_gamepad = Gilrs::new().unwrap();
-            
-            // e.g. Nintendo Wii Remote Nunchuk
-            ignore_controller!(gamepad, "Wii");
-            ignore_controller!(gamepad, "Nunchuk");
-            
-
-            inps.push(Self::ControllerType::new(
-                gilrs_current_gamepad,
-                gamepad.id(),
-            ));
-            println!(
-                "Detected!: {}/{}/{}",
-                gamepad.id(),
-                gamepad.name(),
-                gamepad.os_name()
-            );
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        // gilrs has no API for light bars.
+        if let Feedback::Rumble { strong, weak } = *feedback {
+            self.hub.rumble(self.gil_rs_device_id, strong, weak);
         }
+    }
 
-        return inps;
+    fn name(&self) -> String {
+        self.name.clone()
     }
+}
 
-    fn prep_for_input_events(&mut self) {
-        println!("GilRsInput connected: {}", self.get_gilrs_gamepad().name());
+impl ConfigurableInput for GilRsInput {
+    fn reconfigure(&mut self, config: &Config) {
+        self.set_config(&config.gilrs);
+        let excluded = !config.devices.allows(&self.info);
+        if excluded != self.excluded {
+            self.excluded = excluded;
+            log_filter_change(&self.name, excluded);
+            self.reset_gamepad();
+        }
     }
 
-    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
-        self.map_gilrs_to_gamepad();
-        return Ok(true);
+    fn devices(&self) -> Vec<DeviceInfo> {
+        self.hub.connected_devices()
+    }
+
+    // Wherever the sticks rest right now becomes their center, for pads with drift.
+    fn calibrate(&mut self) {
+        let sticks: Vec<_> = self.raw_sticks.clone().into_iter().collect();
+        for (axis, (value, invert)) in sticks {
+            // Triggers on raw ABS codes come through here too, their rest is the minimum.
+            if matches!(axis, GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger) {
+                continue;
+            }
+            self.stick_centers.insert(axis.clone(), value);
+            self.set_stick_output(axis, value, invert);
+        }
+        log!("Recentered sticks of {}: {:?}", self.name, self.stick_centers);
     }
 }
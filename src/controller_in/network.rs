@@ -0,0 +1,427 @@
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use crate::controller_abs::{
+    ControllerInput, Feedback, Gamepad, GamepadAxis, GamepadButton, PowerState,
+};
+use crate::controller_in::ConfigurableInput;
+use crate::dashboard;
+
+// Gamepad state over UDP, one self-contained packet per update:
+//
+//   0  "CC" magic
+//   2  protocol version
+//   3  reserved, 0
+//   4  sequence number, u32 LE, wraps
+//   8  buttons, u32 LE, bit n is `BUTTON_BITS[n]`
+//  12  axes, u16 LE each in `AXIS_SLOTS` order, 0 is the axis minimum
+//  24  power: 0-100 discharging, 0x80 | level charging, 0xfd charged, 0xfe wired, 0xff unknown
+//
+// Feedback from the receiver's output goes back the same way, "CF", the version, then a kind:
+// 0 for rumble with the strong and weak motor as u16 LE, 1 for a light bar with red, green, blue.
+
+pub const NETWORK_PROTOCOL_VERSION: u8 = 1;
+pub const NETWORK_DEFAULT_PORT: u16 = 24950;
+pub const NETWORK_PACKET_LEN: usize = 25;
+// Senders repeat the state this often, so receivers can tell a quiet pad from a lost one.
+pub const NETWORK_KEEPALIVE: Duration = Duration::from_millis(100);
+
+const MAGIC: [u8; 2] = *b"CC";
+const FEEDBACK_MAGIC: [u8; 2] = *b"CF";
+const FEEDBACK_RUMBLE: u8 = 0;
+const FEEDBACK_LIGHT_BAR: u8 = 1;
+const AXIS_COUNT: usize = 6;
+
+// Part of the protocol, new buttons and axes only go on the end.
+const BUTTON_BITS: [GamepadButton; 16] = [
+    GamepadButton::North,
+    GamepadButton::East,
+    GamepadButton::South,
+    GamepadButton::West,
+    GamepadButton::LeftShoulderButton,
+    GamepadButton::RightShoulderButton,
+    GamepadButton::Select,
+    GamepadButton::Start,
+    GamepadButton::Mode,
+    GamepadButton::LeftThumb,
+    GamepadButton::RightThumb,
+    GamepadButton::DPadUp,
+    GamepadButton::DPadDown,
+    GamepadButton::DPadLeft,
+    GamepadButton::DPadRight,
+    GamepadButton::Share,
+];
+const AXIS_SLOTS: [GamepadAxis; AXIS_COUNT] = [
+    GamepadAxis::LeftTrigger,
+    GamepadAxis::RightTrigger,
+    GamepadAxis::LeftJoystickX,
+    GamepadAxis::LeftJoystickY,
+    GamepadAxis::RightJoystickX,
+    GamepadAxis::RightJoystickY,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NetworkPacket {
+    pub sequence: u32,
+    pub buttons: u32,
+    pub axes: [u16; AXIS_COUNT],
+    pub power: Option<PowerState>,
+}
+
+impl NetworkPacket {
+    pub fn from_gamepad(gamepad: &Gamepad, sequence: u32) -> NetworkPacket {
+        let mut buttons = 0;
+        for (bit, button) in BUTTON_BITS.iter().enumerate() {
+            if gamepad.buttons.get(button).copied().unwrap_or(false) {
+                buttons |= 1 << bit;
+            }
+        }
+        let mut axes = [u16::MAX / 2; AXIS_COUNT];
+        for (i, axis) in AXIS_SLOTS.iter().enumerate() {
+            if let Some(value) = gamepad.axes.get(axis) {
+                axes[i] = (value.value >> 48) as u16;
+            }
+        }
+        NetworkPacket {
+            sequence,
+            buttons,
+            axes,
+            power: gamepad.power,
+        }
+    }
+
+    pub fn apply_to(&self, gamepad: &mut Gamepad) {
+        for (bit, button) in BUTTON_BITS.iter().enumerate() {
+            gamepad.set_button(button.clone(), self.buttons & (1 << bit) != 0);
+        }
+        for (i, axis) in AXIS_SLOTS.iter().enumerate() {
+            // Spread back over the full u64 range, so 0xffff is the maximum.
+            gamepad.get_axis_ref(axis.clone()).value = self.axes[i] as u64 * 0x0001_0001_0001_0001;
+        }
+        gamepad.power = self.power;
+    }
+
+    pub fn encode(&self) -> [u8; NETWORK_PACKET_LEN] {
+        let mut bytes = [0u8; NETWORK_PACKET_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC);
+        bytes[2] = NETWORK_PROTOCOL_VERSION;
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.buttons.to_le_bytes());
+        for (i, axis) in self.axes.iter().enumerate() {
+            bytes[12 + i * 2..14 + i * 2].copy_from_slice(&axis.to_le_bytes());
+        }
+        bytes[24] = match self.power {
+            Some(PowerState::Discharging(level)) => level.min(100),
+            Some(PowerState::Charging(level)) => 0x80 | level.min(100),
+            Some(PowerState::Charged) => 0xfd,
+            Some(PowerState::Wired) => 0xfe,
+            None => 0xff,
+        };
+        bytes
+    }
+
+    pub fn parse(data: &[u8]) -> Result<NetworkPacket, String> {
+        if data.len() < 3 || data[0..2] != MAGIC {
+            return Err("Not a gamepad packet".to_string());
+        }
+        if data[2] != NETWORK_PROTOCOL_VERSION {
+            return Err(format!(
+                "Unsupported protocol version {}, expected {}",
+                data[2], NETWORK_PROTOCOL_VERSION
+            ));
+        }
+        // Later versions may only append fields.
+        if data.len() < NETWORK_PACKET_LEN {
+            return Err(format!("Packet too short: {} bytes", data.len()));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let mut axes = [0u16; AXIS_COUNT];
+        for (i, axis) in axes.iter_mut().enumerate() {
+            *axis = u16_at(12 + i * 2);
+        }
+        let power = match data[24] {
+            level @ 0..=100 => Some(PowerState::Discharging(level)),
+            0xfd => Some(PowerState::Charged),
+            0xfe => Some(PowerState::Wired),
+            0xff => None,
+            level => Some(PowerState::Charging((level & 0x7f).min(100))),
+        };
+        Ok(NetworkPacket {
+            sequence: u32_at(4),
+            buttons: u32_at(8),
+            axes,
+            power,
+        })
+    }
+}
+
+pub fn encode_feedback(feedback: &Feedback) -> Vec<u8> {
+    let mut bytes = FEEDBACK_MAGIC.to_vec();
+    bytes.push(NETWORK_PROTOCOL_VERSION);
+    match *feedback {
+        Feedback::Rumble { strong, weak } => {
+            let motor = |strength: f64| (strength.clamp(0.0, 1.0) * u16::MAX as f64) as u16;
+            bytes.push(FEEDBACK_RUMBLE);
+            bytes.extend(motor(strong).to_le_bytes());
+            bytes.extend(motor(weak).to_le_bytes());
+        }
+        Feedback::LightBar { red, green, blue } => {
+            bytes.extend([FEEDBACK_LIGHT_BAR, red, green, blue]);
+        }
+    }
+    bytes
+}
+
+pub fn parse_feedback(data: &[u8]) -> Option<Feedback> {
+    if data.len() < 4 || data[0..2] != FEEDBACK_MAGIC || data[2] != NETWORK_PROTOCOL_VERSION {
+        return None;
+    }
+    let motor = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]) as f64 / u16::MAX as f64;
+    match data[3] {
+        FEEDBACK_RUMBLE if data.len() >= 8 => Some(Feedback::Rumble {
+            strong: motor(4),
+            weak: motor(6),
+        }),
+        FEEDBACK_LIGHT_BAR if data.len() >= 7 => Some(Feedback::LightBar {
+            red: data[4],
+            green: data[5],
+            blue: data[6],
+        }),
+        _ => None,
+    }
+}
+
+// Drops late and duplicate packets, and notices when the sender goes quiet.
+pub struct SequenceTracker {
+    timeout: Duration,
+    last_sequence: Option<u32>,
+    last_packet: Option<Instant>,
+}
+
+impl SequenceTracker {
+    pub fn new(timeout: Duration) -> SequenceTracker {
+        SequenceTracker {
+            timeout,
+            last_sequence: None,
+            last_packet: None,
+        }
+    }
+
+    // Whether the packet is newer than anything seen so far. After a timeout anything goes, so
+    // a restarted sender is picked up again.
+    pub fn accept(&mut self, sequence: u32, now: Instant) -> bool {
+        if let Some(last) = self.last_sequence {
+            if !self.timed_out(now) && (sequence.wrapping_sub(last) as i32) <= 0 {
+                return false;
+            }
+        }
+        self.last_sequence = Some(sequence);
+        self.last_packet = Some(now);
+        true
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        match self.last_packet {
+            Some(last) => now.duration_since(last) >= self.timeout,
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    // Address `--input network` listens on.
+    pub listen: String,
+    // Receiver `--output network` streams to, `--send` overrides it.
+    pub send_to: Option<String>,
+    // Without packets for this long the pad goes neutral.
+    pub timeout_ms: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen: format!("0.0.0.0:{}", NETWORK_DEFAULT_PORT),
+            send_to: None,
+            timeout_ms: 500,
+        }
+    }
+}
+
+// Everything released, sticks centered and triggers up.
+fn neutral_gamepad() -> Gamepad {
+    let mut gamepad = Gamepad::new();
+    for trigger in [GamepadAxis::LeftTrigger, GamepadAxis::RightTrigger] {
+        gamepad.get_axis_ref(trigger).value = 0;
+    }
+    gamepad
+}
+
+pub struct NetworkInput {
+    socket: UdpSocket,
+    gamepad: Gamepad,
+    tracker: SequenceTracker,
+    sender: Option<SocketAddr>,
+}
+
+impl NetworkInput {
+    // Needs to be called from within the tokio runtime.
+    pub fn bind(config: &NetworkConfig) -> Result<NetworkInput, String> {
+        let socket = StdUdpSocket::bind(&config.listen)
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            })
+            .map_err(|err| format!("Could not listen on {}: {}", config.listen, err))?;
+        Ok(NetworkInput {
+            socket,
+            gamepad: neutral_gamepad(),
+            tracker: SequenceTracker::new(Duration::from_millis(config.timeout_ms)),
+            sender: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.local_addr().ok()
+    }
+
+    fn handle_packet(&mut self, data: &[u8], from: SocketAddr) -> bool {
+        let packet = match NetworkPacket::parse(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log_error!("Ignoring packet from {}: {}", from, err);
+                return false;
+            }
+        };
+        dashboard::raw_event(&self.name(), || {
+            format!("packet {} from {}", packet.sequence, from)
+        });
+        if !self.tracker.accept(packet.sequence, Instant::now()) {
+            return false;
+        }
+        if self.sender != Some(from) {
+            log!("NetworkInput receiving from {}", from);
+            self.sender = Some(from);
+        }
+        packet.apply_to(&mut self.gamepad);
+        true
+    }
+}
+
+impl ControllerInput for NetworkInput {
+    type ControllerType = NetworkInput;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        &self.gamepad
+    }
+
+    fn prep_for_input_events(&mut self) {
+        if let Some(addr) = self.local_addr() {
+            log!("NetworkInput listening on {}", addr);
+        }
+    }
+
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        let mut buffer = [0u8; 64];
+        let received = tokio::select! {
+            res = self.socket.recv_from(&mut buffer) => match res {
+                Ok(received) => Some(received),
+                Err(_) => return Err("Error reading network packets."),
+            },
+            _ = tokio::time::sleep(Duration::from_millis(5)) => None,
+        };
+        if let Some((length, from)) = received {
+            if self.handle_packet(&buffer[..length], from) {
+                return Ok(true);
+            }
+        }
+        if self.sender.is_some() && self.tracker.timed_out(Instant::now()) {
+            log!("NetworkInput timed out, going neutral");
+            self.sender = None;
+            self.gamepad = neutral_gamepad();
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // Back to whoever is sending, if they're listening.
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        if let Some(sender) = self.sender {
+            let _ = self.socket.try_send_to(&encode_feedback(feedback), sender);
+        }
+    }
+
+    fn name(&self) -> String {
+        match self.local_addr() {
+            Some(addr) => format!("network {}", addr),
+            None => "network".to_string(),
+        }
+    }
+}
+
+// Mappings are applied on the sending side.
+impl ConfigurableInput for NetworkInput {}
+
+#[test]
+fn test_network_packet() {
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::Share, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.power = Some(PowerState::Charging(40));
+
+    let packet = NetworkPacket::from_gamepad(&gamepad, 7);
+    let bytes = packet.encode();
+    assert_eq!(bytes[..8], [b'C', b'C', 1, 0, 7, 0, 0, 0]);
+    // South is bit 2, Share bit 15.
+    assert_eq!(bytes[8..12], [0x04, 0x80, 0x00, 0x00]);
+    assert_eq!(bytes[12..16], [0x00, 0x00, 0xff, 0xff]);
+    assert_eq!(bytes[24], 0x80 | 40);
+    assert_eq!(NetworkPacket::parse(&bytes), Ok(packet));
+
+    let mut received = neutral_gamepad();
+    packet.apply_to(&mut received);
+    assert_eq!(received.buttons, gamepad.buttons);
+    assert_eq!(received.axes[&GamepadAxis::RightTrigger].value, u64::MAX);
+    assert_eq!(received.power, gamepad.power);
+
+    assert!(NetworkPacket::parse(&bytes[..20]).is_err());
+    assert!(NetworkPacket::parse(b"hello").is_err());
+    let mut future = bytes;
+    future[2] = 2;
+    assert!(NetworkPacket::parse(&future).is_err());
+
+    let start = Instant::now();
+    let mut tracker = SequenceTracker::new(Duration::from_millis(500));
+    assert!(tracker.accept(10, start));
+    assert!(!tracker.accept(9, start));
+    assert!(!tracker.accept(10, start));
+    assert!(tracker.accept(12, start));
+    assert!(tracker.accept(u32::MAX, start + Duration::from_secs(1)));
+    assert!(tracker.accept(1, start + Duration::from_secs(1)));
+    assert!(!tracker.timed_out(start + Duration::from_millis(1400)));
+    assert!(tracker.timed_out(start + Duration::from_millis(1500)));
+
+    let rumble = Feedback::Rumble {
+        strong: 1.0,
+        weak: 0.0,
+    };
+    assert_eq!(parse_feedback(&encode_feedback(&rumble)), Some(rumble));
+    let light_bar = Feedback::LightBar {
+        red: 1,
+        green: 2,
+        blue: 3,
+    };
+    assert_eq!(
+        parse_feedback(&encode_feedback(&light_bar)),
+        Some(light_bar)
+    );
+    assert_eq!(parse_feedback(&bytes), None);
+}
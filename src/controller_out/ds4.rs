@@ -0,0 +1,539 @@
+use std::time::Instant;
+
+use crate::{
+    controller_abs::{
+        Axis, BitPackedButton, BitPackedButtons, Feedback, Gamepad, GamepadAxis, GamepadButton,
+        JoystickState, MotionState, PowerState, TouchPoint,
+    },
+    AxisNew, JoystickStateNew,
+};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, hid_default_reply,
+    hid_descriptor, interface_descriptor, ControlReply, ControlRequest, GadgetDescriptors,
+    GadgetFunction, HID_REQ_GET_REPORT, USB_ENDPOINT_XFER_INT, USB_TYPE_CLASS,
+};
+
+pub const DS4_VENDOR_ID: u16 = 0x054c;
+// Second revision DualShock 4 (CUH-ZCT2)
+pub const DS4_PRODUCT_ID: u16 = 0x09cc;
+pub const DS4_IN_ENDPOINT: u8 = 0x84;
+pub const DS4_OUT_ENDPOINT: u8 = 0x03;
+pub const DS4_REPORT_LEN: usize = 64;
+
+const TOUCHPAD_WIDTH: f64 = 1920.0;
+const TOUCHPAD_HEIGHT: f64 = 942.0;
+// Matches the calibration we report in feature report 0x02.
+const ACCEL_PER_G: f64 = 8192.0;
+const GYRO_PER_DEG_S: f64 = 16.0;
+
+// Trimmed down DualShock 4 report descriptor, keeping the reports hosts actually use.
+pub const DS4_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x05, // Usage (Game Pad)
+    0xa1, 0x01, // Collection (Application)
+    0x85, 0x01, //   Report ID (1)
+    0x09, 0x30, //   Usage (X)
+    0x09, 0x31, //   Usage (Y)
+    0x09, 0x32, //   Usage (Z)
+    0x09, 0x35, //   Usage (Rz)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, // Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x09, 0x39, //   Usage (Hat switch)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x07, //   Logical Maximum (7)
+    0x35, 0x00, //   Physical Minimum (0)
+    0x46, 0x3b, 0x01, // Physical Maximum (315)
+    0x65, 0x14, //   Unit (Degrees)
+    0x75, 0x04, //   Report Size (4)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x42, //   Input (Data,Var,Abs,Null State)
+    0x65, 0x00, //   Unit (None)
+    0x05, 0x09, //   Usage Page (Button)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x0e, //   Usage Maximum (14)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x0e, //   Report Count (14)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x06, 0x00, 0xff, // Usage Page (Vendor)
+    0x09, 0x20, //   Usage (0x20), frame counter
+    0x75, 0x06, //   Report Size (6)
+    0x95, 0x01, //   Report Count (1)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x7f, //   Logical Maximum (127)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x05, 0x01, //   Usage Page (Generic Desktop)
+    0x09, 0x33, //   Usage (Rx)
+    0x09, 0x34, //   Usage (Ry)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, // Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x02, //   Report Count (2)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x06, 0x00, 0xff, // Usage Page (Vendor)
+    0x09, 0x21, //   Usage (0x21), motion/touch/battery
+    0x95, 0x36, //   Report Count (54)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x85, 0x05, //   Report ID (5)
+    0x09, 0x22, //   Usage (0x22), rumble and light bar
+    0x95, 0x1f, //   Report Count (31)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0x85, 0x02, //   Report ID (2)
+    0x09, 0x24, //   Usage (0x24), motion calibration
+    0x95, 0x24, //   Report Count (36)
+    0xb1, 0x02, //   Feature (Data,Var,Abs)
+    0x85, 0x12, //   Report ID (18)
+    0x06, 0x02, 0xff, // Usage Page (Vendor 2)
+    0x09, 0x21, //   Usage (0x21), pairing info
+    0x95, 0x0f, //   Report Count (15)
+    0xb1, 0x02, //   Feature (Data,Var,Abs)
+    0x85, 0xa3, //   Report ID (163)
+    0x06, 0x80, 0xff, // Usage Page (Vendor 0x80)
+    0x09, 0x23, //   Usage (0x23), firmware info
+    0x95, 0x30, //   Report Count (48)
+    0xb1, 0x02, //   Feature (Data,Var,Abs)
+    0xc0, // End Collection
+];
+
+// Locally administered, the real pads report their Bluetooth address.
+const DS4_MAC: [u8; 6] = [0x02, 0xcc, 0x5e, 0xd5, 0x04, 0x01];
+
+pub fn ds4_descriptors() -> GadgetDescriptors {
+    GadgetDescriptors {
+        device: device_descriptor(DS4_VENDOR_ID, DS4_PRODUCT_ID, 0x0100, (0, 0, 0), 64),
+        configuration: configuration_descriptor(&[[
+            interface_descriptor(0, 2, (0x03, 0, 0)),
+            hid_descriptor(DS4_REPORT_DESCRIPTOR.len()),
+            endpoint_descriptor(DS4_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 64, 5),
+            endpoint_descriptor(DS4_OUT_ENDPOINT, USB_ENDPOINT_XFER_INT, 64, 5),
+        ]
+        .concat()]),
+        strings: vec![
+            "Sony Interactive Entertainment".to_string(),
+            "Wireless Controller".to_string(),
+            String::new(),
+        ],
+        hid_reports: vec![DS4_REPORT_DESCRIPTOR.to_vec()],
+    }
+}
+
+// Feature reports the host reads while setting the pad up.
+pub fn ds4_feature_report(report_id: u8) -> Option<Vec<u8>> {
+    let mut report = vec![report_id];
+    match report_id {
+        0x02 => {
+            let gyro_range: i16 = 8640;
+            let accel_range: i16 = ACCEL_PER_G as i16;
+            let mut values: Vec<i16> = vec![0, 0, 0];
+            // Pitch, yaw and roll, plus then minus.
+            for _ in 0..3 {
+                values.extend([gyro_range, -gyro_range]);
+            }
+            // Gyro speed range, 2x 540 deg/s.
+            values.extend([540, 540]);
+            for _ in 0..3 {
+                values.extend([accel_range, -accel_range]);
+            }
+            for value in values {
+                report.extend_from_slice(&value.to_le_bytes());
+            }
+            report.resize(37, 0);
+        }
+        0x12 => {
+            report.extend(DS4_MAC.iter().rev());
+            report.resize(16, 0);
+        }
+        0xa3 => {
+            report.extend_from_slice(b"Jan  1 2024\0\0\0\0\0");
+            report.extend_from_slice(b"00:00:00\0\0\0\0\0\0\0\0");
+            report.resize(49, 0);
+        }
+        _ => return None,
+    }
+    Some(report)
+}
+
+pub struct Ds4Function;
+
+impl GadgetFunction for Ds4Function {
+    fn control(&mut self, request: &ControlRequest, _data: &[u8]) -> ControlReply {
+        if request.kind() == USB_TYPE_CLASS && request.request == HID_REQ_GET_REPORT {
+            let [report_id, _kind] = request.value.to_le_bytes();
+            return match ds4_feature_report(report_id) {
+                Some(report) => ControlReply::Data(report),
+                None => ControlReply::Stall,
+            };
+        }
+        hid_default_reply(request)
+    }
+}
+
+// Rumble and light bar from output report 0x05.
+pub fn parse_ds4_output_report(data: &[u8]) -> Vec<Feedback> {
+    let mut feedback = vec![];
+    if data.len() < 11 || data[0] != 0x05 {
+        return feedback;
+    }
+    let flags = data[1];
+    if flags & 0x01 != 0 {
+        feedback.push(Feedback::Rumble {
+            strong: data[5] as f64 / 255.0,
+            weak: data[4] as f64 / 255.0,
+        });
+    }
+    if flags & 0x02 != 0 {
+        feedback.push(Feedback::LightBar {
+            red: data[6],
+            green: data[7],
+            blue: data[8],
+        });
+    }
+    feedback
+}
+
+pub struct Ds4ButtonState {
+    pub square: BitPackedButton,
+    pub cross: BitPackedButton,
+    pub circle: BitPackedButton,
+    pub triangle: BitPackedButton,
+    pub l1: BitPackedButton,
+    pub r1: BitPackedButton,
+    pub l2: BitPackedButton,
+    pub r2: BitPackedButton,
+    pub share: BitPackedButton,
+    pub options: BitPackedButton,
+    pub l3: BitPackedButton,
+    pub r3: BitPackedButton,
+    pub ps: BitPackedButton,
+    pub touchpad: BitPackedButton,
+    // The dpad is a hat switch, see `get_hat`.
+    pub dpad_up: bool,
+    pub dpad_down: bool,
+    pub dpad_left: bool,
+    pub dpad_right: bool,
+}
+
+impl Ds4ButtonState {
+    pub fn new() -> Ds4ButtonState {
+        Ds4ButtonState {
+            // Byte 5, above the hat
+            square: BitPackedButton::new(0x04),
+            cross: BitPackedButton::new(0x05),
+            circle: BitPackedButton::new(0x06),
+            triangle: BitPackedButton::new(0x07),
+            // Byte 6
+            l1: BitPackedButton::new(0x00),
+            r1: BitPackedButton::new(0x01),
+            l2: BitPackedButton::new(0x02),
+            r2: BitPackedButton::new(0x03),
+            share: BitPackedButton::new(0x04),
+            options: BitPackedButton::new(0x05),
+            l3: BitPackedButton::new(0x06),
+            r3: BitPackedButton::new(0x07),
+            // Byte 7, below the frame counter
+            ps: BitPackedButton::new(0x00),
+            touchpad: BitPackedButton::new(0x01),
+            dpad_up: false,
+            dpad_down: false,
+            dpad_left: false,
+            dpad_right: false,
+        }
+    }
+
+    // 0 is north going clockwise, 8 is released.
+    pub fn get_hat(&self) -> u8 {
+        match (
+            self.dpad_up,
+            self.dpad_right,
+            self.dpad_down,
+            self.dpad_left,
+        ) {
+            (true, false, _, true) => 7,
+            (true, true, _, false) => 1,
+            (true, _, _, _) => 0,
+            (false, true, true, _) => 3,
+            (false, true, false, _) => 2,
+            (false, false, true, true) => 5,
+            (false, false, true, false) => 4,
+            (false, false, false, true) => 6,
+            (false, false, false, false) => 8,
+        }
+    }
+
+    pub fn get_control_byte_5(&self) -> u8 {
+        let face = BitPackedButtons {
+            buttons: vec![
+                self.square.clone(),
+                self.cross.clone(),
+                self.circle.clone(),
+                self.triangle.clone(),
+            ],
+        }
+        .to_bytes_repr();
+        face | self.get_hat()
+    }
+
+    pub fn get_control_byte_6(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.l1.clone(),
+                self.r1.clone(),
+                self.l2.clone(),
+                self.r2.clone(),
+                self.share.clone(),
+                self.options.clone(),
+                self.l3.clone(),
+                self.r3.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+
+    pub fn get_control_byte_7(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![self.ps.clone(), self.touchpad.clone()],
+        }
+        .to_bytes_repr()
+    }
+}
+
+pub struct Ds4ControllerState {
+    pub buttons: Ds4ButtonState,
+    pub left_trigger: Axis,
+    pub right_trigger: Axis,
+    pub left_joystick: JoystickState,
+    pub right_joystick: JoystickState,
+    pub motion: Option<MotionState>,
+    pub touches: Vec<TouchPoint>,
+    pub power: Option<PowerState>,
+    start: Instant,
+    frame_counter: u8,
+}
+
+impl Ds4ControllerState {
+    pub fn new() -> Ds4ControllerState {
+        Ds4ControllerState {
+            buttons: Ds4ButtonState::new(),
+            left_trigger: AxisNew!(u8::MIN),
+            right_trigger: AxisNew!(u8::MIN),
+            left_joystick: JoystickStateNew!(u8, 128),
+            right_joystick: JoystickStateNew!(u8, 128),
+            motion: None,
+            touches: vec![],
+            power: None,
+            start: Instant::now(),
+            frame_counter: 0,
+        }
+    }
+
+    pub fn update_from_gamepad(&mut self, gamepad: &Gamepad) {
+        for (button, button_state) in &gamepad.buttons {
+            let val = button_state.to_owned();
+            match button {
+                GamepadButton::North => self.buttons.triangle.value = val,
+                GamepadButton::East => self.buttons.circle.value = val,
+                GamepadButton::South => self.buttons.cross.value = val,
+                GamepadButton::West => self.buttons.square.value = val,
+                GamepadButton::LeftShoulderButton => self.buttons.l1.value = val,
+                GamepadButton::RightShoulderButton => self.buttons.r1.value = val,
+                GamepadButton::LeftThumb => self.buttons.l3.value = val,
+                GamepadButton::RightThumb => self.buttons.r3.value = val,
+                GamepadButton::Start => self.buttons.options.value = val,
+                GamepadButton::Select => self.buttons.share.value = val,
+                GamepadButton::Mode => self.buttons.ps.value = val,
+                GamepadButton::DPadUp => self.buttons.dpad_up = val,
+                GamepadButton::DPadDown => self.buttons.dpad_down = val,
+                GamepadButton::DPadLeft => self.buttons.dpad_left = val,
+                GamepadButton::DPadRight => self.buttons.dpad_right = val,
+                // Select already is the share button, a share press clicks the touchpad
+                GamepadButton::Share => self.buttons.touchpad.value = val,
+            }
+        }
+        for (gamepad_axis, axis) in &gamepad.axes {
+            match gamepad_axis {
+                GamepadAxis::LeftJoystickX => {
+                    self.left_joystick.x.value = axis.convert_into(false);
+                }
+                // The DS4 has y pointing down
+                GamepadAxis::LeftJoystickY => {
+                    self.left_joystick.y.value = axis.invert().convert_into(false);
+                }
+                GamepadAxis::RightJoystickX => {
+                    self.right_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickY => {
+                    self.right_joystick.y.value = axis.invert().convert_into(false);
+                }
+                GamepadAxis::LeftTrigger => {
+                    self.left_trigger.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightTrigger => {
+                    self.right_trigger.value = axis.convert_into(false);
+                }
+            }
+        }
+        self.motion = gamepad.motion;
+        self.touches = gamepad.touches.clone();
+        self.power = gamepad.power;
+    }
+
+    // Low nibble is the level in tenths, 11 means full. We're on USB so the cable bit is always set.
+    fn get_battery_byte(&self) -> u8 {
+        let level = match self.power.and_then(|power| power.level()) {
+            Some(level) if level < 100 => level / 10,
+            _ => 11,
+        };
+        0x10 | level
+    }
+
+    pub fn next_report(&mut self) -> [u8; DS4_REPORT_LEN] {
+        let mut report = [0u8; DS4_REPORT_LEN];
+        let left_trigger: u8 = self.left_trigger.convert_into(false);
+        let right_trigger: u8 = self.right_trigger.convert_into(false);
+        self.buttons.l2.value = left_trigger > 0;
+        self.buttons.r2.value = right_trigger > 0;
+
+        report[0] = 0x01; // Report ID
+        report[1] = self.left_joystick.x.convert_into(false);
+        report[2] = self.left_joystick.y.convert_into(false);
+        report[3] = self.right_joystick.x.convert_into(false);
+        report[4] = self.right_joystick.y.convert_into(false);
+        report[5] = self.buttons.get_control_byte_5();
+        report[6] = self.buttons.get_control_byte_6();
+        report[7] = self.buttons.get_control_byte_7() | (self.frame_counter << 2);
+        self.frame_counter = (self.frame_counter + 1) & 0x3f;
+        report[8] = left_trigger;
+        report[9] = right_trigger;
+        // Timestamp in 5.33us units
+        let timestamp = (self.start.elapsed().as_micros() * 3 / 16) as u16;
+        report[10..12].copy_from_slice(&timestamp.to_le_bytes());
+
+        if let Some(motion) = self.motion {
+            let to_i16 = |value: f64| value.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            let gyro = [motion.gyro.0, motion.gyro.1, motion.gyro.2];
+            let accel = [motion.accel.0, motion.accel.1, motion.accel.2];
+            for (i, value) in gyro.iter().enumerate() {
+                report[13 + i * 2..15 + i * 2]
+                    .copy_from_slice(&to_i16(value * GYRO_PER_DEG_S).to_le_bytes());
+            }
+            for (i, value) in accel.iter().enumerate() {
+                report[19 + i * 2..21 + i * 2]
+                    .copy_from_slice(&to_i16(value * ACCEL_PER_G).to_le_bytes());
+            }
+        }
+
+        report[30] = self.get_battery_byte();
+
+        // One touch packet with up to two fingers
+        report[33] = 1;
+        report[34] = self.frame_counter;
+        for finger in 0..2 {
+            let offset = 35 + finger * 4;
+            match self.touches.get(finger) {
+                Some(touch) => {
+                    let x = (touch.x.clamp(0.0, 1.0) * (TOUCHPAD_WIDTH - 1.0)) as u16;
+                    let y = (touch.y.clamp(0.0, 1.0) * (TOUCHPAD_HEIGHT - 1.0)) as u16;
+                    report[offset] = touch.id & 0x7f;
+                    report[offset + 1] = (x & 0xff) as u8;
+                    report[offset + 2] = ((x >> 8) & 0x0f) as u8 | ((y & 0x0f) << 4) as u8;
+                    report[offset + 3] = (y >> 4) as u8;
+                }
+                // Top bit set means not touching
+                None => report[offset] = 0x80,
+            }
+        }
+        report
+    }
+}
+
+#[test]
+fn test_ds4_report() {
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::DPadUp, true);
+    gamepad.set_button(GamepadButton::DPadRight, true);
+    gamepad.set_button(GamepadButton::Mode, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.motion = Some(MotionState {
+        accel: (0.0, 1.0, 0.0),
+        gyro: (0.0, 0.0, -10.0),
+    });
+    gamepad.touches = vec![TouchPoint {
+        id: 3,
+        x: 1.0,
+        y: 1.0,
+    }];
+    gamepad.power = Some(PowerState::Discharging(55));
+
+    let mut state = Ds4ControllerState::new();
+    state.update_from_gamepad(&gamepad);
+    let report = state.next_report();
+    assert_eq!(report[0], 0x01);
+    // Stick up is 0 on a DS4
+    assert_eq!(report[2], 0);
+    // Cross and north-east on the hat
+    assert_eq!(report[5], 0x20 | 1);
+    // R2 digital bit follows the trigger
+    assert_eq!(report[6], 0x08);
+    assert_eq!(report[7] & 0x03, 0x01);
+    assert_eq!(report[9], 255);
+    assert_eq!(i16::from_le_bytes([report[17], report[18]]), -160);
+    assert_eq!(i16::from_le_bytes([report[21], report[22]]), 8192);
+    assert_eq!(report[30], 0x15);
+    assert_eq!(report[35], 3);
+    // 12 bit x/y at the far corner
+    assert_eq!(report[36], 0x7f);
+    assert_eq!(report[37], 0xd7);
+    assert_eq!(report[38], 0x3a);
+    assert_eq!(report[39], 0x80);
+
+    // Frame counter moves along
+    assert_eq!(state.next_report()[7] >> 2, 1);
+}
+
+#[test]
+fn test_ds4_output_and_feature_reports() {
+    let mut output = [0u8; 32];
+    output[0] = 0x05;
+    output[1] = 0x03;
+    output[4] = 0x00;
+    output[5] = 0xff;
+    output[6..9].copy_from_slice(&[0x10, 0x20, 0x30]);
+    assert_eq!(
+        parse_ds4_output_report(&output),
+        vec![
+            Feedback::Rumble {
+                strong: 1.0,
+                weak: 0.0
+            },
+            Feedback::LightBar {
+                red: 0x10,
+                green: 0x20,
+                blue: 0x30
+            },
+        ]
+    );
+    assert!(parse_ds4_output_report(&[0x01, 0xff]).is_empty());
+
+    let calibration = ds4_feature_report(0x02).unwrap();
+    assert_eq!(calibration.len(), 37);
+    assert_eq!(i16::from_le_bytes([calibration[7], calibration[8]]), 8640);
+    assert_eq!(i16::from_le_bytes([calibration[9], calibration[10]]), -8640);
+    assert_eq!(i16::from_le_bytes([calibration[23], calibration[24]]), 8192);
+    assert_eq!(
+        ds4_feature_report(0x12).unwrap()[1..7],
+        [0x01, 0x04, 0xd5, 0x5e, 0xcc, 0x02]
+    );
+    assert_eq!(ds4_feature_report(0xa3).unwrap().len(), 49);
+    assert!(ds4_feature_report(0x42).is_none());
+
+    let descriptors = ds4_descriptors();
+    assert_eq!(descriptors.device[8..12], [0x4c, 0x05, 0xcc, 0x09]);
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::controller_abs::{
+    ControllerInput, Feedback, Gamepad, GamepadAxis, GamepadButton, PowerState,
+};
+
+// DSU ("cemuhook") motion server, for Cemu, Dolphin, Yuzu and friends. Layouts follow the
+// protocol description at https://v1993.github.io/cemuhook-protocol/
+
+pub const DSU_DEFAULT_PORT: u16 = 26760;
+const DSU_PROTOCOL_VERSION: u16 = 1001;
+const DSU_SERVER_MAGIC: &[u8; 4] = b"DSUS";
+const DSU_CLIENT_MAGIC: &[u8; 4] = b"DSUC";
+const HEADER_LEN: usize = 16;
+
+const MESSAGE_VERSION: u32 = 0x100000;
+const MESSAGE_PORTS: u32 = 0x100001;
+const MESSAGE_DATA: u32 = 0x100002;
+
+const SLOT_COUNT: u8 = 4;
+// Clients resend their data request every second or so, and are dropped after 5.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(5);
+const TOUCHPAD_WIDTH: f64 = 1920.0;
+const TOUCHPAD_HEIGHT: f64 = 942.0;
+
+// CRC-32 as used by zlib, over the whole packet with the CRC field zeroed.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DsuConfig {
+    // Localhost only by default, emulators usually run on the same machine.
+    pub listen: String,
+}
+
+impl Default for DsuConfig {
+    fn default() -> Self {
+        DsuConfig {
+            listen: format!("127.0.0.1:{}", DSU_DEFAULT_PORT),
+        }
+    }
+}
+
+// Which slots a client asked for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Subscription {
+    All,
+    Slot(u8),
+}
+
+struct Client {
+    subscriptions: Vec<Subscription>,
+    last_request: Instant,
+}
+
+pub struct DsuServer {
+    server_id: u32,
+    start: Instant,
+    clients: HashMap<SocketAddr, Client>,
+    packet_numbers: [u32; SLOT_COUNT as usize],
+    socket: Option<UdpSocket>,
+    // Slots already logged as serving no motion.
+    warned_no_motion: [bool; SLOT_COUNT as usize],
+}
+
+impl DsuServer {
+    // Without a socket, for driving it by hand.
+    pub fn new() -> DsuServer {
+        DsuServer {
+            server_id: std::process::id(),
+            start: Instant::now(),
+            clients: HashMap::new(),
+            packet_numbers: [0; SLOT_COUNT as usize],
+            socket: None,
+            warned_no_motion: [false; SLOT_COUNT as usize],
+        }
+    }
+
+    pub fn bind(config: &DsuConfig) -> Result<DsuServer, String> {
+        let socket = UdpSocket::bind(&config.listen)
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })
+            .map_err(|err| format!("Could not start DSU server on {}: {}", config.listen, err))?;
+        let server = DsuServer {
+            socket: Some(socket),
+            ..DsuServer::new()
+        };
+        // The port it got when the config leaves it to the OS.
+        let address = server.local_addr();
+        log!(
+            "DSU server listening on {}",
+            address.map_or(config.listen.clone(), |address| address.to_string())
+        );
+        Ok(server)
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.as_ref()?.local_addr().ok()
+    }
+
+    fn packet(&self, message_type: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = DSU_SERVER_MAGIC.to_vec();
+        packet.extend(DSU_PROTOCOL_VERSION.to_le_bytes());
+        packet.extend(((payload.len() + 4) as u16).to_le_bytes());
+        packet.extend([0; 4]);
+        packet.extend(self.server_id.to_le_bytes());
+        packet.extend(message_type.to_le_bytes());
+        packet.extend_from_slice(payload);
+        let crc = crc32(&packet);
+        packet[8..12].copy_from_slice(&crc.to_le_bytes());
+        packet
+    }
+
+    fn mac(slot: u8) -> [u8; 6] {
+        [0x02, 0x00, 0x00, 0x00, 0x00, slot + 1]
+    }
+
+    // Slot, state, model, connection type, MAC and battery, shared by port info and data.
+    fn slot_header(slot: u8, gamepad: Option<&Gamepad>) -> Vec<u8> {
+        let gamepad = match gamepad {
+            Some(gamepad) => gamepad,
+            None => return [vec![slot], vec![0; 10]].concat(),
+        };
+        let battery = match gamepad.power {
+            None => 0x00,
+            Some(PowerState::Charging(_)) => 0xee,
+            Some(PowerState::Charged) | Some(PowerState::Wired) => 0xef,
+            Some(PowerState::Discharging(level)) => match level {
+                0..=10 => 0x01,
+                11..=30 => 0x02,
+                31..=70 => 0x03,
+                71..=99 => 0x04,
+                _ => 0x05,
+            },
+        };
+        // Inputs without motion show up as having no gyro, instead of one that never moves.
+        let model = if gamepad.motion.is_some() { 0x02 } else { 0x01 };
+        // Connected, model, connection type not applicable
+        let mut header = vec![slot, 0x02, model, 0x00];
+        header.extend(Self::mac(slot));
+        header.push(battery);
+        header
+    }
+
+    // Answers a client request, subscribing it to data for `gamepads` (by slot) if it asks.
+    pub fn handle_request(
+        &mut self,
+        data: &[u8],
+        from: SocketAddr,
+        gamepads: &[&Gamepad],
+        now: Instant,
+    ) -> Vec<Vec<u8>> {
+        if data.len() < HEADER_LEN + 4 || &data[0..4] != DSU_CLIENT_MAGIC {
+            return vec![];
+        }
+        let length = u16::from_le_bytes([data[6], data[7]]) as usize;
+        if data.len() < HEADER_LEN + length {
+            return vec![];
+        }
+        let data = &data[..HEADER_LEN + length];
+        let mut unsigned = data.to_vec();
+        unsigned[8..12].copy_from_slice(&[0; 4]);
+        if crc32(&unsigned) != u32::from_le_bytes([data[8], data[9], data[10], data[11]]) {
+            return vec![];
+        }
+        let payload = &data[HEADER_LEN + 4..];
+        match u32::from_le_bytes([data[16], data[17], data[18], data[19]]) {
+            MESSAGE_VERSION => {
+                vec![self.packet(MESSAGE_VERSION, &DSU_PROTOCOL_VERSION.to_le_bytes())]
+            }
+            MESSAGE_PORTS if payload.len() >= 4 => {
+                let count = i32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                payload[4..]
+                    .iter()
+                    .take(count.clamp(0, SLOT_COUNT as i32) as usize)
+                    .filter(|slot| **slot < SLOT_COUNT)
+                    .map(|slot| {
+                        let mut info =
+                            Self::slot_header(*slot, gamepads.get(*slot as usize).copied());
+                        info.push(0);
+                        self.packet(MESSAGE_PORTS, &info)
+                    })
+                    .collect()
+            }
+            MESSAGE_DATA if payload.len() >= 8 => {
+                let flags = payload[0];
+                let subscription = if flags & 0x01 != 0 {
+                    Subscription::Slot(payload[1])
+                } else if flags & 0x02 != 0 {
+                    match (0..SLOT_COUNT).find(|slot| payload[2..8] == Self::mac(*slot)) {
+                        Some(slot) => Subscription::Slot(slot),
+                        None => return vec![],
+                    }
+                } else {
+                    Subscription::All
+                };
+                let client = self.clients.entry(from).or_insert(Client {
+                    subscriptions: vec![],
+                    last_request: now,
+                });
+                client.last_request = now;
+                if !client.subscriptions.contains(&subscription) {
+                    client.subscriptions.push(subscription);
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn controller_data(&mut self, slot: u8, gamepad: &Gamepad, now: Instant) -> Vec<u8> {
+        let button = |button: GamepadButton| gamepad.buttons.get(&button).copied().unwrap_or(false);
+        let axis = |axis: GamepadAxis, default: u8| {
+            gamepad
+                .axes
+                .get(&axis)
+                .map_or(default, |axis| axis.convert_into::<u8, _>(false))
+        };
+        let bits = |buttons: [bool; 8]| {
+            buttons
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (bit, pressed)| byte | ((*pressed as u8) << bit))
+        };
+        let analog = |pressed: bool| if pressed { 0xff } else { 0x00 };
+        let left_trigger = axis(GamepadAxis::LeftTrigger, 0);
+        let right_trigger = axis(GamepadAxis::RightTrigger, 0);
+
+        let number = &mut self.packet_numbers[slot as usize];
+        *number = number.wrapping_add(1);
+        let mut data = Self::slot_header(slot, Some(gamepad));
+        data.push(1);
+        data.extend(number.to_le_bytes());
+        data.push(bits([
+            button(GamepadButton::Select),
+            button(GamepadButton::LeftThumb),
+            button(GamepadButton::RightThumb),
+            button(GamepadButton::Start),
+            button(GamepadButton::DPadUp),
+            button(GamepadButton::DPadRight),
+            button(GamepadButton::DPadDown),
+            button(GamepadButton::DPadLeft),
+        ]));
+        // Lowest bit first: L2, R2, L1, R1, Triangle, Circle, Cross, Square
+        data.push(bits([
+            left_trigger > 0,
+            right_trigger > 0,
+            button(GamepadButton::LeftShoulderButton),
+            button(GamepadButton::RightShoulderButton),
+            button(GamepadButton::North),
+            button(GamepadButton::East),
+            button(GamepadButton::South),
+            button(GamepadButton::West),
+        ]));
+        data.push(button(GamepadButton::Mode) as u8);
+        data.push(button(GamepadButton::Share) as u8);
+        // Sticks with up as 255
+        data.extend([
+            axis(GamepadAxis::LeftJoystickX, 128),
+            axis(GamepadAxis::LeftJoystickY, 128),
+            axis(GamepadAxis::RightJoystickX, 128),
+            axis(GamepadAxis::RightJoystickY, 128),
+        ]);
+        data.extend([
+            analog(button(GamepadButton::DPadLeft)),
+            analog(button(GamepadButton::DPadDown)),
+            analog(button(GamepadButton::DPadRight)),
+            analog(button(GamepadButton::DPadUp)),
+            analog(button(GamepadButton::West)),
+            analog(button(GamepadButton::South)),
+            analog(button(GamepadButton::East)),
+            analog(button(GamepadButton::North)),
+            analog(button(GamepadButton::RightShoulderButton)),
+            analog(button(GamepadButton::LeftShoulderButton)),
+            right_trigger,
+            left_trigger,
+        ]);
+        for finger in 0..2 {
+            match gamepad.touches.get(finger) {
+                Some(touch) => {
+                    let x = (touch.x.clamp(0.0, 1.0) * (TOUCHPAD_WIDTH - 1.0)) as u16;
+                    let y = (touch.y.clamp(0.0, 1.0) * (TOUCHPAD_HEIGHT - 1.0)) as u16;
+                    data.extend([1, touch.id]);
+                    data.extend(x.to_le_bytes());
+                    data.extend(y.to_le_bytes());
+                }
+                None => data.extend([0; 6]),
+            }
+        }
+        let timestamp = now.duration_since(self.start).as_micros() as u64;
+        data.extend(timestamp.to_le_bytes());
+        let motion = gamepad.motion.unwrap_or_default();
+        // Accelerometer x, y, z, then pitch, yaw and roll, all on the DS4 axes.
+        for value in [
+            motion.accel.0,
+            motion.accel.1,
+            motion.accel.2,
+            motion.gyro.0,
+            motion.gyro.1,
+            motion.gyro.2,
+        ] {
+            data.extend((value as f32).to_le_bytes());
+        }
+        self.packet(MESSAGE_DATA, &data)
+    }
+
+    // Clients wanting data for the slot, forgetting the ones that went quiet.
+    pub fn subscribers(&mut self, slot: u8, now: Instant) -> Vec<SocketAddr> {
+        self.clients
+            .retain(|_, client| now.duration_since(client.last_request) < SUBSCRIPTION_TIMEOUT);
+        self.clients
+            .iter()
+            .filter(|(_, client)| {
+                client
+                    .subscriptions
+                    .iter()
+                    .any(|subscription| match subscription {
+                        Subscription::All => true,
+                        Subscription::Slot(subscribed) => *subscribed == slot,
+                    })
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    // Answers pending requests and sends the pads' current state to subscribers.
+    pub fn poll(&mut self, gamepads: &[&Gamepad]) {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut buffer = [0u8; 128];
+        while let Ok((length, from)) = socket.recv_from(&mut buffer) {
+            for reply in self.handle_request(&buffer[..length], from, gamepads, now) {
+                let _ = socket.send_to(&reply, from);
+            }
+        }
+        for (slot, gamepad) in gamepads.iter().enumerate().take(SLOT_COUNT as usize) {
+            let subscribers = self.subscribers(slot as u8, now);
+            if subscribers.is_empty() {
+                continue;
+            }
+            if gamepad.motion.is_none() && !self.warned_no_motion[slot] {
+                self.warned_no_motion[slot] = true;
+                log!(
+                    "DSU slot {} has no motion data, clients only get buttons and sticks",
+                    slot + 1
+                );
+            }
+            let data = self.controller_data(slot as u8, gamepad, now);
+            for addr in subscribers {
+                let _ = socket.send_to(&data, addr);
+            }
+        }
+        self.socket = Some(socket);
+    }
+}
+
+// Publishes an input over DSU as it's read, whatever output it drives.
+pub struct DsuPublisher<'a, I: ControllerInput> {
+    input: &'a mut I,
+    server: DsuServer,
+}
+
+impl<'a, I: ControllerInput> DsuPublisher<'a, I> {
+    pub fn new(input: &'a mut I, server: DsuServer) -> DsuPublisher<'a, I> {
+        DsuPublisher { input, server }
+    }
+}
+
+impl<I: ControllerInput> ControllerInput for DsuPublisher<'_, I> {
+    type ControllerType = I::ControllerType;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        self.input.to_gamepad()
+    }
+
+    fn prep_for_input_events(&mut self) {
+        self.input.prep_for_input_events();
+    }
+
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        let res = self.input.get_next_inputs().await;
+        let gamepad = self.input.to_gamepad();
+        self.server.poll(&[gamepad]);
+        res
+    }
+
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        self.input.apply_feedback(feedback);
+    }
+
+    fn name(&self) -> String {
+        self.input.name()
+    }
+}
+
+#[cfg(test)]
+fn client_packet(message_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut packet = DSU_CLIENT_MAGIC.to_vec();
+    packet.extend(DSU_PROTOCOL_VERSION.to_le_bytes());
+    packet.extend(((payload.len() + 4) as u16).to_le_bytes());
+    packet.extend([0; 4]);
+    packet.extend(0x1234u32.to_le_bytes());
+    packet.extend(message_type.to_le_bytes());
+    packet.extend_from_slice(payload);
+    let crc = crc32(&packet);
+    packet[8..12].copy_from_slice(&crc.to_le_bytes());
+    packet
+}
+
+#[test]
+fn test_dsu_requests() {
+    use crate::controller_abs::MotionState;
+
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::DPadLeft, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = u64::MAX;
+    gamepad.power = Some(PowerState::Discharging(50));
+    gamepad.motion = Some(MotionState {
+        accel: (0.0, 1.0, 0.0),
+        gyro: (10.0, 0.0, -5.0),
+    });
+
+    let now = Instant::now();
+    let mut server = DsuServer::new();
+    let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+    let version = server.handle_request(&client_packet(MESSAGE_VERSION, &[]), client, &[], now);
+    assert_eq!(version.len(), 1);
+    assert_eq!(
+        version[0][..8],
+        [b'D', b'S', b'U', b'S', 0xe9, 0x03, 0x06, 0x00]
+    );
+    assert_eq!(version[0][20..], [0xe9, 0x03]);
+    let mut unsigned = version[0].clone();
+    unsigned[8..12].copy_from_slice(&[0; 4]);
+    assert_eq!(crc32(&unsigned).to_le_bytes(), version[0][8..12]);
+
+    // A bad checksum gets no answer.
+    let mut corrupt = client_packet(MESSAGE_VERSION, &[]);
+    corrupt[8] ^= 0xff;
+    assert!(server.handle_request(&corrupt, client, &[], now).is_empty());
+
+    let ports = server.handle_request(
+        &client_packet(MESSAGE_PORTS, &[2, 0, 0, 0, 0, 1]),
+        client,
+        &[&gamepad],
+        now,
+    );
+    assert_eq!(ports.len(), 2);
+    assert_eq!(ports[0][20..24], [0, 2, 2, 0]);
+    assert_eq!(ports[0][30], 0x03);
+    assert_eq!(ports[1][20..22], [1, 0]);
+    let still = Gamepad::new();
+    let ports = server.handle_request(
+        &client_packet(MESSAGE_PORTS, &[1, 0, 0, 0, 0]),
+        client,
+        &[&still],
+        now,
+    );
+    assert_eq!(ports[0][20..24], [0, 2, 1, 0]);
+
+    assert!(server.subscribers(0, now).is_empty());
+    let subscribe = client_packet(MESSAGE_DATA, &[1, 0, 0, 0, 0, 0, 0, 0]);
+    server.handle_request(&subscribe, client, &[&gamepad], now);
+    assert_eq!(server.subscribers(0, now), vec![client]);
+    assert!(server.subscribers(1, now).is_empty());
+
+    let data = server.controller_data(0, &gamepad, now);
+    assert_eq!(data.len(), 100);
+    let payload = &data[20..];
+    assert_eq!(payload[11], 1);
+    assert_eq!(payload[12..16], [1, 0, 0, 0]);
+    // D-pad left, then Cross (South) and R2
+    assert_eq!(payload[16], 0x80);
+    assert_eq!(payload[17], 0x42);
+    assert_eq!(payload[21], 0xff);
+    assert_eq!(payload[24..28], [0xff, 0, 0, 0]);
+    assert_eq!(payload[28..32], [0, 0xff, 0, 0]);
+    assert_eq!(payload[34..36], [0xff, 0x00]);
+    assert_eq!(payload[56..60], 0.0f32.to_le_bytes());
+    assert_eq!(payload[60..64], 1.0f32.to_le_bytes());
+    assert_eq!(payload[68..72], 10.0f32.to_le_bytes());
+
+    assert!(server.subscribers(0, now + SUBSCRIPTION_TIMEOUT).is_empty());
+}
+
+#[test]
+fn test_dsu_loopback() {
+    let mut server = DsuServer::bind(&DsuConfig {
+        listen: "127.0.0.1:0".to_string(),
+    })
+    .unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    client.connect(server.local_addr().unwrap()).unwrap();
+    let gamepad = Gamepad::new();
+
+    client
+        .send(&client_packet(MESSAGE_DATA, &[0, 0, 0, 0, 0, 0, 0, 0]))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    server.poll(&[&gamepad]);
+    let mut buffer = [0u8; 128];
+    let length = client.recv(&mut buffer).unwrap();
+    assert_eq!(length, 100);
+    assert_eq!(buffer[16..20], MESSAGE_DATA.to_le_bytes());
+}
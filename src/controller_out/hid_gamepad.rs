@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+
+use crate::controller_abs::{
+    BitPackedButton, BitPackedButtons, Gamepad, GamepadAxis, GamepadButton,
+};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, hid_descriptor,
+    interface_descriptor, GadgetDescriptors, GadgetFunction, USB_ENDPOINT_XFER_INT,
+};
+
+pub const HID_GAMEPAD_IN_ENDPOINT: u8 = 0x81;
+// Everything has to fit a single full speed interrupt packet.
+pub const HID_GAMEPAD_MAX_REPORT_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HidAxis {
+    pub axis: GamepadAxis,
+    // 1 to 16 bits, reported unsigned from 0.
+    pub bits: u8,
+}
+
+// Layout of the `hid` output, reports are packed in the order listed here.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HidGamepadConfig {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub name: String,
+    // HID button 1 onwards.
+    pub buttons: Vec<GamepadButton>,
+    // Dpad as a hat switch after the buttons, leave the dpad out of `buttons` then.
+    pub dpad_hat: bool,
+    pub axes: Vec<HidAxis>,
+}
+
+impl Default for HidGamepadConfig {
+    fn default() -> Self {
+        let axis = |axis, bits| HidAxis { axis, bits };
+        HidGamepadConfig {
+            // pid.codes test ids, set your own when shipping this.
+            vendor_id: 0x1209,
+            product_id: 0x0001,
+            name: "cursed_controls gamepad".to_string(),
+            buttons: vec![
+                GamepadButton::South,
+                GamepadButton::East,
+                GamepadButton::West,
+                GamepadButton::North,
+                GamepadButton::LeftShoulderButton,
+                GamepadButton::RightShoulderButton,
+                GamepadButton::Select,
+                GamepadButton::Start,
+                GamepadButton::Mode,
+                GamepadButton::LeftThumb,
+                GamepadButton::RightThumb,
+            ],
+            dpad_hat: true,
+            axes: vec![
+                axis(GamepadAxis::LeftJoystickX, 16),
+                axis(GamepadAxis::LeftJoystickY, 16),
+                axis(GamepadAxis::RightJoystickX, 16),
+                axis(GamepadAxis::RightJoystickY, 16),
+                axis(GamepadAxis::LeftTrigger, 8),
+                axis(GamepadAxis::RightTrigger, 8),
+            ],
+        }
+    }
+}
+
+// Generic desktop usage for each axis, the way Linux and SDL expect a pad.
+fn axis_usage(axis: &GamepadAxis) -> u8 {
+    match axis {
+        GamepadAxis::LeftJoystickX => 0x30,
+        GamepadAxis::LeftJoystickY => 0x31,
+        GamepadAxis::LeftTrigger => 0x32,
+        GamepadAxis::RightJoystickX => 0x33,
+        GamepadAxis::RightJoystickY => 0x34,
+        GamepadAxis::RightTrigger => 0x35,
+    }
+}
+
+// Short item with the smallest data size holding `value`.
+fn hid_item(prefix: u8, value: u32) -> Vec<u8> {
+    match value {
+        0..=0xff => vec![prefix | 1, value as u8],
+        0x100..=0xffff => [vec![prefix | 2], (value as u16).to_le_bytes().to_vec()].concat(),
+        _ => [vec![prefix | 3], value.to_le_bytes().to_vec()].concat(),
+    }
+}
+
+// Logical and physical extents are signed, so 255 takes two bytes and 65535 four.
+fn hid_signed_item(prefix: u8, value: i32) -> Vec<u8> {
+    if let Ok(value) = i8::try_from(value) {
+        vec![prefix | 1, value as u8]
+    } else if let Ok(value) = i16::try_from(value) {
+        [vec![prefix | 2], value.to_le_bytes().to_vec()].concat()
+    } else {
+        [vec![prefix | 3], value.to_le_bytes().to_vec()].concat()
+    }
+}
+
+const USAGE_PAGE: u8 = 0x04;
+const USAGE: u8 = 0x08;
+const USAGE_MINIMUM: u8 = 0x18;
+const USAGE_MAXIMUM: u8 = 0x28;
+const LOGICAL_MINIMUM: u8 = 0x14;
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const PHYSICAL_MINIMUM: u8 = 0x34;
+const PHYSICAL_MAXIMUM: u8 = 0x44;
+const UNIT: u8 = 0x64;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_COUNT: u8 = 0x94;
+const INPUT: u8 = 0x80;
+const COLLECTION: u8 = 0xa0;
+const END_COLLECTION: u8 = 0xc0;
+
+const INPUT_CONSTANT: u32 = 0x03;
+const INPUT_VARIABLE: u32 = 0x02;
+const INPUT_NULL_STATE: u32 = 0x42;
+
+// Writes `bits` bits of `value` at `offset` bits into `report`, little endian like HID.
+pub fn pack_bits(report: &mut [u8], offset: usize, bits: u8, value: u32) {
+    for bit in 0..bits as usize {
+        if value & (1 << bit) != 0 {
+            let position = offset + bit;
+            report[position / 8] |= 1 << (position % 8);
+        }
+    }
+}
+
+impl HidGamepadConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(axis) = self
+            .axes
+            .iter()
+            .find(|axis| axis.bits == 0 || axis.bits > 16)
+        {
+            return Err(format!(
+                "HID axis {:?} needs 1 to 16 bits, not {}",
+                axis.axis, axis.bits
+            ));
+        }
+        if self.report_len() > HID_GAMEPAD_MAX_REPORT_LEN {
+            return Err(format!(
+                "HID report of {} bytes is too long, at most {} fit",
+                self.report_len(),
+                HID_GAMEPAD_MAX_REPORT_LEN
+            ));
+        }
+        Ok(())
+    }
+
+    fn button_bits(&self) -> usize {
+        self.buttons.len().div_ceil(8) * 8
+    }
+
+    fn hat_bits(&self) -> usize {
+        if self.dpad_hat {
+            8
+        } else {
+            0
+        }
+    }
+
+    fn axis_bits(&self) -> usize {
+        self.axes.iter().map(|axis| axis.bits as usize).sum()
+    }
+
+    pub fn report_len(&self) -> usize {
+        (self.button_bits() + self.hat_bits() + self.axis_bits()).div_ceil(8)
+    }
+
+    pub fn report_descriptor(&self) -> Vec<u8> {
+        let mut descriptor = vec![];
+        let mut item = |prefix: u8, value: u32| {
+            let bytes = match prefix {
+                LOGICAL_MINIMUM | LOGICAL_MAXIMUM | PHYSICAL_MINIMUM | PHYSICAL_MAXIMUM => {
+                    hid_signed_item(prefix, value as i32)
+                }
+                _ => hid_item(prefix, value),
+            };
+            descriptor.extend(bytes);
+        };
+        item(USAGE_PAGE, 0x01); // Generic Desktop
+        item(USAGE, 0x05); // Game Pad
+        item(COLLECTION, 0x01); // Application
+
+        if !self.buttons.is_empty() {
+            let count = self.buttons.len() as u32;
+            item(USAGE_PAGE, 0x09); // Button
+            item(USAGE_MINIMUM, 1);
+            item(USAGE_MAXIMUM, count);
+            item(LOGICAL_MINIMUM, 0);
+            item(LOGICAL_MAXIMUM, 1);
+            item(REPORT_SIZE, 1);
+            item(REPORT_COUNT, count);
+            item(INPUT, INPUT_VARIABLE);
+            let padding = self.button_bits() as u32 - count;
+            if padding > 0 {
+                item(REPORT_COUNT, padding);
+                item(INPUT, INPUT_CONSTANT);
+            }
+        }
+
+        if self.dpad_hat {
+            item(USAGE_PAGE, 0x01);
+            item(USAGE, 0x39); // Hat switch
+            item(LOGICAL_MINIMUM, 0);
+            item(LOGICAL_MAXIMUM, 7);
+            item(PHYSICAL_MINIMUM, 0);
+            item(PHYSICAL_MAXIMUM, 315);
+            item(UNIT, 0x14); // Degrees
+            item(REPORT_SIZE, 4);
+            item(REPORT_COUNT, 1);
+            item(INPUT, INPUT_NULL_STATE);
+            item(UNIT, 0);
+            item(REPORT_COUNT, 1);
+            item(INPUT, INPUT_CONSTANT);
+        }
+
+        if !self.axes.is_empty() {
+            item(USAGE_PAGE, 0x01);
+            for axis in self.axes.iter() {
+                item(USAGE, axis_usage(&axis.axis) as u32);
+                item(LOGICAL_MINIMUM, 0);
+                item(LOGICAL_MAXIMUM, (1u32 << axis.bits) - 1);
+                item(REPORT_SIZE, axis.bits as u32);
+                item(REPORT_COUNT, 1);
+                item(INPUT, INPUT_VARIABLE);
+            }
+            let padding =
+                self.report_len() * 8 - self.button_bits() - self.hat_bits() - self.axis_bits();
+            if padding > 0 {
+                item(REPORT_SIZE, 1);
+                item(REPORT_COUNT, padding as u32);
+                item(INPUT, INPUT_CONSTANT);
+            }
+        }
+
+        descriptor.push(END_COLLECTION);
+        descriptor
+    }
+
+    pub fn descriptors(&self) -> GadgetDescriptors {
+        let report_descriptor = self.report_descriptor();
+        GadgetDescriptors {
+            device: device_descriptor(self.vendor_id, self.product_id, 0x0100, (0, 0, 0), 64),
+            configuration: configuration_descriptor(&[[
+                interface_descriptor(0, 1, (0x03, 0, 0)),
+                hid_descriptor(report_descriptor.len()),
+                endpoint_descriptor(
+                    HID_GAMEPAD_IN_ENDPOINT,
+                    USB_ENDPOINT_XFER_INT,
+                    HID_GAMEPAD_MAX_REPORT_LEN as u16,
+                    1,
+                ),
+            ]
+            .concat()]),
+            strings: vec![
+                "cursed_controls".to_string(),
+                self.name.clone(),
+                String::new(),
+            ],
+            hid_reports: vec![report_descriptor],
+        }
+    }
+
+    // 0 is north going clockwise, 8 (outside the logical range) is released.
+    fn hat(gamepad: &Gamepad) -> u32 {
+        let pressed = |button| gamepad.buttons.get(&button).copied().unwrap_or(false);
+        match (
+            pressed(GamepadButton::DPadUp),
+            pressed(GamepadButton::DPadRight),
+            pressed(GamepadButton::DPadDown),
+            pressed(GamepadButton::DPadLeft),
+        ) {
+            (true, false, _, true) => 7,
+            (true, true, _, false) => 1,
+            (true, _, _, _) => 0,
+            (false, true, true, _) => 3,
+            (false, true, false, _) => 2,
+            (false, false, true, true) => 5,
+            (false, false, true, false) => 4,
+            (false, false, false, true) => 6,
+            (false, false, false, false) => 8,
+        }
+    }
+
+    pub fn pack_report(&self, gamepad: &Gamepad) -> Vec<u8> {
+        let mut report = BitPackedButtons {
+            buttons: self
+                .buttons
+                .iter()
+                .enumerate()
+                .map(|(i, button)| {
+                    let mut packed = BitPackedButton::new(i as u8);
+                    packed.value = gamepad.buttons.get(button).copied().unwrap_or(false);
+                    packed
+                })
+                .collect(),
+        }
+        .to_multi_byte_repr(self.report_len());
+
+        let mut offset = self.button_bits();
+        if self.dpad_hat {
+            pack_bits(&mut report, offset, 4, Self::hat(gamepad));
+            offset += self.hat_bits();
+        }
+        for axis in self.axes.iter() {
+            let value = match gamepad.axes.get(&axis.axis) {
+                // HID has y pointing down
+                Some(value)
+                    if matches!(
+                        axis.axis,
+                        GamepadAxis::LeftJoystickY | GamepadAxis::RightJoystickY
+                    ) =>
+                {
+                    value.invert().convert_into::<u16, _>(false)
+                }
+                Some(value) => value.convert_into::<u16, _>(false),
+                None => 0,
+            };
+            pack_bits(
+                &mut report,
+                offset,
+                axis.bits,
+                (value >> (16 - axis.bits)) as u32,
+            );
+            offset += axis.bits as usize;
+        }
+        report
+    }
+}
+
+// No feature or output reports, ep0 only sees the usual HID requests.
+pub struct HidGamepadFunction;
+
+impl GadgetFunction for HidGamepadFunction {}
+
+#[test]
+fn test_hid_gamepad_descriptor() {
+    let config = HidGamepadConfig::default();
+    assert!(config.validate().is_ok());
+    // 11 buttons padded to 16 bits, hat and padding, 4x16 + 2x8 bits of axes
+    assert_eq!(config.report_len(), 2 + 1 + 8 + 2);
+
+    let descriptor = config.report_descriptor();
+    assert_eq!(descriptor[..6], [0x05, 0x01, 0x09, 0x05, 0xa1, 0x01]);
+    assert_eq!(*descriptor.last().unwrap(), 0xc0);
+    assert!(descriptor.windows(3).any(|item| item == [0x26, 0xff, 0x00]));
+    // 16 bit axes need a 4 byte logical maximum to stay positive
+    assert!(descriptor
+        .windows(5)
+        .any(|item| item == [0x27, 0xff, 0xff, 0x00, 0x00]));
+    assert!(descriptor.windows(2).any(|item| item == [0x29, 11]));
+
+    let odd = HidGamepadConfig {
+        buttons: vec![GamepadButton::South],
+        dpad_hat: false,
+        axes: vec![HidAxis {
+            axis: GamepadAxis::LeftJoystickX,
+            bits: 10,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(odd.report_len(), 3);
+    // 6 bits of padding after the axis
+    assert!(odd
+        .report_descriptor()
+        .ends_with(&[0x75, 0x01, 0x95, 0x06, 0x81, 0x03, 0xc0]));
+
+    let too_deep = HidGamepadConfig {
+        axes: vec![HidAxis {
+            axis: GamepadAxis::LeftTrigger,
+            bits: 24,
+        }],
+        ..Default::default()
+    };
+    assert!(too_deep.validate().is_err());
+}
+
+#[test]
+fn test_hid_gamepad_report() {
+    let buttons = BitPackedButtons {
+        buttons: (0..12)
+            .map(|addr| {
+                let mut button = BitPackedButton::new(addr);
+                button.value = addr % 5 == 0;
+                button
+            })
+            .collect(),
+    };
+    assert_eq!(buttons.to_multi_byte_repr(2), vec![0x21, 0x04]);
+
+    let config = HidGamepadConfig::default();
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::RightThumb, true);
+    gamepad.set_button(GamepadButton::DPadDown, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::RightJoystickX).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightJoystickY).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+
+    let report = config.pack_report(&gamepad);
+    assert_eq!(
+        report,
+        vec![0x01, 0x04, 0x04, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0xff]
+    );
+
+    let odd = HidGamepadConfig {
+        buttons: vec![GamepadButton::South],
+        dpad_hat: false,
+        axes: vec![HidAxis {
+            axis: GamepadAxis::LeftJoystickX,
+            bits: 10,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(odd.pack_report(&gamepad), vec![0x01, 0xff, 0x03]);
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::controller_abs::{
+    keyboard::{KeyCode, MouseButton},
+    speed_curve, Gamepad, GamepadAxis, GamepadButton, OutputMapping,
+};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, hid_descriptor,
+    interface_descriptor, GadgetDescriptors, GadgetFunction, USB_ENDPOINT_XFER_INT,
+};
+
+pub const KEYBOARD_IN_ENDPOINT: u8 = 0x81;
+pub const MOUSE_IN_ENDPOINT: u8 = 0x82;
+
+// Boot protocol keyboard, 5 LEDs as the output report.
+pub const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0xe0, //   Usage Minimum (Left Control)
+    0x29, 0xe7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data,Var,Abs), modifiers
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x03, //   Input (Const), reserved
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x03, //   Output (Const)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, // Logical Maximum (255)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x2a, 0xff, 0x00, // Usage Maximum (255)
+    0x81, 0x00, //   Input (Data,Array), pressed keys
+    0xc0, // End Collection
+];
+
+// Boot protocol mouse with a wheel byte on the end, boot hosts just ignore it.
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x05, //     Usage Maximum (5)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x05, //     Report Count (5)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data,Var,Abs)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x03, //     Report Size (3)
+    0x81, 0x03, //     Input (Const)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data,Var,Rel)
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+pub fn keyboard_mouse_descriptors() -> GadgetDescriptors {
+    GadgetDescriptors {
+        // pid.codes test ids
+        device: device_descriptor(0x1209, 0x0002, 0x0100, (0, 0, 0), 64),
+        configuration: configuration_descriptor(&[
+            [
+                // Boot interface subclass, keyboard protocol
+                interface_descriptor(0, 1, (0x03, 0x01, 0x01)),
+                hid_descriptor(KEYBOARD_REPORT_DESCRIPTOR.len()),
+                endpoint_descriptor(KEYBOARD_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 8, 1),
+            ]
+            .concat(),
+            [
+                // Boot interface subclass, mouse protocol
+                interface_descriptor(1, 1, (0x03, 0x01, 0x02)),
+                hid_descriptor(MOUSE_REPORT_DESCRIPTOR.len()),
+                endpoint_descriptor(MOUSE_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 4, 1),
+            ]
+            .concat(),
+        ]),
+        strings: vec![
+            "cursed_controls".to_string(),
+            "cursed_controls keyboard and mouse".to_string(),
+            String::new(),
+        ],
+        hid_reports: vec![
+            KEYBOARD_REPORT_DESCRIPTOR.to_vec(),
+            MOUSE_REPORT_DESCRIPTOR.to_vec(),
+        ],
+    }
+}
+
+// Reports look the same in boot and report protocol, so the default HID replies do.
+pub struct KeyboardMouseFunction;
+
+impl GadgetFunction for KeyboardMouseFunction {}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+impl Stick {
+    fn axes(&self) -> (GamepadAxis, GamepadAxis) {
+        match self {
+            Stick::Left => (GamepadAxis::LeftJoystickX, GamepadAxis::LeftJoystickY),
+            Stick::Right => (GamepadAxis::RightJoystickX, GamepadAxis::RightJoystickY),
+        }
+    }
+}
+
+// Keys for the four stick directions, e.g. WASD.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StickKeys {
+    pub stick: Stick,
+    pub up: KeyCode,
+    pub down: KeyCode,
+    pub left: KeyCode,
+    pub right: KeyCode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct KeyboardMouseConfig {
+    // Only `Key` and `MouseButton` targets do anything here.
+    pub buttons: HashMap<GamepadButton, OutputMapping>,
+    // Pressed once the axis is past half way.
+    pub axes: HashMap<GamepadAxis, OutputMapping>,
+    pub stick_keys: Option<StickKeys>,
+    pub mouse_stick: Option<Stick>,
+    // Pixels per second at full tilt.
+    pub mouse_speed: f64,
+    // 1 is linear, higher values give finer control near the center.
+    pub mouse_curve: f64,
+    // Fraction of the stick's travel that doesn't move the mouse.
+    pub mouse_deadzone: f64,
+    pub scroll_axis: Option<GamepadAxis>,
+    // Wheel detents per second at full tilt.
+    pub scroll_speed: f64,
+}
+
+impl Default for KeyboardMouseConfig {
+    fn default() -> Self {
+        let key = |name| OutputMapping::Key(KeyCode::from_name(name).unwrap());
+        KeyboardMouseConfig {
+            buttons: HashMap::from([
+                (GamepadButton::South, key("Space")),
+                (GamepadButton::East, key("LeftCtrl")),
+                (GamepadButton::West, key("R")),
+                (GamepadButton::North, key("E")),
+                (GamepadButton::LeftShoulderButton, key("Q")),
+                (GamepadButton::RightShoulderButton, key("F")),
+                (GamepadButton::LeftThumb, key("LeftShift")),
+                (
+                    GamepadButton::RightThumb,
+                    OutputMapping::MouseButton(MouseButton::Middle),
+                ),
+                (GamepadButton::Start, key("Escape")),
+                (GamepadButton::Select, key("Tab")),
+                (GamepadButton::DPadUp, key("1")),
+                (GamepadButton::DPadRight, key("2")),
+                (GamepadButton::DPadDown, key("3")),
+                (GamepadButton::DPadLeft, key("4")),
+            ]),
+            axes: HashMap::from([
+                (
+                    GamepadAxis::RightTrigger,
+                    OutputMapping::MouseButton(MouseButton::Left),
+                ),
+                (
+                    GamepadAxis::LeftTrigger,
+                    OutputMapping::MouseButton(MouseButton::Right),
+                ),
+            ]),
+            stick_keys: Some(StickKeys {
+                stick: Stick::Left,
+                up: KeyCode::from_name("W").unwrap(),
+                down: KeyCode::from_name("S").unwrap(),
+                left: KeyCode::from_name("A").unwrap(),
+                right: KeyCode::from_name("D").unwrap(),
+            }),
+            mouse_stick: Some(Stick::Right),
+            mouse_speed: 1200.0,
+            mouse_curve: 2.0,
+            mouse_deadzone: 0.15,
+            scroll_axis: None,
+            scroll_speed: 15.0,
+        }
+    }
+}
+
+// Axis position in [-1, 1], triggers in [0, 1].
+fn axis_position(gamepad: &Gamepad, axis: &GamepadAxis) -> f64 {
+    let value = match gamepad.axes.get(axis) {
+        Some(value) => value,
+        None => return 0.0,
+    };
+    match axis {
+        GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => {
+            value.convert_into::<u8, _>(false) as f64 / u8::MAX as f64
+        }
+        _ => value.convert_into::<i16, _>(false) as f64 / i16::MAX as f64,
+    }
+}
+
+pub struct KeyboardMouseState {
+    pub config: KeyboardMouseConfig,
+    // Sub pixel movement carried over to the next report.
+    mouse_remainder: (f64, f64),
+    scroll_remainder: f64,
+}
+
+impl KeyboardMouseState {
+    pub fn new(config: KeyboardMouseConfig) -> KeyboardMouseState {
+        KeyboardMouseState {
+            config,
+            mouse_remainder: (0.0, 0.0),
+            scroll_remainder: 0.0,
+        }
+    }
+
+    // Everything pressed right now, from the config and from inputs mapping to keys directly.
+    fn pressed(&self, gamepad: &Gamepad) -> (Vec<KeyCode>, u8) {
+        let mut keys: Vec<KeyCode> = gamepad.keys.iter().copied().collect();
+        let mut mouse_buttons = gamepad
+            .mouse_buttons
+            .iter()
+            .fold(0, |acc, button| acc | button.bit());
+        let mut press = |target: &OutputMapping| match target {
+            OutputMapping::Key(key) => keys.push(*key),
+            OutputMapping::MouseButton(button) => mouse_buttons |= button.bit(),
+            _ => {}
+        };
+
+        for (button, target) in self.config.buttons.iter() {
+            if gamepad.buttons.get(button).copied().unwrap_or(false) {
+                press(target);
+            }
+        }
+        for (axis, target) in self.config.axes.iter() {
+            if axis_position(gamepad, axis) > 0.5 {
+                press(target);
+            }
+        }
+        if let Some(stick_keys) = &self.config.stick_keys {
+            let (x_axis, y_axis) = stick_keys.stick.axes();
+            let (x, y) = (
+                axis_position(gamepad, &x_axis),
+                axis_position(gamepad, &y_axis),
+            );
+            for (held, key) in [
+                (y > 0.5, stick_keys.up),
+                (y < -0.5, stick_keys.down),
+                (x < -0.5, stick_keys.left),
+                (x > 0.5, stick_keys.right),
+            ] {
+                if held {
+                    press(&OutputMapping::Key(key));
+                }
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        (keys, mouse_buttons)
+    }
+
+    // Modifier byte, reserved byte and up to six keys, all ErrorRollOver when more are held.
+    pub fn keyboard_report(&self, gamepad: &Gamepad) -> [u8; 8] {
+        let mut report = [0u8; 8];
+        let (keys, _) = self.pressed(gamepad);
+        let (modifiers, keys): (Vec<KeyCode>, Vec<KeyCode>) = keys
+            .into_iter()
+            .partition(|key| key.modifier_bit().is_some());
+        report[0] = modifiers
+            .iter()
+            .filter_map(|key| key.modifier_bit())
+            .fold(0, |acc, bit| acc | bit);
+        if keys.len() > 6 {
+            report[2..].fill(0x01);
+        } else {
+            for (i, key) in keys.iter().enumerate() {
+                report[2 + i] = key.0;
+            }
+        }
+        report
+    }
+
+    // Buttons, x, y and wheel for `elapsed` worth of stick movement.
+    pub fn mouse_report(&mut self, gamepad: &Gamepad, elapsed: Duration) -> [u8; 4] {
+        let (_, buttons) = self.pressed(gamepad);
+        let seconds = elapsed.as_secs_f64();
+        let mut report = [buttons, 0, 0, 0];
+
+        // Whole steps go out, the rest waits for the next report. Past 127 it's dropped.
+        let step = |remainder: &mut f64, delta: f64| {
+            *remainder += delta;
+            let whole = remainder.trunc();
+            *remainder -= whole;
+            whole.clamp(-127.0, 127.0) as i8 as u8
+        };
+
+        if let Some(stick) = self.config.mouse_stick {
+            let (x_axis, y_axis) = stick.axes();
+            let curve = |axis| {
+                speed_curve(
+                    axis_position(gamepad, &axis),
+                    self.config.mouse_deadzone,
+                    self.config.mouse_curve,
+                ) * self.config.mouse_speed
+                    * seconds
+            };
+            // Mouse y points down
+            let (dx, dy) = (curve(x_axis), -curve(y_axis));
+            report[1] = step(&mut self.mouse_remainder.0, dx);
+            report[2] = step(&mut self.mouse_remainder.1, dy);
+        }
+        if let Some(axis) = &self.config.scroll_axis {
+            let speed = speed_curve(
+                axis_position(gamepad, axis),
+                self.config.mouse_deadzone,
+                1.0,
+            );
+            report[3] = step(
+                &mut self.scroll_remainder,
+                speed * self.config.scroll_speed * seconds,
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+fn centered_gamepad() -> Gamepad {
+    let mut gamepad = Gamepad::new();
+    for (axis, value) in gamepad.axes.iter_mut() {
+        value.value = match axis {
+            GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => 0,
+            _ => u64::MAX / 2,
+        };
+    }
+    gamepad
+}
+
+#[test]
+fn test_keyboard_report() {
+    let state = KeyboardMouseState::new(KeyboardMouseConfig::default());
+    let mut gamepad = centered_gamepad();
+    assert_eq!(state.keyboard_report(&gamepad), [0; 8]);
+
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::LeftThumb, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value = 0;
+    // Mapped straight to a key by an input
+    gamepad.set_digital_output(&OutputMapping::Key(KeyCode(0x3a)), true);
+    // Shift, then A, W, space and F1 in usage order
+    assert_eq!(
+        state.keyboard_report(&gamepad),
+        [0x02, 0, 0x04, 0x1a, 0x2c, 0x3a, 0, 0]
+    );
+
+    for button in [
+        GamepadButton::West,
+        GamepadButton::North,
+        GamepadButton::Select,
+    ] {
+        gamepad.set_button(button, true);
+    }
+    assert_eq!(state.keyboard_report(&gamepad)[2..], [0x01; 6]);
+}
+
+#[test]
+fn test_mouse_report() {
+    let mut state = KeyboardMouseState::new(KeyboardMouseConfig {
+        scroll_axis: Some(GamepadAxis::LeftJoystickY),
+        stick_keys: None,
+        ..Default::default()
+    });
+    let mut gamepad = centered_gamepad();
+    let frame = Duration::from_millis(10);
+    assert_eq!(state.mouse_report(&gamepad, frame), [0, 0, 0, 0]);
+
+    gamepad.get_axis_ref(GamepadAxis::RightJoystickX).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::RightJoystickY).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.set_digital_output(&OutputMapping::MouseButton(MouseButton::Back), true);
+    // Full tilt is 12 pixels per 10ms, up is negative y
+    assert_eq!(
+        state.mouse_report(&gamepad, frame),
+        [0x09, 12, (-12i8) as u8, 0]
+    );
+    // Clamped to what fits a report
+    assert_eq!(state.mouse_report(&gamepad, Duration::from_secs(1))[1], 127);
+
+    // Half tilt: (0.5 - 0.15) / 0.85 squared is about 17% speed
+    gamepad.get_axis_ref(GamepadAxis::RightJoystickX).value = u64::MAX / 4 * 3;
+    gamepad.get_axis_ref(GamepadAxis::RightJoystickY).value = u64::MAX / 2;
+    let moved: i32 = (0..10)
+        .map(|_| state.mouse_report(&gamepad, frame)[1] as i8 as i32)
+        .sum();
+    assert_eq!(moved, 20);
+
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = u64::MAX;
+    let scrolled: i32 = (0..10)
+        .map(|_| state.mouse_report(&gamepad, frame)[3] as i8 as i32)
+        .sum();
+    assert_eq!(scrolled, 1);
+
+    assert_eq!(speed_curve(-1.0, 0.15, 2.0), -1.0);
+    assert_eq!(speed_curve(0.1, 0.15, 2.0), 0.0);
+}
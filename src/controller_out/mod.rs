@@ -1 +1,11 @@
+pub mod ds4;
+pub mod dsu;
+pub mod hid_gamepad;
+pub mod keyboard_mouse;
+pub mod network;
+pub mod raw_gadget;
+pub mod switch_pro;
+pub mod usbip;
 pub mod x360;
+pub mod xbox_one;
+pub mod xid;
@@ -0,0 +1,114 @@
+use std::net::UdpSocket;
+use std::time::Instant;
+
+use crate::controller_abs::{Feedback, Gamepad};
+use crate::controller_in::network::{parse_feedback, NetworkPacket, NETWORK_KEEPALIVE};
+use crate::dashboard;
+
+// Streams the pad to another instance's `NetworkInput`, which emits it through its own output.
+pub struct NetworkSender {
+    socket: UdpSocket,
+    sequence: u32,
+    last_packet: Option<(NetworkPacket, Instant)>,
+}
+
+impl NetworkSender {
+    pub fn connect(target: &str) -> Result<NetworkSender, String> {
+        let bind = if target.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(bind)
+            .and_then(|socket| {
+                socket.connect(target)?;
+                // Only for feedback, which shouldn't hold up sending.
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })
+            .map_err(|err| format!("Could not send to {}: {}", target, err))?;
+        Ok(NetworkSender {
+            socket,
+            sequence: 0,
+            last_packet: None,
+        })
+    }
+
+    // Sends on changes and every `NETWORK_KEEPALIVE`, returns whether a packet went out.
+    pub fn send(&mut self, gamepad: &Gamepad, now: Instant) -> Result<bool, String> {
+        let packet = NetworkPacket::from_gamepad(gamepad, self.sequence);
+        if let Some((last, sent_at)) = self.last_packet {
+            let unchanged = NetworkPacket {
+                sequence: last.sequence,
+                ..packet
+            } == last;
+            if unchanged && now.duration_since(sent_at) < NETWORK_KEEPALIVE {
+                return Ok(false);
+            }
+        }
+        let data = packet.encode();
+        dashboard::sent("network", 0, &data);
+        self.socket
+            .send(&data)
+            .map_err(|err| format!("Could not send: {}", err))?;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.last_packet = Some((packet, now));
+        Ok(true)
+    }
+
+    // Rumble and light bar changes the receiver's output got from its host.
+    pub fn poll_feedback(&self) -> Vec<Feedback> {
+        let mut feedback = vec![];
+        let mut buffer = [0u8; 64];
+        // Errors include WouldBlock, and refused connections while the receiver is down.
+        while let Ok(length) = self.socket.recv(&mut buffer) {
+            feedback.extend(parse_feedback(&buffer[..length]));
+        }
+        feedback
+    }
+}
+
+#[tokio::test]
+async fn test_network_loopback() {
+    use crate::controller_abs::{ControllerInput, GamepadAxis, GamepadButton};
+    use crate::controller_in::network::{NetworkConfig, NetworkInput};
+    use std::time::Duration;
+
+    let mut input = NetworkInput::bind(&NetworkConfig {
+        listen: "127.0.0.1:0".to_string(),
+        send_to: None,
+        timeout_ms: 50,
+    })
+    .unwrap();
+    let target = input.local_addr().unwrap().to_string();
+    let mut sender = NetworkSender::connect(&target).unwrap();
+
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::North, true);
+    let start = Instant::now();
+    assert_eq!(sender.send(&gamepad, start), Ok(true));
+    // Unchanged and within the keepalive interval
+    assert_eq!(sender.send(&gamepad, start), Ok(false));
+    assert_eq!(input.get_next_inputs().await, Ok(true));
+    assert!(input.to_gamepad().buttons[&GamepadButton::North]);
+
+    // Rumble from the receiver's output finds its way back.
+    let rumble = Feedback::Rumble {
+        strong: 1.0,
+        weak: 0.0,
+    };
+    input.apply_feedback(&rumble);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(sender.poll_feedback(), vec![rumble]);
+
+    // A late packet from before doesn't undo anything.
+    let late = NetworkPacket::from_gamepad(&Gamepad::new(), 0);
+    sender.socket.send(&late.encode()).unwrap();
+    assert_eq!(input.get_next_inputs().await, Ok(false));
+    assert!(input.to_gamepad().buttons[&GamepadButton::North]);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(input.get_next_inputs().await, Ok(true));
+    assert!(!input.to_gamepad().buttons[&GamepadButton::North]);
+    assert_eq!(input.to_gamepad().axes[&GamepadAxis::RightTrigger].value, 0);
+}
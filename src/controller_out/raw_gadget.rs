@@ -0,0 +1,684 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use crate::dashboard;
+
+// Minimal raw-gadget (https://github.com/xairy/raw-gadget) driver in Rust, so outputs can bring
+// their own descriptors. The 360 output still goes through the 360-w-raw-gadget C library.
+
+const RAW_GADGET_PATH: &str = "/dev/raw-gadget";
+const UDC_CLASS_PATH: &str = "/sys/class/udc";
+
+const EP0_MAX_DATA: usize = 4096;
+const EP_MAX_DATA: usize = 1024;
+
+const USB_SPEED_FULL: u8 = 2;
+
+const USB_RAW_EVENT_CONNECT: u32 = 1;
+const USB_RAW_EVENT_CONTROL: u32 = 2;
+const USB_RAW_EVENT_RESET: u32 = 5;
+const USB_RAW_EVENT_DISCONNECT: u32 = 6;
+
+const fn ioc(dir: u64, nr: u64, size: u64) -> u64 {
+    (dir << 30) | (size << 16) | ((b'U' as u64) << 8) | nr
+}
+const IOC_NONE: u64 = 0;
+const IOC_WRITE: u64 = 1;
+const IOC_READ: u64 = 2;
+
+const USB_RAW_IOCTL_INIT: u64 = ioc(IOC_WRITE, 0, 257);
+const USB_RAW_IOCTL_RUN: u64 = ioc(IOC_NONE, 1, 0);
+const USB_RAW_IOCTL_EVENT_FETCH: u64 = ioc(IOC_READ, 2, 8);
+const USB_RAW_IOCTL_EP0_WRITE: u64 = ioc(IOC_WRITE, 3, 8);
+const USB_RAW_IOCTL_EP0_READ: u64 = ioc(IOC_READ | IOC_WRITE, 4, 8);
+const USB_RAW_IOCTL_EP_ENABLE: u64 = ioc(IOC_WRITE, 5, 9);
+const USB_RAW_IOCTL_EP_DISABLE: u64 = ioc(IOC_WRITE, 6, 4);
+const USB_RAW_IOCTL_EP_WRITE: u64 = ioc(IOC_WRITE, 7, 8);
+const USB_RAW_IOCTL_EP_READ: u64 = ioc(IOC_READ | IOC_WRITE, 8, 8);
+const USB_RAW_IOCTL_CONFIGURE: u64 = ioc(IOC_NONE, 9, 0);
+const USB_RAW_IOCTL_VBUS_DRAW: u64 = ioc(IOC_WRITE, 10, 4);
+const USB_RAW_IOCTL_EP0_STALL: u64 = ioc(IOC_NONE, 12, 0);
+
+// Descriptor types
+pub const USB_DT_DEVICE: u8 = 0x01;
+pub const USB_DT_CONFIG: u8 = 0x02;
+pub const USB_DT_STRING: u8 = 0x03;
+pub const USB_DT_INTERFACE: u8 = 0x04;
+pub const USB_DT_ENDPOINT: u8 = 0x05;
+pub const USB_DT_HID: u8 = 0x21;
+pub const USB_DT_HID_REPORT: u8 = 0x22;
+
+// Standard requests
+const USB_REQ_GET_STATUS: u8 = 0x00;
+const USB_REQ_CLEAR_FEATURE: u8 = 0x01;
+const USB_REQ_SET_FEATURE: u8 = 0x03;
+const USB_REQ_GET_DESCRIPTOR: u8 = 0x06;
+const USB_REQ_GET_CONFIGURATION: u8 = 0x08;
+pub const USB_REQ_SET_CONFIGURATION: u8 = 0x09;
+const USB_REQ_GET_INTERFACE: u8 = 0x0a;
+const USB_REQ_SET_INTERFACE: u8 = 0x0b;
+
+// HID class requests
+pub const HID_REQ_GET_REPORT: u8 = 0x01;
+pub const HID_REQ_SET_REPORT: u8 = 0x09;
+pub const HID_REQ_SET_IDLE: u8 = 0x0a;
+pub const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+
+pub const USB_TYPE_STANDARD: u8 = 0;
+pub const USB_TYPE_CLASS: u8 = 1;
+pub const USB_TYPE_VENDOR: u8 = 2;
+
+pub const USB_ENDPOINT_XFER_INT: u8 = 0x03;
+
+#[repr(C)]
+struct UsbRawInit {
+    driver_name: [u8; 128],
+    device_name: [u8; 128],
+    speed: u8,
+}
+
+#[repr(C)]
+struct UsbRawEvent {
+    kind: u32,
+    length: u32,
+    data: [u8; EP0_MAX_DATA],
+}
+
+#[repr(C)]
+struct UsbRawEpIo<const N: usize> {
+    ep: u16,
+    flags: u16,
+    length: u32,
+    data: [u8; N],
+}
+
+impl<const N: usize> UsbRawEpIo<N> {
+    fn new(ep: u16, data: &[u8], length: usize) -> Self {
+        let mut io = UsbRawEpIo {
+            ep,
+            flags: 0,
+            length: length.min(N) as u32,
+            data: [0; N],
+        };
+        let n = data.len().min(N);
+        io.data[..n].copy_from_slice(&data[..n]);
+        io
+    }
+}
+
+fn raw_ioctl<T>(fd: i32, request: u64, arg: *mut T) -> i32 {
+    unsafe { libc::ioctl(fd, request as _, arg) }
+}
+
+// The 8 byte setup packet of a control transfer.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ControlRequest {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl ControlRequest {
+    pub fn from_bytes(bytes: &[u8]) -> ControlRequest {
+        ControlRequest {
+            request_type: bytes[0],
+            request: bytes[1],
+            value: u16::from_le_bytes([bytes[2], bytes[3]]),
+            index: u16::from_le_bytes([bytes[4], bytes[5]]),
+            length: u16::from_le_bytes([bytes[6], bytes[7]]),
+        }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+
+    // One of the USB_TYPE_* constants.
+    pub fn kind(&self) -> u8 {
+        (self.request_type >> 5) & 0x03
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ControlReply {
+    Data(Vec<u8>),
+    Ack,
+    Stall,
+}
+
+// An output's USB function, answers whatever ep0 traffic isn't standard.
+pub trait GadgetFunction: Send + 'static {
+    // Class and vendor requests, `data` is the data stage of OUT requests.
+    fn control(&mut self, request: &ControlRequest, _data: &[u8]) -> ControlReply {
+        hid_default_reply(request)
+    }
+}
+
+// What HID hosts expect from a device with nothing special to say.
+pub fn hid_default_reply(request: &ControlRequest) -> ControlReply {
+    match (request.kind(), request.request) {
+        (USB_TYPE_CLASS, HID_REQ_SET_IDLE)
+        | (USB_TYPE_CLASS, HID_REQ_SET_PROTOCOL)
+        | (USB_TYPE_CLASS, HID_REQ_SET_REPORT) => ControlReply::Ack,
+        _ => ControlReply::Stall,
+    }
+}
+
+pub struct GadgetDescriptors {
+    pub device: Vec<u8>,
+    // Full configuration, including interface, class and endpoint descriptors.
+    pub configuration: Vec<u8>,
+    // String descriptor 1 onwards, 0 is the language list.
+    pub strings: Vec<String>,
+    // HID report descriptors by interface number, empty for non-HID interfaces.
+    pub hid_reports: Vec<Vec<u8>>,
+}
+
+pub fn device_descriptor(
+    vendor_id: u16,
+    product_id: u16,
+    bcd_device: u16,
+    class: (u8, u8, u8),
+    max_packet_size0: u8,
+) -> Vec<u8> {
+    let mut descriptor = vec![18, USB_DT_DEVICE, 0x00, 0x02, class.0, class.1, class.2];
+    descriptor.push(max_packet_size0);
+    descriptor.extend_from_slice(&vendor_id.to_le_bytes());
+    descriptor.extend_from_slice(&product_id.to_le_bytes());
+    descriptor.extend_from_slice(&bcd_device.to_le_bytes());
+    // Manufacturer, product, serial strings and one configuration
+    descriptor.extend_from_slice(&[1, 2, 3, 1]);
+    descriptor
+}
+
+pub fn interface_descriptor(number: u8, endpoints: u8, class: (u8, u8, u8)) -> Vec<u8> {
+    vec![9, USB_DT_INTERFACE, number, 0, endpoints, class.0, class.1, class.2, 0]
+}
+
+pub fn hid_descriptor(report_length: usize) -> Vec<u8> {
+    let length = (report_length as u16).to_le_bytes();
+    vec![9, USB_DT_HID, 0x11, 0x01, 0, 1, USB_DT_HID_REPORT, length[0], length[1]]
+}
+
+pub fn endpoint_descriptor(address: u8, attributes: u8, max_packet: u16, interval: u8) -> Vec<u8> {
+    let max_packet = max_packet.to_le_bytes();
+    vec![7, USB_DT_ENDPOINT, address, attributes, max_packet[0], max_packet[1], interval]
+}
+
+// Wraps interface level descriptors in a configuration descriptor, bus powered at 500mA.
+pub fn configuration_descriptor(interfaces: &[Vec<u8>]) -> Vec<u8> {
+    let body: Vec<u8> = interfaces.concat();
+    let total = ((body.len() + 9) as u16).to_le_bytes();
+    let count = descriptors_of_type(&body, USB_DT_INTERFACE).count() as u8;
+    let mut descriptor = vec![9, USB_DT_CONFIG, total[0], total[1], count, 1, 0, 0x80, 250];
+    descriptor.extend(body);
+    descriptor
+}
+
+// Walks a descriptor blob, yielding every descriptor of the given type.
+pub fn descriptors_of_type(blob: &[u8], kind: u8) -> impl Iterator<Item = &[u8]> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        while offset + 1 < blob.len() {
+            let length = blob[offset] as usize;
+            if length == 0 {
+                return None;
+            }
+            let descriptor = &blob[offset..(offset + length).min(blob.len())];
+            offset += length;
+            if descriptor[1] == kind {
+                return Some(descriptor);
+            }
+        }
+        None
+    })
+}
+
+fn string_descriptor(strings: &[String], index: u8) -> Option<Vec<u8>> {
+    if index == 0 {
+        // English (US) only
+        return Some(vec![4, USB_DT_STRING, 0x09, 0x04]);
+    }
+    let string = strings.get(index as usize - 1)?;
+    let mut descriptor = vec![0, USB_DT_STRING];
+    for unit in string.encode_utf16() {
+        descriptor.extend_from_slice(&unit.to_le_bytes());
+    }
+    descriptor[0] = descriptor.len() as u8;
+    Some(descriptor)
+}
+
+// HID class descriptor belonging to an interface.
+fn hid_class_descriptor(configuration: &[u8], interface: u16) -> Option<Vec<u8>> {
+    let mut in_interface = false;
+    let mut offset = 9;
+    while offset + 1 < configuration.len() {
+        let length = configuration[offset] as usize;
+        if length == 0 {
+            break;
+        }
+        let descriptor = &configuration[offset..(offset + length).min(configuration.len())];
+        match descriptor[1] {
+            USB_DT_INTERFACE => in_interface = descriptor[2] as u16 == interface,
+            USB_DT_HID if in_interface => return Some(descriptor.to_vec()),
+            _ => {}
+        }
+        offset += length;
+    }
+    None
+}
+
+// Requests any device answers the same way, None for the ones the function handles.
+pub fn standard_reply(
+    descriptors: &GadgetDescriptors,
+    request: &ControlRequest,
+    configuration: u8,
+) -> Option<ControlReply> {
+    if request.kind() != USB_TYPE_STANDARD {
+        return None;
+    }
+    let reply = match request.request {
+        USB_REQ_GET_DESCRIPTOR => {
+            let [index, kind] = request.value.to_le_bytes();
+            let descriptor = match kind {
+                USB_DT_DEVICE => Some(descriptors.device.clone()),
+                USB_DT_CONFIG => Some(descriptors.configuration.clone()),
+                USB_DT_STRING => string_descriptor(&descriptors.strings, index),
+                USB_DT_HID => hid_class_descriptor(&descriptors.configuration, request.index),
+                USB_DT_HID_REPORT => descriptors
+                    .hid_reports
+                    .get(request.index as usize)
+                    .filter(|report| !report.is_empty())
+                    .cloned(),
+                // Full speed only, so no device qualifier.
+                _ => None,
+            };
+            match descriptor {
+                Some(descriptor) => ControlReply::Data(descriptor),
+                None => ControlReply::Stall,
+            }
+        }
+        USB_REQ_GET_STATUS => ControlReply::Data(vec![0, 0]),
+        USB_REQ_GET_CONFIGURATION => ControlReply::Data(vec![configuration]),
+        USB_REQ_GET_INTERFACE => ControlReply::Data(vec![0]),
+        USB_REQ_SET_INTERFACE | USB_REQ_CLEAR_FEATURE | USB_REQ_SET_FEATURE => ControlReply::Ack,
+        _ => return None,
+    };
+    Some(reply)
+}
+
+// Data the host sent us, through an OUT endpoint or a SET_REPORT on ep0 (endpoint 0).
+#[derive(Clone, PartialEq, Debug)]
+pub struct GadgetReport {
+    pub endpoint: u8,
+    pub data: Vec<u8>,
+}
+
+struct GadgetShared {
+    file: File,
+    configured: AtomicBool,
+    // Endpoint address to raw-gadget handle
+    endpoints: Mutex<HashMap<u8, u16>>,
+}
+
+// A report for the writer thread, answered once the host picked it up.
+struct Write {
+    handle: u16,
+    data: Vec<u8>,
+    done: oneshot::Sender<bool>,
+}
+
+pub struct RawGadget {
+    shared: Arc<GadgetShared>,
+    reports: Receiver<GadgetReport>,
+    writes: Sender<Write>,
+}
+
+// First USB device controller, e.g. "20980000.usb" on a Pi Zero.
+pub fn default_udc() -> Option<String> {
+    let mut entries: Vec<String> = fs::read_dir(UDC_CLASS_PATH)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+    entries.into_iter().next()
+}
+
+impl RawGadget {
+    pub fn start(
+        udc: Option<&str>,
+        descriptors: GadgetDescriptors,
+        function: Box<dyn GadgetFunction>,
+    ) -> Result<RawGadget, String> {
+        let udc = match udc {
+            Some(udc) => udc.to_string(),
+            None => default_udc().ok_or("No USB device controller found.")?,
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(RAW_GADGET_PATH)
+            .map_err(|err| format!("Could not open {}: {}", RAW_GADGET_PATH, err))?;
+
+        let mut init = UsbRawInit {
+            driver_name: [0; 128],
+            device_name: [0; 128],
+            speed: USB_SPEED_FULL,
+        };
+        // The UDC's name doubles as its driver name for dwc2.
+        let name = udc.as_bytes();
+        let n = name.len().min(127);
+        init.driver_name[..n].copy_from_slice(&name[..n]);
+        init.device_name[..n].copy_from_slice(&name[..n]);
+        let fd = file.as_raw_fd();
+        if raw_ioctl(fd, USB_RAW_IOCTL_INIT, &mut init) < 0 {
+            return Err(format!("raw-gadget init failed on {}", udc));
+        }
+        if raw_ioctl(fd, USB_RAW_IOCTL_RUN, std::ptr::null_mut::<u8>()) < 0 {
+            return Err("raw-gadget run failed".to_string());
+        }
+
+        let shared = Arc::new(GadgetShared {
+            file,
+            configured: AtomicBool::new(false),
+            endpoints: Mutex::new(HashMap::new()),
+        });
+        let (sender, reports) = channel();
+        let mut ep0 = Ep0Handler {
+            shared: shared.clone(),
+            descriptors,
+            function,
+            sender,
+            configuration: 0,
+        };
+        thread::spawn(move || ep0.run());
+        let (writes, pending) = channel();
+        let writer = shared.clone();
+        thread::spawn(move || write_endpoints(writer, pending));
+        Ok(RawGadget {
+            shared,
+            reports,
+            writes,
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.shared.configured.load(Ordering::SeqCst)
+    }
+
+    // Waits until the host picks the report up, the write itself blocks on the writer thread.
+    // Until the host configured us, and after it resets us, reports are dropped.
+    pub async fn send(&self, endpoint: u8, data: &[u8]) -> bool {
+        if !self.is_configured() {
+            return true;
+        }
+        let handle = match self.shared.endpoints.lock().unwrap().get(&endpoint) {
+            Some(handle) => *handle,
+            None => return false,
+        };
+        dashboard::sent("raw-gadget", endpoint, data);
+        let (done, written) = oneshot::channel();
+        let write = Write {
+            handle,
+            data: data.to_vec(),
+            done,
+        };
+        if self.writes.send(write).is_err() {
+            return false;
+        }
+        // Writes fail once a reset disabled the endpoint, that's not the end of the gadget.
+        written.await.unwrap_or(false) || !self.is_configured()
+    }
+
+    pub fn try_recv(&self) -> Option<GadgetReport> {
+        self.reports.try_recv().ok()
+    }
+}
+
+struct Ep0Handler {
+    shared: Arc<GadgetShared>,
+    descriptors: GadgetDescriptors,
+    function: Box<dyn GadgetFunction>,
+    sender: Sender<GadgetReport>,
+    configuration: u8,
+}
+
+impl Ep0Handler {
+    fn fd(&self) -> i32 {
+        self.shared.file.as_raw_fd()
+    }
+
+    fn run(&mut self) {
+        loop {
+            let mut event = UsbRawEvent {
+                kind: 0,
+                length: EP0_MAX_DATA as u32,
+                data: [0; EP0_MAX_DATA],
+            };
+            if raw_ioctl(self.fd(), USB_RAW_IOCTL_EVENT_FETCH, &mut event) < 0 {
+                log_error!("raw-gadget: fetching events failed, stopping.");
+                return;
+            }
+            match event.kind {
+                USB_RAW_EVENT_CONNECT => log!("raw-gadget: connected"),
+                USB_RAW_EVENT_CONTROL => {
+                    self.handle_control(&ControlRequest::from_bytes(&event.data[..8]))
+                }
+                USB_RAW_EVENT_RESET | USB_RAW_EVENT_DISCONNECT => {
+                    self.shared.configured.store(false, Ordering::SeqCst);
+                    // Fails pending reads and writes, so their threads move on.
+                    let endpoints = std::mem::take(&mut *self.shared.endpoints.lock().unwrap());
+                    for (_, handle) in endpoints {
+                        let mut handle = handle as u32;
+                        raw_ioctl(self.fd(), USB_RAW_IOCTL_EP_DISABLE, &mut handle);
+                    }
+                    self.configuration = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_control(&mut self, request: &ControlRequest) {
+        let data = if !request.is_in() && request.length > 0 {
+            let mut io = UsbRawEpIo::<EP0_MAX_DATA>::new(0, &[], request.length as usize);
+            let read = raw_ioctl(self.fd(), USB_RAW_IOCTL_EP0_READ, &mut io);
+            if read < 0 {
+                return;
+            }
+            io.data[..read as usize].to_vec()
+        } else {
+            vec![]
+        };
+
+        let reply = if request.kind() == USB_TYPE_STANDARD
+            && request.request == USB_REQ_SET_CONFIGURATION
+        {
+            self.configure(request.value as u8)
+        } else if let Some(reply) = standard_reply(&self.descriptors, request, self.configuration)
+        {
+            reply
+        } else {
+            if request.kind() == USB_TYPE_CLASS && request.request == HID_REQ_SET_REPORT {
+                let _ = self.sender.send(GadgetReport {
+                    endpoint: 0,
+                    data: data.clone(),
+                });
+            }
+            self.function.control(request, &data)
+        };
+
+        match reply {
+            ControlReply::Data(reply) if request.is_in() => {
+                let length = reply.len().min(request.length as usize);
+                let mut io = UsbRawEpIo::<EP0_MAX_DATA>::new(0, &reply, length);
+                raw_ioctl(self.fd(), USB_RAW_IOCTL_EP0_WRITE, &mut io);
+            }
+            ControlReply::Stall => {
+                raw_ioctl(self.fd(), USB_RAW_IOCTL_EP0_STALL, std::ptr::null_mut::<u8>());
+            }
+            _ if request.is_in() => {
+                let mut io = UsbRawEpIo::<0>::new(0, &[], 0);
+                raw_ioctl(self.fd(), USB_RAW_IOCTL_EP0_WRITE, &mut io);
+            }
+            // OUT requests with data were acked by reading the data stage.
+            _ if request.length == 0 => {
+                let mut io = UsbRawEpIo::<0>::new(0, &[], 0);
+                raw_ioctl(self.fd(), USB_RAW_IOCTL_EP0_READ, &mut io);
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, configuration: u8) -> ControlReply {
+        if configuration == 0 || self.configuration == configuration {
+            return ControlReply::Ack;
+        }
+        let fd = self.fd();
+        let mut endpoints = HashMap::new();
+        for descriptor in descriptors_of_type(&self.descriptors.configuration, USB_DT_ENDPOINT) {
+            // Kernel struct has two extra audio fields.
+            let mut raw = [0u8; 9];
+            raw[..7].copy_from_slice(&descriptor[..7]);
+            let handle = raw_ioctl(fd, USB_RAW_IOCTL_EP_ENABLE, raw.as_mut_ptr());
+            if handle < 0 {
+                log_error!("raw-gadget: could not enable endpoint {:#04x}", descriptor[2]);
+                return ControlReply::Stall;
+            }
+            endpoints.insert(descriptor[2], handle as u16);
+
+            // Hand OUT endpoint data to the output.
+            if descriptor[2] & 0x80 == 0 {
+                let shared = self.shared.clone();
+                let sender = self.sender.clone();
+                let address = descriptor[2];
+                let max_packet = u16::from_le_bytes([descriptor[4], descriptor[5]]) as usize;
+                thread::spawn(move || read_endpoint(shared, sender, address, handle as u16, max_packet));
+            }
+        }
+        let mut power = self.descriptors.configuration[8] as u32;
+        raw_ioctl(fd, USB_RAW_IOCTL_VBUS_DRAW, &mut power);
+        raw_ioctl(fd, USB_RAW_IOCTL_CONFIGURE, std::ptr::null_mut::<u8>());
+
+        *self.shared.endpoints.lock().unwrap() = endpoints;
+        self.configuration = configuration;
+        self.shared.configured.store(true, Ordering::SeqCst);
+        log!("raw-gadget: configured");
+        ControlReply::Ack
+    }
+}
+
+fn write_endpoints(shared: Arc<GadgetShared>, writes: Receiver<Write>) {
+    // Ends once the gadget is dropped.
+    for write in writes {
+        let mut io = UsbRawEpIo::<EP_MAX_DATA>::new(write.handle, &write.data, write.data.len());
+        let written = raw_ioctl(shared.file.as_raw_fd(), USB_RAW_IOCTL_EP_WRITE, &mut io) >= 0;
+        let _ = write.done.send(written);
+    }
+}
+
+fn read_endpoint(
+    shared: Arc<GadgetShared>,
+    sender: Sender<GadgetReport>,
+    address: u8,
+    handle: u16,
+    max_packet: usize,
+) {
+    loop {
+        let mut io = UsbRawEpIo::<EP_MAX_DATA>::new(handle, &[], max_packet);
+        let read = raw_ioctl(shared.file.as_raw_fd(), USB_RAW_IOCTL_EP_READ, &mut io);
+        if read < 0 {
+            return;
+        }
+        let report = GadgetReport {
+            endpoint: address,
+            data: io.data[..read as usize].to_vec(),
+        };
+        if sender.send(report).is_err() {
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_raw_gadget_ioctl_numbers() {
+    // Values from linux/usb/raw_gadget.h
+    assert_eq!(USB_RAW_IOCTL_INIT, 0x4101_5500);
+    assert_eq!(USB_RAW_IOCTL_RUN, 0x5501);
+    assert_eq!(USB_RAW_IOCTL_EVENT_FETCH, 0x8008_5502);
+    assert_eq!(USB_RAW_IOCTL_EP0_WRITE, 0x4008_5503);
+    assert_eq!(USB_RAW_IOCTL_EP0_READ, 0xc008_5504);
+    assert_eq!(USB_RAW_IOCTL_EP_ENABLE, 0x4009_5505);
+    assert_eq!(USB_RAW_IOCTL_EP_DISABLE, 0x4004_5506);
+    assert_eq!(USB_RAW_IOCTL_EP_WRITE, 0x4008_5507);
+    assert_eq!(USB_RAW_IOCTL_EP_READ, 0xc008_5508);
+    assert_eq!(USB_RAW_IOCTL_CONFIGURE, 0x5509);
+    assert_eq!(USB_RAW_IOCTL_VBUS_DRAW, 0x4004_550a);
+    assert_eq!(USB_RAW_IOCTL_EP0_STALL, 0x550c);
+}
+
+#[test]
+fn test_raw_gadget_standard_requests() {
+    let report = vec![0x05, 0x01, 0x09, 0x05, 0xa1, 0x01, 0xc0];
+    let descriptors = GadgetDescriptors {
+        device: device_descriptor(0x1234, 0x5678, 0x0100, (0, 0, 0), 64),
+        configuration: configuration_descriptor(&[[
+            interface_descriptor(0, 1, (0x03, 0, 0)),
+            hid_descriptor(report.len()),
+            endpoint_descriptor(0x81, USB_ENDPOINT_XFER_INT, 64, 4),
+        ]
+        .concat()]),
+        strings: vec!["Maker".to_string()],
+        hid_reports: vec![report.clone()],
+    };
+    assert_eq!(descriptors.device.len(), 18);
+    assert_eq!(descriptors.configuration.len(), 9 + 9 + 9 + 7);
+    assert_eq!(descriptors.configuration[2], 34);
+    assert_eq!(descriptors.configuration[4], 1);
+
+    let get = |request_type: u8, value: u16, index: u16| {
+        let request = ControlRequest::from_bytes(&[
+            request_type,
+            USB_REQ_GET_DESCRIPTOR,
+            value.to_le_bytes()[0],
+            value.to_le_bytes()[1],
+            index.to_le_bytes()[0],
+            index.to_le_bytes()[1],
+            0xff,
+            0,
+        ]);
+        standard_reply(&descriptors, &request, 0)
+    };
+    assert_eq!(
+        get(0x80, 0x0100, 0),
+        Some(ControlReply::Data(descriptors.device.clone()))
+    );
+    assert_eq!(
+        get(0x80, 0x0301, 0),
+        Some(ControlReply::Data(vec![12, 3, b'M', 0, b'a', 0, b'k', 0, b'e', 0, b'r', 0]))
+    );
+    assert_eq!(get(0x81, 0x2200, 0), Some(ControlReply::Data(report)));
+    assert_eq!(
+        get(0x81, 0x2100, 0),
+        Some(ControlReply::Data(hid_descriptor(7)))
+    );
+    // Device qualifier
+    assert_eq!(get(0x80, 0x0600, 0), Some(ControlReply::Stall));
+    // Class requests go to the function
+    assert_eq!(get(0xa1, 0x0100, 0), None);
+
+    let endpoints: Vec<u8> = descriptors_of_type(&descriptors.configuration, USB_DT_ENDPOINT)
+        .map(|endpoint| endpoint[2])
+        .collect();
+    assert_eq!(endpoints, vec![0x81]);
+}
@@ -0,0 +1,678 @@
+use crate::{
+    controller_abs::{
+        Axis, BitPackedButton, BitPackedButtons, Feedback, Gamepad, GamepadAxis, GamepadButton,
+        JoystickState, MotionState, PowerState,
+    },
+    AxisNew, JoystickStateNew,
+};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, hid_descriptor,
+    interface_descriptor, GadgetDescriptors, GadgetFunction, USB_ENDPOINT_XFER_INT,
+};
+
+// Protocol reference: https://github.com/dekuNukem/Nintendo_Switch_Reverse_Engineering
+
+pub const SWITCH_PRO_VENDOR_ID: u16 = 0x057e;
+pub const SWITCH_PRO_PRODUCT_ID: u16 = 0x2009;
+pub const SWITCH_PRO_IN_ENDPOINT: u8 = 0x81;
+pub const SWITCH_PRO_OUT_ENDPOINT: u8 = 0x01;
+pub const SWITCH_PRO_REPORT_LEN: usize = 64;
+
+// Matches the factory calibration in `SPI_REGIONS`.
+const ACCEL_PER_G: f64 = 4096.0;
+const GYRO_PER_DEG_S: f64 = 13371.0 / 936.0;
+
+// Vendor reports only, every report is 63 bytes after its id.
+pub const SWITCH_PRO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x05, // Usage (Game Pad)
+    0xa1, 0x01, // Collection (Application)
+    0x06, 0x01, 0xff, //   Usage Page (Vendor 1)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, // Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x3f, //   Report Count (63)
+    0x85, 0x21, //   Report ID (0x21), subcommand replies
+    0x09, 0x21, //   Usage (0x21)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x85, 0x30, //   Report ID (0x30), standard input
+    0x09, 0x30, //   Usage (0x30)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x85, 0x31, //   Report ID (0x31), NFC/IR input
+    0x09, 0x31, //   Usage (0x31)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x85, 0x81, //   Report ID (0x81), USB command replies
+    0x09, 0x81, //   Usage (0x81)
+    0x81, 0x02, //   Input (Data,Var,Abs)
+    0x85, 0x01, //   Report ID (0x01), rumble and subcommand
+    0x09, 0x01, //   Usage (0x01)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0x85, 0x10, //   Report ID (0x10), rumble only
+    0x09, 0x10, //   Usage (0x10)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0x85, 0x11, //   Report ID (0x11), NFC/IR request
+    0x09, 0x11, //   Usage (0x11)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0x85, 0x80, //   Report ID (0x80), USB commands
+    0x09, 0x80, //   Usage (0x80)
+    0x91, 0x02, //   Output (Data,Var,Abs)
+    0xc0, // End Collection
+];
+
+pub fn switch_pro_descriptors() -> GadgetDescriptors {
+    GadgetDescriptors {
+        device: device_descriptor(
+            SWITCH_PRO_VENDOR_ID,
+            SWITCH_PRO_PRODUCT_ID,
+            0x0210,
+            (0, 0, 0),
+            64,
+        ),
+        configuration: configuration_descriptor(&[[
+            interface_descriptor(0, 2, (0x03, 0, 0)),
+            hid_descriptor(SWITCH_PRO_REPORT_DESCRIPTOR.len()),
+            endpoint_descriptor(SWITCH_PRO_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 64, 8),
+            endpoint_descriptor(SWITCH_PRO_OUT_ENDPOINT, USB_ENDPOINT_XFER_INT, 64, 8),
+        ]
+        .concat()]),
+        strings: vec![
+            "Nintendo Co., Ltd.".to_string(),
+            "Pro Controller".to_string(),
+            "000000000001".to_string(),
+        ],
+        hid_reports: vec![SWITCH_PRO_REPORT_DESCRIPTOR.to_vec()],
+    }
+}
+
+// Everything goes over the interrupt endpoints, ep0 only sees the usual HID requests.
+pub struct SwitchProFunction;
+
+impl GadgetFunction for SwitchProFunction {}
+
+// Stick calibration: center 0x800 and 0x7ff either way, packed as 12 bit pairs.
+const STICK_CALIBRATION: [u8; 18] = [
+    // Left: above center, center, below center
+    0xff, 0xf7, 0x7f, 0x00, 0x08, 0x80, 0xff, 0xf7, 0x7f,
+    // Right: center, below center, above center
+    0x00, 0x08, 0x80, 0xff, 0xf7, 0x7f, 0xff, 0xf7, 0x7f,
+];
+
+// Dead zone and range ratio, as a real Pro Controller reports them.
+const STICK_PARAMETERS: [u8; 18] = [
+    0x0f, 0x30, 0x61, 0x96, 0x30, 0xf3, 0xd4, 0x14, 0x54, 0x41, 0x15, 0x54, 0xc7, 0x79, 0x9c, 0x33,
+    0x36, 0x63,
+];
+
+// SPI flash contents we answer with, everything else reads as erased (0xff).
+const SPI_REGIONS: &[(u32, &[u8])] = &[
+    // Factory IMU calibration: accel origin and sensitivity, gyro origin and sensitivity.
+    (
+        0x6020,
+        &[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x40, 0x00, 0x40, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x3b, 0x34, 0x3b, 0x34, 0x3b, 0x34,
+        ],
+    ),
+    (0x603d, &STICK_CALIBRATION),
+    // Body, buttons, left and right grip colours.
+    (
+        0x6050,
+        &[
+            0x32, 0x32, 0x32, 0xff, 0xff, 0xff, 0x32, 0x32, 0x32, 0x32, 0x32, 0x32,
+        ],
+    ),
+    // IMU horizontal offsets, then left stick parameters.
+    (0x6080, &[0x50, 0xfd, 0x00, 0x00, 0xc6, 0x0f]),
+    (0x6086, &STICK_PARAMETERS),
+    (0x6098, &STICK_PARAMETERS),
+];
+
+pub fn spi_read(address: u32, length: usize) -> Vec<u8> {
+    (address..address + length as u32)
+        .map(|address| {
+            SPI_REGIONS
+                .iter()
+                .find_map(|(start, data)| {
+                    address
+                        .checked_sub(*start)
+                        .and_then(|offset| data.get(offset as usize))
+                        .copied()
+                })
+                .unwrap_or(0xff)
+        })
+        .collect()
+}
+
+// HD rumble amplitudes, one motor per side, roughly back to [0, 1].
+fn decode_rumble(data: &[u8]) -> Option<Feedback> {
+    if data.len() < 8 {
+        return None;
+    }
+    let side = |motor: &[u8]| {
+        let high = (motor[1] & 0xfe) as f64 / 0xc8 as f64;
+        let low = (motor[3] & 0x7f).saturating_sub(0x40) as f64 / 0x32 as f64;
+        high.max(low).min(1.0)
+    };
+    Some(Feedback::Rumble {
+        strong: side(&data[0..4]),
+        weak: side(&data[4..8]),
+    })
+}
+
+pub struct SwitchProButtonState {
+    pub y: BitPackedButton,
+    pub x: BitPackedButton,
+    pub b: BitPackedButton,
+    pub a: BitPackedButton,
+    pub r: BitPackedButton,
+    pub zr: BitPackedButton,
+    pub minus: BitPackedButton,
+    pub plus: BitPackedButton,
+    pub r_stick: BitPackedButton,
+    pub l_stick: BitPackedButton,
+    pub home: BitPackedButton,
+    pub capture: BitPackedButton,
+    pub dpad_down: BitPackedButton,
+    pub dpad_up: BitPackedButton,
+    pub dpad_right: BitPackedButton,
+    pub dpad_left: BitPackedButton,
+    pub l: BitPackedButton,
+    pub zl: BitPackedButton,
+}
+
+impl SwitchProButtonState {
+    pub fn new() -> SwitchProButtonState {
+        SwitchProButtonState {
+            // Right byte
+            y: BitPackedButton::new(0x00),
+            x: BitPackedButton::new(0x01),
+            b: BitPackedButton::new(0x02),
+            a: BitPackedButton::new(0x03),
+            r: BitPackedButton::new(0x06),
+            zr: BitPackedButton::new(0x07),
+            // Shared byte
+            minus: BitPackedButton::new(0x00),
+            plus: BitPackedButton::new(0x01),
+            r_stick: BitPackedButton::new(0x02),
+            l_stick: BitPackedButton::new(0x03),
+            home: BitPackedButton::new(0x04),
+            capture: BitPackedButton::new(0x05),
+            // Left byte
+            dpad_down: BitPackedButton::new(0x00),
+            dpad_up: BitPackedButton::new(0x01),
+            dpad_right: BitPackedButton::new(0x02),
+            dpad_left: BitPackedButton::new(0x03),
+            l: BitPackedButton::new(0x06),
+            zl: BitPackedButton::new(0x07),
+        }
+    }
+
+    pub fn get_right_byte(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.y.clone(),
+                self.x.clone(),
+                self.b.clone(),
+                self.a.clone(),
+                self.r.clone(),
+                self.zr.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+
+    pub fn get_shared_byte(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.minus.clone(),
+                self.plus.clone(),
+                self.r_stick.clone(),
+                self.l_stick.clone(),
+                self.home.clone(),
+                self.capture.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+
+    pub fn get_left_byte(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.dpad_down.clone(),
+                self.dpad_up.clone(),
+                self.dpad_right.clone(),
+                self.dpad_left.clone(),
+                self.l.clone(),
+                self.zl.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+}
+
+pub struct SwitchProControllerState {
+    pub buttons: SwitchProButtonState,
+    pub left_joystick: JoystickState,
+    pub right_joystick: JoystickState,
+    pub motion: Option<MotionState>,
+    pub power: Option<PowerState>,
+}
+
+// 12 bit x and y, packed into 3 bytes.
+fn pack_stick(joystick: &JoystickState) -> [u8; 3] {
+    let x = joystick.x.convert_into::<u16, _>(false) >> 4;
+    let y = joystick.y.convert_into::<u16, _>(false) >> 4;
+    [
+        (x & 0xff) as u8,
+        ((x >> 8) as u8) | ((y & 0x0f) << 4) as u8,
+        (y >> 4) as u8,
+    ]
+}
+
+impl SwitchProControllerState {
+    pub fn new() -> SwitchProControllerState {
+        SwitchProControllerState {
+            buttons: SwitchProButtonState::new(),
+            left_joystick: JoystickStateNew!(u8, 128),
+            right_joystick: JoystickStateNew!(u8, 128),
+            motion: None,
+            power: None,
+        }
+    }
+
+    pub fn update_from_gamepad(&mut self, gamepad: &Gamepad) {
+        for (button, button_state) in &gamepad.buttons {
+            let val = button_state.to_owned();
+            // Nintendo layout: A is east, B south
+            match button {
+                GamepadButton::North => self.buttons.x.value = val,
+                GamepadButton::East => self.buttons.a.value = val,
+                GamepadButton::South => self.buttons.b.value = val,
+                GamepadButton::West => self.buttons.y.value = val,
+                GamepadButton::LeftShoulderButton => self.buttons.l.value = val,
+                GamepadButton::RightShoulderButton => self.buttons.r.value = val,
+                GamepadButton::LeftThumb => self.buttons.l_stick.value = val,
+                GamepadButton::RightThumb => self.buttons.r_stick.value = val,
+                GamepadButton::Start => self.buttons.plus.value = val,
+                GamepadButton::Select => self.buttons.minus.value = val,
+                GamepadButton::Mode => self.buttons.home.value = val,
+                GamepadButton::DPadUp => self.buttons.dpad_up.value = val,
+                GamepadButton::DPadDown => self.buttons.dpad_down.value = val,
+                GamepadButton::DPadLeft => self.buttons.dpad_left.value = val,
+                GamepadButton::DPadRight => self.buttons.dpad_right.value = val,
+                GamepadButton::Share => self.buttons.capture.value = val,
+            }
+        }
+        for (gamepad_axis, axis) in &gamepad.axes {
+            match gamepad_axis {
+                GamepadAxis::LeftJoystickX => {
+                    self.left_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::LeftJoystickY => {
+                    self.left_joystick.y.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickX => {
+                    self.right_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickY => {
+                    self.right_joystick.y.value = axis.convert_into(false);
+                }
+                // ZL/ZR are digital
+                GamepadAxis::LeftTrigger => {
+                    self.buttons.zl.value = axis.convert_into::<u8, _>(false) > 127;
+                }
+                GamepadAxis::RightTrigger => {
+                    self.buttons.zr.value = axis.convert_into::<u8, _>(false) > 127;
+                }
+            }
+        }
+        self.motion = gamepad.motion;
+        self.power = gamepad.power;
+    }
+
+    // Battery in the high nibble (8 full, 0 empty, +1 charging), low bit means USB powered.
+    fn get_battery_byte(&self) -> u8 {
+        let (level, charging) = match self.power {
+            Some(PowerState::Discharging(level)) => (level, false),
+            Some(PowerState::Charging(level)) => (level, true),
+            _ => (100, false),
+        };
+        let level = match level {
+            0..=10 => 0,
+            11..=25 => 2,
+            26..=50 => 4,
+            51..=75 => 6,
+            _ => 8,
+        };
+        (level | charging as u8) << 4 | 0x01
+    }
+
+    // Bytes 2 to 11 of every input report.
+    pub fn input_bytes(&self) -> [u8; 10] {
+        let mut bytes = [0u8; 10];
+        bytes[0] = self.get_battery_byte();
+        bytes[1] = self.buttons.get_right_byte();
+        bytes[2] = self.buttons.get_shared_byte();
+        bytes[3] = self.buttons.get_left_byte();
+        bytes[4..7].copy_from_slice(&pack_stick(&self.left_joystick));
+        bytes[7..10].copy_from_slice(&pack_stick(&self.right_joystick));
+        bytes
+    }
+
+    // One IMU sample, the report carries three 5ms apart but we only have the latest.
+    // Switch axes: x towards the shoulder buttons, y to the left, z up out of the face.
+    pub fn imu_sample(&self) -> [u8; 12] {
+        let mut sample = [0u8; 12];
+        let motion = match self.motion {
+            Some(motion) => motion,
+            None => return sample,
+        };
+        let to_i16 = |value: f64| value.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+        let accel = [-motion.accel.2, -motion.accel.0, motion.accel.1];
+        let gyro = [-motion.gyro.2, -motion.gyro.0, motion.gyro.1];
+        for (i, value) in accel.iter().enumerate() {
+            sample[i * 2..i * 2 + 2].copy_from_slice(&to_i16(value * ACCEL_PER_G).to_le_bytes());
+        }
+        for (i, value) in gyro.iter().enumerate() {
+            sample[6 + i * 2..8 + i * 2]
+                .copy_from_slice(&to_i16(value * GYRO_PER_DEG_S).to_le_bytes());
+        }
+        sample
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HandshakeState {
+    // Waiting for the host to ask for our status (0x80 0x01).
+    Idle,
+    // Host knows who we are, handshakes and baud rate changes follow.
+    Identified,
+    // After 0x80 0x04, the host wants 0x30 reports without timeouts.
+    Streaming,
+}
+
+// What an output report from the host made us do.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SwitchProResponse {
+    // Input reports to send back, in order.
+    pub replies: Vec<[u8; SWITCH_PRO_REPORT_LEN]>,
+    pub feedback: Vec<Feedback>,
+}
+
+// The host side protocol, without any USB, so it can be driven by recorded packets.
+pub struct SwitchProController {
+    pub state: SwitchProControllerState,
+    pub handshake: HandshakeState,
+    pub mac: [u8; 6],
+    pub imu_enabled: bool,
+    pub vibration_enabled: bool,
+    // Low nibble on, high nibble flashing, player 1 is 0x01.
+    pub player_lights: u8,
+    timer: u8,
+}
+
+impl SwitchProController {
+    pub fn new() -> SwitchProController {
+        SwitchProController {
+            state: SwitchProControllerState::new(),
+            handshake: HandshakeState::Idle,
+            // Locally administered
+            mac: [0x02, 0x5e, 0x57, 0x17, 0xc4, 0x01],
+            imu_enabled: false,
+            vibration_enabled: false,
+            player_lights: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.handshake == HandshakeState::Streaming
+    }
+
+    fn next_timer(&mut self) -> u8 {
+        self.timer = self.timer.wrapping_add(1);
+        self.timer
+    }
+
+    pub fn next_input_report(&mut self) -> [u8; SWITCH_PRO_REPORT_LEN] {
+        let mut report = [0u8; SWITCH_PRO_REPORT_LEN];
+        report[0] = 0x30;
+        report[1] = self.next_timer();
+        report[2..12].copy_from_slice(&self.state.input_bytes());
+        // Vibrator ack
+        report[12] = 0x80;
+        if self.imu_enabled {
+            let sample = self.state.imu_sample();
+            for frame in 0..3 {
+                report[13 + frame * 12..25 + frame * 12].copy_from_slice(&sample);
+            }
+        }
+        report
+    }
+
+    fn usb_reply(&self, command: u8, data: &[u8]) -> [u8; SWITCH_PRO_REPORT_LEN] {
+        let mut report = [0u8; SWITCH_PRO_REPORT_LEN];
+        report[0] = 0x81;
+        report[1] = command;
+        report[2..2 + data.len()].copy_from_slice(data);
+        report
+    }
+
+    fn subcommand_reply(
+        &mut self,
+        subcommand: u8,
+        ack: u8,
+        data: &[u8],
+    ) -> [u8; SWITCH_PRO_REPORT_LEN] {
+        let mut report = [0u8; SWITCH_PRO_REPORT_LEN];
+        report[0] = 0x21;
+        report[1] = self.next_timer();
+        report[2..12].copy_from_slice(&self.state.input_bytes());
+        report[12] = 0x80;
+        report[13] = ack;
+        report[14] = subcommand;
+        let n = data.len().min(SWITCH_PRO_REPORT_LEN - 15);
+        report[15..15 + n].copy_from_slice(&data[..n]);
+        report
+    }
+
+    // Reply to a subcommand from output report 0x01, `args` start after the subcommand id.
+    fn handle_subcommand(&mut self, subcommand: u8, args: &[u8]) -> [u8; SWITCH_PRO_REPORT_LEN] {
+        let arg = args.first().copied().unwrap_or(0);
+        match subcommand {
+            // Device info: firmware 3.72, Pro Controller, MAC, use SPI colours
+            0x02 => {
+                let mut data = vec![0x03, 0x48, 0x03, 0x02];
+                data.extend(self.mac);
+                data.extend([0x01, 0x01]);
+                self.subcommand_reply(subcommand, 0x82, &data)
+            }
+            // Trigger buttons elapsed time
+            0x04 => self.subcommand_reply(subcommand, 0x83, &[0; 14]),
+            // SPI flash read: address (u32 LE) and length, echoed before the data
+            0x10 => {
+                let mut header = [0u8; 5];
+                let n = args.len().min(5);
+                header[..n].copy_from_slice(&args[..n]);
+                let address = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let length = (header[4] as usize).min(0x1d);
+                let mut data = header.to_vec();
+                data.extend(spi_read(address, length));
+                self.subcommand_reply(subcommand, 0x90, &data)
+            }
+            // NFC/IR MCU configuration, we report it as standing by
+            0x21 => self.subcommand_reply(
+                subcommand,
+                0xa0,
+                &[0x01, 0x00, 0xff, 0x00, 0x03, 0x00, 0x05, 0x01],
+            ),
+            0x30 => {
+                self.player_lights = arg;
+                self.subcommand_reply(subcommand, 0x80, &[])
+            }
+            0x40 => {
+                self.imu_enabled = arg != 0;
+                self.subcommand_reply(subcommand, 0x80, &[])
+            }
+            0x48 => {
+                self.vibration_enabled = arg != 0;
+                self.subcommand_reply(subcommand, 0x80, &[])
+            }
+            // Input mode, shipment state, MCU state, home light, IMU sensitivity, ...
+            // all just need an ack.
+            _ => self.subcommand_reply(subcommand, 0x80, &[]),
+        }
+    }
+
+    pub fn handle_output_report(&mut self, data: &[u8]) -> SwitchProResponse {
+        let mut response = SwitchProResponse::default();
+        let (report_id, command) = match data {
+            [report_id, command, ..] => (*report_id, *command),
+            _ => return response,
+        };
+        match report_id {
+            0x80 => match command {
+                // Status: connection type and our MAC, reversed
+                0x01 => {
+                    self.handshake = HandshakeState::Identified;
+                    let mut data = vec![0x00, 0x03];
+                    data.extend(self.mac.iter().rev());
+                    response.replies.push(self.usb_reply(command, &data));
+                }
+                // Handshake and baud rate switch
+                0x02 | 0x03 => response.replies.push(self.usb_reply(command, &[])),
+                0x04 => self.handshake = HandshakeState::Streaming,
+                // Back to timing out, the host talks again before the next stream.
+                0x05 => self.handshake = HandshakeState::Identified,
+                _ => {}
+            },
+            // Rumble, then a subcommand
+            0x01 if data.len() >= 11 => {
+                if self.vibration_enabled {
+                    response.feedback.extend(decode_rumble(&data[2..10]));
+                }
+                let reply = self.handle_subcommand(data[10], &data[11..]);
+                response.replies.push(reply);
+            }
+            0x10 if self.vibration_enabled => {
+                response.feedback.extend(decode_rumble(&data[2..]));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+fn hex_packet(hex: &str) -> Vec<u8> {
+    hex.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_switch_pro_handshake() {
+    let mut controller = SwitchProController::new();
+    controller.state.update_from_gamepad(&Gamepad::new());
+    controller.state.buttons.a.value = true;
+
+    // What a Switch sends after the pad gets plugged in.
+    let status = controller.handle_output_report(&hex_packet("80 01"));
+    assert_eq!(controller.handshake, HandshakeState::Identified);
+    assert_eq!(
+        status.replies[0][..10],
+        hex_packet("81 01 00 03 01 c4 17 57 5e 02")[..]
+    );
+    let handshake = controller.handle_output_report(&hex_packet("80 02"));
+    assert_eq!(handshake.replies[0][..3], [0x81, 0x02, 0x00]);
+    let baud_rate = controller.handle_output_report(&hex_packet("80 03"));
+    assert_eq!(baud_rate.replies[0][..2], [0x81, 0x03]);
+    controller.handle_output_report(&hex_packet("80 02"));
+    assert!(!controller.is_streaming());
+    let no_timeout = controller.handle_output_report(&hex_packet("80 04"));
+    assert!(no_timeout.replies.is_empty());
+    assert!(controller.is_streaming());
+
+    let device_info =
+        controller.handle_output_report(&hex_packet("01 00 00 01 40 40 00 01 40 40 02"));
+    let reply = device_info.replies[0];
+    assert_eq!(reply[0], 0x21);
+    // A pressed while answering
+    assert_eq!(reply[3], 0x08);
+    assert_eq!(reply[13..15], [0x82, 0x02]);
+    assert_eq!(reply[15..19], [0x03, 0x48, 0x03, 0x02]);
+    assert_eq!(reply[19..25], controller.mac);
+
+    let spi = controller.handle_output_report(&hex_packet(
+        "01 01 00 01 40 40 00 01 40 40 10 3d 60 00 00 12",
+    ));
+    let reply = spi.replies[0];
+    assert_eq!(reply[13..15], [0x90, 0x10]);
+    assert_eq!(reply[15..20], [0x3d, 0x60, 0x00, 0x00, 0x12]);
+    assert_eq!(reply[20..38], STICK_CALIBRATION);
+    // No user calibration
+    assert_eq!(spi_read(0x8010, 2), vec![0xff, 0xff]);
+    assert_eq!(spi_read(0x6050, 3), vec![0x32, 0x32, 0x32]);
+
+    let imu = controller.handle_output_report(&hex_packet("01 02 00 01 40 40 00 01 40 40 40 01"));
+    assert_eq!(imu.replies[0][13..15], [0x80, 0x40]);
+    assert!(controller.imu_enabled);
+    controller.handle_output_report(&hex_packet("01 03 00 01 40 40 00 01 40 40 30 01"));
+    assert_eq!(controller.player_lights, 0x01);
+
+    // Rumble is ignored until vibration gets enabled.
+    let rumble = hex_packet("10 04 00 c9 40 72 00 01 40 40");
+    assert!(controller.handle_output_report(&rumble).feedback.is_empty());
+    controller.handle_output_report(&hex_packet("01 05 00 01 40 40 00 01 40 40 48 01"));
+    assert_eq!(
+        controller.handle_output_report(&rumble).feedback,
+        vec![Feedback::Rumble {
+            strong: 1.0,
+            weak: 0.0
+        }]
+    );
+
+    assert!(controller
+        .handle_output_report(&hex_packet("80 05"))
+        .replies
+        .is_empty());
+    assert!(!controller.is_streaming());
+    assert!(controller.handle_output_report(&[]).replies.is_empty());
+}
+
+#[test]
+fn test_switch_pro_input_report() {
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::Mode, true);
+    gamepad.set_button(GamepadButton::DPadLeft, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.motion = Some(MotionState {
+        accel: (0.0, 1.0, 0.0),
+        gyro: (0.0, 0.0, 0.0),
+    });
+    gamepad.power = Some(PowerState::Charging(40));
+
+    let mut controller = SwitchProController::new();
+    controller.imu_enabled = true;
+    controller.state.update_from_gamepad(&gamepad);
+    let report = controller.next_input_report();
+    assert_eq!(report[0], 0x30);
+    assert_eq!(report[1], 1);
+    assert_eq!(report[2], 0x51);
+    // B and ZR
+    assert_eq!(report[3], 0x84);
+    assert_eq!(report[4], 0x10);
+    assert_eq!(report[5], 0x08);
+    // Full right, full down
+    assert_eq!(report[6..9], [0xff, 0x0f, 0x00]);
+    // Flat on the table, z is up in all three frames
+    for frame in 0..3 {
+        let z = 13 + frame * 12 + 4;
+        assert_eq!(i16::from_le_bytes([report[z], report[z + 1]]), 4096);
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+use crate::dashboard;
+
+use super::raw_gadget::{
+    descriptors_of_type, standard_reply, ControlReply, ControlRequest, GadgetDescriptors,
+    GadgetFunction, GadgetReport, HID_REQ_SET_REPORT, USB_DT_INTERFACE, USB_REQ_SET_CONFIGURATION,
+    USB_TYPE_CLASS, USB_TYPE_STANDARD,
+};
+
+// USB/IP server exporting a gadget's descriptors and endpoints over TCP, for hosts that attach
+// it with `usbip attach` (Linux vhci-hcd) or usbip-win instead of a cable. Everything on the wire
+// is big endian, see Documentation/usb/usbip_protocol.rst in the kernel.
+
+pub const USBIP_DEFAULT_PORT: u16 = 3240;
+pub const USBIP_BUS_ID: &str = "1-1";
+
+const USBIP_VERSION: u16 = 0x0111;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+
+const USBIP_CMD_SUBMIT: u32 = 0x01;
+const USBIP_CMD_UNLINK: u32 = 0x02;
+const USBIP_RET_SUBMIT: u32 = 0x03;
+const USBIP_RET_UNLINK: u32 = 0x04;
+const USBIP_DIR_IN: u32 = 1;
+// Far above what a pad transfers, the peer's length isn't trusted with more memory than this.
+const MAX_TRANSFER_LEN: usize = 64 * 1024;
+
+const USB_REQ_SET_ADDRESS: u8 = 0x05;
+const USB_SPEED_FULL: u32 = 2;
+const EPIPE: i32 = 32;
+const ECONNRESET: i32 = 104;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct UsbIpConfig {
+    pub listen: String,
+}
+
+impl Default for UsbIpConfig {
+    fn default() -> Self {
+        UsbIpConfig {
+            listen: format!("0.0.0.0:{}", USBIP_DEFAULT_PORT),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum UsbIpCommand {
+    Submit {
+        seqnum: u32,
+        direction: u32,
+        endpoint: u8,
+        length: usize,
+        setup: [u8; 8],
+        // Only for OUT transfers
+        data: Vec<u8>,
+    },
+    Unlink {
+        seqnum: u32,
+        victim: u32,
+    },
+}
+
+async fn read_command<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<UsbIpCommand> {
+    let mut header = [0u8; 48];
+    reader.read_exact(&mut header).await?;
+    let u32_at =
+        |i: usize| u32::from_be_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    match u32_at(0) {
+        USBIP_CMD_SUBMIT => {
+            let direction = u32_at(12);
+            let length = u32_at(24) as usize;
+            if length > MAX_TRANSFER_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("USB/IP transfer of {} bytes is too long", length),
+                ));
+            }
+            let mut data = vec![];
+            if direction != USBIP_DIR_IN && length > 0 {
+                data = vec![0u8; length];
+                reader.read_exact(&mut data).await?;
+            }
+            let mut setup = [0u8; 8];
+            setup.copy_from_slice(&header[40..48]);
+            Ok(UsbIpCommand::Submit {
+                seqnum: u32_at(4),
+                direction,
+                endpoint: u32_at(16) as u8,
+                length,
+                setup,
+                data,
+            })
+        }
+        USBIP_CMD_UNLINK => Ok(UsbIpCommand::Unlink {
+            seqnum: u32_at(4),
+            victim: u32_at(20),
+        }),
+        command => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unknown USB/IP command {:#x}", command),
+        )),
+    }
+}
+
+fn ret_submit(seqnum: u32, status: i32, actual_length: usize, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![];
+    for field in [USBIP_RET_SUBMIT, seqnum, 0, 0, 0] {
+        packet.extend(field.to_be_bytes());
+    }
+    // Status, actual length, start frame, number of packets, error count
+    for field in [status, actual_length as i32, 0, 0, 0] {
+        packet.extend(field.to_be_bytes());
+    }
+    packet.extend([0; 8]);
+    packet.extend_from_slice(data);
+    packet
+}
+
+fn ret_unlink(seqnum: u32, status: i32) -> Vec<u8> {
+    let mut packet = vec![];
+    for field in [USBIP_RET_UNLINK, seqnum, 0, 0, 0] {
+        packet.extend(field.to_be_bytes());
+    }
+    packet.extend(status.to_be_bytes());
+    packet.extend([0; 24]);
+    packet
+}
+
+fn op_header(code: u16, status: u32) -> Vec<u8> {
+    let mut header = USBIP_VERSION.to_be_bytes().to_vec();
+    header.extend(code.to_be_bytes());
+    header.extend(status.to_be_bytes());
+    header
+}
+
+// The device as `usbip list` shows it, with the interface list for OP_REP_DEVLIST.
+fn device_info(descriptors: &GadgetDescriptors, with_interfaces: bool) -> Vec<u8> {
+    let device = &descriptors.device;
+    let configuration = &descriptors.configuration;
+    let mut info = vec![0u8; 256 + 32];
+    let path = format!("/sys/devices/cursed_controls/usb1/{}", USBIP_BUS_ID);
+    info[..path.len()].copy_from_slice(path.as_bytes());
+    info[256..256 + USBIP_BUS_ID.len()].copy_from_slice(USBIP_BUS_ID.as_bytes());
+    for field in [1u32, 1, USB_SPEED_FULL] {
+        info.extend(field.to_be_bytes());
+    }
+    // Vendor, product and device release, little endian in the descriptor
+    for i in [8, 10, 12] {
+        info.extend([device[i + 1], device[i]]);
+    }
+    info.extend(&device[4..7]);
+    info.extend([configuration[5], device[17], configuration[4]]);
+    if with_interfaces {
+        for interface in descriptors_of_type(configuration, USB_DT_INTERFACE) {
+            info.extend([interface[5], interface[6], interface[7], 0]);
+        }
+    }
+    info
+}
+
+struct ExportShared {
+    descriptors: GadgetDescriptors,
+    // Only one host can have the device attached at a time.
+    function: tokio::sync::Mutex<Box<dyn GadgetFunction>>,
+    // Latest report per IN endpoint
+    reports: watch::Sender<HashMap<u8, Vec<u8>>>,
+    received: mpsc::UnboundedSender<GadgetReport>,
+    configured: AtomicBool,
+}
+
+// A pending IN transfer on an interrupt endpoint.
+struct PendingIn {
+    seqnum: u32,
+    endpoint: u8,
+    length: usize,
+}
+
+struct Attachment<'a> {
+    shared: &'a ExportShared,
+    function: &'a mut dyn GadgetFunction,
+    configuration: u8,
+    pending: VecDeque<PendingIn>,
+    // Last report sent per endpoint, IN transfers wait until there's something new.
+    sent: HashMap<u8, Vec<u8>>,
+}
+
+impl Attachment<'_> {
+    // Data for IN requests, or a negative errno.
+    fn control(&mut self, setup: &[u8; 8], data: &[u8]) -> Result<Vec<u8>, i32> {
+        let request = ControlRequest::from_bytes(setup);
+        let standard = request.kind() == USB_TYPE_STANDARD;
+        let reply = if standard && request.request == USB_REQ_SET_CONFIGURATION {
+            self.configuration = request.value as u8;
+            self.shared
+                .configured
+                .store(self.configuration != 0, Ordering::SeqCst);
+            ControlReply::Ack
+        } else if standard && request.request == USB_REQ_SET_ADDRESS {
+            ControlReply::Ack
+        } else if let Some(reply) =
+            standard_reply(&self.shared.descriptors, &request, self.configuration)
+        {
+            reply
+        } else {
+            if request.kind() == USB_TYPE_CLASS && request.request == HID_REQ_SET_REPORT {
+                let _ = self.shared.received.send(GadgetReport {
+                    endpoint: 0,
+                    data: data.to_vec(),
+                });
+            }
+            self.function.control(&request, data)
+        };
+        match reply {
+            ControlReply::Data(mut reply) => {
+                reply.truncate(request.length as usize);
+                Ok(reply)
+            }
+            ControlReply::Ack => Ok(vec![]),
+            ControlReply::Stall => Err(-EPIPE),
+        }
+    }
+
+    // Replies to send right away.
+    fn handle_command(&mut self, command: UsbIpCommand) -> Vec<Vec<u8>> {
+        match command {
+            UsbIpCommand::Submit {
+                seqnum,
+                endpoint: 0,
+                length,
+                setup,
+                data,
+                ..
+            } => match self.control(&setup, &data) {
+                Ok(reply) if setup[0] & 0x80 != 0 => {
+                    let reply = &reply[..reply.len().min(length)];
+                    vec![ret_submit(seqnum, 0, reply.len(), reply)]
+                }
+                Ok(_) => vec![ret_submit(seqnum, 0, data.len(), &[])],
+                Err(status) => vec![ret_submit(seqnum, status, 0, &[])],
+            },
+            UsbIpCommand::Submit {
+                seqnum,
+                direction: USBIP_DIR_IN,
+                endpoint,
+                length,
+                ..
+            } => {
+                self.pending.push_back(PendingIn {
+                    seqnum,
+                    endpoint: endpoint | 0x80,
+                    length,
+                });
+                vec![]
+            }
+            UsbIpCommand::Submit {
+                seqnum,
+                endpoint,
+                data,
+                ..
+            } => {
+                let length = data.len();
+                let _ = self.shared.received.send(GadgetReport { endpoint, data });
+                vec![ret_submit(seqnum, 0, length, &[])]
+            }
+            UsbIpCommand::Unlink { seqnum, victim } => {
+                match self.pending.iter().position(|urb| urb.seqnum == victim) {
+                    Some(i) => {
+                        self.pending.remove(i);
+                        vec![ret_unlink(seqnum, -ECONNRESET)]
+                    }
+                    // Already completed
+                    None => vec![ret_unlink(seqnum, 0)],
+                }
+            }
+        }
+    }
+
+    // Completes pending IN transfers that have a new report.
+    fn complete_pending(&mut self, reports: &HashMap<u8, Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut replies = vec![];
+        let mut i = 0;
+        while i < self.pending.len() {
+            let endpoint = self.pending[i].endpoint;
+            match reports.get(&endpoint) {
+                Some(report) if self.sent.get(&endpoint) != Some(report) => {
+                    let urb = self.pending.remove(i).unwrap();
+                    let data = &report[..report.len().min(urb.length)];
+                    replies.push(ret_submit(urb.seqnum, 0, data.len(), data));
+                    self.sent.insert(endpoint, report.clone());
+                }
+                _ => i += 1,
+            }
+        }
+        replies
+    }
+}
+
+async fn serve_attached(
+    stream: TcpStream,
+    shared: &ExportShared,
+    function: &mut dyn GadgetFunction,
+) -> std::io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    // read_exact isn't cancel safe, so commands are read on their own task.
+    let (commands_sender, mut commands) = mpsc::unbounded_channel();
+    let reader_task = tokio::spawn(async move {
+        while let Ok(command) = read_command(&mut reader).await {
+            if commands_sender.send(command).is_err() {
+                break;
+            }
+        }
+    });
+    let mut reports = shared.reports.subscribe();
+    let mut attachment = Attachment {
+        shared,
+        function,
+        configuration: 0,
+        pending: VecDeque::new(),
+        sent: HashMap::new(),
+    };
+
+    let result = 'serve: loop {
+        let latest = reports.borrow_and_update().clone();
+        let mut replies = attachment.complete_pending(&latest);
+        if replies.is_empty() {
+            replies = tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => attachment.handle_command(command),
+                    None => break 'serve Ok(()),
+                },
+                changed = reports.changed() => match changed {
+                    Ok(()) => vec![],
+                    Err(_) => break 'serve Ok(()),
+                },
+            };
+        }
+        for reply in replies {
+            if let Err(err) = writer.write_all(&reply).await {
+                break 'serve Err(err);
+            }
+        }
+    };
+    reader_task.abort();
+    shared.configured.store(false, Ordering::SeqCst);
+    result
+}
+
+async fn serve_connection(mut stream: TcpStream, shared: Arc<ExportShared>) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    match u16::from_be_bytes([header[2], header[3]]) {
+        OP_REQ_DEVLIST => {
+            let mut reply = op_header(OP_REP_DEVLIST, 0);
+            reply.extend(1u32.to_be_bytes());
+            reply.extend(device_info(&shared.descriptors, true));
+            stream.write_all(&reply).await
+        }
+        OP_REQ_IMPORT => {
+            let mut bus_id = [0u8; 32];
+            stream.read_exact(&mut bus_id).await?;
+            let requested = String::from_utf8_lossy(&bus_id);
+            let mut function = match shared.function.try_lock() {
+                Ok(function) if requested.trim_end_matches('\0') == USBIP_BUS_ID => function,
+                _ => return stream.write_all(&op_header(OP_REP_IMPORT, 1)).await,
+            };
+            let mut reply = op_header(OP_REP_IMPORT, 0);
+            reply.extend(device_info(&shared.descriptors, false));
+            stream.write_all(&reply).await?;
+            log!("USB/IP: attached by {}", peer);
+            let result = serve_attached(stream, &shared, function.as_mut()).await;
+            log!("USB/IP: detached by {}", peer);
+            result
+        }
+        _ => Ok(()),
+    }
+}
+
+// A gadget served over USB/IP, used like `RawGadget`.
+pub struct UsbIpExport {
+    shared: Arc<ExportShared>,
+    received: Mutex<mpsc::UnboundedReceiver<GadgetReport>>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl UsbIpExport {
+    // Needs to be called from within the tokio runtime.
+    pub fn start(
+        config: &UsbIpConfig,
+        descriptors: GadgetDescriptors,
+        function: Box<dyn GadgetFunction>,
+    ) -> Result<UsbIpExport, String> {
+        let listener = StdTcpListener::bind(&config.listen)
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .map_err(|err| format!("Could not listen on {}: {}", config.listen, err))?;
+        let local_addr = listener.local_addr().ok();
+        let (received_sender, received) = mpsc::unbounded_channel();
+        let shared = Arc::new(ExportShared {
+            descriptors,
+            function: tokio::sync::Mutex::new(function),
+            reports: watch::Sender::new(HashMap::new()),
+            received: received_sender,
+            configured: AtomicBool::new(false),
+        });
+        let accept_shared = shared.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let shared = accept_shared.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve_connection(stream, shared).await {
+                        log_error!("USB/IP: {}", err);
+                    }
+                });
+            }
+        });
+        let export = UsbIpExport {
+            shared,
+            received: Mutex::new(received),
+            local_addr,
+        };
+        // The port it got when the config leaves it to the OS.
+        let address = export.local_addr();
+        log!(
+            "USB/IP: exporting bus id {} on {}, attach with `usbip attach -r <host> -b {}`",
+            USBIP_BUS_ID,
+            address.map_or(config.listen.clone(), |address| address.to_string()),
+            USBIP_BUS_ID
+        );
+        Ok(export)
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.shared.configured.load(Ordering::SeqCst)
+    }
+
+    // Never blocks, the host gets the newest report with its next poll.
+    pub fn send(&self, endpoint: u8, data: &[u8]) {
+        dashboard::sent("usbip", endpoint, data);
+        self.shared.reports.send_modify(|reports| {
+            reports.insert(endpoint, data.to_vec());
+        });
+    }
+
+    pub fn try_recv(&self) -> Option<GadgetReport> {
+        self.received.lock().unwrap().try_recv().ok()
+    }
+}
+
+#[tokio::test]
+async fn test_usbip_transfer_limit() {
+    let mut header = [0u8; 48];
+    header[..4].copy_from_slice(&USBIP_CMD_SUBMIT.to_be_bytes());
+    header[24..28].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(read_command(&mut &header[..]).await.is_err());
+
+    header[24..28].copy_from_slice(&4u32.to_be_bytes());
+    let mut packet = header.to_vec();
+    packet.extend([1, 2, 3, 4]);
+    match read_command(&mut &packet[..]).await.unwrap() {
+        UsbIpCommand::Submit { length, data, .. } => {
+            assert_eq!(length, 4);
+            assert_eq!(data, [1, 2, 3, 4]);
+        }
+        UsbIpCommand::Unlink { .. } => panic!("expected a submit"),
+    }
+}
+
+#[tokio::test]
+async fn test_usbip_attach() {
+    use super::x360::{parse_x360_output, x360_descriptors, X360Function, X360_IN_ENDPOINT};
+    use crate::controller_abs::Feedback;
+
+    async fn submit(
+        stream: &mut TcpStream,
+        seqnum: u32,
+        dir: u32,
+        ep: u32,
+        length: u32,
+        setup: [u8; 8],
+        data: &[u8],
+    ) {
+        let mut packet = vec![];
+        for field in [
+            USBIP_CMD_SUBMIT,
+            seqnum,
+            0x10002,
+            dir,
+            ep,
+            0,
+            length,
+            0,
+            0,
+            0,
+        ] {
+            packet.extend(field.to_be_bytes());
+        }
+        packet.extend(setup);
+        packet.extend_from_slice(data);
+        stream.write_all(&packet).await.unwrap();
+    }
+    // (seqnum, status, data)
+    async fn reply(stream: &mut TcpStream) -> (u32, i32, Vec<u8>) {
+        let mut header = [0u8; 48];
+        stream.read_exact(&mut header).await.unwrap();
+        let i32_at =
+            |i: usize| i32::from_be_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        // Replies don't say the direction, the tests use odd sequence numbers for IN transfers.
+        let mut data = vec![];
+        if i32_at(0) == USBIP_RET_SUBMIT as i32 && header[7] % 2 == 1 {
+            data = vec![0u8; i32_at(24) as usize];
+            stream.read_exact(&mut data).await.unwrap();
+        }
+        (i32_at(4) as u32, i32_at(20), data)
+    }
+
+    let export = UsbIpExport::start(
+        &UsbIpConfig {
+            listen: "127.0.0.1:0".to_string(),
+        },
+        x360_descriptors(),
+        Box::new(X360Function),
+    )
+    .unwrap();
+    let address = export.local_addr().unwrap();
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(&op_header(OP_REQ_DEVLIST, 0))
+        .await
+        .unwrap();
+    let mut devlist = vec![];
+    stream.read_to_end(&mut devlist).await.unwrap();
+    assert_eq!(devlist[..4], [0x01, 0x11, 0x00, 0x05]);
+    assert_eq!(devlist[12 + 256..12 + 259], *b"1-1");
+    assert_eq!(devlist[12 + 300..12 + 304], [0x04, 0x5e, 0x02, 0x8e]);
+    // Vendor class pad interface
+    assert_eq!(devlist[12 + 312..], [0xff, 0x5d, 0x01, 0x00]);
+
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let mut import = op_header(OP_REQ_IMPORT, 0);
+    let mut bus_id = [0u8; 32];
+    bus_id[..3].copy_from_slice(b"1-1");
+    import.extend(bus_id);
+    stream.write_all(&import).await.unwrap();
+    let mut imported = [0u8; 8 + 312];
+    stream.read_exact(&mut imported).await.unwrap();
+    assert_eq!(imported[..8], [0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0]);
+
+    submit(
+        &mut stream,
+        1,
+        USBIP_DIR_IN,
+        0,
+        18,
+        [0x80, 0x06, 0x00, 0x01, 0, 0, 18, 0],
+        &[],
+    )
+    .await;
+    let (seqnum, status, device) = reply(&mut stream).await;
+    assert_eq!((seqnum, status), (1, 0));
+    assert_eq!(device, x360_descriptors().device);
+    assert!(!export.is_configured());
+    submit(
+        &mut stream,
+        2,
+        0,
+        0,
+        0,
+        [0x00, 0x09, 0x01, 0x00, 0, 0, 0, 0],
+        &[],
+    )
+    .await;
+    assert_eq!(reply(&mut stream).await, (2, 0, vec![]));
+    assert!(export.is_configured());
+
+    // The IN transfer waits for a report.
+    let mut packet = [0u8; 20];
+    packet[1] = 0x14;
+    packet[2] = 0x10;
+    submit(&mut stream, 3, USBIP_DIR_IN, 1, 32, [0; 8], &[]).await;
+    export.send(X360_IN_ENDPOINT, &packet);
+    assert_eq!(reply(&mut stream).await, (3, 0, packet.to_vec()));
+
+    let rumble = [0x00, 0x08, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00];
+    submit(&mut stream, 4, 0, 1, 8, [0; 8], &rumble).await;
+    assert_eq!(reply(&mut stream).await, (4, 0, vec![]));
+    let report = export.try_recv().unwrap();
+    assert_eq!(report.endpoint, 1);
+    assert_eq!(
+        parse_x360_output(&report.data),
+        Some(Feedback::Rumble {
+            strong: 1.0,
+            weak: 0.0
+        })
+    );
+
+    // Nothing new to report, so this one stays pending until it's unlinked.
+    submit(&mut stream, 5, USBIP_DIR_IN, 1, 32, [0; 8], &[]).await;
+    let mut unlink = vec![];
+    for field in [USBIP_CMD_UNLINK, 6, 0x10002, 0, 0, 5, 0, 0, 0, 0, 0, 0] {
+        unlink.extend(field.to_be_bytes());
+    }
+    stream.write_all(&unlink).await.unwrap();
+    assert_eq!(reply(&mut stream).await, (6, -ECONNRESET, vec![]));
+
+    // One host at a time
+    let mut second = TcpStream::connect(address).await.unwrap();
+    second.write_all(&import).await.unwrap();
+    let mut refused = [0u8; 8];
+    second.read_exact(&mut refused).await.unwrap();
+    assert_eq!(refused, [0x01, 0x11, 0x00, 0x03, 0, 0, 0, 1]);
+}
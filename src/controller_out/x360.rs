@@ -1,10 +1,63 @@
 use crate::{
     controller_abs::{
-        Axis, BitPackedButton, BitPackedButtons, Gamepad, GamepadAxis, GamepadButton, JoystickState,
+        Axis, BitPackedButton, BitPackedButtons, Feedback, Gamepad, GamepadAxis, GamepadButton,
+        JoystickState,
     },
     AxisNew, JoystickStateNew,
 };
-use std::{u8, vec};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, interface_descriptor,
+    GadgetDescriptors, GadgetFunction, USB_ENDPOINT_XFER_INT,
+};
+
+pub const X360_VENDOR_ID: u16 = 0x045e;
+pub const X360_PRODUCT_ID: u16 = 0x028e;
+pub const X360_IN_ENDPOINT: u8 = 0x81;
+pub const X360_OUT_ENDPOINT: u8 = 0x01;
+
+// The wired pad's gamepad interface, for exports that don't go through the 360 gadget library.
+// Real pads add audio, plug-in module and security interfaces, xpad doesn't need those but
+// Windows and consoles do.
+pub fn x360_descriptors() -> GadgetDescriptors {
+    let gamepad_class = (0xff, 0x5d, 0x01);
+    GadgetDescriptors {
+        device: device_descriptor(X360_VENDOR_ID, X360_PRODUCT_ID, 0x0114, (0xff, 0xff, 0xff), 8),
+        configuration: configuration_descriptor(&[[
+            interface_descriptor(0, 2, gamepad_class),
+            // Undocumented, xusb22 wants it
+            vec![
+                0x11, 0x21, 0x00, 0x01, 0x01, 0x25, 0x81, 0x14, 0x00, 0x00, 0x00, 0x00, 0x13, 0x01,
+                0x08, 0x00, 0x00,
+            ],
+            endpoint_descriptor(X360_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 32, 4),
+            endpoint_descriptor(X360_OUT_ENDPOINT, USB_ENDPOINT_XFER_INT, 32, 8),
+        ]
+        .concat()]),
+        strings: vec![
+            "\u{a9}Microsoft Corporation".to_string(),
+            "Controller".to_string(),
+            "08FEC93".to_string(),
+        ],
+        hid_reports: vec![vec![]],
+    }
+}
+
+// Vendor class, everything comes through the interrupt endpoints.
+pub struct X360Function;
+
+impl GadgetFunction for X360Function {}
+
+// Rumble from an OUT report, the LED report (type 0x01) is ignored.
+pub fn parse_x360_output(data: &[u8]) -> Option<Feedback> {
+    if data.len() < 5 || data[0] != 0x00 || data[1] != 0x08 {
+        return None;
+    }
+    Some(Feedback::Rumble {
+        strong: data[3] as f64 / 255.0,
+        weak: data[4] as f64 / 255.0,
+    })
+}
 
 pub struct XboxButtonState {
     pub a: BitPackedButton,
@@ -27,25 +80,25 @@ pub struct XboxButtonState {
 impl XboxButtonState {
     pub fn new() -> XboxButtonState {
         XboxButtonState {
-            a: BitPackedButton::new("A".to_string(), 0x04),
-            b: BitPackedButton::new("B".to_string(), 0x05),
-            x: BitPackedButton::new("X".to_string(), 0x06),
-            y: BitPackedButton::new("Y".to_string(), 0x07),
-            lb: BitPackedButton::new("LB".to_string(), 0x00),
-            rb: BitPackedButton::new("RB".to_string(), 0x01),
+            a: BitPackedButton::new(0x04),
+            b: BitPackedButton::new(0x05),
+            x: BitPackedButton::new(0x06),
+            y: BitPackedButton::new(0x07),
+            lb: BitPackedButton::new(0x00),
+            rb: BitPackedButton::new(0x01),
             // Joystick buttons
-            l3: BitPackedButton::new("L3".to_string(), 0x06),
-            r3: BitPackedButton::new("R3".to_string(), 0x07),
+            l3: BitPackedButton::new(0x06),
+            r3: BitPackedButton::new(0x07),
 
-            start: BitPackedButton::new("START".to_string(), 0x04),
-            options: BitPackedButton::new("OPTIONS".to_string(), 0x05),
-            xbox: BitPackedButton::new("XBOX".to_string(), 0x02),
+            start: BitPackedButton::new(0x04),
+            options: BitPackedButton::new(0x05),
+            xbox: BitPackedButton::new(0x02),
 
             // Dpad
-            dpad_up: BitPackedButton::new("DPAD_UP".to_string(), 0x00),
-            dpad_down: BitPackedButton::new("DPAD_DOWN".to_string(), 0x01),
-            dpad_left: BitPackedButton::new("DPAD_LEFT".to_string(), 0x02),
-            dpad_right: BitPackedButton::new("DPAD_RIGHT".to_string(), 0x03),
+            dpad_up: BitPackedButton::new(0x00),
+            dpad_down: BitPackedButton::new(0x01),
+            dpad_left: BitPackedButton::new(0x02),
+            dpad_right: BitPackedButton::new(0x03),
         }
     }
 
@@ -100,6 +153,7 @@ impl XboxControllerState {
         }
     }
 
+    // The wired 360 report has no battery field, so `gamepad.power` isn't forwarded here.
     pub fn update_from_gamepad(&mut self, gamepad: &Gamepad) {
         for (button, button_state) in &gamepad.buttons {
             let val = button_state.to_owned();
@@ -119,6 +173,8 @@ impl XboxControllerState {
                 GamepadButton::DPadDown => self.buttons.dpad_down.value = val,
                 GamepadButton::DPadLeft => self.buttons.dpad_left.value = val,
                 GamepadButton::DPadRight => self.buttons.dpad_right.value = val,
+                // The 360 pad has no share button
+                GamepadButton::Share => {}
             }
         }
         for (gamepad_axis, axis) in &gamepad.axes {
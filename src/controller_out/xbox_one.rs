@@ -0,0 +1,674 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    controller_abs::{
+        Axis, BitPackedButton, BitPackedButtons, Feedback, Gamepad, GamepadAxis, GamepadButton,
+        JoystickState, PowerState,
+    },
+    AxisNew, JoystickStateNew,
+};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, interface_descriptor,
+    GadgetDescriptors, GadgetFunction, USB_ENDPOINT_XFER_INT,
+};
+
+// Gaming Input Protocol, as spoken by Xbox One and Series pads. Layouts follow the Linux xpad
+// and xone drivers.
+
+pub const XBOX_ONE_VENDOR_ID: u16 = 0x045e;
+// Series X|S controller, the one with a share button
+pub const XBOX_ONE_PRODUCT_ID: u16 = 0x0b12;
+pub const XBOX_ONE_IN_ENDPOINT: u8 = 0x82;
+pub const XBOX_ONE_OUT_ENDPOINT: u8 = 0x02;
+
+const GIP_CMD_ACK: u8 = 0x01;
+const GIP_CMD_ANNOUNCE: u8 = 0x02;
+const GIP_CMD_STATUS: u8 = 0x03;
+const GIP_CMD_IDENTIFY: u8 = 0x04;
+const GIP_CMD_POWER: u8 = 0x05;
+const GIP_CMD_GUIDE_BUTTON: u8 = 0x07;
+const GIP_CMD_RUMBLE: u8 = 0x09;
+const GIP_CMD_INPUT: u8 = 0x20;
+
+const GIP_OPT_ACK: u8 = 0x10;
+const GIP_OPT_INTERNAL: u8 = 0x20;
+const GIP_OPT_CHUNK_START: u8 = 0x40;
+const GIP_OPT_CHUNK: u8 = 0x80;
+
+const GIP_POWER_ON: u8 = 0x00;
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+// Payload bytes per identify chunk, keeps packets under 64 bytes.
+const MAX_CHUNK: usize = 56;
+// Base report plus the Series X|S extension
+const INPUT_PAYLOAD_LEN: usize = 44;
+const SHARE_OFFSET: usize = 18;
+
+const GAMEPAD_CLASSES: [&str; 2] = [
+    "Windows.Xbox.Input.Gamepad",
+    "Windows.Xbox.Input.NavigationController",
+];
+// Gamepad, navigation and system input interfaces.
+const GAMEPAD_INTERFACES: [u128; 3] = [
+    0x082e402c_07df_45e1_a5ab_a3127af197b5,
+    0xb8f31fe7_7386_40e9_a9f8_2f21263acfb7,
+    0x9776ff56_9bfd_4581_ad45_b645bba526d6,
+];
+
+pub fn xbox_one_descriptors() -> GadgetDescriptors {
+    let gip_class = (0xff, 0x47, 0xd0);
+    GadgetDescriptors {
+        device: device_descriptor(
+            XBOX_ONE_VENDOR_ID,
+            XBOX_ONE_PRODUCT_ID,
+            0x0509,
+            gip_class,
+            64,
+        ),
+        configuration: configuration_descriptor(&[[
+            interface_descriptor(0, 2, gip_class),
+            endpoint_descriptor(XBOX_ONE_OUT_ENDPOINT, USB_ENDPOINT_XFER_INT, 64, 4),
+            endpoint_descriptor(XBOX_ONE_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 64, 4),
+        ]
+        .concat()]),
+        strings: vec![
+            "Microsoft".to_string(),
+            "Controller".to_string(),
+            "000000000001".to_string(),
+        ],
+        // Not HID
+        hid_reports: vec![vec![]],
+    }
+}
+
+// Vendor class, there's nothing to answer on ep0.
+pub struct XboxOneFunction;
+
+impl GadgetFunction for XboxOneFunction {}
+
+// LEB128, used for GIP lengths and chunk offsets.
+fn varint(mut value: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for (i, byte) in data.iter().take(4).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+// Command, options and sequence number, then the payload length.
+#[derive(Clone, PartialEq, Debug)]
+pub struct GipPacket {
+    pub command: u8,
+    pub options: u8,
+    pub sequence: u8,
+    pub payload: Vec<u8>,
+}
+
+impl GipPacket {
+    pub fn parse(data: &[u8]) -> Option<GipPacket> {
+        let (length, used) = read_varint(data.get(3..)?)?;
+        let start = 3 + used;
+        Some(GipPacket {
+            command: data[0],
+            options: data[1],
+            sequence: data[2],
+            payload: data.get(start..start + length)?.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.command, self.options, self.sequence];
+        bytes.extend(varint(self.payload.len()));
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+// Capabilities the host reads before talking to the pad, sent in chunks.
+pub fn identify_descriptor() -> Vec<u8> {
+    let mut blocks: Vec<Vec<u8>> = vec![
+        // External commands
+        vec![0],
+        // Firmware version 5.9
+        vec![1, 0x05, 0x00, 0x09, 0x00],
+        // Audio formats, capabilities out and in
+        vec![0],
+        vec![0],
+        vec![0],
+    ];
+    let mut classes = vec![GAMEPAD_CLASSES.len() as u8];
+    for class in GAMEPAD_CLASSES {
+        classes.extend((class.len() as u16).to_le_bytes());
+        classes.extend(class.as_bytes());
+    }
+    blocks.push(classes);
+    let mut interfaces = vec![GAMEPAD_INTERFACES.len() as u8];
+    for guid in GAMEPAD_INTERFACES {
+        // Windows GUID layout, the first three fields little endian
+        let bytes = guid.to_be_bytes();
+        interfaces.extend(bytes[0..4].iter().rev());
+        interfaces.extend(bytes[4..6].iter().rev());
+        interfaces.extend(bytes[6..8].iter().rev());
+        interfaces.extend(&bytes[8..]);
+    }
+    blocks.push(interfaces);
+
+    // 16 unknown bytes, then an offset per block and one for the (missing) HID descriptor.
+    let header_length = 16 + (blocks.len() + 1) * 2;
+    let mut descriptor = vec![0u8; 16];
+    let mut offset = header_length;
+    for block in blocks.iter() {
+        descriptor.extend((offset as u16).to_le_bytes());
+        offset += block.len();
+    }
+    descriptor.extend(0u16.to_le_bytes());
+    descriptor.extend(blocks.concat());
+    descriptor
+}
+
+pub struct XboxOneButtonState {
+    pub menu: BitPackedButton,
+    pub view: BitPackedButton,
+    pub a: BitPackedButton,
+    pub b: BitPackedButton,
+    pub x: BitPackedButton,
+    pub y: BitPackedButton,
+    pub dpad_up: BitPackedButton,
+    pub dpad_down: BitPackedButton,
+    pub dpad_left: BitPackedButton,
+    pub dpad_right: BitPackedButton,
+    pub lb: BitPackedButton,
+    pub rb: BitPackedButton,
+    pub l3: BitPackedButton,
+    pub r3: BitPackedButton,
+    // Sent as its own GIP command
+    pub guide: bool,
+    pub share: bool,
+}
+
+impl XboxOneButtonState {
+    pub fn new() -> XboxOneButtonState {
+        XboxOneButtonState {
+            menu: BitPackedButton::new(0x02),
+            view: BitPackedButton::new(0x03),
+            a: BitPackedButton::new(0x04),
+            b: BitPackedButton::new(0x05),
+            x: BitPackedButton::new(0x06),
+            y: BitPackedButton::new(0x07),
+
+            dpad_up: BitPackedButton::new(0x00),
+            dpad_down: BitPackedButton::new(0x01),
+            dpad_left: BitPackedButton::new(0x02),
+            dpad_right: BitPackedButton::new(0x03),
+            lb: BitPackedButton::new(0x04),
+            rb: BitPackedButton::new(0x05),
+            l3: BitPackedButton::new(0x06),
+            r3: BitPackedButton::new(0x07),
+            guide: false,
+            share: false,
+        }
+    }
+
+    pub fn get_control_byte_0(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.menu.clone(),
+                self.view.clone(),
+                self.a.clone(),
+                self.b.clone(),
+                self.x.clone(),
+                self.y.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+
+    pub fn get_control_byte_1(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.dpad_up.clone(),
+                self.dpad_down.clone(),
+                self.dpad_left.clone(),
+                self.dpad_right.clone(),
+                self.lb.clone(),
+                self.rb.clone(),
+                self.l3.clone(),
+                self.r3.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+}
+
+pub struct XboxOneControllerState {
+    pub buttons: XboxOneButtonState,
+    // 10 bit, unlike the 16 bit sticks
+    pub left_trigger: Axis,
+    pub right_trigger: Axis,
+    pub left_joystick: JoystickState,
+    pub right_joystick: JoystickState,
+    pub power: Option<PowerState>,
+}
+
+impl XboxOneControllerState {
+    pub fn new() -> XboxOneControllerState {
+        XboxOneControllerState {
+            buttons: XboxOneButtonState::new(),
+            left_trigger: AxisNew!(u8::MIN),
+            right_trigger: AxisNew!(u8::MIN),
+            left_joystick: JoystickStateNew!(i16, 0),
+            right_joystick: JoystickStateNew!(i16, 0),
+            power: None,
+        }
+    }
+
+    pub fn update_from_gamepad(&mut self, gamepad: &Gamepad) {
+        for (button, button_state) in &gamepad.buttons {
+            let val = button_state.to_owned();
+            match button {
+                GamepadButton::North => self.buttons.y.value = val,
+                GamepadButton::East => self.buttons.b.value = val,
+                GamepadButton::South => self.buttons.a.value = val,
+                GamepadButton::West => self.buttons.x.value = val,
+                GamepadButton::LeftShoulderButton => self.buttons.lb.value = val,
+                GamepadButton::RightShoulderButton => self.buttons.rb.value = val,
+                GamepadButton::LeftThumb => self.buttons.l3.value = val,
+                GamepadButton::RightThumb => self.buttons.r3.value = val,
+                GamepadButton::Start => self.buttons.menu.value = val,
+                GamepadButton::Select => self.buttons.view.value = val,
+                GamepadButton::Mode => self.buttons.guide = val,
+                GamepadButton::DPadUp => self.buttons.dpad_up.value = val,
+                GamepadButton::DPadDown => self.buttons.dpad_down.value = val,
+                GamepadButton::DPadLeft => self.buttons.dpad_left.value = val,
+                GamepadButton::DPadRight => self.buttons.dpad_right.value = val,
+                GamepadButton::Share => self.buttons.share = val,
+            }
+        }
+        for (gamepad_axis, axis) in &gamepad.axes {
+            match gamepad_axis {
+                GamepadAxis::LeftJoystickX => {
+                    self.left_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::LeftJoystickY => {
+                    self.left_joystick.y.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickX => {
+                    self.right_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickY => {
+                    self.right_joystick.y.value = axis.convert_into(false);
+                }
+                GamepadAxis::LeftTrigger => {
+                    self.left_trigger.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightTrigger => {
+                    self.right_trigger.value = axis.convert_into(false);
+                }
+            }
+        }
+        self.power = gamepad.power;
+    }
+
+    pub fn to_payload(&self) -> [u8; INPUT_PAYLOAD_LEN] {
+        let mut payload = [0u8; INPUT_PAYLOAD_LEN];
+        payload[0] = self.buttons.get_control_byte_0();
+        payload[1] = self.buttons.get_control_byte_1();
+        let trigger = |axis: &Axis| (axis.convert_into::<u16, _>(false) >> 6).to_le_bytes();
+        payload[2..4].copy_from_slice(&trigger(&self.left_trigger));
+        payload[4..6].copy_from_slice(&trigger(&self.right_trigger));
+        let stick = |axis: &Axis| axis.convert_into::<i16, _>(false).to_le_bytes();
+        payload[6..8].copy_from_slice(&stick(&self.left_joystick.x));
+        payload[8..10].copy_from_slice(&stick(&self.left_joystick.y));
+        payload[10..12].copy_from_slice(&stick(&self.right_joystick.x));
+        payload[12..14].copy_from_slice(&stick(&self.right_joystick.y));
+        payload[SHARE_OFFSET] = self.buttons.share as u8;
+        payload
+    }
+
+    // Battery level in bits 0-1 (3 is full), battery type in bits 2-3, 0 meaning wired.
+    fn status_byte(&self) -> u8 {
+        match self.power.and_then(|power| power.level()) {
+            Some(level) => 0x04 | (level.min(99) / 25),
+            None => 0x00,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GipState {
+    // Announcing ourselves until the host powers us on.
+    Announcing,
+    Powered,
+}
+
+// Packets for the host and feedback for the inputs.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct GipResponse {
+    pub packets: Vec<Vec<u8>>,
+    pub feedback: Vec<Feedback>,
+}
+
+// The device side of GIP without any USB, so it can be driven by captured traces.
+pub struct XboxOneController {
+    pub state: XboxOneControllerState,
+    pub gip_state: GipState,
+    pub mac: [u8; 6],
+    sequence: u8,
+    last_announce: Option<Instant>,
+    last_status: Option<Instant>,
+    last_payload: Option<[u8; INPUT_PAYLOAD_LEN]>,
+    guide_sent: bool,
+}
+
+impl XboxOneController {
+    pub fn new() -> XboxOneController {
+        XboxOneController {
+            state: XboxOneControllerState::new(),
+            gip_state: GipState::Announcing,
+            // Locally administered
+            mac: [0x02, 0x5e, 0x0b, 0x12, 0x00, 0x01],
+            sequence: 0,
+            last_announce: None,
+            last_status: None,
+            last_payload: None,
+            guide_sent: false,
+        }
+    }
+
+    // Sequence numbers skip 0.
+    fn next_sequence(&mut self) -> u8 {
+        self.sequence = self.sequence.checked_add(1).unwrap_or(1);
+        self.sequence
+    }
+
+    fn packet(&mut self, command: u8, options: u8, payload: Vec<u8>) -> Vec<u8> {
+        GipPacket {
+            command,
+            options,
+            sequence: self.next_sequence(),
+            payload,
+        }
+        .to_bytes()
+    }
+
+    fn announce(&mut self) -> Vec<u8> {
+        let mut payload = self.mac.to_vec();
+        payload.extend([0x00, 0x00]);
+        payload.extend(XBOX_ONE_VENDOR_ID.to_le_bytes());
+        payload.extend(XBOX_ONE_PRODUCT_ID.to_le_bytes());
+        // Firmware 5.9.2709.0, hardware 1.0.1.0
+        for version in [5u16, 9, 2709, 0, 1, 0, 1, 0] {
+            payload.extend(version.to_le_bytes());
+        }
+        self.packet(GIP_CMD_ANNOUNCE, GIP_OPT_INTERNAL, payload)
+    }
+
+    fn status(&mut self) -> Vec<u8> {
+        let payload = vec![self.state.status_byte(), 0x00, 0x00, 0x00];
+        self.packet(GIP_CMD_STATUS, GIP_OPT_INTERNAL, payload)
+    }
+
+    // Large messages go out in chunks, ending with an empty one holding the total length.
+    fn chunked(&mut self, command: u8, payload: &[u8]) -> Vec<Vec<u8>> {
+        let sequence = self.next_sequence();
+        let mut packets = vec![];
+        for (i, chunk) in payload.chunks(MAX_CHUNK).enumerate() {
+            let offset = i * MAX_CHUNK;
+            let (options, position) = if offset == 0 {
+                (
+                    GIP_OPT_CHUNK | GIP_OPT_CHUNK_START | GIP_OPT_INTERNAL | GIP_OPT_ACK,
+                    payload.len(),
+                )
+            } else {
+                (GIP_OPT_CHUNK | GIP_OPT_INTERNAL, offset)
+            };
+            let mut packet = vec![command, options, sequence];
+            packet.extend(varint(chunk.len()));
+            packet.extend(varint(position));
+            packet.extend_from_slice(chunk);
+            packets.push(packet);
+        }
+        let mut end = vec![command, GIP_OPT_CHUNK | GIP_OPT_INTERNAL, sequence, 0];
+        end.extend(varint(payload.len()));
+        packets.push(end);
+        packets
+    }
+
+    fn ack(packet: &GipPacket) -> Vec<u8> {
+        let length = (packet.payload.len() as u16).to_le_bytes();
+        GipPacket {
+            command: GIP_CMD_ACK,
+            options: GIP_OPT_INTERNAL,
+            sequence: packet.sequence,
+            payload: vec![
+                0x00,
+                packet.command,
+                packet.options & GIP_OPT_INTERNAL,
+                length[0],
+                length[1],
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+            ],
+        }
+        .to_bytes()
+    }
+
+    pub fn handle_packet(&mut self, data: &[u8]) -> GipResponse {
+        let mut response = GipResponse::default();
+        let packet = match GipPacket::parse(data) {
+            Some(packet) => packet,
+            None => return response,
+        };
+        if packet.options & GIP_OPT_ACK != 0 {
+            response.packets.push(Self::ack(&packet));
+        }
+        match packet.command {
+            GIP_CMD_IDENTIFY => {
+                let descriptor = identify_descriptor();
+                response
+                    .packets
+                    .extend(self.chunked(GIP_CMD_IDENTIFY, &descriptor));
+            }
+            GIP_CMD_POWER => {
+                if packet.payload.first() == Some(&GIP_POWER_ON) {
+                    self.gip_state = GipState::Powered;
+                    // Let the host know right away, and resend the full input state.
+                    self.last_payload = None;
+                    self.last_status = None;
+                } else {
+                    self.gip_state = GipState::Announcing;
+                    self.last_announce = None;
+                }
+            }
+            // Enable mask, trigger motors, left and right motors (0-100), then timing.
+            GIP_CMD_RUMBLE if packet.payload.len() >= 6 => {
+                response.feedback.push(Feedback::Rumble {
+                    strong: (packet.payload[4] as f64 / 100.0).min(1.0),
+                    weak: (packet.payload[5] as f64 / 100.0).min(1.0),
+                });
+            }
+            // Acks, LEDs and authentication we don't take part in.
+            _ => {}
+        }
+        response
+    }
+
+    // Everything due at `now`: announces, status and changed input.
+    pub fn poll(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let due = |last: Option<Instant>, interval| match last {
+            Some(last) => now.duration_since(last) >= interval,
+            None => true,
+        };
+        let mut packets = vec![];
+        match self.gip_state {
+            GipState::Announcing => {
+                if due(self.last_announce, ANNOUNCE_INTERVAL) {
+                    self.last_announce = Some(now);
+                    packets.push(self.announce());
+                }
+            }
+            GipState::Powered => {
+                if due(self.last_status, STATUS_INTERVAL) {
+                    self.last_status = Some(now);
+                    packets.push(self.status());
+                }
+                if self.state.buttons.guide != self.guide_sent {
+                    self.guide_sent = self.state.buttons.guide;
+                    let (options, pressed) = match self.guide_sent {
+                        true => (GIP_OPT_INTERNAL | GIP_OPT_ACK, 0x01),
+                        false => (GIP_OPT_INTERNAL, 0x00),
+                    };
+                    packets.push(self.packet(GIP_CMD_GUIDE_BUTTON, options, vec![pressed, 0x5b]));
+                }
+                let payload = self.state.to_payload();
+                if self.last_payload != Some(payload) {
+                    self.last_payload = Some(payload);
+                    packets.push(self.packet(GIP_CMD_INPUT, 0x00, payload.to_vec()));
+                }
+            }
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+fn hex_packet(hex: &str) -> Vec<u8> {
+    hex.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_gip_handshake() {
+    let start = Instant::now();
+    let mut controller = XboxOneController::new();
+    controller.state.update_from_gamepad(&Gamepad::new());
+
+    let announce = controller.poll(start);
+    assert_eq!(announce.len(), 1);
+    assert_eq!(announce[0][..4], [0x02, 0x20, 0x01, 0x1c]);
+    assert_eq!(announce[0][4..10], controller.mac);
+    assert_eq!(announce[0][12..16], [0x5e, 0x04, 0x12, 0x0b]);
+    assert!(controller
+        .poll(start + Duration::from_millis(100))
+        .is_empty());
+    assert_eq!(
+        controller.poll(start + Duration::from_millis(600))[0][..3],
+        [0x02, 0x20, 0x02]
+    );
+
+    // Windows asks for the identify descriptor first.
+    let identify = controller.handle_packet(&hex_packet("04 20 01 00"));
+    let chunks = identify.packets;
+    assert_eq!(chunks[0][..2], [0x04, 0xf0]);
+    let total = identify_descriptor().len();
+    let mut reassembled = vec![];
+    for chunk in chunks.iter() {
+        let (length, used) = read_varint(&chunk[3..]).unwrap();
+        let (position, used_position) = read_varint(&chunk[3 + used..]).unwrap();
+        let start = 3 + used + used_position;
+        if length == 0 {
+            assert_eq!(position, total);
+        } else if chunk[1] & GIP_OPT_CHUNK_START == 0 {
+            assert_eq!(position, reassembled.len());
+        }
+        reassembled.extend_from_slice(&chunk[start..start + length]);
+        assert!(chunk.len() <= 64);
+    }
+    assert_eq!(reassembled, identify_descriptor());
+    let classes = String::from_utf8_lossy(&reassembled).into_owned();
+    assert!(classes.contains("Windows.Xbox.Input.Gamepad"));
+
+    // Linux xpad: power on, LED on, then a rumble pulse, with the ack flag set on the LED.
+    let power = controller.handle_packet(&hex_packet("05 20 00 01 00"));
+    assert!(power.packets.is_empty());
+    assert_eq!(controller.gip_state, GipState::Powered);
+    let led = controller.handle_packet(&hex_packet("0a 30 02 03 00 01 14"));
+    assert_eq!(
+        led.packets,
+        vec![hex_packet("01 20 02 09 00 0a 20 03 00 00 00 00 00")]
+    );
+    let rumble = controller.handle_packet(&hex_packet("09 00 03 09 00 0f 00 00 1d 1d ff 00 00"));
+    assert_eq!(
+        rumble.feedback,
+        vec![Feedback::Rumble {
+            strong: 0.29,
+            weak: 0.29
+        }]
+    );
+
+    // Status and the first input report go out once powered.
+    let packets = controller.poll(start + Duration::from_millis(700));
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0][..4], [0x03, 0x20, 0x04, 0x04]);
+    assert_eq!(packets[1][..4], [0x20, 0x00, 0x05, 0x2c]);
+    assert!(controller
+        .poll(start + Duration::from_millis(710))
+        .is_empty());
+
+    controller.handle_packet(&hex_packet("05 20 04 01 04"));
+    assert_eq!(controller.gip_state, GipState::Announcing);
+    assert!(controller.handle_packet(&[0x05, 0x20]).packets.is_empty());
+}
+
+#[test]
+fn test_gip_input_report() {
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::Start, true);
+    gamepad.set_button(GamepadButton::DPadLeft, true);
+    gamepad.set_button(GamepadButton::RightThumb, true);
+    gamepad.set_button(GamepadButton::Share, true);
+    gamepad.set_button(GamepadButton::Mode, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = 0;
+    gamepad.power = Some(PowerState::Discharging(60));
+
+    let mut controller = XboxOneController::new();
+    controller.handle_packet(&hex_packet("05 20 00 01 00"));
+    controller.state.update_from_gamepad(&gamepad);
+    let packets = controller.poll(Instant::now());
+    assert_eq!(packets.len(), 3);
+    // Battery type standard, level 2 of 3
+    assert_eq!(packets[0][4], 0x06);
+    assert_eq!(packets[1], vec![0x07, 0x30, 0x02, 0x02, 0x01, 0x5b]);
+    let payload = &packets[2][4..];
+    assert_eq!(payload[0], 0x14);
+    assert_eq!(payload[1], 0x84);
+    // 10 bit triggers
+    assert_eq!(payload[2..6], [0x00, 0x00, 0xff, 0x03]);
+    assert_eq!(payload[6..10], [0xff, 0x7f, 0x00, 0x80]);
+    assert_eq!(payload[SHARE_OFFSET], 0x01);
+
+    gamepad.set_button(GamepadButton::Mode, false);
+    controller.state.update_from_gamepad(&gamepad);
+    assert_eq!(
+        controller.poll(Instant::now()),
+        vec![vec![0x07, 0x20, 0x04, 0x02, 0x00, 0x5b]]
+    );
+
+    assert_eq!(varint(300), vec![0xac, 0x02]);
+    assert_eq!(read_varint(&[0xac, 0x02]), Some((300, 2)));
+}
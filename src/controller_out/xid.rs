@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller_abs::{
+        Axis, BitPackedButton, BitPackedButtons, Feedback, Gamepad, GamepadAxis, GamepadButton,
+        JoystickState,
+    },
+    AxisNew, JoystickStateNew,
+};
+
+use super::raw_gadget::{
+    configuration_descriptor, device_descriptor, endpoint_descriptor, hid_default_reply,
+    interface_descriptor, ControlReply, ControlRequest, GadgetDescriptors, GadgetFunction,
+    HID_REQ_GET_REPORT, USB_ENDPOINT_XFER_INT, USB_TYPE_CLASS, USB_TYPE_VENDOR,
+};
+
+// Original Xbox controller (Controller S), as the console and USB adapters see it.
+
+pub const XID_VENDOR_ID: u16 = 0x045e;
+pub const XID_PRODUCT_ID: u16 = 0x0289;
+pub const XID_IN_ENDPOINT: u8 = 0x81;
+pub const XID_OUT_ENDPOINT: u8 = 0x02;
+pub const XID_REPORT_LEN: usize = 20;
+
+const XID_REQ_GET_CAPABILITIES: u8 = 0x01;
+const XID_REQ_GET_DESCRIPTOR: u8 = 0x06;
+
+// bcdXid 1.00, gamepad, Controller S, 20 byte input and 6 byte output reports.
+const XID_DESCRIPTOR: [u8; 16] = [
+    0x10, 0x42, 0x00, 0x01, 0x01, 0x02, 0x14, 0x06, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+// Every bit of the reports does something.
+const XID_INPUT_CAPABILITIES: [u8; 20] = [
+    0x00, 0x14, 0xff, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff,
+];
+const XID_OUTPUT_CAPABILITIES: [u8; 6] = [0x00, 0x06, 0xff, 0xff, 0xff, 0xff];
+
+pub fn xid_descriptors() -> GadgetDescriptors {
+    let mut device = device_descriptor(XID_VENDOR_ID, XID_PRODUCT_ID, 0x0121, (0, 0, 0), 8);
+    // Full speed USB 1.1 like the real thing.
+    device[2..4].copy_from_slice(&0x0110u16.to_le_bytes());
+    GadgetDescriptors {
+        device,
+        configuration: configuration_descriptor(&[[
+            interface_descriptor(0, 2, (0x58, 0x42, 0x00)),
+            endpoint_descriptor(XID_IN_ENDPOINT, USB_ENDPOINT_XFER_INT, 32, 4),
+            endpoint_descriptor(XID_OUT_ENDPOINT, USB_ENDPOINT_XFER_INT, 32, 4),
+        ]
+        .concat()]),
+        strings: vec![
+            "Microsoft".to_string(),
+            "Xbox Controller S".to_string(),
+            "000000000001".to_string(),
+        ],
+        // XID isn't HID, the console asks for the XID descriptor instead.
+        hid_reports: vec![vec![]],
+    }
+}
+
+// Answers the XID vendor requests, and GET_REPORT with the last report sent.
+pub struct XidFunction {
+    pub report: Arc<Mutex<[u8; XID_REPORT_LEN]>>,
+}
+
+impl GadgetFunction for XidFunction {
+    fn control(&mut self, request: &ControlRequest, _data: &[u8]) -> ControlReply {
+        match (request.kind(), request.request, request.value) {
+            (USB_TYPE_VENDOR, XID_REQ_GET_DESCRIPTOR, 0x4200) => {
+                ControlReply::Data(XID_DESCRIPTOR.to_vec())
+            }
+            (USB_TYPE_VENDOR, XID_REQ_GET_CAPABILITIES, 0x0100) => {
+                ControlReply::Data(XID_INPUT_CAPABILITIES.to_vec())
+            }
+            (USB_TYPE_VENDOR, XID_REQ_GET_CAPABILITIES, 0x0200) => {
+                ControlReply::Data(XID_OUTPUT_CAPABILITIES.to_vec())
+            }
+            (USB_TYPE_CLASS, HID_REQ_GET_REPORT, 0x0100) => {
+                ControlReply::Data(self.report.lock().unwrap().to_vec())
+            }
+            _ => hid_default_reply(request),
+        }
+    }
+}
+
+// Rumble from the 6 byte output report, left (strong) and right (weak) motor as u16.
+pub fn parse_xid_rumble(data: &[u8]) -> Option<Feedback> {
+    if data.len() < 6 || data[0] != 0x00 || data[1] != 0x06 {
+        return None;
+    }
+    Some(Feedback::Rumble {
+        strong: u16::from_le_bytes([data[2], data[3]]) as f64 / u16::MAX as f64,
+        weak: u16::from_le_bytes([data[4], data[5]]) as f64 / u16::MAX as f64,
+    })
+}
+
+// The pressure sensitive buttons.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum XidAnalogButton {
+    A,
+    B,
+    X,
+    Y,
+    Black,
+    White,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct XidConfig {
+    // Axes that set a button's pressure, on top of its digital button. Sticks only count their
+    // positive half.
+    pub pressure_axes: HashMap<XidAnalogButton, GamepadAxis>,
+}
+
+pub struct XidButtonState {
+    pub dpad_up: BitPackedButton,
+    pub dpad_down: BitPackedButton,
+    pub dpad_left: BitPackedButton,
+    pub dpad_right: BitPackedButton,
+    pub start: BitPackedButton,
+    pub back: BitPackedButton,
+    pub l3: BitPackedButton,
+    pub r3: BitPackedButton,
+    // Pressure, 0 to 255
+    pub analog: HashMap<XidAnalogButton, u8>,
+}
+
+impl XidButtonState {
+    pub fn new() -> XidButtonState {
+        XidButtonState {
+            dpad_up: BitPackedButton::new(0x00),
+            dpad_down: BitPackedButton::new(0x01),
+            dpad_left: BitPackedButton::new(0x02),
+            dpad_right: BitPackedButton::new(0x03),
+            start: BitPackedButton::new(0x04),
+            back: BitPackedButton::new(0x05),
+            l3: BitPackedButton::new(0x06),
+            r3: BitPackedButton::new(0x07),
+            analog: HashMap::new(),
+        }
+    }
+
+    pub fn get_control_byte_2(&self) -> u8 {
+        BitPackedButtons {
+            buttons: vec![
+                self.dpad_up.clone(),
+                self.dpad_down.clone(),
+                self.dpad_left.clone(),
+                self.dpad_right.clone(),
+                self.start.clone(),
+                self.back.clone(),
+                self.l3.clone(),
+                self.r3.clone(),
+            ],
+        }
+        .to_bytes_repr()
+    }
+
+    fn pressure(&self, button: XidAnalogButton) -> u8 {
+        self.analog.get(&button).copied().unwrap_or(0)
+    }
+}
+
+// How far an axis is pushed, 0 to 255.
+fn axis_pressure(gamepad: &Gamepad, axis: &GamepadAxis) -> u8 {
+    let value = match gamepad.axes.get(axis) {
+        Some(value) => value,
+        None => return 0,
+    };
+    match axis {
+        GamepadAxis::LeftTrigger | GamepadAxis::RightTrigger => value.convert_into(false),
+        _ => (value.convert_into::<i16, _>(false).max(0) >> 7) as u8,
+    }
+}
+
+pub struct XidControllerState {
+    pub buttons: XidButtonState,
+    pub left_trigger: Axis,
+    pub right_trigger: Axis,
+    pub left_joystick: JoystickState,
+    pub right_joystick: JoystickState,
+}
+
+impl XidControllerState {
+    pub fn new() -> XidControllerState {
+        XidControllerState {
+            buttons: XidButtonState::new(),
+            left_trigger: AxisNew!(u8::MIN),
+            right_trigger: AxisNew!(u8::MIN),
+            left_joystick: JoystickStateNew!(i16, 0),
+            right_joystick: JoystickStateNew!(i16, 0),
+        }
+    }
+
+    // Digital buttons press fully, configured axes add their own pressure.
+    pub fn update_from_gamepad(&mut self, gamepad: &Gamepad, config: &XidConfig) {
+        self.buttons.analog.clear();
+        for (button, button_state) in &gamepad.buttons {
+            let val = button_state.to_owned();
+            let analog = match button {
+                GamepadButton::North => XidAnalogButton::Y,
+                GamepadButton::East => XidAnalogButton::B,
+                GamepadButton::South => XidAnalogButton::A,
+                GamepadButton::West => XidAnalogButton::X,
+                GamepadButton::LeftShoulderButton => XidAnalogButton::White,
+                GamepadButton::RightShoulderButton => XidAnalogButton::Black,
+                _ => {
+                    self.set_digital_button(button, val);
+                    continue;
+                }
+            };
+            if val {
+                self.buttons.analog.insert(analog, u8::MAX);
+            }
+        }
+        for (button, axis) in &config.pressure_axes {
+            let pressure = axis_pressure(gamepad, axis);
+            let current = self.buttons.analog.entry(*button).or_insert(0);
+            *current = (*current).max(pressure);
+        }
+        for (gamepad_axis, axis) in &gamepad.axes {
+            match gamepad_axis {
+                GamepadAxis::LeftJoystickX => {
+                    self.left_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::LeftJoystickY => {
+                    self.left_joystick.y.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickX => {
+                    self.right_joystick.x.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightJoystickY => {
+                    self.right_joystick.y.value = axis.convert_into(false);
+                }
+                GamepadAxis::LeftTrigger => {
+                    self.left_trigger.value = axis.convert_into(false);
+                }
+                GamepadAxis::RightTrigger => {
+                    self.right_trigger.value = axis.convert_into(false);
+                }
+            }
+        }
+    }
+
+    fn set_digital_button(&mut self, button: &GamepadButton, val: bool) {
+        match button {
+            GamepadButton::LeftThumb => self.buttons.l3.value = val,
+            GamepadButton::RightThumb => self.buttons.r3.value = val,
+            GamepadButton::Start => self.buttons.start.value = val,
+            GamepadButton::Select => self.buttons.back.value = val,
+            GamepadButton::DPadUp => self.buttons.dpad_up.value = val,
+            GamepadButton::DPadDown => self.buttons.dpad_down.value = val,
+            GamepadButton::DPadLeft => self.buttons.dpad_left.value = val,
+            GamepadButton::DPadRight => self.buttons.dpad_right.value = val,
+            // The original pad has no guide or share button, face buttons are analog.
+            _ => {}
+        }
+    }
+
+    pub fn to_packet(&self) -> [u8; XID_REPORT_LEN] {
+        let mut packet = [0u8; XID_REPORT_LEN];
+        packet[0] = 0x00; // Report ID
+        packet[1] = XID_REPORT_LEN as u8;
+        packet[2] = self.buttons.get_control_byte_2();
+        // packet[3] is reserved
+        for (i, button) in [
+            XidAnalogButton::A,
+            XidAnalogButton::B,
+            XidAnalogButton::X,
+            XidAnalogButton::Y,
+            XidAnalogButton::Black,
+            XidAnalogButton::White,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            packet[4 + i] = self.buttons.pressure(button);
+        }
+        packet[10] = self.left_trigger.convert_into(false);
+        packet[11] = self.right_trigger.convert_into(false);
+        let stick = |axis: &Axis| axis.convert_into::<i16, _>(false).to_le_bytes();
+        packet[12..14].copy_from_slice(&stick(&self.left_joystick.x));
+        packet[14..16].copy_from_slice(&stick(&self.left_joystick.y));
+        packet[16..18].copy_from_slice(&stick(&self.right_joystick.x));
+        packet[18..20].copy_from_slice(&stick(&self.right_joystick.y));
+        packet
+    }
+}
+
+#[test]
+fn test_xid_packet() {
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.set_button(GamepadButton::RightShoulderButton, true);
+    gamepad.set_button(GamepadButton::Start, true);
+    gamepad.set_button(GamepadButton::DPadDown, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = 0;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickX).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::LeftJoystickY).value = 0;
+    let config = XidConfig {
+        pressure_axes: HashMap::from([
+            (XidAnalogButton::X, GamepadAxis::RightTrigger),
+            (XidAnalogButton::Y, GamepadAxis::LeftTrigger),
+            (XidAnalogButton::B, GamepadAxis::RightJoystickX),
+        ]),
+    };
+
+    let mut state = XidControllerState::new();
+    state.update_from_gamepad(&gamepad, &config);
+    let packet = state.to_packet();
+    assert_eq!(packet[..4], [0x00, 0x14, 0x12, 0x00]);
+    // A, B (stick centered), X (full trigger), Y (released trigger), black, white
+    assert_eq!(packet[4..10], [0xff, 0x00, 0xff, 0x00, 0xff, 0x00]);
+    assert_eq!(packet[10..12], [0x00, 0xff]);
+    assert_eq!(packet[12..16], [0xff, 0x7f, 0x00, 0x80]);
+
+    gamepad.set_button(GamepadButton::South, false);
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = u64::MAX / 2;
+    state.update_from_gamepad(&gamepad, &config);
+    let packet = state.to_packet();
+    assert_eq!(packet[4], 0x00);
+    assert_eq!(packet[6], 0x7f);
+
+    assert_eq!(
+        parse_xid_rumble(&[0x00, 0x06, 0xff, 0xff, 0x00, 0x00]),
+        Some(Feedback::Rumble {
+            strong: 1.0,
+            weak: 0.0
+        })
+    );
+    assert_eq!(parse_xid_rumble(&[0x00, 0x05, 0xff, 0xff, 0x00]), None);
+}
@@ -0,0 +1,474 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::control::gamepad_json;
+use crate::controller_abs::{ControllerInput, Feedback, Gamepad};
+use crate::controller_in::device_filter::DeviceInfo;
+use crate::controller_in::ConfigurableInput;
+
+// Terminal dashboard for `--tui`: inputs with their raw events, the resulting pad, what goes
+// out on each endpoint and the log, redrawn a few times a second. Plain ANSI escapes, so it
+// works over any SSH session.
+
+// Prints a line, or puts it in the dashboard's log while that's on screen.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::dashboard::log_line(format!($($arg)*))
+    };
+}
+
+// Same, shown in red on the dashboard.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::dashboard::error(format!($($arg)*))
+    };
+}
+
+const LOG_LINES: usize = 200;
+const RAW_EVENTS: usize = 5;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<DashboardState> = Mutex::new(DashboardState::new());
+
+struct InputStatus {
+    name: String,
+    events: VecDeque<String>,
+    last_event: Option<Instant>,
+    errors: u64,
+}
+
+struct OutputStatus {
+    output: String,
+    endpoint: u8,
+    last: Vec<u8>,
+    // Sends since `window_start`, turned into `rate` once a second.
+    window_start: Option<Instant>,
+    window_sends: u32,
+    rate: f64,
+}
+
+impl OutputStatus {
+    fn record(&mut self, data: &[u8], now: Instant) {
+        self.last = data.to_vec();
+        let window_start = match self.window_start {
+            Some(window_start) => window_start,
+            None => {
+                self.window_start = Some(now);
+                return;
+            }
+        };
+        self.window_sends += 1;
+        let elapsed = now.duration_since(window_start);
+        if elapsed >= Duration::from_secs(1) {
+            self.rate = self.window_sends as f64 / elapsed.as_secs_f64();
+            self.window_start = Some(now);
+            self.window_sends = 0;
+        }
+    }
+
+    fn rate(&self, now: Instant) -> f64 {
+        match self.window_start {
+            // Stopped sending, don't keep showing the old rate.
+            Some(start) if now.duration_since(start) > Duration::from_secs(2) => 0.0,
+            _ => self.rate,
+        }
+    }
+}
+
+struct LogLine {
+    text: String,
+    error: bool,
+}
+
+pub struct DashboardState {
+    output: String,
+    inputs: Vec<InputStatus>,
+    gamepad: Value,
+    outputs: Vec<OutputStatus>,
+    log: VecDeque<LogLine>,
+}
+
+impl DashboardState {
+    const fn new() -> DashboardState {
+        DashboardState {
+            output: String::new(),
+            inputs: vec![],
+            gamepad: Value::Null,
+            outputs: vec![],
+            log: VecDeque::new(),
+        }
+    }
+
+    fn input(&mut self, name: &str) -> &mut InputStatus {
+        let i = match self.inputs.iter().position(|input| input.name == name) {
+            Some(i) => i,
+            None => {
+                self.inputs.push(InputStatus {
+                    name: name.to_string(),
+                    events: VecDeque::new(),
+                    last_event: None,
+                    errors: 0,
+                });
+                self.inputs.len() - 1
+            }
+        };
+        &mut self.inputs[i]
+    }
+
+    fn push_log(&mut self, text: String, error: bool) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(LogLine { text, error });
+    }
+
+    fn raw_event(&mut self, device: &str, event: String, now: Instant) {
+        let input = self.input(device);
+        if input.events.len() == RAW_EVENTS {
+            input.events.pop_front();
+        }
+        input.events.push_back(event);
+        input.last_event = Some(now);
+    }
+
+    fn sent(&mut self, output: &str, endpoint: u8, data: &[u8], now: Instant) {
+        let position = self
+            .outputs
+            .iter()
+            .position(|status| status.output == output && status.endpoint == endpoint);
+        let i = match position {
+            Some(i) => i,
+            None => {
+                self.outputs.push(OutputStatus {
+                    output: output.to_string(),
+                    endpoint,
+                    last: vec![],
+                    window_start: None,
+                    window_sends: 0,
+                    rate: 0.0,
+                });
+                self.outputs.len() - 1
+            }
+        };
+        self.outputs[i].record(data, now);
+    }
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn log_line(text: String) {
+    if is_active() {
+        STATE.lock().unwrap().push_log(text, false);
+    } else {
+        println!("{}", text);
+    }
+}
+
+pub fn error(text: String) {
+    if is_active() {
+        STATE.lock().unwrap().push_log(text, true);
+    } else {
+        eprintln!("{}", text);
+    }
+}
+
+// Only formats the event when someone's looking.
+pub fn raw_event<F: FnOnce() -> String>(device: &str, event: F) {
+    if is_active() {
+        STATE
+            .lock()
+            .unwrap()
+            .raw_event(device, event(), Instant::now());
+    }
+}
+
+pub fn sent(output: &str, endpoint: u8, data: &[u8]) {
+    if is_active() {
+        STATE
+            .lock()
+            .unwrap()
+            .sent(output, endpoint, data, Instant::now());
+    }
+}
+
+fn bar(value: f64, width: usize) -> String {
+    let filled = ((value.clamp(0.0, 1.0) * width as f64).round() as usize).min(width);
+    format!("[{}{}]", "#".repeat(filled), ".".repeat(width - filled))
+}
+
+// A stick as a small grid with its position marked, y up.
+fn crosshair(x: f64, y: f64) -> [String; 5] {
+    let column = (((x.clamp(-1.0, 1.0) + 1.0) / 2.0) * 10.0).round() as usize;
+    let row = (((1.0 - y.clamp(-1.0, 1.0)) / 2.0) * 4.0).round() as usize;
+    std::array::from_fn(|r| {
+        (0..11)
+            .map(|c| match (r == row && c == column, r == 2, c == 5) {
+                (true, _, _) => 'O',
+                (_, true, true) => '+',
+                (_, true, false) => '-',
+                (_, false, true) => '|',
+                _ => ' ',
+            })
+            .collect()
+    })
+}
+
+const BUTTONS: [(&str, &str); 16] = [
+    ("North", "N"),
+    ("East", "E"),
+    ("South", "S"),
+    ("West", "W"),
+    ("LeftShoulderButton", "LB"),
+    ("RightShoulderButton", "RB"),
+    ("Select", "Sel"),
+    ("Start", "Start"),
+    ("Mode", "Mode"),
+    ("Share", "Share"),
+    ("LeftThumb", "L3"),
+    ("RightThumb", "R3"),
+    ("DPadUp", "Up"),
+    ("DPadDown", "Down"),
+    ("DPadLeft", "Left"),
+    ("DPadRight", "Right"),
+];
+
+fn render(state: &DashboardState, now: Instant, rows: usize) -> String {
+    let mut screen = String::new();
+    let _ = writeln!(
+        screen,
+        "\x1b[1mcursed_controls\x1b[0m  output: {}    Ctrl-C quits",
+        state.output
+    );
+
+    let _ = writeln!(screen, "\n\x1b[1mInputs\x1b[0m");
+    for input in state.inputs.iter() {
+        let last_event = match input.last_event {
+            Some(at) => format!("{:.1}s ago", now.duration_since(at).as_secs_f64()),
+            None => "never".to_string(),
+        };
+        let _ = writeln!(
+            screen,
+            " {}  last event {}  errors {}",
+            input.name, last_event, input.errors
+        );
+        for event in input.events.iter() {
+            let _ = writeln!(screen, "   {}", event);
+        }
+    }
+
+    let _ = writeln!(screen, "\n\x1b[1mGamepad\x1b[0m");
+    let buttons: Vec<String> = BUTTONS
+        .iter()
+        .map(
+            |(button, label)| match state.gamepad["buttons"][button].as_bool() {
+                // Pressed buttons light up in inverse video.
+                Some(true) => format!("\x1b[7m{}\x1b[0m", label),
+                _ => label.to_string(),
+            },
+        )
+        .collect();
+    let _ = writeln!(screen, " {}", buttons.join(" "));
+    let axis = |name: &str| state.gamepad["axes"][name].as_f64().unwrap_or(0.0);
+    for (label, trigger) in [("LT", "LeftTrigger"), ("RT", "RightTrigger")] {
+        let value = axis(trigger);
+        let _ = writeln!(screen, " {} {} {:.2}", label, bar(value, 20), value);
+    }
+    let left = crosshair(axis("LeftJoystickX"), axis("LeftJoystickY"));
+    let right = crosshair(axis("RightJoystickX"), axis("RightJoystickY"));
+    for (left, right) in left.iter().zip(right.iter()) {
+        let _ = writeln!(screen, "  {}    {}", left, right);
+    }
+    if let Some(power) = state.gamepad["power"].as_str() {
+        let _ = writeln!(screen, " battery {}", power);
+    }
+
+    let _ = writeln!(screen, "\n\x1b[1mOutput\x1b[0m");
+    for output in state.outputs.iter() {
+        let hex: Vec<String> = output
+            .last
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let _ = writeln!(
+            screen,
+            " {} ep {:#04x}  {:.0}/s  {}",
+            output.output,
+            output.endpoint,
+            output.rate(now),
+            hex.join(" ")
+        );
+    }
+
+    // The log gets whatever room is left.
+    let _ = writeln!(screen, "\n\x1b[1mLog\x1b[0m");
+    let used = screen.lines().count();
+    let room = rows.saturating_sub(used + 1);
+    let skip = state.log.len().saturating_sub(room);
+    for line in state.log.iter().skip(skip) {
+        if line.error {
+            let _ = writeln!(screen, " \x1b[31m{}\x1b[0m", line.text);
+        } else {
+            let _ = writeln!(screen, " {}", line.text);
+        }
+    }
+    screen
+}
+
+fn terminal_rows() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if ok && size.ws_row > 0 {
+        size.ws_row as usize
+    } else {
+        24
+    }
+}
+
+// Takes over the terminal until `stop`. Needs to be called from within the tokio runtime.
+pub fn start(output: String) {
+    STATE.lock().unwrap().output = output;
+    ACTIVE.store(true, Ordering::SeqCst);
+    // Alternate screen, hidden cursor
+    print!("\x1b[?1049h\x1b[?25l");
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Checked under the lock, so nothing gets drawn once `stop` restored the terminal.
+            let state = STATE.lock().unwrap();
+            if !ACTIVE.load(Ordering::SeqCst) {
+                break;
+            }
+            let screen = render(&state, Instant::now(), terminal_rows());
+            // Home, draw, then clear whatever the last frame left below.
+            let screen = screen.replace('\n', "\x1b[K\n");
+            print!("\x1b[H{}\x1b[J", screen);
+            let _ = std::io::stdout().flush();
+        }
+    });
+}
+
+// Gives the terminal back, log lines get printed again. Also called while unwinding a panic, so
+// a lock poisoned by it doesn't matter.
+pub fn stop() {
+    let _state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+    }
+}
+
+// Shows an input's pad and errors on the dashboard.
+pub struct DashboardInput<'a, I: ControllerInput> {
+    input: &'a mut I,
+    name: String,
+}
+
+impl<'a, I: ControllerInput> DashboardInput<'a, I> {
+    pub fn new(input: &'a mut I) -> DashboardInput<'a, I> {
+        let name = input.name();
+        STATE.lock().unwrap().input(&name);
+        DashboardInput { input, name }
+    }
+}
+
+impl<I: ControllerInput> ControllerInput for DashboardInput<'_, I> {
+    type ControllerType = I::ControllerType;
+
+    fn to_gamepad(&mut self) -> &Gamepad {
+        self.input.to_gamepad()
+    }
+
+    fn prep_for_input_events(&mut self) {
+        self.input.prep_for_input_events();
+    }
+
+    async fn get_next_inputs(&mut self) -> Result<bool, &'static str> {
+        let res = self.input.get_next_inputs().await;
+        let gamepad = gamepad_json(self.input.to_gamepad());
+        let mut state = STATE.lock().unwrap();
+        state.gamepad = gamepad;
+        if let Err(err) = res {
+            state.input(&self.name).errors += 1;
+            state.push_log(format!("{}: {}", self.name, err), true);
+        }
+        res
+    }
+
+    fn apply_feedback(&mut self, feedback: &Feedback) {
+        self.input.apply_feedback(feedback);
+    }
+
+    fn name(&self) -> String {
+        self.input.name()
+    }
+}
+
+impl<I: ConfigurableInput> ConfigurableInput for DashboardInput<'_, I> {
+    fn reconfigure(&mut self, config: &Config) {
+        self.input.reconfigure(config);
+    }
+
+    fn calibrate(&mut self) {
+        self.input.calibrate();
+    }
+
+    fn devices(&self) -> Vec<DeviceInfo> {
+        self.input.devices()
+    }
+
+    fn show_profile(&mut self, number: usize) {
+        self.input.show_profile(number);
+    }
+}
+
+#[test]
+fn test_dashboard_render() {
+    use crate::controller_abs::{GamepadAxis, GamepadButton};
+
+    let start = Instant::now();
+    let mut state = DashboardState::new();
+    state.output = "x360".to_string();
+    state.raw_event("Pad", "ButtonPressed(South)".to_string(), start);
+    let mut gamepad = Gamepad::new();
+    gamepad.set_button(GamepadButton::South, true);
+    gamepad.get_axis_ref(GamepadAxis::LeftTrigger).value = u64::MAX;
+    gamepad.get_axis_ref(GamepadAxis::RightTrigger).value = 0;
+    state.gamepad = gamepad_json(&gamepad);
+    for i in 0..=100 {
+        state.sent(
+            "x360",
+            0x81,
+            &[0x00, 0x14, 0x10],
+            start + Duration::from_millis(i * 10),
+        );
+    }
+    state.push_log("Unknown axis!: ABS(7)/0.5".to_string(), true);
+
+    let now = start + Duration::from_millis(1000);
+    let screen = render(&state, now, 40);
+    assert!(screen.contains(" Pad  last event 1.0s ago  errors 0"));
+    assert!(screen.contains("   ButtonPressed(South)"));
+    assert!(screen.contains("\x1b[7mS\x1b[0m"));
+    assert!(screen.contains(" LT [####################] 1.00"));
+    assert!(screen.contains(" RT [....................] 0.00"));
+    // Both sticks centered
+    assert!(screen.contains("  -----O-----    -----O-----"));
+    assert!(screen.contains(" x360 ep 0x81  100/s  00 14 10"));
+    assert!(screen.contains("\x1b[31mUnknown axis!: ABS(7)/0.5\x1b[0m"));
+    // Nothing sent for a while
+    let screen = render(&state, now + Duration::from_secs(3), 40);
+    assert!(screen.contains(" x360 ep 0x81  0/s"));
+}
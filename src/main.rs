@@ -1,27 +1,56 @@
-use controller_abs::{ControllerInput, GamepadAxis, OutputMapping};
-use futures_util::TryStreamExt;
-use std::thread::sleep;
-use std::time::Duration;
-use tokio;
-use xwiimote::events::{Event, Key, KeyState, NunchukKey};
-use xwiimote::{Monitor, Result};
-
-#[allow(dead_code)]
+use controller_abs::ControllerInput;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// First, so the other modules can use its `log!` macros.
+#[macro_use]
+mod dashboard;
+mod config;
+mod control;
 mod controller_abs;
-#[allow(dead_code)]
 mod controller_in;
-#[allow(dead_code)]
 mod controller_out;
+#[cfg(feature = "web")]
+mod web;
 
-use controller_abs::GamepadButton;
-use controller_in::{GilRsInput, XWiiInput};
+use config::Config;
+use control::{ControlServer, ControlSession, ControlledInput};
+use controller_in::balance_board::BalanceBoardInput;
+use controller_in::merged::MergedInput;
+use controller_in::network::NetworkInput;
+use controller_in::{ConfigurableInput, GilRsInput, XWiiInput};
 
-use controller_out::x360::XboxControllerState;
+use controller_out::ds4::{
+    ds4_descriptors, parse_ds4_output_report, Ds4ControllerState, Ds4Function, DS4_IN_ENDPOINT,
+};
+use controller_out::dsu::{DsuPublisher, DsuServer};
+use controller_out::hid_gamepad::{HidGamepadConfig, HidGamepadFunction, HID_GAMEPAD_IN_ENDPOINT};
+use controller_out::keyboard_mouse::{
+    keyboard_mouse_descriptors, KeyboardMouseConfig, KeyboardMouseFunction, KeyboardMouseState,
+    KEYBOARD_IN_ENDPOINT, MOUSE_IN_ENDPOINT,
+};
+use controller_out::network::NetworkSender;
+use controller_out::raw_gadget::RawGadget;
+use controller_out::switch_pro::{
+    switch_pro_descriptors, SwitchProController, SwitchProFunction, SWITCH_PRO_IN_ENDPOINT,
+};
+use controller_out::usbip::{UsbIpConfig, UsbIpExport};
+use controller_out::x360::{
+    parse_x360_output, x360_descriptors, X360Function, XboxControllerState, X360_IN_ENDPOINT,
+};
+use controller_out::xbox_one::{
+    xbox_one_descriptors, XboxOneController, XboxOneFunction, XBOX_ONE_IN_ENDPOINT,
+};
+use controller_out::xid::{
+    parse_xid_rumble, xid_descriptors, XidConfig, XidControllerState, XidFunction, XID_IN_ENDPOINT,
+    XID_REPORT_LEN,
+};
+use dashboard::DashboardInput;
 
 // Declare externals
 extern "C" {
     fn init_360_gadget(await_endpoint_availability: bool, n_interfaces: i32) -> i32;
-    fn close_360_gadget(fd: i32);
     fn send_to_ep(fd: i32, n: i32, data: *const u8, len: usize) -> bool;
 }
 
@@ -29,148 +58,48 @@ fn init_360_gadget_c(await_endpoint_availability: bool, n_interfaces: i32) -> i3
     unsafe { init_360_gadget(await_endpoint_availability, n_interfaces) }
 }
 
-#[allow(dead_code)]
-fn close_360_gadget_c(fd: i32) {
-    unsafe { close_360_gadget(fd) }
-}
-
 fn send_to_ep_c(fd: i32, n: i32, data: *const u8, len: usize) -> bool {
     unsafe { send_to_ep(fd, n, data, len) }
 }
 
-#[allow(dead_code)]
-fn example_loop() {
-    print!("Starting 360 gadget...");
-
-    let fd = init_360_gadget_c(true, 1);
-    let mut controller_state = XboxControllerState::new();
-    loop {
-        sleep(std::time::Duration::from_secs(1));
-        controller_state.buttons.a.value = !controller_state.buttons.a.value;
-        controller_state.buttons.b.value = !controller_state.buttons.b.value;
-        controller_state.buttons.x.value = !controller_state.buttons.x.value;
-        controller_state.buttons.y.value = !controller_state.buttons.y.value;
-        // Set left joystick to north-east
-        controller_state.left_joystick.x.value = 32760;
-        controller_state.left_joystick.y.value = 32760;
-        let packet = controller_state.to_packet();
-        send_to_ep_c(fd, 0, packet.as_ptr(), 20);
-    }
-    // close_360_gadget_c(fd);
-}
-
-// #[tokio::main(flavor = "current_thread")]
-// async fn main() -> Result<()> {
-//     // Create a monitor to enumerate connected Wii Remotes
-//     let mut monitor = Monitor::enumerate().unwrap();
-//     let address = monitor.try_next().await.unwrap().unwrap();
-//     let mut wii_input = XWiiInput::new(&address);
-
-//     // Set mapping for rocket league
-//     macro_rules! MapKeyToKey {
-//         ($key: expr, $button: expr) => {
-//             wii_input.map_event(
-//                 Event::Key($key, KeyState::Up),
-//                 OutputMapping::Button($button),
-//             )
-//         };
-//     }
-
-//     // Jump
-//     MapKeyToKey!(Key::A, GamepadButton::South);
-//     // Throttle
-//     wii_input.map_event(
-//         Event::Key(Key::B, KeyState::Up),
-//         OutputMapping::Axis(GamepadAxis::RightTrigger),
-//     );
-//     // Menu
-//     MapKeyToKey!(Key::Plus, GamepadButton::Start);
-//     MapKeyToKey!(Key::Minus, GamepadButton::Select);
-//     MapKeyToKey!(Key::Home, GamepadButton::Mode);
-//     // Boost
-//     MapKeyToKey!(Key::Down, GamepadButton::East);
-//     // Ball cam
-//     MapKeyToKey!(Key::One, GamepadButton::North);
-//     // Dpad
-//     MapKeyToKey!(Key::Up, GamepadButton::DPadUp);
-//     MapKeyToKey!(Key::Left, GamepadButton::DPadLeft);
-//     MapKeyToKey!(Key::Right, GamepadButton::DPadRight);
-//     MapKeyToKey!(Key::Two, GamepadButton::DPadDown);
-
-//     // Nunchuck
-//     // Brake
-//     wii_input.map_event(
-//         Event::NunchukKey(NunchukKey::Z, KeyState::Up),
-//         OutputMapping::Axis(GamepadAxis::LeftTrigger),
-//     );
-//     // Handbrake
-//     wii_input.map_event(
-//         Event::NunchukKey(NunchukKey::C, KeyState::Up),
-//         OutputMapping::Button(GamepadButton::West),
-//     );
-
-//     macro_rules! EventNunchuckMove {
-//         () => {
-//             Event::NunchukMove {
-//                 x: 0,
-//                 y: 0,
-//                 x_acceleration: 0,
-//                 y_acceleration: 0,
-//             }
-//         };
-//     }
-
-//     wii_input.map_event(
-//         EventNunchuckMove!(),
-//         OutputMapping::Axis(controller_abs::GamepadAxis::LeftJoystickX),
-//     );
-//     wii_input.map_event(
-//         EventNunchuckMove!(),
-//         OutputMapping::Axis(controller_abs::GamepadAxis::LeftJoystickY),
-//     );
-
-//     let fd = init_360_gadget_c(true, 1);
-//     let mut controller_state = XboxControllerState::new();
-//     wii_input.prep_for_input_events();
-
-//     loop {
-//         // println!("Getting inputs...");
-//         let _res = wii_input.get_next_inputs().await;
-//         // println!("Updating state...");
-//         controller_state.update_from_gamepad(wii_input.to_gamepad());
-//         // println!("A button: {}", controller_state.buttons.a.value);
-
-//         let success = send_to_ep_c(fd, 0, controller_state.to_packet().as_ptr(), 20);
-//         if !success {
-//             // Probably crashed?
-//             break;
-//         }
-//         // After sending state, sleep 1ms.
-//         tokio::time::sleep(Duration::from_micros(900)).await;
-//     }
-
-//     Ok(())
-// }
-
+// Value following `name` on the command line.
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let i = args.iter().position(|arg| arg == name)?;
+    Some(
+        args.get(i + 1)
+            .unwrap_or_else(|| panic!("{} needs a value", name))
+            .clone(),
+    )
+}
 
+fn has_arg(name: &str) -> bool {
+    std::env::args().any(|arg| arg == name)
+}
 
+// Mapping file from `--config <path>`, defaults otherwise.
+fn load_config() -> Config {
+    let path = match arg_value("--config") {
+        Some(path) => path,
+        None => return Config::default(),
+    };
+    match Config::load(std::path::Path::new(&path)) {
+        Ok(config) => config,
+        Err(err) => panic!("{}", err),
+    }
+}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+async fn run_x360<I: ControllerInput>(input: &mut I) {
     let fd = init_360_gadget_c(true, 1);
     let mut controller_state = XboxControllerState::new();
-    
-    let mut gil_inps = GilRsInput::discover_all();
-    gil_inps[0].prep_for_input_events();
 
     loop {
-        // println!("Getting inputs...");
-        let _res = gil_inps[0].get_next_inputs().await;
-        // println!("Updating state...");
-        controller_state.update_from_gamepad(gil_inps[0].to_gamepad());
-        // println!("A button: {}", controller_state.buttons.a.value);
+        let _res = input.get_next_inputs().await;
+        controller_state.update_from_gamepad(input.to_gamepad());
 
-        let success = send_to_ep_c(fd, 0, controller_state.to_packet().as_ptr(), 20);
+        let packet = controller_state.to_packet();
+        dashboard::sent("x360", X360_IN_ENDPOINT, &packet);
+        let success = send_to_ep_c(fd, 0, packet.as_ptr(), 20);
         if !success {
             // Probably crashed?
             break;
@@ -178,6 +107,387 @@ async fn main() -> Result<()> {
         // After sending state, sleep 1ms.
         tokio::time::sleep(Duration::from_micros(900)).await;
     }
+}
+
+async fn run_ds4<I: ControllerInput>(input: &mut I) {
+    let gadget = match RawGadget::start(None, ds4_descriptors(), Box::new(Ds4Function)) {
+        Ok(gadget) => gadget,
+        Err(err) => panic!("{}", err),
+    };
+    let mut controller_state = Ds4ControllerState::new();
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        controller_state.update_from_gamepad(input.to_gamepad());
+
+        if !gadget.send(DS4_IN_ENDPOINT, &controller_state.next_report()).await {
+            break;
+        }
+        while let Some(report) = gadget.try_recv() {
+            for feedback in parse_ds4_output_report(&report.data) {
+                input.apply_feedback(&feedback);
+            }
+        }
+        // The host polls every 5ms, send() blocks until then once configured.
+        if !gadget.is_configured() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+}
+
+async fn run_switch_pro<I: ControllerInput>(input: &mut I) {
+    let descriptors = switch_pro_descriptors();
+    let gadget = match RawGadget::start(None, descriptors, Box::new(SwitchProFunction)) {
+        Ok(gadget) => gadget,
+        Err(err) => panic!("{}", err),
+    };
+    let mut controller = SwitchProController::new();
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        controller.state.update_from_gamepad(input.to_gamepad());
+
+        while let Some(report) = gadget.try_recv() {
+            let response = controller.handle_output_report(&report.data);
+            for reply in response.replies {
+                gadget.send(SWITCH_PRO_IN_ENDPOINT, &reply).await;
+            }
+            for feedback in response.feedback {
+                input.apply_feedback(&feedback);
+            }
+        }
+        // Until the handshake is done the host only wants replies.
+        if !controller.is_streaming() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        } else if !gadget.send(SWITCH_PRO_IN_ENDPOINT, &controller.next_input_report()).await {
+            break;
+        }
+    }
+}
+
+async fn run_xbox_one<I: ControllerInput>(input: &mut I) {
+    let descriptors = xbox_one_descriptors();
+    let gadget = match RawGadget::start(None, descriptors, Box::new(XboxOneFunction)) {
+        Ok(gadget) => gadget,
+        Err(err) => panic!("{}", err),
+    };
+    let mut controller = XboxOneController::new();
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        controller.state.update_from_gamepad(input.to_gamepad());
+
+        let mut packets = vec![];
+        while let Some(report) = gadget.try_recv() {
+            let response = controller.handle_packet(&report.data);
+            packets.extend(response.packets);
+            for feedback in response.feedback {
+                input.apply_feedback(&feedback);
+            }
+        }
+        packets.extend(controller.poll(Instant::now()));
+        if packets.is_empty() {
+            tokio::time::sleep(Duration::from_millis(4)).await;
+        }
+        for packet in packets {
+            if !gadget.send(XBOX_ONE_IN_ENDPOINT, &packet).await {
+                return;
+            }
+        }
+    }
+}
+
+async fn run_xid<I: ControllerInput>(input: &mut I, config: &XidConfig) {
+    let report = Arc::new(Mutex::new([0u8; XID_REPORT_LEN]));
+    let function = XidFunction {
+        report: report.clone(),
+    };
+    let gadget = match RawGadget::start(None, xid_descriptors(), Box::new(function)) {
+        Ok(gadget) => gadget,
+        Err(err) => panic!("{}", err),
+    };
+    let mut controller_state = XidControllerState::new();
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        controller_state.update_from_gamepad(input.to_gamepad(), config);
+
+        let packet = controller_state.to_packet();
+        *report.lock().unwrap() = packet;
+        if !gadget.send(XID_IN_ENDPOINT, &packet).await {
+            break;
+        }
+        // Rumble comes on the OUT endpoint or as a SET_REPORT, depending on the game.
+        while let Some(report) = gadget.try_recv() {
+            if let Some(feedback) = parse_xid_rumble(&report.data) {
+                input.apply_feedback(&feedback);
+            }
+        }
+        if !gadget.is_configured() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+}
+
+async fn run_hid_gamepad<I: ControllerInput>(input: &mut I, config: &HidGamepadConfig) {
+    let gadget = match RawGadget::start(None, config.descriptors(), Box::new(HidGamepadFunction)) {
+        Ok(gadget) => gadget,
+        Err(err) => panic!("{}", err),
+    };
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        let report = config.pack_report(input.to_gamepad());
+        if !gadget.send(HID_GAMEPAD_IN_ENDPOINT, &report).await {
+            break;
+        }
+        if !gadget.is_configured() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+}
+
+async fn run_keyboard_mouse<I: ControllerInput>(input: &mut I, config: &KeyboardMouseConfig) {
+    let descriptors = keyboard_mouse_descriptors();
+    let gadget = match RawGadget::start(None, descriptors, Box::new(KeyboardMouseFunction)) {
+        Ok(gadget) => gadget,
+        Err(err) => panic!("{}", err),
+    };
+    let mut state = KeyboardMouseState::new(config.clone());
+    let mut last_keyboard = [0u8; 8];
+    let mut last_mouse_buttons = 0;
+    let mut last_update = Instant::now();
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        let gamepad = input.to_gamepad();
+        let keyboard = state.keyboard_report(gamepad);
+        let mouse = state.mouse_report(gamepad, last_update.elapsed());
+        last_update = Instant::now();
+
+        // A host configuring us starts with nothing pressed, so whatever is held then goes out.
+        if !gadget.is_configured() {
+            last_keyboard = [0; 8];
+            last_mouse_buttons = 0;
+            continue;
+        }
+        // Only changes go out, so the endpoints don't block on idle reports.
+        if keyboard != last_keyboard {
+            if !gadget.send(KEYBOARD_IN_ENDPOINT, &keyboard).await {
+                break;
+            }
+            last_keyboard = keyboard;
+        }
+        if mouse[0] != last_mouse_buttons || mouse[1..] != [0, 0, 0] {
+            if !gadget.send(MOUSE_IN_ENDPOINT, &mouse).await {
+                break;
+            }
+            last_mouse_buttons = mouse[0];
+        }
+        // Keyboard LED reports, nothing to do with them.
+        while gadget.try_recv().is_some() {}
+    }
+}
+
+// The 360 pad for a host attaching it with `usbip attach`, no gadget support needed here.
+async fn run_usbip<I: ControllerInput>(input: &mut I, config: &UsbIpConfig) {
+    let export = match UsbIpExport::start(config, x360_descriptors(), Box::new(X360Function)) {
+        Ok(export) => export,
+        Err(err) => panic!("{}", err),
+    };
+    let mut controller_state = XboxControllerState::new();
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        controller_state.update_from_gamepad(input.to_gamepad());
+        export.send(X360_IN_ENDPOINT, &controller_state.to_packet());
+        while let Some(report) = export.try_recv() {
+            if let Some(feedback) = parse_x360_output(&report.data) {
+                input.apply_feedback(&feedback);
+            }
+        }
+        if !export.is_configured() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+}
+
+// Streams the pad to another instance running `--input network`, which does the USB side.
+async fn run_network<I: ControllerInput>(input: &mut I, target: &str) {
+    let mut sender = match NetworkSender::connect(target) {
+        Ok(sender) => sender,
+        Err(err) => panic!("{}", err),
+    };
+    log!("Sending to {}", target);
+
+    loop {
+        let _res = input.get_next_inputs().await;
+        if let Err(err) = sender.send(input.to_gamepad(), Instant::now()) {
+            log_error!("{}", err);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        for feedback in sender.poll_feedback() {
+            input.apply_feedback(&feedback);
+        }
+    }
+}
+
+fn selected_output() -> String {
+    // `--send <address>` on its own is short for `--output network`.
+    arg_value("--output")
+        .or_else(|| arg_value("--send").map(|_| "network".to_string()))
+        .unwrap_or_else(|| "x360".to_string())
+}
+
+async fn run_output<I: ControllerInput>(input: &mut I, config: &Config) {
+    let send_to = arg_value("--send").or_else(|| config.network.send_to.clone());
+    match selected_output().as_str() {
+        "x360" => run_x360(input).await,
+        "ds4" => run_ds4(input).await,
+        "switch" => run_switch_pro(input).await,
+        "xboxone" => run_xbox_one(input).await,
+        "xid" => run_xid(input, &config.xid).await,
+        "hid" => run_hid_gamepad(input, &config.hid_gamepad).await,
+        "keyboard" => run_keyboard_mouse(input, &config.keyboard_mouse).await,
+        "usbip" => run_usbip(input, &config.usbip).await,
+        "network" => match send_to {
+            Some(target) => run_network(input, &target).await,
+            None => panic!("The network output needs --send <host:port> or network.send_to"),
+        },
+        other => panic!(
+            "Unknown output {}, expected x360, ds4, switch, xboxone, xid, hid, keyboard, usbip \
+             or network",
+            other
+        ),
+    }
+}
+
+// With `--dsu`, motion and buttons also go to emulators over the cemuhook protocol.
+async fn run_with_dsu<I: ControllerInput>(input: &mut I, config: &Config) {
+    if !has_arg("--dsu") {
+        return run_output(input, config).await;
+    }
+    let server = match DsuServer::bind(&config.dsu) {
+        Ok(server) => server,
+        Err(err) => panic!("{}", err),
+    };
+    run_output(&mut DsuPublisher::new(input, server), config).await;
+}
+
+#[cfg(feature = "web")]
+fn web_address(config: &Config) -> Option<String> {
+    arg_value("--web").or_else(|| config.web.listen.clone())
+}
+
+#[cfg(not(feature = "web"))]
+fn web_address(_config: &Config) -> Option<String> {
+    if has_arg("--web") {
+        panic!("--web needs a build with `--features web`");
+    }
+    None
+}
+
+// With `--control <path>` or `control.socket`, scripts can inspect and reconfigure the input,
+// `--web <address>` does the same from a browser. Also switches `profiles` by chord or device,
+// and reloads the mapping file when it's saved.
+async fn run_with_control<I: ConfigurableInput>(input: &mut I, config: &Config) {
+    let socket = arg_value("--control")
+        .map(PathBuf::from)
+        .or_else(|| config.control.socket.clone());
+    let web = web_address(config);
+    let config_path = arg_value("--config").map(PathBuf::from);
+    let watch = config.control.watch && config_path.is_some();
+    if socket.is_none() && web.is_none() && config.profiles.is_empty() && !watch {
+        return run_with_dsu(input, config).await;
+    }
+    let mut server = ControlServer::new();
+    if let Some(socket) = socket {
+        if let Err(err) = server.listen(&socket) {
+            panic!("{}", err);
+        }
+    }
+    #[cfg(feature = "web")]
+    if let Some(address) = web {
+        if let Err(err) = web::serve(&address, server.handle()) {
+            panic!("{}", err);
+        }
+    }
+    let mut outputs = vec![selected_output()];
+    if has_arg("--dsu") {
+        outputs.push("dsu".to_string());
+    }
+    let session = ControlSession::new(config.clone(), config_path, outputs);
+    let mut controlled = ControlledInput::new(input, server, session);
+    if watch {
+        if let Err(err) = controlled.watch_mapping() {
+            log_error!("Not reloading the mapping file on changes: {}", err);
+        }
+    }
+    run_with_dsu(&mut controlled, config).await;
+}
+
+// `--tui` shows inputs, the pad, outgoing reports and the log on a terminal dashboard.
+async fn run_with_dashboard<I: ConfigurableInput>(input: &mut I, config: &Config) {
+    if !has_arg("--tui") {
+        return run_with_control(input, config).await;
+    }
+    dashboard::start(selected_output());
+    run_with_control(&mut DashboardInput::new(input), config).await;
+}
+
+// Runs until the output gives up or Ctrl-C. Both return from here, so everything set up on the
+// way is dropped and cleans up after itself, e.g. the control socket.
+async fn run_input<I: ConfigurableInput>(input: &mut I, config: &Config) {
+    let _dashboard = DashboardGuard;
+    tokio::select! {
+        _ = run_with_dashboard(input, config) => {}
+        _ = tokio::signal::ctrl_c() => log!("Stopping"),
+    }
+}
+
+// Restores the terminal however the input loop ends, panics included.
+struct DashboardGuard;
+
+impl Drop for DashboardGuard {
+    fn drop(&mut self) {
+        dashboard::stop();
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::result::Result<(), String> {
+    let config = load_config();
+
+    match arg_value("--input").as_deref() {
+        // Every pad gilrs finds drives the one output.
+        None | Some("gilrs") => {
+            let mut input = MergedInput::new(GilRsInput::discover_configured(&config)?)?;
+            input.prep_for_input_events();
+            run_input(&mut input, &config).await;
+        }
+        // Likewise every Wii remote, nunchuks come along with their remote.
+        Some("wii") => {
+            let mut input = MergedInput::new(XWiiInput::discover_configured(&config))?;
+            input.prep_for_input_events();
+            run_input(&mut input, &config).await;
+        }
+        Some("balance_board") => {
+            let mut input = MergedInput::new(BalanceBoardInput::discover_configured(&config))?;
+            input.prep_for_input_events();
+            run_input(&mut input, &config).await;
+        }
+        Some("network") => {
+            let mut input = NetworkInput::bind(&config.network)?;
+            input.prep_for_input_events();
+            run_input(&mut input, &config).await;
+        }
+        Some(other) => {
+            return Err(format!(
+                "Unknown input {}, expected gilrs, wii, balance_board or network",
+                other
+            ))
+        }
+    }
 
     Ok(())
 }
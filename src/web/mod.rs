@@ -0,0 +1,149 @@
+use std::net::TcpListener as StdTcpListener;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::control::{ControlHandle, RpcClient};
+
+// Browser UI for setup and monitoring. The page talks the control socket's JSON-RPC over a
+// WebSocket at /ws, so it can do whatever scripts can.
+
+const INDEX_HTML: &str = include_str!("index.html");
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct WebConfig {
+    // Off unless set here or with `--web <address>`, e.g. "0.0.0.0:8080".
+    pub listen: Option<String>,
+}
+
+// Needs to be called from within the tokio runtime.
+pub fn serve(listen: &str, handle: ControlHandle) -> Result<(), String> {
+    let listener = StdTcpListener::bind(listen)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .map_err(|err| format!("Could not listen on {}: {}", listen, err))?;
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/ws", get(websocket))
+        .with_state(handle);
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, app).await {
+            log_error!("Web interface: {}", err);
+        }
+    });
+    log!("Web interface on http://{}", listen);
+    Ok(())
+}
+
+async fn index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+async fn websocket(
+    upgrade: WebSocketUpgrade,
+    headers: HeaderMap,
+    State(handle): State<ControlHandle>,
+) -> Response {
+    if !same_origin(&headers) {
+        return (StatusCode::FORBIDDEN, "Cross-origin WebSocket refused").into_response();
+    }
+    upgrade.on_upgrade(move |socket| serve_socket(socket, RpcClient::new(handle)))
+}
+
+// Browsers let any page open a WebSocket, but say which page did. Only our own page gets in, so
+// other sites open in the browser can't reconfigure the pad. Scripts don't send an Origin.
+fn same_origin(headers: &HeaderMap) -> bool {
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin.to_str().unwrap_or_default(),
+        None => return true,
+    };
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default();
+    match origin.split_once("://") {
+        Some((_, origin_host)) => !host.is_empty() && origin_host.eq_ignore_ascii_case(host),
+        None => false,
+    }
+}
+
+async fn serve_socket(mut socket: WebSocket, mut client: RpcClient) {
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => client.handle_message(&text).await,
+                // Pings are answered for us.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => None,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+            },
+            notification = client.next_notification() => match notification {
+                Some(notification) => Some(notification),
+                None => return,
+            },
+        };
+        if let Some(reply) = reply {
+            if socket.send(Message::Text(reply.into())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_web_interface() {
+    use crate::control::ControlServer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    // Something else picks the port, then hands it over.
+    let port = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = format!("127.0.0.1:{}", port);
+    let server = ControlServer::new();
+    serve(&address, server.handle()).unwrap();
+
+    let mut stream = TcpStream::connect(&address).await.unwrap();
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut page = String::new();
+    stream.read_to_string(&mut page).await.unwrap();
+    assert!(page.starts_with("HTTP/1.1 200"));
+    assert!(page.contains("<title>cursed_controls</title>"));
+
+    let mut stream = TcpStream::connect(&address).await.unwrap();
+    let upgrade = "GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+                   Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+    stream.write_all(upgrade.as_bytes()).await.unwrap();
+    let mut reply = [0u8; 256];
+    let length = stream.read(&mut reply).await.unwrap();
+    let reply = String::from_utf8_lossy(&reply[..length]);
+    assert!(reply.starts_with("HTTP/1.1 101"));
+    // From the example in RFC 6455
+    assert!(reply.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    for (origin, status) in [
+        ("http://localhost", "HTTP/1.1 101"),
+        ("http://evil.example", "HTTP/1.1 403"),
+        ("null", "HTTP/1.1 403"),
+    ] {
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        let upgrade = upgrade.replace("\r\n\r\n", &format!("\r\nOrigin: {}\r\n\r\n", origin));
+        stream.write_all(upgrade.as_bytes()).await.unwrap();
+        let mut reply = [0u8; 256];
+        let length = stream.read(&mut reply).await.unwrap();
+        assert!(String::from_utf8_lossy(&reply[..length]).starts_with(status));
+    }
+}